@@ -0,0 +1,148 @@
+use colored::Colorize;
+use std::process;
+
+use super::remote::is_server_running;
+use crate::config;
+use crate::server::route;
+use crate::Args;
+
+use config::schema;
+
+pub async fn check_databases(db: Option<String>, repair: bool, args: Args) {
+    let config = config::load_configuration(Some(args));
+
+    if is_server_running(&config).await {
+        println!("[Check] The server is running, stop it before checking the databases");
+        process::exit(1);
+    }
+
+    let databases = match db {
+        Some(db) => vec![db],
+        None => route::get_existing_routes(&config.storage.path),
+    };
+
+    let mut healthy = true;
+
+    for database in databases {
+        healthy &= check_database(&config, &database, repair);
+    }
+
+    for file in route::orphaned_files(&config.storage.path) {
+        let name = file.file_name().unwrap().to_string_lossy().to_string();
+
+        if repair {
+            match route::quarantine_dustdata(&config.storage.path, &name) {
+                Ok(quarantine) => println!(
+                    "[Check] orphaned file {} moved to {}",
+                    name,
+                    quarantine.display()
+                ),
+                Err(e) => {
+                    println!("[Check] orphaned file {}: {}", name.red(), e);
+                    healthy = false;
+                }
+            }
+        } else {
+            println!("[Check] orphaned file {}", name.yellow());
+            healthy = false;
+        }
+    }
+
+    if healthy {
+        println!("[Check] Done. {}", "no problems found".green());
+    } else {
+        println!(
+            "[Check] Done. {}, run with --repair to fix them",
+            "problems found".red()
+        );
+        process::exit(1);
+    }
+}
+
+fn check_database(config: &schema::RustbaseConfig, database: &str, repair: bool) -> bool {
+    if !config.storage.path.join(database).is_dir() {
+        println!("[Check] {}: not found", database.red());
+        return false;
+    }
+
+    // the database is only opened for writing to repair it
+    let validation =
+        match route::open_dustdata_read_only(config, database, route::validate_dustdata) {
+            Ok(validation) => validation,
+            Err(e) => {
+                println!("[Check] {}: could not be opened: {}", database.red(), e);
+
+                if repair {
+                    return match route::quarantine_dustdata(&config.storage.path, database) {
+                        Ok(quarantine) => {
+                            println!("[Check] {} moved to {}", database, quarantine.display());
+                            true
+                        }
+                        Err(e) => {
+                            println!("[Check] {} could not be quarantined: {}", database.red(), e);
+                            false
+                        }
+                    };
+                }
+
+                return false;
+            }
+        };
+
+    let (keys, corrupt) = match validation {
+        Ok(result) => result,
+        Err(e) => {
+            println!(
+                "[Check] {}: keys could not be listed: {}",
+                database.red(),
+                e
+            );
+            return false;
+        }
+    };
+
+    if corrupt.is_empty() {
+        println!("[Check] {}: ok ({} keys)", database.green(), keys);
+        return true;
+    }
+
+    println!(
+        "[Check] {}: {} of {} keys are corrupt",
+        database.red(),
+        corrupt.len(),
+        keys
+    );
+
+    for key in &corrupt {
+        println!("  - {}", key);
+    }
+
+    if !repair {
+        return false;
+    }
+
+    let mut dd = match route::open_dustdata(config, database) {
+        Ok(dd) => dd,
+        Err(e) => {
+            println!("[Check] {}: could not be opened: {}", database.red(), e);
+            return false;
+        }
+    };
+
+    for key in &corrupt {
+        dd.delete(key).ok();
+    }
+
+    if let Err(e) = dd.flush() {
+        println!(
+            "[Check] {}: could not be flushed: {:?}",
+            database.red(),
+            e.code
+        );
+        return false;
+    }
+
+    println!("[Check] {}: corrupt keys removed", database);
+
+    true
+}
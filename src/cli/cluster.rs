@@ -0,0 +1,62 @@
+use bson::Bson;
+use colored::Colorize;
+use std::process;
+
+use super::remote::connect_to_server;
+use crate::config;
+use crate::server::wirewave::server::{ReqHeader, Request, Type};
+use crate::Args;
+
+pub async fn rebalance(username: Option<String>, password: Option<String>, args: Args) {
+    let config = config::load_configuration(Some(args));
+
+    let mut client = match connect_to_server(&config, username, password).await {
+        Ok(Some(client)) => client,
+        Ok(None) => {
+            println!("[Cluster] the router is not running");
+            process::exit(1);
+        }
+        Err(e) => {
+            println!("[Cluster] {}", e);
+            process::exit(1);
+        }
+    };
+
+    let request = Request {
+        header: ReqHeader {
+            type_: Type::Cluster,
+            auth: None,
+        },
+        body: bson::doc! { "action": "rebalance" },
+    };
+
+    println!("[Cluster] rebalancing, this can take a while...");
+
+    let response = match client.request(&request).await {
+        Ok(response) => response,
+        Err(e) => {
+            println!("[Cluster] {} (does the router require --username?)", e);
+            process::exit(1);
+        }
+    };
+
+    if response.header.is_error {
+        println!(
+            "[Cluster] rebalance failed: {}",
+            response
+                .header
+                .messages
+                .unwrap_or_default()
+                .join(", ")
+                .red()
+        );
+        process::exit(1);
+    }
+
+    let moved = match response.body {
+        Some(Bson::Document(body)) => body.get_i64("moved").unwrap_or_default(),
+        _ => 0,
+    };
+
+    println!("[Cluster] Done. {} keys moved", moved.to_string().green());
+}
@@ -0,0 +1,40 @@
+use colored::Colorize;
+use std::process;
+
+use crate::config;
+use crate::{Args, ConfigSubCommand};
+
+pub fn run_config_subcommands(sub_command: ConfigSubCommand, args: Args) {
+    match sub_command {
+        ConfigSubCommand::Check => check_configuration(args),
+    }
+}
+
+fn check_configuration(args: Args) {
+    let config_path = config::configuration_path(Some(args));
+
+    match &config_path {
+        Some(path) => println!("[Config] checking {}", path.display().to_string().cyan()),
+        None => println!("[Config] no configuration file, checking the default configuration"),
+    }
+
+    let config = match config::read_configuration(config_path.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("[Config] {}", e.red());
+            process::exit(1);
+        }
+    };
+
+    match config::validate::validate(&config) {
+        Ok(()) => println!("[Config] Done. {}", "the configuration is valid".green()),
+        Err(problems) => {
+            for problem in &problems {
+                println!("  - {}", problem);
+            }
+
+            println!("[Config] Done. {}", "problems found".red());
+            process::exit(1);
+        }
+    }
+}
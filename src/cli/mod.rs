@@ -1,20 +1,48 @@
+mod check;
+mod cluster;
+mod config;
+mod remote;
 mod snapshot;
+mod transfer;
 mod upgrade;
 
 use std::process;
 
-use crate::SubCommand;
+use crate::{Args, SubCommand};
 
-pub async fn run_subcommands(subcommands: Option<SubCommand>) {
-    if subcommands.is_none() {
-        return;
-    }
-
-    let subcommands = subcommands.unwrap();
+pub async fn run_subcommands(args: Args) {
+    let subcommands = match args.sub_commands.clone() {
+        Some(subcommands) => subcommands,
+        None => return,
+    };
 
     match subcommands {
         SubCommand::Snapshot { sub_command } => {
-            snapshot::run_snapshots_subcommands(sub_command);
+            snapshot::run_snapshots_subcommands(sub_command, args).await;
+        }
+
+        SubCommand::Export {
+            db,
+            format,
+            out,
+            username,
+            password,
+        } => transfer::export_database(db, format, out, username, password, args).await,
+
+        SubCommand::Import {
+            db,
+            input,
+            format,
+            username,
+            password,
+        } => transfer::import_database(db, input, format, username, password, args).await,
+
+        SubCommand::Check { db, repair } => check::check_databases(db, repair, args).await,
+
+        SubCommand::Config { sub_command } => config::run_config_subcommands(sub_command, args),
+
+        SubCommand::Rebalance { username, password } => {
+            cluster::rebalance(username, password, args).await
         }
 
         SubCommand::Upgrade { version } => upgrade::upgrade_rustbase(version).await,
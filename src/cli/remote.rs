@@ -0,0 +1,77 @@
+use bson::{Bson, Document};
+use tokio::net::TcpStream;
+
+use crate::config;
+use crate::server::wirewave::client::Client;
+
+use config::schema;
+
+fn server_address(config: &schema::RustbaseConfig) -> String {
+    let host = if config.net.host == "0.0.0.0" {
+        "127.0.0.1"
+    } else {
+        config.net.host.as_str()
+    };
+
+    format!("{}:{}", host, config.net.port)
+}
+
+/// Whether the local server is running, e.g. before working on its files offline.
+pub async fn is_server_running(config: &schema::RustbaseConfig) -> bool {
+    TcpStream::connect(server_address(config)).await.is_ok()
+}
+
+/// Connects to the local server, returns `None` if it is not running, or an error if it
+/// cannot be used (TLS, authentication).
+pub async fn connect_to_server(
+    config: &schema::RustbaseConfig,
+    username: Option<String>,
+    password: Option<String>,
+) -> Result<Option<Client<TcpStream>>, String> {
+    let mut client = match Client::connect(server_address(config)).await {
+        Ok(client) => client,
+        Err(_) => return Ok(None),
+    };
+
+    if config.net.tls.is_some() {
+        return Err("the server is running with TLS, which is not supported by this command. Stop the server to work offline.".to_string());
+    }
+
+    if let Some(username) = username {
+        let password = password.unwrap_or_default();
+
+        client
+            .authenticate(&username, &password)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(Some(client))
+}
+
+/// Runs a single statement and returns its result.
+pub async fn query_online(
+    client: &mut Client<TcpStream>,
+    database: &str,
+    query: &str,
+    variables: Option<Document>,
+) -> Result<Bson, String> {
+    let response = client
+        .query(database, query, variables)
+        .await
+        .map_err(|e| format!("{} (does the server require --username?)", e))?;
+
+    if response.header.is_error {
+        return Err(response
+            .header
+            .messages
+            .map(|messages| messages.join(", "))
+            .unwrap_or_else(|| format!("{:?}", response.header.status)));
+    }
+
+    // the body is an array with one result per statement
+    match response.body {
+        Some(Bson::Array(mut results)) if !results.is_empty() => Ok(results.remove(0)),
+        _ => Ok(Bson::Null),
+    }
+}
@@ -1,20 +1,87 @@
+use chrono::{DateTime, Utc};
 use dustdata::snapshots::Snapshot;
 use dustdata::storage::lsm::{Lsm, LsmConfig};
+use path_absolutize::*;
+use std::fs;
 use std::path::Path;
 
-use crate::{config, SnapshotSubCommand};
+use super::remote::{connect_to_server, is_server_running, query_online};
+use crate::server::backup::Manifest;
+use crate::server::oplog::{self, OpLogReader};
+use crate::server::route;
+use crate::{config, Args, SnapshotSubCommand};
 
-pub fn run_snapshots_subcommands(subcommands: SnapshotSubCommand) {
+pub async fn run_snapshots_subcommands(subcommands: SnapshotSubCommand, args: Args) {
     match subcommands {
-        SnapshotSubCommand::Restore { path, db } => restore_snapshot(path, db),
-        SnapshotSubCommand::Create { db, path } => create_snapshot(db, path),
+        SnapshotSubCommand::Restore {
+            db,
+            until: Some(until),
+            ..
+        } => point_in_time_restore(db, until, args).await,
+        SnapshotSubCommand::Restore {
+            path, db, latest, ..
+        } => {
+            let path = if latest {
+                latest_backup(&db, args.clone())
+            } else {
+                path
+            };
+
+            if let Some(path) = path {
+                restore_snapshot(path, db, args);
+            }
+        }
+        SnapshotSubCommand::List => list_backups(args),
+        SnapshotSubCommand::Create {
+            db,
+            path,
+            username,
+            password,
+        } => create_snapshot(db, path, username, password, args).await,
     }
 }
 
-fn create_snapshot(db: String, path: String) {
+async fn create_snapshot(
+    db: String,
+    path: String,
+    username: Option<String>,
+    password: Option<String>,
+    args: Args,
+) {
     println!("[Snapshot] Creating snapshot of {} to {}", db, path);
     let snapshot_path = Path::new(&path);
-    let config = config::load_configuration(None);
+    let config = config::load_configuration(Some(args));
+
+    // a running server owns the database files, so it has to create the snapshot itself
+    let client = connect_to_server(&config, username, password)
+        .await
+        .unwrap_or_else(|e| {
+            println!("[Snapshot] {}", e);
+            std::process::exit(1);
+        });
+
+    if let Some(mut client) = client {
+        let snapshot_path = snapshot_path.absolutize().unwrap().to_path_buf();
+
+        let variables = bson::doc! {
+            "db": db.clone(),
+            "path": snapshot_path.to_string_lossy().to_string(),
+        };
+
+        match query_online(
+            &mut client,
+            &db,
+            "snapshot database $db to $path",
+            Some(variables),
+        )
+        .await
+        {
+            Ok(_) => println!("[Snapshot] Done."),
+            Err(e) => println!("[Snapshot] {}", e),
+        }
+
+        return;
+    }
 
     let db_path = config.storage.path.join(&db);
 
@@ -32,10 +99,10 @@ fn create_snapshot(db: String, path: String) {
     println!("[Snapshot] Done.");
 }
 
-fn restore_snapshot(path: String, db: String) {
+fn restore_snapshot(path: String, db: String, args: Args) {
     println!("[Restore] Restoring database from {} to {}", path, db);
     let snapshot_path = Path::new(&path);
-    let config = config::load_configuration(None);
+    let config = config::load_configuration(Some(args));
 
     let db_path = config.storage.path.join(db);
 
@@ -44,8 +111,239 @@ fn restore_snapshot(path: String, db: String) {
         return;
     }
 
-    let snapshot = Snapshot::load_snapshot(snapshot_path.to_path_buf());
+    let snapshot_file = match route::find_snapshot_file(snapshot_path) {
+        Some(file) => file,
+        None => {
+            println!("[Restore] No snapshot found in {}", path);
+            return;
+        }
+    };
+
+    let snapshot = Snapshot::load_snapshot(snapshot_file);
 
     Lsm::load_snapshot(db_path, snapshot);
     println!("[Snapshot] Done.");
 }
+
+async fn point_in_time_restore(db: String, until: String, args: Args) {
+    let until = match DateTime::parse_from_rfc3339(&until) {
+        Ok(until) => until.with_timezone(&Utc),
+        Err(e) => {
+            println!("[Restore] Invalid time {}: {}", until, e);
+            return;
+        }
+    };
+
+    let config = config::load_configuration(Some(args));
+
+    let oplog_path = match oplog::oplog_path(&config) {
+        Some(path) => path,
+        None => {
+            println!("[Restore] The operation log is not enabled");
+            return;
+        }
+    };
+
+    if is_server_running(&config).await {
+        println!("[Restore] The server is running, stop it before restoring");
+        return;
+    }
+
+    println!("[Restore] Restoring {} as of {}", db, until.to_rfc3339());
+
+    let backup = match &config.backup {
+        Some(backup) => match Manifest::load(&backup.path) {
+            Ok(manifest) => manifest
+                .latest_before(&db, Some(until))
+                .map(|(entry, time)| (backup.path.join(&entry.id).join(&db), time)),
+            Err(e) => {
+                println!("[Restore] {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    // everything that can fail is checked before the database is touched
+    let snapshot = match &backup {
+        Some((backup_path, time)) => match route::find_snapshot_file(backup_path) {
+            Some(file) => {
+                println!("[Restore] Using backup taken at {}", time.to_rfc3339());
+                Some((file, time.timestamp_millis()))
+            }
+            None => {
+                println!("[Restore] No snapshot found in {}", backup_path.display());
+                return;
+            }
+        },
+        None => {
+            println!("[Restore] No backup before this time, replaying the whole operation log");
+            None
+        }
+    };
+
+    let reader = match OpLogReader::open(&oplog::database_log_path(&oplog_path, &db)) {
+        Ok(reader) => Some(reader),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && snapshot.is_some() => None,
+        Err(e) => {
+            println!("[Restore] Cannot read the operation log of {}: {}", db, e);
+            return;
+        }
+    };
+
+    // the database is rebuilt next to the live one, which is only replaced once it is complete
+    let restore_path = config.storage.path.join(route::RESTORE_DIRECTORY);
+    let rebuilt_path = restore_path.join(&db);
+    let previous_path = restore_path.join(format!("{}-previous", db));
+
+    for path in [&rebuilt_path, &previous_path] {
+        if path.exists() {
+            if let Err(e) = fs::remove_dir_all(path) {
+                println!("[Restore] Cannot clean {}: {}", path.display(), e);
+                return;
+            }
+        }
+    }
+
+    if let Err(e) = fs::create_dir_all(&rebuilt_path) {
+        println!("[Restore] Cannot create {}: {}", rebuilt_path.display(), e);
+        return;
+    }
+
+    let since = snapshot.map(|(file, time)| {
+        Lsm::load_snapshot(rebuilt_path.clone(), Snapshot::load_snapshot(file));
+        time
+    });
+
+    let mut dd = route::create_dustdata_at(&config, &rebuilt_path);
+    let mut replayed = 0;
+
+    for entry in reader.into_iter().flatten() {
+        let timestamp = entry.timestamp.timestamp_millis();
+
+        if since.is_some_and(|since| timestamp <= since) {
+            continue;
+        }
+
+        if timestamp > until.timestamp_millis() {
+            break;
+        }
+
+        if let Err(e) = oplog::apply_entry(&mut dd, &entry) {
+            println!(
+                "[Restore] Cannot replay operation {}: {:?}, {} is unchanged",
+                entry.seq, e.code, db
+            );
+            return;
+        }
+
+        replayed += 1;
+    }
+
+    if let Err(e) = dd.flush() {
+        println!("[Restore] Cannot flush: {:?}, {} is unchanged", e.code, db);
+        return;
+    }
+
+    drop(dd);
+
+    let db_path = config.storage.path.join(&db);
+
+    if db_path.exists() {
+        if let Err(e) = fs::rename(&db_path, &previous_path) {
+            println!(
+                "[Restore] Cannot move {}: {}, {} is unchanged",
+                db_path.display(),
+                e,
+                db
+            );
+            return;
+        }
+    }
+
+    if let Err(e) = fs::rename(&rebuilt_path, &db_path) {
+        println!("[Restore] Cannot replace {}: {}", db_path.display(), e);
+
+        if previous_path.exists() {
+            fs::rename(&previous_path, &db_path).ok();
+        }
+
+        return;
+    }
+
+    fs::remove_dir_all(&restore_path).ok();
+
+    println!("[Restore] Done. {} operations replayed", replayed);
+}
+
+fn latest_backup(db: &str, args: Args) -> Option<String> {
+    let config = config::load_configuration(Some(args));
+
+    let backup = match &config.backup {
+        Some(backup) => backup,
+        None => {
+            println!("[Restore] Scheduled backups are not configured");
+            return None;
+        }
+    };
+
+    let manifest = match Manifest::load(&backup.path) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            println!("[Restore] {}", e);
+            return None;
+        }
+    };
+
+    match manifest.latest_for(db) {
+        Some((entry, _)) => {
+            println!("[Restore] Using backup {} ({})", entry.id, entry.timestamp);
+
+            Some(
+                backup
+                    .path
+                    .join(&entry.id)
+                    .join(db)
+                    .to_string_lossy()
+                    .to_string(),
+            )
+        }
+        None => {
+            println!("[Restore] No backup of {} found", db);
+            None
+        }
+    }
+}
+
+fn list_backups(args: Args) {
+    let config = config::load_configuration(Some(args));
+
+    let backup = match &config.backup {
+        Some(backup) => backup,
+        None => {
+            println!("[Snapshot] Scheduled backups are not configured");
+            return;
+        }
+    };
+
+    let manifest = match Manifest::load(&backup.path) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            println!("[Snapshot] {}", e);
+            return;
+        }
+    };
+
+    if manifest.backups.is_empty() {
+        println!("[Snapshot] No backups found in {}", backup.path.display());
+        return;
+    }
+
+    for entry in manifest.backups {
+        println!("{} ({})", entry.id, entry.timestamp);
+
+        for database in entry.databases {
+            println!("  - {}: {} bytes", database.name, database.size);
+        }
+    }
+}
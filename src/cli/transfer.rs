@@ -0,0 +1,430 @@
+use bson::{Bson, Document};
+use colored::Colorize;
+use dustdata::DustData;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::process;
+use tokio::net::TcpStream;
+
+use super::remote::{connect_to_server, query_online};
+use crate::config;
+use crate::server::route;
+use crate::server::wirewave::client::Client;
+use crate::server::wirewave::server::Status;
+use crate::utils::extjson;
+use crate::{Args, TransferFormat};
+
+const PROGRESS_INTERVAL: usize = 1000;
+
+enum Target {
+    Offline(DustData),
+    Online(Client<TcpStream>),
+}
+
+pub async fn export_database(
+    db: String,
+    format: TransferFormat,
+    out: String,
+    username: Option<String>,
+    password: Option<String>,
+    args: Args,
+) {
+    let config = config::load_configuration(Some(args));
+
+    let mut target = match connect_to_server(&config, username, password).await {
+        Ok(Some(client)) => {
+            println!("[Export] server is running, exporting through Wirewave");
+            Target::Online(client)
+        }
+        Err(e) => {
+            println!("[Export] {}", e);
+            process::exit(1);
+        }
+        Ok(None) => {
+            if !config.storage.path.join(&db).exists() {
+                println!("[Export] Database {} does not exist", db);
+                process::exit(1);
+            }
+
+            Target::Offline(route::create_dustdata(&config, Some(&db)))
+        }
+    };
+
+    let keys = match &mut target {
+        Target::Offline(dd) => dd.list_keys().unwrap_or_else(|e| {
+            println!("[Export] could not list the keys of {}: {:?}", db, e.code);
+            process::exit(1);
+        }),
+        Target::Online(client) => match query_online(client, &db, "list", None).await {
+            Ok(Bson::Array(keys)) => keys
+                .into_iter()
+                .filter_map(|key| key.as_str().map(|key| key.to_string()))
+                .collect(),
+            Ok(_) => Vec::new(),
+            Err(e) => {
+                println!("[Export] {}", e);
+                process::exit(1);
+            }
+        },
+    };
+
+    println!(
+        "[Export] Exporting {} keys of {} to {}",
+        keys.len().to_string().green(),
+        db,
+        out
+    );
+
+    let file = File::create(&out).unwrap_or_else(|e| {
+        println!("[Export] could not create {}: {}", out, e);
+        process::exit(1);
+    });
+    let mut writer = BufWriter::new(file);
+
+    let mut exported = 0;
+    let mut skipped = 0;
+
+    // values are fetched and written one at a time, so only the key list is kept in memory
+    for key in keys {
+        let value = match &mut target {
+            Target::Offline(dd) => dd.get(&key).unwrap_or_else(|e| {
+                println!("[Export] could not read {}: {:?}", key, e.code);
+                process::exit(1);
+            }),
+            Target::Online(client) => {
+                // `get $key` would return the variable, a quoted name reads the stored key
+                let query = format!("get `{}`", key.replace('`', "``"));
+
+                match query_online(client, &db, &query, None).await {
+                    Ok(value) => Some(value),
+                    Err(e) => {
+                        println!("[Export] skipping {}: {}", key, e);
+                        skipped += 1;
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let value = match value {
+            Some(value) => value,
+            None => continue,
+        };
+
+        if let Err(e) = write_record(&mut writer, &format, &key, value) {
+            println!("[Export] could not write {} to {}: {}", key, out, e);
+            process::exit(1);
+        }
+
+        exported += 1;
+
+        if exported % PROGRESS_INTERVAL == 0 {
+            println!("[Export] {} keys exported", exported);
+        }
+    }
+
+    if let Err(e) = writer.flush() {
+        println!("[Export] could not write {}: {}", out, e);
+        process::exit(1);
+    }
+
+    println!(
+        "[Export] Done. {} keys exported, {} skipped",
+        exported.to_string().green(),
+        skipped
+    );
+
+    // an incomplete export must not pass for a backup
+    if skipped > 0 {
+        println!("[Export] {}", "the export is incomplete".red());
+        process::exit(1);
+    }
+}
+
+pub async fn import_database(
+    db: String,
+    input: String,
+    format: Option<TransferFormat>,
+    username: Option<String>,
+    password: Option<String>,
+    args: Args,
+) {
+    let config = config::load_configuration(Some(args));
+
+    let format = format.unwrap_or(if input.ends_with(".bson") {
+        TransferFormat::Bson
+    } else {
+        TransferFormat::Jsonl
+    });
+
+    let file = File::open(&input).unwrap_or_else(|e| {
+        println!("[Import] could not open {}: {}", input, e);
+        process::exit(1);
+    });
+    let mut reader = BufReader::new(file);
+
+    let mut target = match connect_to_server(&config, username, password).await {
+        Ok(Some(client)) => {
+            println!("[Import] server is running, importing through Wirewave");
+            Target::Online(client)
+        }
+        Err(e) => {
+            println!("[Import] {}", e);
+            process::exit(1);
+        }
+        Ok(None) => Target::Offline(route::create_dustdata(&config, Some(&db))),
+    };
+
+    println!("[Import] Importing {} into {}", input, db);
+
+    let mut imported = 0;
+    let mut line = 0;
+
+    while let Some(record) = read_record(&mut reader, &format) {
+        line += 1;
+
+        let (key, value) = match record {
+            Ok(record) => record,
+            Err(e) => {
+                println!("[Import] record {} is invalid: {}", line, e);
+                process::exit(1);
+            }
+        };
+
+        let result = match &mut target {
+            Target::Offline(dd) => upsert_offline(dd, &key, value),
+            Target::Online(client) => upsert_online(client, &db, key.clone(), value).await,
+        };
+
+        if let Err(e) = result {
+            println!("[Import] could not import {}: {}", key, e);
+            process::exit(1);
+        }
+
+        imported += 1;
+
+        if imported % PROGRESS_INTERVAL == 0 {
+            println!("[Import] {} keys imported", imported);
+        }
+    }
+
+    if let Target::Offline(dd) = &mut target {
+        if let Err(e) = dd.flush() {
+            println!("[Import] could not flush {}: {:?}", db, e.code);
+            process::exit(1);
+        }
+    }
+
+    println!(
+        "[Import] Done. {} keys imported",
+        imported.to_string().green()
+    );
+}
+
+fn upsert_offline(dd: &mut DustData, key: &str, value: Bson) -> Result<(), String> {
+    match dd.insert(key, value.clone()) {
+        Ok(_) => Ok(()),
+        Err(e) => match e.code {
+            dustdata::ErrorCode::KeyExists => dd.update(key, value).map_err(|e| format!("{:?}", e)),
+            _ => Err(format!("{:?}", e)),
+        },
+    }
+}
+
+async fn upsert_online(
+    client: &mut Client<TcpStream>,
+    database: &str,
+    key: String,
+    value: Bson,
+) -> Result<(), String> {
+    let variables = bson::doc! {
+        "key": key,
+        "value": value,
+    };
+
+    let response = client
+        .query(database, "insert $value into $key", Some(variables.clone()))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if !matches!(response.header.status, Status::AlreadyExists) {
+        return if response.header.is_error {
+            Err(format!("{:?}", response.header.messages))
+        } else {
+            Ok(())
+        };
+    }
+
+    query_online(client, database, "update $value into $key", Some(variables))
+        .await
+        .map(|_| ())
+}
+
+fn write_record<W: Write>(
+    writer: &mut W,
+    format: &TransferFormat,
+    key: &str,
+    value: Bson,
+) -> std::io::Result<()> {
+    match format {
+        TransferFormat::Jsonl => {
+            // canonical extended JSON keeps every BSON type (int32 vs int64, dates, binary...)
+            let record = serde_json::json!({
+                "key": key,
+                "value": extjson::to_canonical(value),
+            });
+
+            writeln!(writer, "{}", record)
+        }
+
+        TransferFormat::Bson => {
+            let record = bson::doc! {
+                "key": key,
+                "value": value,
+            };
+
+            record.to_writer(writer).map_err(std::io::Error::other)
+        }
+    }
+}
+
+fn read_record<R: BufRead>(
+    reader: &mut R,
+    format: &TransferFormat,
+) -> Option<Result<(String, Bson), String>> {
+    let record = match format {
+        TransferFormat::Jsonl => {
+            let mut line = String::new();
+
+            loop {
+                line.clear();
+
+                match reader.read_line(&mut line) {
+                    Ok(0) => return None,
+                    Ok(_) if line.trim().is_empty() => continue,
+                    Ok(_) => break,
+                    Err(e) => return Some(Err(e.to_string())),
+                }
+            }
+
+            let value: serde_json::Value = match serde_json::from_str(&line) {
+                Ok(value) => value,
+                Err(e) => return Some(Err(e.to_string())),
+            };
+
+            match extjson::from_value(value) {
+                Ok(Bson::Document(doc)) => doc,
+                Ok(_) => return Some(Err("record must be an object".to_string())),
+                Err(e) => return Some(Err(e.to_string())),
+            }
+        }
+
+        TransferFormat::Bson => {
+            match reader.fill_buf() {
+                Ok([]) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e.to_string())),
+            }
+
+            match Document::from_reader(reader) {
+                Ok(doc) => doc,
+                Err(e) => return Some(Err(e.to_string())),
+            }
+        }
+    };
+
+    let key = match record.get_str("key") {
+        Ok(key) => key.to_string(),
+        Err(_) => return Some(Err("record has no string \"key\"".to_string())),
+    };
+
+    match record.get("value") {
+        Some(value) => Some(Ok((key, value.clone()))),
+        None => Some(Err("record has no \"value\"".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::oid::ObjectId;
+    use bson::spec::BinarySubtype;
+    use bson::{Binary, DateTime};
+    use std::io::Cursor;
+
+    use crate::utils::decimal;
+
+    fn values() -> Vec<(&'static str, Bson)> {
+        vec![
+            ("int32", Bson::Int32(1)),
+            ("int64", Bson::Int64(1)),
+            ("double", Bson::Double(1.5)),
+            ("string", Bson::String("été".to_string())),
+            ("null", Bson::Null),
+            (
+                "date",
+                Bson::DateTime(DateTime::from_millis(1_700_000_000_000)),
+            ),
+            ("oid", Bson::ObjectId(ObjectId::from_bytes([7; 12]))),
+            (
+                "binary",
+                Bson::Binary(Binary {
+                    subtype: BinarySubtype::Generic,
+                    bytes: vec![0, 1, 255],
+                }),
+            ),
+            ("decimal", Bson::Decimal128(decimal::parse("1.10").unwrap())),
+            (
+                "document",
+                Bson::Document(bson::doc! { "n": 1_i64, "list": [1, 2.5, "a"] }),
+            ),
+        ]
+    }
+
+    fn round_trip(format: TransferFormat) {
+        let mut written = Vec::new();
+
+        for (key, value) in values() {
+            write_record(&mut written, &format, key, value).unwrap();
+        }
+
+        let mut reader = Cursor::new(written);
+
+        for (key, value) in values() {
+            let (read_key, read_value) = read_record(&mut reader, &format).unwrap().unwrap();
+
+            assert_eq!(read_key, key);
+            assert_eq!(read_value, value, "{}", key);
+        }
+
+        assert!(read_record(&mut reader, &format).is_none());
+    }
+
+    #[test]
+    fn keeps_every_type_through_extended_json() {
+        round_trip(TransferFormat::Jsonl);
+    }
+
+    #[test]
+    fn keeps_every_type_through_bson() {
+        round_trip(TransferFormat::Bson);
+    }
+
+    #[test]
+    fn refuses_an_invalid_record() {
+        for line in ["[1]", "{\"value\": 1}", "{\"key\": \"k\"}", "not json"] {
+            let mut reader = Cursor::new(format!("{}\n", line));
+
+            assert!(
+                read_record(&mut reader, &TransferFormat::Jsonl)
+                    .unwrap()
+                    .is_err(),
+                "{}",
+                line
+            );
+        }
+
+        let mut reader = Cursor::new("\n\n");
+        assert!(read_record(&mut reader, &TransferFormat::Jsonl).is_none());
+    }
+}
@@ -0,0 +1,213 @@
+use serde_json::{Map, Value};
+
+pub const ENV_PREFIX: &str = "RUSTBASE_";
+
+enum Kind {
+    String,
+    Integer,
+    Boolean,
+    /// Comma separated strings
+    List,
+}
+
+/// The fields that can be set from the environment, e.g. `net.port` from `RUSTBASE_NET_PORT`
+const OVERRIDES: &[(&str, Kind)] = &[
+    ("threads", Kind::Integer),
+    ("cache_size", Kind::Integer),
+    ("net.host", Kind::String),
+    ("net.port", Kind::Integer),
+    ("net.shutdown_timeout", Kind::Integer),
+    ("net.tls.ca_file", Kind::String),
+    ("net.tls.pem_key_file", Kind::String),
+    ("net.tls.client_ca_file", Kind::String),
+    ("net.tls.client_auth", Kind::String),
+    ("net.http.host", Kind::String),
+    ("net.http.port", Kind::Integer),
+    ("net.http.tls.ca_file", Kind::String),
+    ("net.http.tls.pem_key_file", Kind::String),
+    ("net.http.token_ttl", Kind::Integer),
+    ("net.limits.max_connections", Kind::Integer),
+    ("net.limits.max_connections_per_user", Kind::Integer),
+    ("net.limits.max_connections_per_ip", Kind::Integer),
+    ("net.limits.idle_timeout", Kind::Integer),
+    ("net.limits.request_timeout", Kind::Integer),
+    ("net.limits.rate_limit.requests_per_second", Kind::Integer),
+    ("net.limits.rate_limit.burst", Kind::Integer),
+    ("storage.path", Kind::String),
+    ("storage.dustdata.flush_threshold", Kind::Integer),
+    ("storage.oplog.path", Kind::String),
+    ("storage.oplog.sync", Kind::Boolean),
+    ("auth.enable_auth_bypass", Kind::Boolean),
+    ("auth.auth_type", Kind::String),
+    ("auth.required", Kind::Boolean),
+    ("auth.admin.username", Kind::String),
+    ("auth.admin.password", Kind::String),
+    ("auth.iterations", Kind::Integer),
+    ("backup.path", Kind::String),
+    ("backup.interval", Kind::Integer),
+    ("backup.cron", Kind::String),
+    ("backup.retention.count", Kind::Integer),
+    ("backup.retention.max_age", Kind::Integer),
+    ("cluster.role", Kind::String),
+    ("cluster.primary", Kind::String),
+    ("cluster.nodes", Kind::List),
+    ("cluster.address", Kind::String),
+    ("cluster.election_timeout", Kind::Integer),
+    ("cluster.username", Kind::String),
+    ("cluster.password", Kind::String),
+    ("cluster.max_lag", Kind::Integer),
+];
+
+pub fn variable_name(field: &str) -> String {
+    format!("{}{}", ENV_PREFIX, field.replace('.', "_").to_uppercase())
+}
+
+/// Overrides the fields of `config` with the environment variables that are set
+pub fn apply_overrides(config: &mut Value) -> Result<(), String> {
+    overrides_from(config, |name| std::env::var(name).ok())
+}
+
+/// Overrides the fields of `config` with the variables `lookup` finds
+fn overrides_from(
+    config: &mut Value,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<(), String> {
+    for (field, kind) in OVERRIDES {
+        let name = variable_name(field);
+
+        let raw = match lookup(&name) {
+            Some(raw) => raw,
+            None => continue,
+        };
+
+        let value = match kind {
+            Kind::String => Value::String(raw),
+            Kind::Integer => raw
+                .trim()
+                .parse::<u64>()
+                .map(Value::from)
+                .map_err(|_| format!("{}: expected a positive number, got {}", name, raw))?,
+            Kind::Boolean => raw
+                .trim()
+                .parse::<bool>()
+                .map(Value::Bool)
+                .map_err(|_| format!("{}: expected true or false, got {}", name, raw))?,
+            Kind::List => Value::Array(
+                raw.split(',')
+                    .map(|item| item.trim())
+                    .filter(|item| !item.is_empty())
+                    .map(|item| Value::String(item.to_string()))
+                    .collect(),
+            ),
+        };
+
+        set(config, field, value);
+    }
+
+    Ok(())
+}
+
+/// Sets a dotted field, creating the sections that are missing
+fn set(config: &mut Value, field: &str, value: Value) {
+    let mut current = config;
+    let mut parts = field.split('.').peekable();
+
+    while let Some(part) = parts.next() {
+        if !current.is_object() {
+            *current = Value::Object(Map::new());
+        }
+
+        let object = current.as_object_mut().unwrap();
+
+        if parts.peek().is_none() {
+            object.insert(part.to_string(), value);
+            return;
+        }
+
+        current = object
+            .entry(part)
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn overridden(config: Value, variables: &[(&str, &str)]) -> Result<Value, String> {
+        let variables: HashMap<String, String> = variables
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        let mut config = config;
+        overrides_from(&mut config, |name| variables.get(name).cloned())?;
+
+        Ok(config)
+    }
+
+    #[test]
+    fn names_the_variables_after_the_fields() {
+        assert_eq!(variable_name("net.port"), "RUSTBASE_NET_PORT");
+        assert_eq!(
+            variable_name("net.limits.rate_limit.burst"),
+            "RUSTBASE_NET_LIMITS_RATE_LIMIT_BURST"
+        );
+    }
+
+    #[test]
+    fn sets_the_fields_by_their_kind() {
+        let config = overridden(
+            json!({ "threads": 2, "net": { "host": "0.0.0.0", "port": 23561 } }),
+            &[
+                ("RUSTBASE_NET_PORT", " 24000 "),
+                ("RUSTBASE_STORAGE_OPLOG_SYNC", "false"),
+                ("RUSTBASE_CLUSTER_NODES", "a:1, b:2,,"),
+                ("RUSTBASE_AUTH_ADMIN_USERNAME", "root"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            config,
+            json!({
+                "threads": 2,
+                "net": { "host": "0.0.0.0", "port": 24000 },
+                "storage": { "oplog": { "sync": false } },
+                "cluster": { "nodes": ["a:1", "b:2"] },
+                "auth": { "admin": { "username": "root" } },
+            })
+        );
+    }
+
+    #[test]
+    fn replaces_a_section_that_is_not_an_object() {
+        let config = overridden(
+            json!({ "net": { "limits": null } }),
+            &[("RUSTBASE_NET_LIMITS_RATE_LIMIT_BURST", "5")],
+        )
+        .unwrap();
+
+        assert_eq!(
+            config,
+            json!({ "net": { "limits": { "rate_limit": { "burst": 5 } } } })
+        );
+    }
+
+    #[test]
+    fn refuses_the_invalid_values() {
+        let error = overridden(json!({}), &[("RUSTBASE_THREADS", "-1")]).unwrap_err();
+        assert_eq!(
+            error,
+            "RUSTBASE_THREADS: expected a positive number, got -1"
+        );
+
+        let error = overridden(json!({}), &[("RUSTBASE_AUTH_REQUIRED", "yes")]).unwrap_err();
+        assert_eq!(
+            error,
+            "RUSTBASE_AUTH_REQUIRED: expected true or false, got yes"
+        );
+    }
+}
@@ -1,13 +1,16 @@
+pub mod env;
 pub mod schema;
 pub mod spec;
+pub mod validate;
 
 use crate::utils::get_current_path;
 use crate::Args;
 
 use colored::Colorize;
 use path_absolutize::*;
-use std::fs::File;
-use std::io::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
 
 pub fn default_configuration() -> schema::RustbaseConfig {
     schema::RustbaseConfig {
@@ -15,10 +18,16 @@ pub fn default_configuration() -> schema::RustbaseConfig {
         cache_size: spec::DEFAULT_CACHE_SIZE,
         net: schema::Net {
             host: "0.0.0.0".to_string(),
-            port: "23561".to_string(),
+            port: 23561,
             tls: None,
+            shutdown_timeout: None,
+            listeners: None,
+            http: None,
+            limits: None,
         },
         auth: None,
+        backup: None,
+        cluster: None,
         storage: schema::Storage {
             path: get_current_path()
                 .join("./data")
@@ -26,41 +35,122 @@ pub fn default_configuration() -> schema::RustbaseConfig {
                 .unwrap()
                 .to_path_buf(),
             dustdata: None,
+            oplog: None,
         },
     }
 }
 
+/// Every address to listen on: `net.host` and `net.port` first, then `net.listeners`
+pub fn listeners(config: &schema::RustbaseConfig) -> Vec<schema::Listener> {
+    let main = schema::Listener {
+        host: Some(config.net.host.clone()),
+        port: Some(config.net.port),
+        path: None,
+        mode: None,
+        peer_credentials: None,
+        websocket: None,
+        tls: config.net.tls.clone(),
+        auth_required: None,
+    };
+
+    let mut listeners = vec![main];
+    listeners.extend(config.net.listeners.clone().unwrap_or_default());
+
+    listeners
+}
+
+/// `host:port`, or the path of the unix socket
+pub fn listener_name(listener: &schema::Listener) -> String {
+    match &listener.path {
+        Some(path) => path.display().to_string(),
+        None => format!(
+            "{}:{}",
+            listener.host.clone().unwrap_or_default(),
+            listener.port.unwrap_or_default()
+        ),
+    }
+}
+
+/// Parses the octal permissions of a unix socket, e.g. `660`
+pub fn socket_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("invalid mode {}, expected octal permissions like 660", mode))
+}
+
+/// Loads the configuration, exiting with a readable error when it is invalid
 pub fn load_configuration(args: Option<Args>) -> schema::RustbaseConfig {
-    let mut config = default_configuration();
+    let config_path = configuration_path(args);
 
-    let default_path = get_current_path().join(spec::DEFAULT_CONFIG_NAME);
+    match read_configuration(config_path.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("[Config] {}", e.red());
+            process::exit(1);
+        }
+    }
+}
 
-    if !default_path.exists() {
-        let mut file = File::create(default_path.clone()).unwrap();
-        file.write_all(serde_json::to_string_pretty(&config).unwrap().as_bytes())
-            .unwrap();
+/// The path of the configuration file, the one given in the arguments or the first
+/// default file found next to the executable. `None` when there is no file, the
+/// default configuration is used then.
+pub fn configuration_path(args: Option<Args>) -> Option<PathBuf> {
+    if let Some(config_path) = args.and_then(|args| args.config) {
+        return Some(get_current_path().join(config_path));
     }
 
-    let config_path = if let Some(args) = args {
-        if let Some(config_path) = args.config {
-            get_current_path().join(config_path)
+    spec::DEFAULT_CONFIG_NAMES
+        .iter()
+        .map(|name| get_current_path().join(name))
+        .find(|path| path.exists())
+}
+
+/// Reads the configuration file (JSON, TOML or YAML, from the extension) and applies
+/// the `RUSTBASE_*` environment variables over it
+pub fn read_configuration(path: Option<&Path>) -> Result<schema::RustbaseConfig, String> {
+    let mut value = match path {
+        Some(path) => parse_file(path)?,
+        None => serde_json::to_value(default_configuration()).unwrap(),
+    };
+
+    env::apply_overrides(&mut value)?;
+
+    let origin = path
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|| "default configuration".to_string());
+
+    serde_path_to_error::deserialize(value).map_err(|e| {
+        let field = e.path().to_string();
+        let error = e.into_inner();
+
+        // the root is `.` when the error is not about a field
+        if field == "." {
+            format!("{}: {}", origin, error)
         } else {
-            default_path
+            format!("{}: {}: {}", origin, field, error)
         }
-    } else {
-        default_path
-    };
+    })
+}
 
-    if !config_path.exists() {
-        panic!(
-            "[Config] {} not found",
-            config_path.file_name().unwrap().to_str().unwrap().cyan()
-        );
-    }
+fn parse_file(path: &Path) -> Result<serde_json::Value, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
 
-    let file = File::open(config_path).unwrap();
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("json")
+        .to_lowercase();
 
-    config = serde_json::from_reader(file).unwrap();
+    let parsed = match extension.as_str() {
+        "json" => serde_json::from_str(&content).map_err(|e| e.to_string()),
+        "toml" => toml::from_str(&content).map_err(|e| e.to_string()),
+        "yaml" | "yml" => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+        _ => Err(format!(
+            "unsupported format {}, expected json, toml or yaml",
+            extension
+        )),
+    };
 
-    config
+    parsed.map_err(|e| format!("{}: {}", path.display(), e.trim_end()))
 }
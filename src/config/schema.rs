@@ -1,46 +1,213 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct RustbaseConfig {
     pub threads: usize,
     pub cache_size: usize,
     pub net: Net,
     pub storage: Storage,
     pub auth: Option<Auth>,
+    pub backup: Option<Backup>,
+    pub cluster: Option<Cluster>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Net {
     pub host: String,
-    pub port: String,
+    #[serde(deserialize_with = "deserialize_port")]
+    pub port: u16,
     pub tls: Option<Tls>,
+    pub shutdown_timeout: Option<u64>,
+    pub listeners: Option<Vec<Listener>>,
+    pub http: Option<Http>,
+    pub limits: Option<Limits>,
 }
 
+/// What a client can use of the server, unlimited unless set
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    pub max_connections: Option<usize>,
+    pub max_connections_per_user: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    /// Seconds a connection can wait between two requests
+    pub idle_timeout: Option<u64>,
+    /// Seconds a request can run
+    pub request_timeout: Option<u64>,
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// A token bucket per user, or per address for the anonymous clients
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub requests_per_second: u32,
+    /// The requests that can be sent at once (defaults to `requests_per_second`)
+    pub burst: Option<u32>,
+}
+
+/// The HTTP/JSON gateway, off unless set
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Http {
+    pub host: String,
+    #[serde(deserialize_with = "deserialize_port")]
+    pub port: u16,
+    pub tls: Option<Tls>,
+    /// Seconds before a bearer token expires
+    pub token_ttl: Option<u64>,
+}
+
+/// An address served in addition to `host` and `port`, either `host` and `port` or the
+/// `path` of a unix socket
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Listener {
+    pub host: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_optional_port")]
+    pub port: Option<u16>,
+    pub path: Option<std::path::PathBuf>,
+    pub mode: Option<String>,
+    pub peer_credentials: Option<bool>,
+    /// Carries Wirewave over WebSocket
+    pub websocket: Option<bool>,
+    pub tls: Option<Tls>,
+    pub auth_required: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Tls {
     pub ca_file: String,
     pub pem_key_file: String,
+    pub client_ca_file: Option<String>,
+    pub client_auth: Option<ClientAuth>,
+}
+
+/// Whether the clients must present a certificate signed by `client_ca_file`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ClientAuth {
+    #[serde(rename = "optional")]
+    Optional,
+    #[default]
+    #[serde(rename = "required")]
+    Required,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Storage {
     pub path: std::path::PathBuf,
     pub dustdata: Option<DustDataStorageConfig>,
+    pub oplog: Option<OpLogStorageConfig>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct DustDataStorageConfig {
     pub flush_threshold: usize,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct OpLogStorageConfig {
+    pub path: Option<std::path::PathBuf>,
+    pub sync: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Auth {
     pub enable_auth_bypass: Option<bool>,
     pub auth_type: Option<AuthType>,
+    pub required: Option<bool>,
+    pub admin: Option<AdminUser>,
+    pub iterations: Option<u16>,
 }
 
+/// Created when the server starts if no user has this name
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AdminUser {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub enum AuthType {
+    #[default]
     #[serde(rename = "scram-sha-256")]
     ScramSha256,
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Backup {
+    pub path: std::path::PathBuf,
+    pub interval: Option<u64>,
+    pub cron: Option<String>,
+    pub retention: Option<Retention>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Retention {
+    pub count: Option<usize>,
+    pub max_age: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Cluster {
+    pub role: ClusterRole,
+    pub primary: Option<String>,
+    pub nodes: Option<Vec<String>>,
+    pub address: Option<String>,
+    pub election_timeout: Option<u64>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub max_lag: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ClusterRole {
+    #[serde(rename = "primary")]
+    Primary,
+    #[serde(rename = "replica")]
+    Replica,
+    #[serde(rename = "router")]
+    Router,
+    #[serde(rename = "raft")]
+    Raft,
+}
+
+/// Accepts the port as a number or, as written by the previous versions, as a string
+fn deserialize_port<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Port {
+        Number(i64),
+        String(String),
+    }
+
+    let port = match Port::deserialize(deserializer)? {
+        Port::Number(port) => port.to_string(),
+        Port::String(port) => port,
+    };
+
+    match port.trim().parse::<u16>() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(serde::de::Error::custom(format!(
+            "invalid port {}, expected a number between 1 and 65535",
+            port
+        ))),
+    }
+}
+
+fn deserialize_optional_port<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u16>, D::Error> {
+    deserialize_port(deserializer).map(Some)
+}
@@ -1,2 +1,9 @@
-pub const DEFAULT_CONFIG_NAME: &str = "rustbaseconf.json";
+/// Looked up next to the executable, in this order
+pub const DEFAULT_CONFIG_NAMES: &[&str] = &[
+    "rustbaseconf.json",
+    "rustbaseconf.toml",
+    "rustbaseconf.yaml",
+    "rustbaseconf.yml",
+];
 pub const DEFAULT_CACHE_SIZE: usize = 128 * 1024 * 1024;
+pub const DEFAULT_SCRAM_ITERATIONS: u16 = 15_000;
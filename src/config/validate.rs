@@ -0,0 +1,375 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::path::Path;
+
+use super::schema;
+use crate::server::backup;
+use crate::server::wirewave;
+
+use wirewave::authentication;
+
+const WRITE_CHECK_NAME: &str = ".rustbase_write_check";
+
+/// Checks what the server needs before binding, returning every problem found
+pub fn validate(config: &schema::RustbaseConfig) -> Result<(), Vec<String>> {
+    let mut problems = Vec::new();
+
+    if config.threads == 0 {
+        problems.push("threads: must be at least 1".to_string());
+    }
+
+    let iterations = config.auth.as_ref().and_then(|auth| auth.iterations);
+
+    if iterations.is_some_and(|iterations| iterations < authentication::LEGACY_ITERATIONS) {
+        problems.push(format!(
+            "auth.iterations: must be at least {}",
+            authentication::LEGACY_ITERATIONS
+        ));
+    }
+
+    if let Some(tls) = &config.net.tls {
+        check_tls("net.tls", tls, &mut problems);
+    }
+
+    let mut addresses = HashSet::new();
+
+    for (index, listener) in super::listeners(config).iter().enumerate() {
+        let address = super::listener_name(listener);
+
+        if !addresses.insert(address.clone()) {
+            problems.push(format!("net.listeners: {} is listed twice", address));
+        }
+
+        // the first one is `net`, checked above
+        if index > 0 {
+            check_listener(
+                &format!("net.listeners[{}]", index - 1),
+                listener,
+                &mut problems,
+            );
+        }
+    }
+
+    if let Some(http) = &config.net.http {
+        let address = format!("{}:{}", http.host, http.port);
+
+        if addresses.contains(&address) {
+            problems.push(format!("net.http: {} is already a listener", address));
+        }
+
+        if let Some(tls) = &http.tls {
+            check_tls("net.http.tls", tls, &mut problems);
+        }
+    }
+
+    if let Some(limits) = &config.net.limits {
+        let zeros = [
+            (
+                "max_connections",
+                limits.max_connections.map(|max| max as u64),
+            ),
+            (
+                "max_connections_per_user",
+                limits.max_connections_per_user.map(|max| max as u64),
+            ),
+            (
+                "max_connections_per_ip",
+                limits.max_connections_per_ip.map(|max| max as u64),
+            ),
+            ("idle_timeout", limits.idle_timeout),
+            ("request_timeout", limits.request_timeout),
+            (
+                "rate_limit.requests_per_second",
+                limits
+                    .rate_limit
+                    .as_ref()
+                    .map(|rate_limit| rate_limit.requests_per_second as u64),
+            ),
+        ];
+
+        for (field, _) in zeros.iter().filter(|(_, value)| *value == Some(0)) {
+            problems.push(format!("net.limits.{}: must be at least 1", field));
+        }
+    }
+
+    check_writable("storage.path", &config.storage.path, &mut problems);
+
+    if let Some(path) = config
+        .storage
+        .oplog
+        .as_ref()
+        .and_then(|oplog| oplog.path.as_ref())
+    {
+        check_writable("storage.oplog.path", path, &mut problems);
+    }
+
+    if let Some(backup) = &config.backup {
+        check_writable("backup.path", &backup.path, &mut problems);
+
+        if let Err(e) = backup::check_schedule(backup) {
+            problems.push(format!("backup: {}", e));
+        }
+
+        // 0 would remove the backup just taken
+        if backup
+            .retention
+            .as_ref()
+            .and_then(|retention| retention.count)
+            == Some(0)
+        {
+            problems.push("backup.retention.count: must be at least 1".to_string());
+        }
+    }
+
+    if let Some(cluster) = &config.cluster {
+        let no_nodes = cluster.nodes.as_ref().is_none_or(|nodes| nodes.is_empty());
+
+        let required = match cluster.role {
+            schema::ClusterRole::Primary => vec![],
+            schema::ClusterRole::Replica => vec![("cluster.primary", cluster.primary.is_none())],
+            schema::ClusterRole::Router => vec![("cluster.nodes", no_nodes)],
+            schema::ClusterRole::Raft => vec![
+                ("cluster.nodes", no_nodes),
+                ("cluster.address", cluster.address.is_none()),
+            ],
+        };
+
+        for (field, _) in required.into_iter().filter(|(_, missing)| *missing) {
+            problems.push(format!("{}: required for this cluster role", field));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems)
+    }
+}
+
+fn check_listener(field: &str, listener: &schema::Listener, problems: &mut Vec<String>) {
+    let tcp = listener.host.is_some() || listener.port.is_some();
+
+    if listener.path.is_none() {
+        if listener.host.is_none() || listener.port.is_none() {
+            problems.push(format!("{}: host and port, or path, are required", field));
+        }
+
+        if listener.mode.is_some() || listener.peer_credentials.is_some() {
+            problems.push(format!(
+                "{}: mode and peer_credentials only apply to unix sockets",
+                field
+            ));
+        }
+    } else if tcp {
+        problems.push(format!("{}: set either host and port, or path", field));
+    } else if listener.tls.is_some() || listener.websocket.is_some() {
+        problems.push(format!(
+            "{}: tls and websocket are not supported on unix sockets",
+            field
+        ));
+    } else if cfg!(not(unix)) {
+        problems.push(format!(
+            "{}: unix sockets are not supported on this platform",
+            field
+        ));
+    }
+
+    if let Some(mode) = &listener.mode {
+        if let Err(e) = super::socket_mode(mode) {
+            problems.push(format!("{}.mode: {}", field, e));
+        }
+    }
+
+    if let Some(tls) = &listener.tls {
+        check_tls(&format!("{}.tls", field), tls, problems);
+    }
+}
+
+fn check_tls(field: &str, tls: &schema::Tls, problems: &mut Vec<String>) {
+    let mut readable = check_readable(&format!("{}.ca_file", field), &tls.ca_file, problems)
+        & check_readable(
+            &format!("{}.pem_key_file", field),
+            &tls.pem_key_file,
+            problems,
+        );
+
+    if let Some(client_ca_file) = &tls.client_ca_file {
+        readable &= check_readable(
+            &format!("{}.client_ca_file", field),
+            client_ca_file,
+            problems,
+        );
+    }
+
+    // only worth parsing once every file can be read
+    if readable {
+        if let Err(e) = wirewave::server::tls_acceptor(tls) {
+            problems.push(format!("{}: {}", field, e));
+        }
+    }
+}
+
+fn check_readable(field: &str, path: &str, problems: &mut Vec<String>) -> bool {
+    match File::open(path) {
+        Ok(_) => true,
+        Err(e) => {
+            problems.push(format!("{}: cannot read {}: {}", field, path, e));
+            false
+        }
+    }
+}
+
+/// Writes a file in the directory, or in its nearest existing parent when it is still to be
+/// created, so that checking creates nothing
+fn check_writable(field: &str, path: &Path, problems: &mut Vec<String>) {
+    let existing = match path.ancestors().find(|ancestor| ancestor.exists()) {
+        Some(existing) => existing,
+        // a relative path is created in the current directory
+        None => Path::new("."),
+    };
+
+    if !existing.is_dir() {
+        problems.push(format!(
+            "{}: {} is not a directory",
+            field,
+            existing.display()
+        ));
+        return;
+    }
+
+    let probe = existing.join(WRITE_CHECK_NAME);
+    let result = File::create(&probe).and_then(|_| fs::remove_file(&probe));
+
+    if let Err(e) = result {
+        problems.push(format!(
+            "{}: {} is not writable: {}",
+            field,
+            existing.display(),
+            e
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("rustbase-validate-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&path).ok();
+        fs::create_dir_all(&path).unwrap();
+
+        path
+    }
+
+    /// The default configuration with `patch` merged over it
+    fn configuration(name: &str, patch: Value) -> schema::RustbaseConfig {
+        let mut config = super::super::default_configuration();
+        config.storage.path = temp_dir(name).join("data");
+
+        let mut value = serde_json::to_value(config).unwrap();
+        merge(&mut value, patch);
+
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn merge(value: &mut Value, patch: Value) {
+        match (value, patch) {
+            (Value::Object(value), Value::Object(patch)) => {
+                for (key, patch) in patch {
+                    merge(value.entry(key).or_insert(Value::Null), patch);
+                }
+            }
+            (value, patch) => *value = patch,
+        }
+    }
+
+    #[test]
+    fn accepts_the_default_configuration() {
+        assert_eq!(validate(&configuration("default", json!({}))), Ok(()));
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let config = configuration(
+            "problems",
+            json!({
+                "threads": 0,
+                "auth": { "iterations": 1 },
+                "net": { "limits": { "max_connections": 0, "idle_timeout": 0 } },
+                "backup": {
+                    "path": temp_dir("problems").join("backups"),
+                    "interval": 60,
+                    "retention": { "count": 0 },
+                },
+                "cluster": { "role": "raft", "nodes": [] },
+            }),
+        );
+
+        assert_eq!(
+            validate(&config).unwrap_err(),
+            vec![
+                "threads: must be at least 1".to_string(),
+                format!(
+                    "auth.iterations: must be at least {}",
+                    authentication::LEGACY_ITERATIONS
+                ),
+                "net.limits.max_connections: must be at least 1".to_string(),
+                "net.limits.idle_timeout: must be at least 1".to_string(),
+                "backup.retention.count: must be at least 1".to_string(),
+                "cluster.nodes: required for this cluster role".to_string(),
+                "cluster.address: required for this cluster role".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_a_listener_listed_twice() {
+        let config = configuration(
+            "twice",
+            json!({
+                "net": {
+                    "host": "127.0.0.1",
+                    "port": 23561,
+                    "listeners": [{ "host": "127.0.0.1", "port": 23561 }],
+                },
+            }),
+        );
+
+        assert_eq!(
+            validate(&config).unwrap_err(),
+            vec!["net.listeners: 127.0.0.1:23561 is listed twice".to_string()]
+        );
+    }
+
+    #[test]
+    fn checks_a_missing_directory_without_creating_it() {
+        let path = temp_dir("missing").join("a").join("b");
+        let mut problems = Vec::new();
+
+        check_writable("storage.path", &path, &mut problems);
+
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert!(!path.parent().unwrap().exists());
+    }
+
+    #[test]
+    fn refuses_a_directory_under_a_file() {
+        let file = temp_dir("file").join("file");
+        fs::write(&file, "").unwrap();
+
+        let mut problems = Vec::new();
+        check_writable("storage.path", &file.join("data"), &mut problems);
+
+        assert_eq!(
+            problems,
+            vec![format!(
+                "storage.path: {} is not a directory",
+                file.display()
+            )]
+        );
+    }
+}
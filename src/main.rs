@@ -14,10 +14,10 @@ extern crate dustdata;
 #[derive(clap_derive::Parser, Clone)]
 #[clap(author, about, long_about = None)]
 pub struct Args {
-    /// The path to the configuration file
+    /// The path to the configuration file (JSON, TOML or YAML)
     /// If not specified, the default configuration file will be used
     /// The default configuration file is located at ~/rustbase/bin/rustbaseconf.json
-    /// If the default configuration file does not exist, it will be created
+    /// If the default configuration file does not exist, the default configuration is used
     #[clap(short, long)]
     config: Option<std::path::PathBuf>,
 
@@ -33,6 +33,80 @@ pub enum SubCommand {
         sub_command: SnapshotSubCommand,
     },
 
+    /// Export a database to a JSON lines or BSON dump file
+    Export {
+        /// The name of the database to export
+        #[clap(short, long)]
+        db: String,
+
+        /// The format of the output file
+        #[clap(short, long, value_enum, default_value_t = TransferFormat::Jsonl)]
+        format: TransferFormat,
+
+        /// The path of the output file
+        #[clap(short, long)]
+        out: String,
+
+        /// The user to authenticate as, when the server is running
+        #[clap(short, long)]
+        username: Option<String>,
+
+        /// The password of the user
+        #[clap(short, long)]
+        password: Option<String>,
+    },
+
+    /// Import a JSON lines or BSON dump file into a database
+    Import {
+        /// The name of the database to import into
+        #[clap(short, long)]
+        db: String,
+
+        /// The path of the input file
+        #[clap(short, long = "in")]
+        input: String,
+
+        /// The format of the input file, inferred from the extension if not specified
+        #[clap(short, long, value_enum)]
+        format: Option<TransferFormat>,
+
+        /// The user to authenticate as, when the server is running
+        #[clap(short, long)]
+        username: Option<String>,
+
+        /// The password of the user
+        #[clap(short, long)]
+        password: Option<String>,
+    },
+
+    /// Check that the databases on disk are readable
+    Check {
+        /// The name of the database to check, all databases are checked if not specified
+        #[clap(short, long)]
+        db: Option<String>,
+
+        /// Remove corrupt keys and move unreadable databases and orphaned files to quarantine
+        #[clap(short, long)]
+        repair: bool,
+    },
+
+    /// Manage the configuration
+    Config {
+        #[clap(subcommand)]
+        sub_command: ConfigSubCommand,
+    },
+
+    /// Move the keys of a sharded cluster to the nodes owning them, e.g. after adding a node
+    Rebalance {
+        /// The user to authenticate as on the router
+        #[clap(short, long)]
+        username: Option<String>,
+
+        /// The password of the user
+        #[clap(short, long)]
+        password: Option<String>,
+    },
+
     /// Upgrade the Rustbase server
     Upgrade {
         /// The version to upgrade to
@@ -46,14 +120,26 @@ pub enum SnapshotSubCommand {
     /// Restore a snapshot with given path and database name
     Restore {
         /// The path to the snapshot file
-        #[clap(short, long)]
-        path: String,
+        #[clap(short, long, required_unless_present_any = ["latest", "until"])]
+        path: Option<String>,
 
         /// The name of the database to restore to
         #[clap(short, long)]
         db: String,
+
+        /// Restore the latest scheduled backup of the database instead of a given path
+        #[clap(short, long, conflicts_with = "path")]
+        latest: bool,
+
+        /// Restore the database as it was at the given RFC 3339 time, using the latest
+        /// scheduled backup before it and the operation log
+        #[clap(short, long, conflicts_with_all = ["path", "latest"])]
+        until: Option<String>,
     },
 
+    /// List the scheduled backups
+    List,
+
     /// Create a snapshot of a database with given name and path
     Create {
         /// The name of the database to create a snapshot of
@@ -63,14 +149,36 @@ pub enum SnapshotSubCommand {
         /// The path to save the snapshot file to
         #[clap(short, long)]
         path: String,
+
+        /// The user to authenticate as, when the server is running
+        #[clap(short, long)]
+        username: Option<String>,
+
+        /// The password of the user
+        #[clap(long)]
+        password: Option<String>,
     },
 }
 
+#[derive(clap_derive::Subcommand, Clone)]
+pub enum ConfigSubCommand {
+    /// Check the configuration file and the environment variables, without starting the server
+    Check,
+}
+
+#[derive(clap::ValueEnum, Clone)]
+pub enum TransferFormat {
+    /// One extended JSON document per line
+    Jsonl,
+    /// Concatenated BSON documents
+    Bson,
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    cli::run_subcommands(args.clone().sub_commands).await;
+    cli::run_subcommands(args.clone()).await;
 
     set_title("Rustbase Database Server");
 
@@ -83,7 +191,18 @@ async fn main() {
     );
     println!();
 
+    let config_path = config::configuration_path(Some(args.clone()));
     let config = config::load_configuration(Some(args));
 
-    server::initalize_server(config).await;
+    if let Err(problems) = config::validate::validate(&config) {
+        println!("[Config] {}", "invalid configuration".red());
+
+        for problem in problems {
+            println!("  - {}", problem);
+        }
+
+        std::process::exit(1);
+    }
+
+    server::initalize_server(config, config_path).await;
 }
@@ -1,8 +1,13 @@
 use super::{GrammarError, Result};
-use bson::{Bson, Document};
-use pest::iterators::Pair;
+use base64::Engine;
+use bson::spec::BinarySubtype;
+use bson::{Binary, Bson, Document};
+use pest::iterators::{Pair, Pairs};
+use pest::pratt_parser::{Assoc, Op, PrattParser};
 use pest::Parser;
 
+use crate::utils;
+
 #[derive(Debug, Clone)]
 pub enum Keywords {
     Insert,
@@ -10,12 +15,20 @@ pub enum Keywords {
     Update,
     Delete,
     List,
+    Snapshot,
+    Reload,
+    Show,
+    Set,
+    Validate,
 }
 
 #[derive(Debug, Clone)]
 pub enum Verbs {
     User,
     Database,
+    All,
+    Quota,
+    Schema,
 }
 
 #[derive(Debug, Clone)]
@@ -43,9 +56,50 @@ pub enum ASTNode {
         ident: Option<Box<ASTNode>>,
     },
 
+    ToExpression {
+        keyword: Keywords,
+        verb: Verbs,
+        ident: Option<Box<ASTNode>>,
+        target: Box<ASTNode>,
+    },
+
     Bson(Bson),
     Identifier(String),
     VariableIdentifier(String),
+    Expression(Expression),
+}
+
+/// A value computed when its statement runs, the constant ones are parsed into `ASTNode::Bson`
+#[derive(Debug, Clone)]
+pub enum Expression {
+    Literal(Bson),
+    Variable(String),
+    Document(Vec<(String, Expression)>),
+    Array(Vec<Expression>),
+    Field(Box<Expression>, String),
+    Index(Box<Expression>, Box<Expression>),
+    Unary(Operator, Box<Expression>),
+    Binary(Operator, Box<Expression>, Box<Expression>),
+    Call(String, Vec<Expression>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    Not,
+    Neg,
 }
 
 #[derive(pest_derive::Parser)]
@@ -93,10 +147,7 @@ fn build_expr(pair: Pair<Rule>) -> Result<ASTNode> {
             let mut exprs = Vec::new();
 
             for pair in inner_rules {
-                match pair.as_rule() {
-                    Rule::ident => exprs.push(build_term(pair)?),
-                    _ => exprs.push(build_expr(pair)?),
-                }
+                exprs.push(build_expr(pair)?);
             }
 
             Ok(ASTNode::MonadicExpression {
@@ -104,17 +155,50 @@ fn build_expr(pair: Pair<Rule>) -> Result<ASTNode> {
                     "insert" => Keywords::Insert,
                     "delete" => Keywords::Delete,
                     "update" => Keywords::Update,
+                    "show" => Keywords::Show,
+                    "set" => Keywords::Set,
+                    "validate" => Keywords::Validate,
                     _ => return Err(GrammarError::with_pair("invalid keyword", keyword)),
                 },
                 verb: match verb.as_str() {
                     "user" => Verbs::User,
                     "database" => Verbs::Database,
+                    "quota" => Verbs::Quota,
+                    "schema" => Verbs::Schema,
                     _ => return Err(GrammarError::with_pair("invalid verb", verb)),
                 },
                 expr: if exprs.is_empty() { None } else { Some(exprs) },
             })
         }
 
+        Rule::toExpr => {
+            let mut inner_rules = pair.into_inner();
+            let keyword = inner_rules.next().unwrap();
+            let verb = inner_rules.next().unwrap();
+
+            let mut terms = Vec::new();
+
+            for pair in inner_rules {
+                terms.push(build_term(pair)?);
+            }
+
+            let target = terms.pop().unwrap();
+
+            Ok(ASTNode::ToExpression {
+                keyword: match keyword.as_str() {
+                    "snapshot" => Keywords::Snapshot,
+                    _ => return Err(GrammarError::with_pair("invalid keyword", keyword)),
+                },
+                verb: match verb.as_str() {
+                    "database" => Verbs::Database,
+                    "all" => Verbs::All,
+                    _ => return Err(GrammarError::with_pair("invalid verb", verb)),
+                },
+                ident: terms.pop().map(Box::new),
+                target: Box::new(target),
+            })
+        }
+
         Rule::intoExpr => {
             let mut inner_rules = pair.into_inner();
             let keyword = inner_rules.next().unwrap();
@@ -142,6 +226,7 @@ fn build_expr(pair: Pair<Rule>) -> Result<ASTNode> {
                     "get" => Keywords::Get,
                     "delete" => Keywords::Delete,
                     "list" => Keywords::List,
+                    "reload" => Keywords::Reload,
                     _ => return Err(GrammarError::with_pair("invalid keyword", keyword)),
                 },
                 ident: if let Some(ident) = ident {
@@ -169,45 +254,536 @@ fn build_term(pair: Pair<Rule>) -> Result<ASTNode> {
         | Rule::boolean
         | Rule::null
         | Rule::array
-        | Rule::object => Ok(ASTNode::Bson(parse_to_bson(pair))),
+        | Rule::object
+        | Rule::date
+        | Rule::oid
+        | Rule::bin
+        | Rule::decimal
+        | Rule::int32
+        | Rule::uuid
+        | Rule::call => Ok(to_node(build_operand(pair)?)),
         Rule::ident => Ok(ASTNode::Identifier(pair.as_str().to_string())),
+        Rule::quoted_ident => Ok(ASTNode::Identifier(unquote(pair))),
         Rule::var_ident => Ok(ASTNode::VariableIdentifier(
             pair.into_inner().next().unwrap().as_str().to_string(),
         )),
         Rule::term => Ok(build_term(pair.into_inner().next().unwrap())?),
+        Rule::expression => {
+            let inner = pair.clone().into_inner();
+
+            // a single value keeps its meaning, e.g. a name is a key and not an error
+            if inner.len() == 1 {
+                return build_term(inner.peek().unwrap());
+            }
+
+            Ok(to_node(parse_expression(pair.into_inner())?))
+        }
         _ => {
             unreachable!()
         }
     }
 }
 
-fn parse_to_bson(pair: Pair<Rule>) -> Bson {
+fn to_node(expression: Expression) -> ASTNode {
+    match expression {
+        Expression::Literal(value) => ASTNode::Bson(value),
+        expression => ASTNode::Expression(expression),
+    }
+}
+
+fn pratt() -> PrattParser<Rule> {
+    PrattParser::new()
+        .op(Op::infix(Rule::or, Assoc::Left))
+        .op(Op::infix(Rule::and, Assoc::Left))
+        .op(Op::infix(Rule::eq, Assoc::Left) | Op::infix(Rule::ne, Assoc::Left))
+        .op(Op::infix(Rule::lt, Assoc::Left)
+            | Op::infix(Rule::le, Assoc::Left)
+            | Op::infix(Rule::gt, Assoc::Left)
+            | Op::infix(Rule::ge, Assoc::Left))
+        .op(Op::infix(Rule::add, Assoc::Left) | Op::infix(Rule::sub, Assoc::Left))
+        .op(Op::infix(Rule::mul, Assoc::Left)
+            | Op::infix(Rule::div, Assoc::Left)
+            | Op::infix(Rule::rem, Assoc::Left))
+        .op(Op::prefix(Rule::not) | Op::prefix(Rule::neg))
+        .op(Op::postfix(Rule::field) | Op::postfix(Rule::index))
+}
+
+fn parse_expression(pairs: Pairs<Rule>) -> Result<Expression> {
+    pratt()
+        .map_primary(build_operand)
+        .map_prefix(|op, operand| {
+            let operator = match op.as_rule() {
+                Rule::not => Operator::Not,
+                _ => Operator::Neg,
+            };
+
+            Ok(Expression::Unary(operator, Box::new(operand?)))
+        })
+        .map_postfix(|operand, op| {
+            let operand = Box::new(operand?);
+            let inner = op.into_inner().next().unwrap();
+
+            match inner.as_rule() {
+                Rule::expression => Ok(Expression::Index(
+                    operand,
+                    Box::new(parse_expression(inner.into_inner())?),
+                )),
+                Rule::quoted_ident => Ok(Expression::Field(operand, unquote(inner))),
+                _ => Ok(Expression::Field(operand, inner.as_str().to_string())),
+            }
+        })
+        .map_infix(|left, op, right| {
+            let operator = match op.as_rule() {
+                Rule::add => Operator::Add,
+                Rule::sub => Operator::Sub,
+                Rule::mul => Operator::Mul,
+                Rule::div => Operator::Div,
+                Rule::rem => Operator::Rem,
+                Rule::eq => Operator::Eq,
+                Rule::ne => Operator::Ne,
+                Rule::lt => Operator::Lt,
+                Rule::le => Operator::Le,
+                Rule::gt => Operator::Gt,
+                Rule::ge => Operator::Ge,
+                Rule::and => Operator::And,
+                _ => Operator::Or,
+            };
+
+            Ok(Expression::Binary(
+                operator,
+                Box::new(left?),
+                Box::new(right?),
+            ))
+        })
+        .parse(pairs)
+}
+
+/// The values an expression is made of, the objects and arrays of literals are kept literal
+fn build_operand(pair: Pair<Rule>) -> Result<Expression> {
     match pair.as_rule() {
         Rule::object => {
-            let mut doc = Document::new();
+            let mut fields = Vec::new();
+
             for pair in pair.into_inner() {
                 let mut inner_rules = pair.into_inner();
-                let key = parse_to_bson(inner_rules.next().unwrap());
+                let key = parse_key(inner_rules.next().unwrap())?;
+                let value = parse_expression(inner_rules.next().unwrap().into_inner())?;
 
-                let value = parse_to_bson(inner_rules.next().unwrap());
-                doc.insert(key.as_str().unwrap(), value);
+                fields.push((key, value));
             }
-            Bson::Document(doc)
+
+            if fields
+                .iter()
+                .all(|(_, value)| matches!(value, Expression::Literal(_)))
+            {
+                let doc: Document = fields
+                    .into_iter()
+                    .map(|(key, value)| match value {
+                        Expression::Literal(value) => (key, value),
+                        _ => unreachable!(),
+                    })
+                    .collect();
+
+                return Ok(Expression::Literal(Bson::Document(doc)));
+            }
+
+            Ok(Expression::Document(fields))
         }
         Rule::array => {
-            let mut arr = Vec::new();
-            for pair in pair.into_inner() {
-                arr.push(parse_to_bson(pair));
+            let items = pair
+                .into_inner()
+                .map(|pair| parse_expression(pair.into_inner()))
+                .collect::<Result<Vec<_>>>()?;
+
+            if items
+                .iter()
+                .all(|item| matches!(item, Expression::Literal(_)))
+            {
+                let arr = items
+                    .into_iter()
+                    .map(|item| match item {
+                        Expression::Literal(value) => value,
+                        _ => unreachable!(),
+                    })
+                    .collect();
+
+                return Ok(Expression::Literal(Bson::Array(arr)));
             }
-            Bson::Array(arr)
+
+            Ok(Expression::Array(items))
         }
-        Rule::string => Bson::String(pair.into_inner().next().unwrap().as_str().to_string()),
-        Rule::integer => Bson::Int64(pair.as_str().parse().unwrap()),
-        Rule::float => Bson::Double(pair.as_str().parse().unwrap()),
-        Rule::boolean => Bson::Boolean(pair.as_str().parse().unwrap()),
-        Rule::null => Bson::Null,
+        Rule::call => {
+            let mut inner_rules = pair.into_inner();
+            let name = inner_rules.next().unwrap().as_str().to_string();
+            let args = inner_rules
+                .map(|pair| parse_expression(pair.into_inner()))
+                .collect::<Result<Vec<_>>>()?;
+
+            Ok(Expression::Call(name, args))
+        }
+        Rule::expression => parse_expression(pair.into_inner()),
+        Rule::var_ident => Ok(Expression::Variable(
+            pair.into_inner().next().unwrap().as_str().to_string(),
+        )),
+        _ => parse_to_bson(pair).map(Expression::Literal),
+    }
+}
+
+fn parse_to_bson(pair: Pair<Rule>) -> Result<Bson> {
+    match pair.as_rule() {
+        Rule::string => unescape(pair).map(Bson::String),
+        Rule::integer => pair
+            .as_str()
+            .parse()
+            .map(Bson::Int64)
+            .map_err(|_| GrammarError::with_pair("integer out of range", pair)),
+        Rule::float => Ok(Bson::Double(pair.as_str().parse().unwrap())),
+        Rule::boolean => Ok(Bson::Boolean(pair.as_str().parse().unwrap())),
+        Rule::null => Ok(Bson::Null),
+        Rule::date | Rule::oid | Rule::bin | Rule::decimal | Rule::int32 | Rule::uuid => {
+            parse_typed(pair)
+        }
+        Rule::ident | Rule::quoted_ident => Err(GrammarError::with_pair(
+            "a name is not a value here, quote it as a string",
+            pair,
+        )),
         _ => {
             unreachable!();
         }
     }
 }
+
+/// An object key: a string, a name between backticks or a bare identifier
+fn parse_key(pair: Pair<Rule>) -> Result<String> {
+    match pair.as_rule() {
+        Rule::string => unescape(pair),
+        Rule::quoted_ident => Ok(unquote(pair)),
+        _ => Ok(pair.as_str().to_string()),
+    }
+}
+
+fn string_of(pair: Pair<Rule>) -> String {
+    pair.into_inner().next().unwrap().as_str().to_string()
+}
+
+/// A name between backticks, where ``` `` ``` stands for a backtick
+fn unquote(pair: Pair<Rule>) -> String {
+    string_of(pair).replace("``", "`")
+}
+
+/// Decodes the escapes of a JSON string, the grammar only lets the valid ones through
+fn unescape(pair: Pair<Rule>) -> Result<String> {
+    let span = pair.clone();
+    let raw = string_of(pair);
+
+    let mut decoded = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            decoded.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => decoded.push('\n'),
+            Some('t') => decoded.push('\t'),
+            Some('r') => decoded.push('\r'),
+            Some('b') => decoded.push('\u{8}'),
+            Some('f') => decoded.push('\u{c}'),
+            Some('u') => {
+                let mut code = hex_code(&mut chars);
+
+                // the characters outside of the BMP are written as a surrogate pair
+                if (0xD800..0xDC00).contains(&code) && chars.as_str().starts_with("\\u") {
+                    let mut rest = chars.clone();
+                    rest.nth(1);
+                    let low = hex_code(&mut rest);
+
+                    if (0xDC00..0xE000).contains(&low) {
+                        code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                        chars = rest;
+                    }
+                }
+
+                match char::from_u32(code) {
+                    Some(c) => decoded.push(c),
+                    None => {
+                        return Err(GrammarError::with_pair(
+                            &format!("invalid unicode escape \\u{:04x}", code),
+                            span,
+                        ))
+                    }
+                }
+            }
+            // `"`, `\` and `/` stand for themselves
+            Some(c) => decoded.push(c),
+            None => unreachable!(),
+        }
+    }
+
+    Ok(decoded)
+}
+
+fn hex_code(chars: &mut std::str::Chars) -> u32 {
+    let digits: String = chars.take(4).collect();
+
+    u32::from_str_radix(&digits, 16).unwrap()
+}
+
+/// The typed literals, e.g. `date("2026-01-01T00:00:00Z")` or `int32(5)`
+fn parse_typed(pair: Pair<Rule>) -> Result<Bson> {
+    let rule = pair.as_rule();
+    let span = pair.clone();
+    let mut args = pair.into_inner();
+    let arg = args.next().unwrap();
+
+    let value = match rule {
+        Rule::date => match arg.as_rule() {
+            Rule::integer => arg
+                .as_str()
+                .parse()
+                .map(|millis| Bson::DateTime(bson::DateTime::from_millis(millis)))
+                .map_err(|e| format!("invalid date: {}", e)),
+            _ => bson::DateTime::parse_rfc3339_str(unescape(arg)?)
+                .map(Bson::DateTime)
+                .map_err(|e| format!("invalid date, expected RFC 3339: {}", e)),
+        },
+
+        Rule::oid => bson::oid::ObjectId::parse_str(unescape(arg)?)
+            .map(Bson::ObjectId)
+            .map_err(|e| format!("invalid ObjectId: {}", e)),
+
+        Rule::bin => {
+            let encoding = arg.as_str().to_string();
+            let data = unescape(args.next().unwrap())?;
+
+            let subtype = match args.next() {
+                Some(subtype) => subtype
+                    .as_str()
+                    .parse::<u8>()
+                    .map(BinarySubtype::from)
+                    .map_err(|_| "the binary subtype must be between 0 and 255".to_string()),
+                None => Ok(BinarySubtype::Generic),
+            };
+
+            let bytes = match encoding.as_str() {
+                "base64" => base64::engine::general_purpose::STANDARD
+                    .decode(data)
+                    .map_err(|e| format!("invalid base64: {}", e)),
+                _ => decode_hex(&data),
+            };
+
+            subtype.and_then(|subtype| bytes.map(|bytes| Bson::Binary(Binary { subtype, bytes })))
+        }
+
+        Rule::decimal => utils::decimal::parse(&unescape(arg)?).map(Bson::Decimal128),
+
+        Rule::int32 => arg
+            .as_str()
+            .parse()
+            .map(Bson::Int32)
+            .map_err(|_| format!("{} does not fit in an int32", arg.as_str())),
+
+        Rule::uuid => bson::Uuid::parse_str(unescape(arg)?)
+            .map(|uuid| Bson::Binary(Binary::from_uuid(uuid)))
+            .map_err(|e| format!("invalid UUID: {}", e)),
+
+        _ => unreachable!(),
+    };
+
+    value.map_err(|message| GrammarError::with_pair(&message, span))
+}
+
+fn decode_hex(data: &str) -> std::result::Result<Vec<u8>, String> {
+    if !data.len().is_multiple_of(2) {
+        return Err("invalid hex: odd number of digits".to_string());
+    }
+
+    (0..data.len())
+        .step_by(2)
+        .map(|i| {
+            data.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("invalid hex: {}", data))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    /// The value of `insert <value> into key`
+    fn value(input: &str) -> Result<Bson> {
+        let mut ast = parse(&format!("insert {} into key", input))?;
+
+        match ast.remove(0) {
+            ASTNode::IntoExpression { value, .. } => match *value {
+                ASTNode::Bson(value) => Ok(value),
+                node => panic!("expected a value, got {:?}", node),
+            },
+            node => panic!("expected an insert, got {:?}", node),
+        }
+    }
+
+    fn target(input: &str) -> String {
+        match parse(input).unwrap().remove(0) {
+            ASTNode::IntoExpression { ident, .. } => match *ident {
+                ASTNode::Identifier(ident) => ident,
+                node => panic!("expected an identifier, got {:?}", node),
+            },
+            node => panic!("expected an insert, got {:?}", node),
+        }
+    }
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(
+            value(r#""line\nbreak\t\"quoted\" \\ \/""#).unwrap(),
+            Bson::String("line\nbreak\t\"quoted\" \\ /".to_string())
+        );
+    }
+
+    #[test]
+    fn decodes_unicode_escapes() {
+        assert_eq!(
+            value(r#""caf\u00e9""#).unwrap(),
+            Bson::String("café".to_string())
+        );
+        assert_eq!(
+            value(r#""\ud83e\udd80""#).unwrap(),
+            Bson::String("🦀".to_string())
+        );
+        assert_eq!(
+            value(r#""café 🦀""#).unwrap(),
+            Bson::String("café 🦀".to_string())
+        );
+    }
+
+    #[test]
+    fn refuses_lone_surrogates() {
+        assert!(value(r#""\ud83e""#).is_err());
+    }
+
+    #[test]
+    fn accepts_trailing_commas() {
+        assert_eq!(
+            value(r#"{"a": [1, 2,], "b": 3,}"#).unwrap(),
+            Bson::Document(doc! { "a": [1_i64, 2_i64], "b": 3_i64 })
+        );
+    }
+
+    #[test]
+    fn accepts_unquoted_and_backtick_keys() {
+        assert_eq!(
+            value(r#"{name: "John", `user-42`: true, "é\n": null}"#).unwrap(),
+            Bson::Document(doc! { "name": "John", "user-42": true, "é\n": Bson::Null })
+        );
+    }
+
+    #[test]
+    fn accepts_unicode_identifiers() {
+        assert_eq!(
+            value("{café: 1, 名前: 2}").unwrap(),
+            Bson::Document(doc! { "café": 1_i64, "名前": 2_i64 })
+        );
+        assert_eq!(target("insert 1 into café"), "café");
+        assert_eq!(target("insert 1 into `user-42`"), "user-42");
+        assert_eq!(target("insert 1 into `a``b`"), "a`b");
+    }
+
+    #[test]
+    fn keeps_keywords_whole() {
+        assert!(parse("getté").is_err());
+        assert!(parse("get té").is_ok());
+    }
+
+    #[test]
+    fn refuses_names_as_values() {
+        assert!(value("{a: b}").is_err());
+    }
+
+    #[test]
+    fn keeps_constants_literal() {
+        assert_eq!(
+            value("[1, {a: -2}]").unwrap(),
+            Bson::Array(vec![1_i64.into(), doc! { "a": -2_i64 }.into()])
+        );
+    }
+
+    #[test]
+    fn parses_typed_literals() {
+        assert_eq!(value("int32(-2147483648)").unwrap(), Bson::Int32(i32::MIN));
+        assert_eq!(
+            value(r#"oid("507f1f77bcf86cd799439011")"#).unwrap(),
+            Bson::ObjectId(bson::oid::ObjectId::parse_str("507f1f77bcf86cd799439011").unwrap())
+        );
+        assert_eq!(
+            value(r#"bin(hex, "00ff", 128)"#).unwrap(),
+            Bson::Binary(Binary {
+                subtype: BinarySubtype::from(128),
+                bytes: vec![0, 255],
+            })
+        );
+        assert_eq!(
+            value("date(0)").unwrap(),
+            Bson::DateTime(bson::DateTime::from_millis(0))
+        );
+        assert_eq!(
+            value(r#"date("1970-01-01T00:00:01Z")"#).unwrap(),
+            Bson::DateTime(bson::DateTime::from_millis(1000))
+        );
+        assert_eq!(
+            value(r#"decimal("1.10")"#).unwrap(),
+            Bson::Decimal128(utils::decimal::parse("1.10").unwrap())
+        );
+    }
+
+    #[test]
+    fn refuses_int32_overflows() {
+        assert!(value("int32(2147483648)").is_err());
+        assert!(value("int32(-2147483649)").is_err());
+        assert!(value("int32(99999999999999999999)").is_err());
+    }
+
+    #[test]
+    fn refuses_bad_typed_literals() {
+        for input in [
+            r#"oid("507f1f77bcf86cd79943901")"#,
+            r#"oid("zz7f1f77bcf86cd799439011")"#,
+            r#"bin(hex, "0f0")"#,
+            r#"bin(hex, "zz")"#,
+            r#"bin(base64, "not base64!")"#,
+            r#"bin(hex, "00", 256)"#,
+            r#"date("yesterday")"#,
+            r#"date("2024-13-01T00:00:00Z")"#,
+            r#"date(99999999999999999999)"#,
+            r#"decimal("1E-6177")"#,
+        ] {
+            assert!(value(input).is_err(), "{} was read", input);
+        }
+    }
+
+    #[test]
+    fn parses_expressions_by_precedence() {
+        let mut ast = parse("get $a.b[0] + 2 * len($c) == 3 || !$d").unwrap();
+
+        let ident = match ast.remove(0) {
+            ASTNode::SingleExpression { ident, .. } => ident.unwrap(),
+            node => panic!("expected a get, got {:?}", node),
+        };
+
+        assert_eq!(
+            format!("{:?}", ident),
+            "Expression(Binary(Or, \
+             Binary(Eq, \
+             Binary(Add, \
+             Index(Field(Variable(\"a\"), \"b\"), Literal(Int64(0))), \
+             Binary(Mul, Literal(Int64(2)), Call(\"len\", [Variable(\"c\")]))), \
+             Literal(Int64(3))), \
+             Unary(Not, Variable(\"d\"))))"
+        );
+    }
+}
@@ -0,0 +1,402 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use colored::Colorize;
+use cron::Schedule;
+use dustdata::DustData;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::watch;
+
+use super::route;
+use crate::config::schema;
+
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Manifest {
+    pub backups: Vec<BackupEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BackupEntry {
+    pub id: String,
+    pub timestamp: String,
+    pub databases: Vec<DatabaseEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DatabaseEntry {
+    pub name: String,
+    pub size: u64,
+}
+
+impl Manifest {
+    pub fn load(backup_path: &Path) -> Result<Self, String> {
+        let path = backup_path.join(MANIFEST_NAME);
+
+        if !path.exists() {
+            return Ok(Manifest::default());
+        }
+
+        let file =
+            File::open(&path).map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+
+        serde_json::from_reader(file).map_err(|e| format!("cannot read {}: {}", path.display(), e))
+    }
+
+    /// Writes the manifest next to the old one and renames it over, so a failed
+    /// write never leaves a truncated manifest behind
+    pub fn save(&self, backup_path: &Path) -> Result<(), String> {
+        let path = backup_path.join(MANIFEST_NAME);
+        let tmp = backup_path.join(format!("{}.tmp", MANIFEST_NAME));
+
+        let file =
+            File::create(&tmp).map_err(|e| format!("cannot create {}: {}", tmp.display(), e))?;
+
+        serde_json::to_writer_pretty(&file, self)
+            .map_err(|e| format!("cannot write {}: {}", tmp.display(), e))?;
+        file.sync_all()
+            .map_err(|e| format!("cannot write {}: {}", tmp.display(), e))?;
+
+        fs::rename(&tmp, &path).map_err(|e| format!("cannot replace {}: {}", path.display(), e))
+    }
+
+    /// The most recent backup that contains `database`
+    pub fn latest_for(&self, database: &str) -> Option<(&BackupEntry, DateTime<Utc>)> {
+        self.latest_before(database, None)
+    }
+
+    /// The most recent backup that contains `database` and was taken before `until`.
+    /// The backups with an unreadable timestamp are ignored.
+    pub fn latest_before(
+        &self,
+        database: &str,
+        until: Option<DateTime<Utc>>,
+    ) -> Option<(&BackupEntry, DateTime<Utc>)> {
+        self.backups
+            .iter()
+            .filter(|backup| backup.databases.iter().any(|db| db.name == database))
+            .filter_map(|backup| backup.time().ok().map(|time| (backup, time)))
+            .filter(|(_, time)| until.is_none_or(|until| *time <= until))
+            .max_by_key(|(_, time)| *time)
+    }
+}
+
+impl BackupEntry {
+    pub fn time(&self) -> Result<DateTime<Utc>, String> {
+        DateTime::parse_from_rfc3339(&self.timestamp)
+            .map(|time| time.with_timezone(&Utc))
+            .map_err(|e| {
+                format!(
+                    "invalid timestamp {} of backup {}: {}",
+                    self.timestamp, self.id, e
+                )
+            })
+    }
+}
+
+/// Checks that the backups have a valid interval or cron expression
+pub fn check_schedule(config: &schema::Backup) -> Result<(), String> {
+    Trigger::from_config(config).map(|_| ())
+}
+
+enum Trigger {
+    Interval(Duration),
+    Cron(Box<Schedule>),
+}
+
+impl Trigger {
+    fn from_config(config: &schema::Backup) -> Result<Self, String> {
+        match (&config.cron, config.interval) {
+            (Some(cron), _) => Schedule::from_str(cron)
+                .map(|schedule| Trigger::Cron(Box::new(schedule)))
+                .map_err(|e| format!("invalid cron expression {}: {}", cron, e)),
+
+            (None, Some(interval)) if interval > 0 => {
+                Ok(Trigger::Interval(Duration::from_secs(interval)))
+            }
+
+            _ => Err("an interval or a cron expression is required".to_string()),
+        }
+    }
+
+    /// The time until the next backup, `None` when a cron expression has no upcoming run
+    fn next_delay(&self) -> Option<Duration> {
+        match self {
+            Trigger::Interval(interval) => Some(*interval),
+            // the next run may already be due by the time it is computed
+            Trigger::Cron(schedule) => schedule
+                .upcoming(Utc)
+                .next()
+                .map(|next| (next - Utc::now()).to_std().unwrap_or_default()),
+        }
+    }
+}
+
+/// Runs forever, backing up every database on the configured schedule.
+pub async fn run_scheduler(
+    config: schema::Backup,
+    routers: Arc<RwLock<HashMap<String, DustData>>>,
+    system_db: Arc<RwLock<DustData>>,
+    mut stop: watch::Receiver<bool>,
+) {
+    let trigger = match Trigger::from_config(&config) {
+        Ok(trigger) => trigger,
+        Err(e) => {
+            println!("[Backup] scheduled backups disabled: {}", e);
+            return;
+        }
+    };
+
+    println!(
+        "[Backup] scheduled backups enabled to {}",
+        config.path.display().to_string().yellow()
+    );
+
+    loop {
+        let delay = match trigger.next_delay() {
+            Some(delay) => delay,
+            None => {
+                println!(
+                    "[Backup] the cron expression has no upcoming run, scheduled backups stopped"
+                );
+                return;
+            }
+        };
+
+        // a backup already started is finished before stopping
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = stop.changed() => return,
+        }
+
+        let config = config.clone();
+        let routers = routers.clone();
+        let system_db = system_db.clone();
+
+        // snapshots take blocking locks and disk io
+        let result =
+            tokio::task::spawn_blocking(move || create_backup(&config, &routers, &system_db))
+                .await
+                .unwrap_or_else(|e| Err(format!("backup task failed: {}", e)));
+
+        match result {
+            Ok(backup) => println!(
+                "[Backup] backup {} created with {} databases",
+                backup.id.green(),
+                backup.databases.len()
+            ),
+            Err(e) => println!("[Backup] backup failed: {}", e.red()),
+        }
+    }
+}
+
+pub fn create_backup(
+    config: &schema::Backup,
+    routers: &RwLock<HashMap<String, DustData>>,
+    system_db: &RwLock<DustData>,
+) -> Result<BackupEntry, String> {
+    // loaded first, a backup which cannot be recorded is not taken
+    let mut manifest = Manifest::load(&config.path)?;
+
+    let now = Utc::now();
+    let id = reserve_backup_id(&config.path, &now.format("%Y%m%dT%H%M%SZ").to_string())?;
+    let path = config.path.join(&id);
+
+    let databases = route::snapshot_all_dustdata(routers, system_db, &path).map_err(|e| {
+        fs::remove_dir_all(&path).ok();
+        format!("snapshot failed: {}", e)
+    })?;
+
+    let backup = BackupEntry {
+        id,
+        timestamp: now.to_rfc3339_opts(SecondsFormat::Millis, true),
+        databases: databases
+            .into_iter()
+            .map(|name| DatabaseEntry {
+                size: dir_size(&path.join(&name)),
+                name,
+            })
+            .collect(),
+    };
+
+    manifest.backups.push(backup.clone());
+
+    if let Some(retention) = &config.retention {
+        prune(&config.path, &mut manifest, retention);
+    }
+
+    manifest.save(&config.path)?;
+
+    Ok(backup)
+}
+
+/// Creates the directory of a new backup, named after its second. A backup taken
+/// in the same second as another one gets a `-1`, `-2`, ... suffix.
+fn reserve_backup_id(backup_path: &Path, base: &str) -> Result<String, String> {
+    fs::create_dir_all(backup_path)
+        .map_err(|e| format!("cannot create {}: {}", backup_path.display(), e))?;
+
+    for n in 0.. {
+        let id = match n {
+            0 => base.to_string(),
+            n => format!("{}-{}", base, n),
+        };
+
+        match fs::create_dir(backup_path.join(&id)) {
+            Ok(()) => return Ok(id),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(format!("cannot create backup {}: {}", id, e)),
+        }
+    }
+
+    unreachable!()
+}
+
+fn prune(backup_path: &Path, manifest: &mut Manifest, retention: &schema::Retention) {
+    let now = Utc::now();
+
+    let mut backups: Vec<_> = manifest
+        .backups
+        .drain(..)
+        .map(|backup| (backup.time(), backup))
+        .collect();
+
+    // the backups without a readable timestamp sort first and are never removed
+    backups.sort_by_key(|(time, _)| time.as_ref().ok().copied());
+
+    let undated = backups.iter().filter(|(time, _)| time.is_err()).count();
+    let total = backups.len() - undated;
+
+    for (i, (time, backup)) in backups.into_iter().enumerate() {
+        let time = match time {
+            Ok(time) => time,
+            Err(e) => {
+                println!("[Backup] keeping {}", e);
+                manifest.backups.push(backup);
+                continue;
+            }
+        };
+
+        let too_many = retention
+            .count
+            .is_some_and(|count| total - (i - undated) > count);
+        let too_old = retention
+            .max_age
+            .is_some_and(|max_age| (now - time).num_seconds() > max_age as i64);
+
+        if too_many || too_old {
+            println!("[Backup] removing backup {}", backup.id);
+            fs::remove_dir_all(backup_path.join(&backup.id)).ok();
+        } else {
+            manifest.backups.push(backup);
+        }
+    }
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let path = entry.path();
+
+            if path.is_dir() {
+                dir_size(&path)
+            } else {
+                entry.metadata().map(|meta| meta.len()).unwrap_or(0)
+            }
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backups(name: &str, ages: &[i64]) -> (std::path::PathBuf, Manifest) {
+        let path =
+            std::env::temp_dir().join(format!("rustbase-backup-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&path).ok();
+
+        let now = Utc::now();
+        let mut manifest = Manifest::default();
+
+        for (index, age) in ages.iter().enumerate() {
+            let id = format!("backup-{}", index);
+            fs::create_dir_all(path.join(&id)).unwrap();
+
+            manifest.backups.push(BackupEntry {
+                id,
+                timestamp: (now - chrono::Duration::seconds(*age))
+                    .to_rfc3339_opts(SecondsFormat::Secs, true),
+                databases: Vec::new(),
+            });
+        }
+
+        (path, manifest)
+    }
+
+    fn kept(path: &Path, manifest: &Manifest) -> Vec<String> {
+        let mut ids: Vec<String> = manifest.backups.iter().map(|b| b.id.clone()).collect();
+        ids.sort();
+
+        for id in &ids {
+            assert!(path.join(id).exists(), "{} was removed", id);
+        }
+
+        ids
+    }
+
+    #[test]
+    fn keeps_the_most_recent_backups_by_count() {
+        let (path, mut manifest) = backups("count", &[30, 10, 20, 0]);
+        let retention = schema::Retention {
+            count: Some(2),
+            max_age: None,
+        };
+
+        prune(&path, &mut manifest, &retention);
+
+        assert_eq!(kept(&path, &manifest), vec!["backup-1", "backup-3"]);
+        assert!(!path.join("backup-0").exists());
+        assert!(!path.join("backup-2").exists());
+    }
+
+    #[test]
+    fn removes_the_backups_older_than_max_age() {
+        let (path, mut manifest) = backups("age", &[3600, 60, 7200]);
+        let retention = schema::Retention {
+            count: None,
+            max_age: Some(600),
+        };
+
+        prune(&path, &mut manifest, &retention);
+
+        assert_eq!(kept(&path, &manifest), vec!["backup-1"]);
+    }
+
+    #[test]
+    fn keeps_the_backups_without_a_readable_timestamp() {
+        let (path, mut manifest) = backups("undated", &[10, 0]);
+        manifest.backups[0].timestamp = "yesterday".to_string();
+
+        let retention = schema::Retention {
+            count: Some(1),
+            max_age: Some(1),
+        };
+
+        prune(&path, &mut manifest, &retention);
+
+        assert_eq!(kept(&path, &manifest), vec!["backup-0", "backup-1"]);
+    }
+}
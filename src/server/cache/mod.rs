@@ -63,6 +63,12 @@ impl Cache {
         Ok(())
     }
 
+    /// Changes the budget of the cache, evicting the oldest values if it is now too small
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.manage_cache(0);
+    }
+
     fn manage_cache(&mut self, size_to_insert: usize) {
         while self.cache_size + size_to_insert > self.max_size {
             let value = self.cache.pop_front();
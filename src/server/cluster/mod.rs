@@ -0,0 +1,755 @@
+use bson::Bson;
+use colored::Colorize;
+use dustdata::DustData;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+
+use super::cache::Cache;
+use super::oplog::{self, ChangeStream, OpEntry, OpLog, Operation};
+use super::quota::{Change, Counters};
+use super::route;
+use super::wirewave;
+use crate::config::schema;
+
+use wirewave::client::{Client, ClientError};
+use wirewave::server::{ReqHeader, Request, ResHeader, Response, Status, Type};
+
+pub const DEFAULT_MAX_LAG: u64 = 100_000;
+pub const CLUSTER_DIRECTORY: &str = "_cluster";
+const STATE_NAME: &str = "replica.json";
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A message of the replication stream, sent by the primary to a replica
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ClusterEvent {
+    /// The replica is too far behind, every database is sent again. The replica drops
+    /// the databases which are not in `databases`.
+    Resync { seq: i64, databases: Vec<String> },
+    /// A key of a database, part of a resync
+    Record {
+        database: String,
+        key: String,
+        value: Bson,
+    },
+    /// The resync is done, the replica is at `seq`
+    Synced { seq: i64 },
+    /// Every entry of the system database (users, quotas, schemas), sent on a resync
+    /// and whenever it changed since the last heartbeat
+    System { records: bson::Document },
+    /// A write of the primary, `head` is the last sequence of the primary
+    Operation { entry: OpEntry, head: i64 },
+    /// Sent when there was no write for a while
+    Heartbeat { head: i64 },
+}
+
+/// The replication progress of a replica
+#[derive(Default)]
+pub struct ReplicaState {
+    applied: AtomicI64,
+    head: AtomicI64,
+    connected: AtomicBool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct PersistedState {
+    applied: i64,
+}
+
+impl ReplicaState {
+    pub fn load(config: &schema::RustbaseConfig) -> Self {
+        let path = state_path(config);
+
+        let persisted: PersistedState = File::open(path)
+            .ok()
+            .and_then(|file| serde_json::from_reader(file).ok())
+            .unwrap_or_default();
+
+        Self {
+            applied: AtomicI64::new(persisted.applied),
+            head: AtomicI64::new(persisted.applied),
+            connected: AtomicBool::new(false),
+        }
+    }
+
+    fn save(&self, config: &schema::RustbaseConfig) -> io::Result<()> {
+        let path = state_path(config);
+
+        fs::create_dir_all(config.storage.path.join(CLUSTER_DIRECTORY))?;
+
+        let persisted = PersistedState {
+            applied: self.applied.load(Ordering::SeqCst),
+        };
+
+        serde_json::to_writer(File::create(path)?, &persisted).map_err(io::Error::other)
+    }
+
+    /// A state which cannot be saved is only logged, the replica resumes from an
+    /// older sequence after a restart and the operations are applied again
+    fn save_or_log(&self, config: &schema::RustbaseConfig) {
+        if let Err(e) = self.save(config) {
+            println!(
+                "[Cluster] cannot save the replica state: {}",
+                e.to_string().red()
+            );
+        }
+    }
+
+    /// The number of operations of the primary not applied yet
+    pub fn lag(&self) -> i64 {
+        (self.head.load(Ordering::SeqCst) - self.applied.load(Ordering::SeqCst)).max(0)
+    }
+
+    pub fn status(&self) -> bson::Document {
+        bson::doc! {
+            "role": "replica",
+            "connected": self.connected.load(Ordering::SeqCst),
+            "seq": self.applied.load(Ordering::SeqCst),
+            "head": self.head.load(Ordering::SeqCst),
+            "lag": self.lag(),
+        }
+    }
+}
+
+fn state_path(config: &schema::RustbaseConfig) -> PathBuf {
+    config.storage.path.join(CLUSTER_DIRECTORY).join(STATE_NAME)
+}
+
+pub fn primary_status(oplog: &OpLog) -> bson::Document {
+    bson::doc! {
+        "role": "primary",
+        "seq": oplog.seq(),
+    }
+}
+
+fn event_response(event: &ClusterEvent) -> Response {
+    Response {
+        header: ResHeader {
+            status: Status::Ok,
+            messages: None,
+            is_error: false,
+        },
+        body: Some(bson::to_bson(event).unwrap()),
+    }
+}
+
+/// Streams the writes of every database to a replica, starting after `after`. When the
+/// replica has no state, has diverged or is more than `max_lag` operations behind, every
+/// database is sent first. The system database is sent whenever it changed.
+pub fn replication_stream(
+    oplog: Arc<OpLog>,
+    routers: Arc<RwLock<HashMap<String, DustData>>>,
+    system_db: Arc<RwLock<DustData>>,
+    after: Option<i64>,
+    max_lag: u64,
+) -> mpsc::Receiver<Response> {
+    let (sender, receiver) = mpsc::channel(256);
+
+    let head = oplog.seq();
+    let resync = match after {
+        Some(after) => after > head || (head - after) as u64 > max_lag,
+        None => true,
+    };
+
+    // subscribe before the copy, the writes made during it are replayed afterwards
+    let mut stream = if resync {
+        ChangeStream::new(oplog.clone(), None, None, Some(head))
+    } else {
+        ChangeStream::new(oplog.clone(), None, None, after)
+    };
+
+    tokio::spawn(async move {
+        if resync {
+            let c_sender = sender.clone();
+            let c_routers = routers.clone();
+
+            // reading the databases takes blocking locks
+            let copied =
+                tokio::task::spawn_blocking(move || send_databases(&c_routers, head, &c_sender))
+                    .await
+                    .unwrap_or(false);
+
+            if !copied {
+                return;
+            }
+        }
+
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        let mut last_system = None;
+
+        loop {
+            let event = tokio::select! {
+                entry = stream.next() => match entry {
+                    Some(entry) => ClusterEvent::Operation { entry, head: oplog.seq() },
+                    None => return,
+                },
+
+                _ = heartbeat.tick() => {
+                    let c_system_db = system_db.clone();
+                    let records = tokio::task::spawn_blocking(move || {
+                        system_records(&c_system_db.read().unwrap())
+                    })
+                    .await;
+
+                    match records {
+                        Ok(records) if last_system.as_ref() != Some(&records) => {
+                            last_system = Some(records.clone());
+
+                            let system = event_response(&ClusterEvent::System { records });
+
+                            if sender.send(system).await.is_err() {
+                                return;
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => println!("[Cluster] cannot read the system database: {}", e),
+                    }
+
+                    ClusterEvent::Heartbeat { head: oplog.seq() }
+                }
+            };
+
+            // the replica is gone
+            if sender.send(event_response(&event)).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    receiver
+}
+
+fn system_records(system_db: &DustData) -> bson::Document {
+    system_db
+        .list_keys()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|key| Some((key.clone(), system_db.get(&key).ok()??)))
+        .collect()
+}
+
+fn send_databases(
+    routers: &RwLock<HashMap<String, DustData>>,
+    seq: i64,
+    sender: &mpsc::Sender<Response>,
+) -> bool {
+    let send = |event: ClusterEvent| sender.blocking_send(event_response(&event)).is_ok();
+
+    // the system database is sent on the first heartbeat
+    let databases: Vec<String> = routers
+        .read()
+        .unwrap()
+        .keys()
+        .filter(|database| *database != "_default")
+        .cloned()
+        .collect();
+
+    if !send(ClusterEvent::Resync {
+        seq,
+        databases: databases.clone(),
+    }) {
+        return false;
+    }
+
+    for database in databases {
+        // copy one database at a time, so the writes to the others are not blocked
+        let records = {
+            let routers = routers.read().unwrap();
+
+            let dd = match routers.get(&database) {
+                Some(dd) => dd,
+                None => continue,
+            };
+
+            let keys = dd.list_keys().unwrap_or_default();
+
+            keys.into_iter()
+                .filter_map(|key| Some((dd.get(&key).ok()??, key)))
+                .collect::<Vec<_>>()
+        };
+
+        for (value, key) in records {
+            let record = ClusterEvent::Record {
+                database: database.clone(),
+                key,
+                value,
+            };
+
+            if !send(record) {
+                return false;
+            }
+        }
+    }
+
+    send(ClusterEvent::Synced { seq })
+}
+
+/// The databases a replica writes the events of its primary to
+#[derive(Clone)]
+pub struct Replica {
+    pub config: Arc<schema::RustbaseConfig>,
+    pub routers: Arc<RwLock<HashMap<String, DustData>>>,
+    pub system_db: Arc<RwLock<DustData>>,
+    pub cache: Arc<RwLock<Cache>>,
+    pub state: Arc<ReplicaState>,
+}
+
+/// Runs forever, replicating the primary into the local databases and reconnecting
+/// when the connection is lost.
+/// Follows the primary until `stop` changes, the event being applied is finished first
+pub async fn run_replica(replica: Replica, mut stop: watch::Receiver<bool>) {
+    let cluster = replica.config.cluster.clone().unwrap();
+
+    let primary = match &cluster.primary {
+        Some(primary) => primary.clone(),
+        None => {
+            println!("[Cluster] replication disabled: the address of the primary is missing");
+            return;
+        }
+    };
+
+    loop {
+        let result = replicate(&replica, &cluster, &primary, &mut stop).await;
+
+        replica.state.connected.store(false, Ordering::SeqCst);
+
+        match result {
+            Ok(()) => return,
+            Err(e) => println!(
+                "[Cluster] replication from {} stopped: {}",
+                primary.yellow(),
+                e
+            ),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(RETRY_INTERVAL) => {}
+            _ = stop.changed() => return,
+        }
+    }
+}
+
+async fn replicate(
+    replica: &Replica,
+    cluster: &schema::Cluster,
+    primary: &str,
+    stop: &mut watch::Receiver<bool>,
+) -> Result<(), ClientError> {
+    let mut client = Client::connect(primary).await?;
+
+    if let (Some(username), Some(password)) = (&cluster.username, &cluster.password) {
+        client.authenticate(username, password).await?;
+    }
+
+    let applied = replica.state.applied.load(Ordering::SeqCst);
+
+    let request = Request {
+        header: ReqHeader {
+            type_: Type::Cluster,
+            auth: None,
+        },
+        body: bson::doc! {
+            "action": "replicate",
+            "after": if applied > 0 { Bson::Int64(applied) } else { Bson::Null },
+        },
+    };
+
+    let mut response = client.request(&request).await?;
+
+    replica.state.connected.store(true, Ordering::SeqCst);
+    println!("[Cluster] replicating from {}", primary.yellow());
+
+    loop {
+        if response.header.is_error {
+            return Err(ClientError::BadResponse(
+                response.header.messages.unwrap_or_default().join(", "),
+            ));
+        }
+
+        let event: ClusterEvent = bson::from_bson(response.body.unwrap_or(Bson::Null))
+            .map_err(|e| ClientError::BadResponse(e.to_string()))?;
+
+        // applying takes blocking locks and disk io
+        let c_replica = replica.clone();
+        tokio::task::spawn_blocking(move || apply_event(&c_replica, event))
+            .await
+            .map_err(|e| ClientError::BadResponse(e.to_string()))?
+            .map_err(|e| ClientError::BadResponse(format!("{:?}", e.code)))?;
+
+        response = tokio::select! {
+            response = client.read_response() => response?,
+            _ = stop.changed() => return Ok(()),
+        };
+    }
+}
+
+pub fn apply_event(replica: &Replica, event: ClusterEvent) -> dustdata::Result<()> {
+    let Replica {
+        config,
+        routers,
+        system_db,
+        cache,
+        state,
+    } = replica;
+
+    match event {
+        ClusterEvent::Resync { seq, databases } => {
+            println!(
+                "[Cluster] resyncing from the primary at {}",
+                seq.to_string().yellow()
+            );
+
+            *cache.write().unwrap() = Cache::new(config.cache_size);
+
+            let mut routers = routers.write().unwrap();
+
+            // the databases the primary no longer has are dropped, the others emptied
+            let dropped: Vec<String> = routers
+                .keys()
+                .filter(|database| *database != "_default" && !databases.contains(database))
+                .cloned()
+                .collect();
+
+            for database in dropped {
+                if let Some(mut dd) = routers.remove(&database) {
+                    dd.flush()?;
+                    drop(dd);
+
+                    route::remove_dustdata(&config.storage.path, database.clone());
+                    println!("[Cluster] database {} deleted", database);
+                }
+            }
+
+            for (database, dd) in routers.iter_mut() {
+                if database == "_default" {
+                    continue;
+                }
+
+                for key in dd.list_keys()? {
+                    dd.delete(&key).ok();
+                }
+            }
+        }
+
+        ClusterEvent::Record {
+            database,
+            key,
+            value,
+        } => {
+            let cache_key = format!("{}:{}", database, key);
+            cache.write().unwrap().remove(&cache_key).ok();
+
+            let mut routers = routers.write().unwrap();
+            let dd = router(config, &mut routers, &database);
+
+            match dd.insert(&key, value.clone()) {
+                Err(e) if matches!(e.code, dustdata::ErrorCode::KeyExists) => {
+                    dd.update(&key, value)?
+                }
+                result => result?,
+            }
+        }
+
+        ClusterEvent::Synced { seq } => {
+            state.applied.store(seq, Ordering::SeqCst);
+            state.head.fetch_max(seq, Ordering::SeqCst);
+            state.save_or_log(config);
+
+            println!("[Cluster] resynced at {}", seq.to_string().yellow());
+        }
+
+        ClusterEvent::System { records } => {
+            let mut system_db = system_db.write().unwrap();
+
+            for key in system_db.list_keys()? {
+                if !records.contains_key(&key) {
+                    system_db.delete(&key)?;
+                }
+            }
+
+            for (key, value) in records {
+                match system_db.insert(&key, value.clone()) {
+                    Err(e) if matches!(e.code, dustdata::ErrorCode::KeyExists) => {
+                        system_db.update(&key, value)?
+                    }
+                    result => result?,
+                }
+            }
+        }
+
+        ClusterEvent::Operation { entry, head } => {
+            apply_operation(config, routers, cache, None, &entry)?;
+
+            state.applied.store(entry.seq, Ordering::SeqCst);
+            state.head.store(head, Ordering::SeqCst);
+        }
+
+        ClusterEvent::Heartbeat { head } => {
+            state.head.store(head, Ordering::SeqCst);
+            state.save_or_log(config);
+
+            let lag = state.lag();
+
+            if lag > 0 {
+                println!("[Cluster] replica is {} operations behind", lag);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies a write of another server to the local databases, returning what it changed in
+/// the usage of the database. The usage is added to `counters` when given.
+pub fn apply_operation(
+    config: &schema::RustbaseConfig,
+    routers: &RwLock<HashMap<String, DustData>>,
+    cache: &RwLock<Cache>,
+    counters: Option<&Counters>,
+    entry: &OpEntry,
+) -> dustdata::Result<Change> {
+    if let Some(key) = &entry.key {
+        let cache_key = format!("{}:{}", entry.database, key);
+        cache.write().unwrap().remove(&cache_key).ok();
+    }
+
+    let mut routers = routers.write().unwrap();
+
+    if entry.operation == Operation::DropDatabase {
+        if let Some(counters) = counters {
+            counters.forget(&entry.database);
+        }
+
+        if let Some(mut dd) = routers.remove(&entry.database) {
+            dd.flush()?;
+            drop(dd);
+
+            route::remove_dustdata(&config.storage.path, entry.database.clone());
+            println!("[Cluster] database {} deleted", entry.database);
+        }
+
+        return Ok(Change::default());
+    }
+
+    let dd = router(config, &mut routers, &entry.database);
+
+    let change = match (&entry.key, &entry.operation) {
+        (Some(key), operation) => {
+            let previous = dd.get(key)?;
+
+            match (operation, &previous, &entry.value) {
+                (Operation::Delete, _, _) => Change::delete(key, previous.as_ref()),
+                // an insert of an existing key replaces it, as an update of a missing one adds it
+                (_, Some(_), Some(value)) => Change::update(previous.as_ref(), value),
+                (_, None, Some(value)) => Change::insert(key, value),
+                _ => Change::default(),
+            }
+        }
+        (None, _) => Change::default(),
+    };
+
+    oplog::apply_entry(dd, entry)?;
+
+    if let Some(counters) = counters {
+        counters.record(&entry.database, &change);
+    }
+
+    Ok(change)
+}
+
+fn router<'a>(
+    config: &schema::RustbaseConfig,
+    routers: &'a mut HashMap<String, DustData>,
+    database: &str,
+) -> &'a mut DustData {
+    routers.entry(database.to_string()).or_insert_with(|| {
+        println!("[Cluster] created database {}", database);
+        route::create_dustdata(config, Some(database))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    struct Node {
+        config: Arc<schema::RustbaseConfig>,
+        routers: Arc<RwLock<HashMap<String, DustData>>>,
+        system_db: Arc<RwLock<DustData>>,
+    }
+
+    impl Node {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "rustbase-cluster-{}-{}",
+                name,
+                std::process::id()
+            ));
+            fs::remove_dir_all(&path).ok();
+
+            let mut config = config::default_configuration();
+            config.storage.path = path;
+            config.storage.oplog = Some(schema::OpLogStorageConfig {
+                path: None,
+                sync: Some(false),
+            });
+
+            let system_db = route::create_dustdata(&config, Some("_default"));
+
+            Self {
+                config: Arc::new(config),
+                routers: Arc::new(RwLock::new(HashMap::new())),
+                system_db: Arc::new(RwLock::new(system_db)),
+            }
+        }
+
+        fn insert(&self, database: &str, key: &str, value: Bson) {
+            let mut routers = self.routers.write().unwrap();
+            let dd = router(&self.config, &mut routers, database);
+
+            dd.insert(key, value).unwrap();
+        }
+
+        fn get(&self, database: &str, key: &str) -> Option<Bson> {
+            let routers = self.routers.read().unwrap();
+
+            routers.get(database)?.get(key).unwrap()
+        }
+
+        fn replica(&self) -> Replica {
+            Replica {
+                config: self.config.clone(),
+                routers: self.routers.clone(),
+                system_db: self.system_db.clone(),
+                cache: Arc::new(RwLock::new(Cache::new(1024))),
+                state: Arc::new(ReplicaState::default()),
+            }
+        }
+    }
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.config.storage.path).ok();
+        }
+    }
+
+    async fn next_event(receiver: &mut mpsc::Receiver<Response>) -> ClusterEvent {
+        let response = receiver.recv().await.unwrap();
+
+        bson::from_bson(response.body.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn replica_follows_the_primary() {
+        let primary = Node::new("primary");
+        let oplog = Arc::new(OpLog::open(&primary.config).unwrap());
+
+        primary.insert("shop", "a", Bson::Int32(1));
+        primary
+            .system_db
+            .write()
+            .unwrap()
+            .insert("alice", Bson::Document(bson::doc! { "permission": 1 }))
+            .unwrap();
+
+        // the replica has a database and a user the primary does not have
+        let secondary = Node::new("replica");
+        secondary.insert("stale", "x", Bson::Int32(0));
+        secondary
+            .system_db
+            .write()
+            .unwrap()
+            .insert("bob", Bson::Null)
+            .unwrap();
+
+        let replica = secondary.replica();
+
+        let mut receiver = replication_stream(
+            oplog.clone(),
+            primary.routers.clone(),
+            primary.system_db.clone(),
+            None,
+            DEFAULT_MAX_LAG,
+        );
+
+        loop {
+            let event = next_event(&mut receiver).await;
+            let heartbeat = matches!(event, ClusterEvent::Heartbeat { .. });
+
+            apply_event(&replica, event).unwrap();
+
+            if heartbeat {
+                break;
+            }
+        }
+
+        assert_eq!(secondary.get("shop", "a"), Some(Bson::Int32(1)));
+        assert!(!secondary.routers.read().unwrap().contains_key("stale"));
+
+        {
+            let system_db = secondary.system_db.read().unwrap();
+            assert!(system_db.get("alice").unwrap().is_some());
+            assert!(system_db.get("bob").unwrap().is_none());
+        }
+
+        primary.insert("shop", "b", Bson::Int32(2));
+        oplog
+            .append(
+                "shop",
+                Operation::Insert,
+                Some("b".to_string()),
+                Some(Bson::Int32(2)),
+            )
+            .unwrap();
+
+        let event = next_event(&mut receiver).await;
+        assert!(matches!(event, ClusterEvent::Operation { .. }));
+
+        apply_event(&replica, event).unwrap();
+
+        assert_eq!(secondary.get("shop", "b"), Some(Bson::Int32(2)));
+        assert_eq!(replica.state.lag(), 0);
+    }
+
+    #[tokio::test]
+    async fn replica_within_the_lag_is_not_resynced() {
+        let primary = Node::new("primary-lag");
+        let oplog = Arc::new(OpLog::open(&primary.config).unwrap());
+
+        for key in ["a", "b"] {
+            oplog
+                .append("shop", Operation::Insert, Some(key.to_string()), None)
+                .unwrap();
+        }
+
+        let mut receiver = replication_stream(
+            oplog,
+            primary.routers.clone(),
+            primary.system_db.clone(),
+            Some(1),
+            DEFAULT_MAX_LAG,
+        );
+
+        // the first heartbeat may come before the missed operation
+        loop {
+            match next_event(&mut receiver).await {
+                ClusterEvent::Operation { entry, head } => {
+                    assert_eq!(entry.seq, 2);
+                    assert_eq!(head, 2);
+                    break;
+                }
+                ClusterEvent::System { .. } | ClusterEvent::Heartbeat { .. } => {}
+                event => panic!("expected the missed operation, got {:?}", event),
+            }
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use bson::Bson;
 use dustdata::DustData;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 
 use crate::config;
@@ -10,20 +11,33 @@ use crate::server::wirewave::server::ResHeader;
 
 use config::schema;
 use server::cache;
+use server::oplog;
+use server::quota;
+use server::raft;
+use server::reload;
+use server::shard;
+use server::validation;
 use server::wirewave;
 
 use cache::Cache;
+use oplog::OpLog;
 use query::parser::{ASTNode, Keywords, Verbs};
+use quota::Scope;
+use raft::Raft;
+use reload::LiveConfig;
+use shard::{ShardOperation, ShardRequest, ShardRouter};
+use validation::{DatabaseSchema, Mode};
 use wirewave::authorization::UserPermission;
 use wirewave::server::{Error, Response, Status};
 
 use interface::TransactionError;
 
-use super::{interface, var_manager};
+use super::{evaluator, interface, var_manager};
 
 pub struct Core {
     interface: interface::DustDataInterface,
     variable_manager: var_manager::VariableManager,
+    live_config: Option<Arc<LiveConfig>>,
 }
 
 struct ExpressionResponse(Option<Bson>);
@@ -36,6 +50,7 @@ impl Core {
         system_db: Arc<RwLock<DustData>>,
         current_database: String,
         current_user: Option<String>,
+        oplog: Option<Arc<OpLog>>,
     ) -> Self {
         let interface = interface::DustDataInterface::new(
             cache,
@@ -44,6 +59,7 @@ impl Core {
             system_db,
             current_database,
             current_user,
+            oplog,
         );
 
         let variable_manager = var_manager::VariableManager::new();
@@ -51,14 +67,121 @@ impl Core {
         Self {
             interface,
             variable_manager,
+            live_config: None,
         }
     }
 
+    /// Makes the given variables available to the query, e.g. when sent in the request body.
+    pub fn set_variables(&mut self, variables: &bson::Document) {
+        for (name, value) in variables {
+            self.variable_manager.set(name, value.clone());
+        }
+    }
+
+    /// Runs the key operations on the nodes of a sharded cluster.
+    pub fn set_shards(&mut self, shards: Arc<ShardRouter>) {
+        self.interface.set_shards(shards);
+    }
+
+    /// Commits the writes to the Raft log before they are applied.
+    pub fn set_raft(&mut self, raft: Arc<Raft>) {
+        self.interface.set_raft(raft);
+    }
+
+    /// Shares the usage of the databases counted for the quotas.
+    pub fn set_counters(&mut self, counters: Arc<quota::Counters>) {
+        self.interface.set_counters(counters);
+    }
+
+    /// Charges the writes to the user a router runs them for, instead of the current user.
+    pub fn set_quota_user(&mut self, user: Option<String>) {
+        self.interface.set_quota_user(user);
+    }
+
+    /// Allows `reload config` to apply the configuration file to the running server.
+    pub fn set_live_config(&mut self, live_config: Arc<LiveConfig>) {
+        self.live_config = Some(live_config);
+    }
+
+    /// Runs an operation forwarded by a router on the current database.
+    pub fn run_shard_operation(&mut self, request: ShardRequest) -> Result<Response, Error> {
+        let key = request.key.ok_or_else(|| query_error("key is required"));
+        let value = request
+            .value
+            .ok_or_else(|| query_error("value is required"));
+
+        let result = match request.operation {
+            ShardOperation::Get => self.interface.get_from_dustdata(key?),
+            ShardOperation::Insert => self.interface.insert_into_dustdata(key?, value?),
+            ShardOperation::Update => self.interface.update_dustdata(key?, value?),
+            ShardOperation::Delete => self.interface.delete_from_dustdata(key?),
+            ShardOperation::List => self
+                .interface
+                .list_from_dustdata()
+                .map(|keys| Bson::Array(keys.into_iter().map(Bson::String).collect())),
+            ShardOperation::DropDatabase => self.interface.delete_database(request.database),
+            ShardOperation::Databases => Ok(Bson::Array(
+                self.interface
+                    .list_databases()
+                    .into_iter()
+                    .map(Bson::String)
+                    .collect(),
+            )),
+        }
+        .map_err(|e| self.dd_error(e))?;
+
+        Ok(Response {
+            header: ResHeader {
+                status: Status::Ok,
+                messages: None,
+                is_error: false,
+            },
+            body: Some(result),
+        })
+    }
+
+    /// Checks that the current user can read the current database, e.g. to watch its changes.
+    pub fn authorize_read(&self) -> Result<(), Error> {
+        if self.interface.current_database == "_default" {
+            return Err(Error {
+                message: "database reserved".to_string(),
+                query_message: None,
+                status: Status::Reserved,
+            });
+        }
+
+        self.authorize(UserPermission::Read)
+    }
+
+    /// Checks that the current user is an admin, e.g. to replicate the databases.
+    pub fn authorize_admin(&self) -> Result<(), Error> {
+        self.authorize(UserPermission::Admin)
+    }
+
+    fn authorize(&self, perm: UserPermission) -> Result<(), Error> {
+        if let Some(current_user) = &self.interface.current_user {
+            let has_perm = self
+                .interface
+                .user_has_perm(current_user.clone(), perm)
+                .map_err(|e| self.dd_error(e))?;
+
+            if !has_perm {
+                return Err(Error {
+                    message: "permission denied".to_string(),
+                    query_message: None,
+                    status: Status::NotAuthorized,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn run_ast(&mut self, ast: Vec<ASTNode>) -> Result<Response, Error> {
         let mut bodies = Vec::new();
 
         for node in ast {
-            let result = match node {
+            let result = match self.resolve(node)? {
                 ASTNode::IntoExpression {
                     keyword,
                     value,
@@ -75,6 +198,13 @@ impl Core {
 
                 ASTNode::AssignmentExpression { ident, value } => self.assignment(ident, *value),
 
+                ASTNode::ToExpression {
+                    keyword,
+                    verb,
+                    ident,
+                    target,
+                } => self.expr_to(keyword, verb, ident, *target),
+
                 _ => {
                     let error = Error {
                         message: "Invalid query".to_string(),
@@ -101,6 +231,68 @@ impl Core {
         })
     }
 
+    /// Computes the expressions of a statement, with the variables as they are when it runs
+    fn resolve(&self, node: ASTNode) -> Result<ASTNode, Error> {
+        let resolve_box = |node: Box<ASTNode>| self.resolve(*node).map(Box::new);
+
+        Ok(match node {
+            ASTNode::Expression(expression) => ASTNode::Bson(
+                evaluator::evaluate(&expression, &self.variable_manager)
+                    .map_err(|e| query_error(&e))?,
+            ),
+
+            ASTNode::AssignmentExpression { ident, value } => ASTNode::AssignmentExpression {
+                ident,
+                value: resolve_box(value)?,
+            },
+
+            ASTNode::MonadicExpression {
+                keyword,
+                verb,
+                expr,
+            } => ASTNode::MonadicExpression {
+                keyword,
+                verb,
+                expr: expr
+                    .map(|expr| {
+                        expr.into_iter()
+                            .map(|node| self.resolve(node))
+                            .collect::<Result<Vec<_>, _>>()
+                    })
+                    .transpose()?,
+            },
+
+            ASTNode::IntoExpression {
+                keyword,
+                value,
+                ident,
+            } => ASTNode::IntoExpression {
+                keyword,
+                value: resolve_box(value)?,
+                ident,
+            },
+
+            ASTNode::SingleExpression { keyword, ident } => ASTNode::SingleExpression {
+                keyword,
+                ident: ident.map(resolve_box).transpose()?,
+            },
+
+            ASTNode::ToExpression {
+                keyword,
+                verb,
+                ident,
+                target,
+            } => ASTNode::ToExpression {
+                keyword,
+                verb,
+                ident,
+                target: resolve_box(target)?,
+            },
+
+            node => node,
+        })
+    }
+
     fn assignment(&mut self, ident: String, value: ASTNode) -> Result<ExpressionResponse, Error> {
         let value = match value {
             ASTNode::Bson(bson) => bson,
@@ -116,6 +308,12 @@ impl Core {
                 verb,
                 expr,
             } => self.monadic_expr(keyword, verb, expr)?.0.into(),
+            ASTNode::ToExpression {
+                keyword,
+                verb,
+                ident,
+                target,
+            } => self.expr_to(keyword, verb, ident, *target)?.0.into(),
 
             _ => {
                 return Err(query_error(
@@ -177,6 +375,16 @@ impl Core {
                 Verbs::Database => self.ast_database_delete(expr),
 
                 Verbs::User => self.ast_user_delete(expr),
+
+                _ => {
+                    let error = Error {
+                        message: format!("{:?} is unexpected for delete expression", verb),
+                        query_message: None,
+                        status: Status::InvalidQuery,
+                    };
+
+                    Err(error)
+                }
             },
 
             Keywords::Update => match verb {
@@ -193,6 +401,54 @@ impl Core {
                 }
             },
 
+            Keywords::Show => match verb {
+                Verbs::User => self.ast_user_show(expr),
+
+                Verbs::Quota => self.ast_quota_show(expr),
+
+                Verbs::Schema => self.ast_schema_show(expr),
+
+                _ => {
+                    let error = Error {
+                        message: format!("{:?} is unexpected for show expression", verb),
+                        query_message: None,
+                        status: Status::InvalidQuery,
+                    };
+
+                    Err(error)
+                }
+            },
+
+            Keywords::Set => match verb {
+                Verbs::Quota => self.ast_quota_set(expr),
+
+                Verbs::Schema => self.ast_schema_set(expr),
+
+                _ => {
+                    let error = Error {
+                        message: format!("{:?} is unexpected for set expression", verb),
+                        query_message: None,
+                        status: Status::InvalidQuery,
+                    };
+
+                    Err(error)
+                }
+            },
+
+            Keywords::Validate => match verb {
+                Verbs::Schema => self.ast_schema_validate(expr),
+
+                _ => {
+                    let error = Error {
+                        message: format!("{:?} is unexpected for validate expression", verb),
+                        query_message: None,
+                        status: Status::InvalidQuery,
+                    };
+
+                    Err(error)
+                }
+            },
+
             _ => {
                 let error = Error {
                     message: format!("{:?} is unexpected for monadic expression", keyword),
@@ -205,6 +461,42 @@ impl Core {
         }
     }
 
+    fn expr_to(
+        &mut self,
+        keyword: Keywords,
+        verb: Verbs,
+        ident: Option<Box<ASTNode>>,
+        target: ASTNode,
+    ) -> Result<ExpressionResponse, Error> {
+        match keyword {
+            Keywords::Snapshot => match verb {
+                Verbs::Database => self.ast_database_snapshot(ident, target),
+
+                Verbs::All => self.ast_snapshot_all(target),
+
+                _ => {
+                    let error = Error {
+                        message: format!("{:?} is unexpected for snapshot expression", verb),
+                        query_message: None,
+                        status: Status::InvalidQuery,
+                    };
+
+                    Err(error)
+                }
+            },
+
+            _ => {
+                let error = Error {
+                    message: format!("{:?} is unexpected for to expression", keyword),
+                    query_message: None,
+                    status: Status::InvalidQuery,
+                };
+
+                Err(error)
+            }
+        }
+    }
+
     fn sgl_expr(
         &mut self,
         keyword: Keywords,
@@ -217,6 +509,8 @@ impl Core {
 
             Keywords::List => self.ast_sgl_list(),
 
+            Keywords::Reload => self.ast_sgl_reload(ident),
+
             _ => {
                 let error = Error {
                     message: format!("{:?} is unexpected for single expression", keyword),
@@ -437,6 +731,25 @@ impl Core {
         }
     }
 
+    fn ast_user_show(&mut self, expr: Option<Vec<ASTNode>>) -> Result<ExpressionResponse, Error> {
+        let user = match expr.as_deref() {
+            None | Some([]) => None,
+            Some([ASTNode::Identifier(ident)]) => Some(ident.clone()),
+            Some([ASTNode::VariableIdentifier(key)]) => match self.variable_manager.get(key) {
+                Some(Bson::String(key)) => Some(key.to_owned()),
+                Some(_) => return Err(query_error("variable must be a string")),
+                None => return Err(query_error("variable not found")),
+            },
+            _ => return Err(query_error("show user only takes a username")),
+        };
+
+        match self.interface.show_users(user) {
+            Ok(result) => Ok(ExpressionResponse(Some(result))),
+
+            Err(e) => Err(self.dd_error(e)),
+        }
+    }
+
     fn ast_user_update(&mut self, expr: Option<Vec<ASTNode>>) -> Result<ExpressionResponse, Error> {
         if expr.is_none() {
             return Err(query_error("user update must have an expression"));
@@ -548,6 +861,268 @@ impl Core {
         }
     }
 
+    fn ast_quota_show(&mut self, expr: Option<Vec<ASTNode>>) -> Result<ExpressionResponse, Error> {
+        let mut names = Vec::new();
+
+        for node in expr.unwrap_or_default() {
+            match node {
+                ASTNode::Identifier(ident) => names.push(ident),
+                _ => {
+                    return Err(query_error(
+                        "show quota only takes database or user and a name",
+                    ))
+                }
+            }
+        }
+
+        let scope = self.quota_scope(names)?;
+
+        match self.interface.show_quota(scope) {
+            Ok(result) => Ok(ExpressionResponse(Some(result))),
+
+            Err(e) => Err(self.dd_error(e)),
+        }
+    }
+
+    fn ast_quota_set(&mut self, expr: Option<Vec<ASTNode>>) -> Result<ExpressionResponse, Error> {
+        let mut names = Vec::new();
+        let mut limits = Vec::new();
+
+        for node in expr.unwrap_or_default() {
+            match node {
+                ASTNode::Identifier(ident) => names.push(ident),
+
+                ASTNode::AssignmentExpression { ident, value } => {
+                    let value = match *value {
+                        ASTNode::Bson(value) => value,
+                        ASTNode::VariableIdentifier(ref key) => {
+                            match self.variable_manager.get(key) {
+                                Some(value) => value.clone(),
+                                None => return Err(query_error("variable not found")),
+                            }
+                        }
+                        _ => Bson::Boolean(false),
+                    };
+
+                    let limit = match value {
+                        Bson::Null => None,
+                        Bson::Int32(limit) if limit >= 0 => Some(limit as i64),
+                        Bson::Int64(limit) if limit >= 0 => Some(limit),
+                        _ => {
+                            return Err(query_error(&format!(
+                                "{} must be a positive integer, or null to remove it",
+                                ident
+                            )))
+                        }
+                    };
+
+                    limits.push((ident, limit));
+                }
+
+                _ => {
+                    return Err(query_error(
+                        "set quota only takes limits, like max_keys = 1000",
+                    ))
+                }
+            }
+        }
+
+        if limits.is_empty() {
+            return Err(query_error("set quota must have at least one limit"));
+        }
+
+        let scope = self.quota_scope(names)?;
+
+        match self.interface.set_quota(scope, limits) {
+            Ok(result) => Ok(ExpressionResponse(Some(result))),
+
+            Err(e) => Err(self.dd_error(e)),
+        }
+    }
+
+    /// `database <name>` or `user <name>`, the current database when not given
+    fn quota_scope(&self, names: Vec<String>) -> Result<Scope, Error> {
+        match names.as_slice() {
+            [] => Ok(Scope::Database(self.interface.current_database.clone())),
+            [kind, name] if kind == "database" => Ok(Scope::Database(name.clone())),
+            [kind, name] if kind == "user" => Ok(Scope::User(name.clone())),
+            _ => Err(query_error(
+                "a quota is the one of database <name> or user <name>",
+            )),
+        }
+    }
+
+    fn ast_schema_show(&mut self, expr: Option<Vec<ASTNode>>) -> Result<ExpressionResponse, Error> {
+        let (database, schema, mode) = self.schema_args(expr)?;
+
+        if schema.is_some() || mode.is_some() {
+            return Err(query_error("show schema only takes on <database>"));
+        }
+
+        match self.interface.show_schema(database) {
+            Ok(result) => Ok(ExpressionResponse(Some(result))),
+
+            Err(e) => Err(self.dd_error(e)),
+        }
+    }
+
+    fn ast_schema_set(&mut self, expr: Option<Vec<ASTNode>>) -> Result<ExpressionResponse, Error> {
+        let (database, schema, mode) = self.schema_args(expr)?;
+
+        let schema = match schema {
+            Some(Bson::Document(schema)) => Some(DatabaseSchema {
+                schema,
+                mode: mode.unwrap_or_default(),
+            }),
+            Some(Bson::Null) if mode.is_none() => None,
+            _ => {
+                return Err(query_error(
+                    "set schema must have a schema document, or null to remove it",
+                ))
+            }
+        };
+
+        match self.interface.set_schema(database, schema) {
+            Ok(result) => Ok(ExpressionResponse(Some(result))),
+
+            Err(e) => Err(self.dd_error(e)),
+        }
+    }
+
+    fn ast_schema_validate(
+        &mut self,
+        expr: Option<Vec<ASTNode>>,
+    ) -> Result<ExpressionResponse, Error> {
+        let (database, schema, mode) = self.schema_args(expr)?;
+
+        let schema = match schema {
+            Some(Bson::Document(schema)) if mode.is_none() => Some(schema),
+            None if mode.is_none() => None,
+            _ => {
+                return Err(query_error(
+                    "validate schema only takes on <database> and a schema document",
+                ))
+            }
+        };
+
+        match self.interface.validate_database(database, schema) {
+            Ok(result) => Ok(ExpressionResponse(Some(result))),
+
+            Err(e) => Err(self.dd_error(e)),
+        }
+    }
+
+    /// `on <database>` (the current database when not given), the schema and its mode
+    fn schema_args(
+        &self,
+        expr: Option<Vec<ASTNode>>,
+    ) -> Result<(String, Option<Bson>, Option<Mode>), Error> {
+        let mut names = Vec::new();
+        let mut schema = None;
+        let mut mode = None;
+
+        for node in expr.unwrap_or_default() {
+            match node {
+                ASTNode::Identifier(ident) => names.push(ident),
+
+                ASTNode::Bson(value) => schema = Some(value),
+
+                ASTNode::VariableIdentifier(ref key) => match self.variable_manager.get(key) {
+                    Some(value) => schema = Some(value.clone()),
+                    None => return Err(query_error("variable not found")),
+                },
+
+                ASTNode::AssignmentExpression { ident, value } if ident == "mode" => {
+                    mode = match *value {
+                        ASTNode::Bson(Bson::String(mode)) => {
+                            Some(mode.parse().map_err(|e: String| query_error(&e))?)
+                        }
+                        _ => return Err(query_error("mode must be \"strict\" or \"warn\"")),
+                    }
+                }
+
+                _ => return Err(query_error("unexpected argument for a schema")),
+            }
+        }
+
+        let database = match names.as_slice() {
+            [] => self.interface.current_database.clone(),
+            [on, database] if on == "on" => database.clone(),
+            _ => {
+                return Err(query_error(
+                    "the database of a schema is given by on <database>",
+                ))
+            }
+        };
+
+        Ok((database, schema, mode))
+    }
+
+    fn ast_database_snapshot(
+        &mut self,
+        ident: Option<Box<ASTNode>>,
+        target: ASTNode,
+    ) -> Result<ExpressionResponse, Error> {
+        let database = match ident.map(|ident| *ident) {
+            Some(ASTNode::Identifier(ident)) => ident,
+            Some(ASTNode::VariableIdentifier(ref key)) => {
+                let value = self.variable_manager.get(key);
+
+                if value.is_none() {
+                    return Err(query_error("variable not found"));
+                }
+
+                if let Bson::String(key) = value.unwrap() {
+                    key.to_owned()
+                } else {
+                    return Err(query_error("variable must be a string"));
+                }
+            }
+            Some(_) => return Err(query_error("database must be an identifier")),
+            None => self.interface.current_database.clone(),
+        };
+
+        let path = self.snapshot_path(target)?;
+
+        match self.interface.snapshot_database(database, path) {
+            Ok(result) => Ok(ExpressionResponse(Some(result))),
+
+            Err(e) => Err(self.dd_error(e)),
+        }
+    }
+
+    fn ast_snapshot_all(&mut self, target: ASTNode) -> Result<ExpressionResponse, Error> {
+        let path = self.snapshot_path(target)?;
+
+        match self.interface.snapshot_all(path) {
+            Ok(result) => Ok(ExpressionResponse(Some(result))),
+
+            Err(e) => Err(self.dd_error(e)),
+        }
+    }
+
+    fn snapshot_path(&self, target: ASTNode) -> Result<PathBuf, Error> {
+        let path = match target {
+            ASTNode::Bson(Bson::String(path)) => path,
+            ASTNode::VariableIdentifier(ref key) => {
+                let value = self.variable_manager.get(key);
+
+                if value.is_none() {
+                    return Err(query_error("variable not found"));
+                }
+
+                if let Bson::String(path) = value.unwrap() {
+                    path.to_owned()
+                } else {
+                    return Err(query_error("variable must be a string"));
+                }
+            }
+            _ => return Err(query_error("snapshot path must be a string")),
+        };
+
+        Ok(PathBuf::from(path))
+    }
+
     fn ast_sgl_get(&mut self, ident: Option<Box<ASTNode>>) -> Result<ExpressionResponse, Error> {
         if ident.is_none() {
             return Err(query_error("get must have an expression"));
@@ -604,9 +1179,7 @@ impl Core {
                 }
             }
 
-            _ => {
-                unreachable!()
-            }
+            _ => Err(query_error("key must be an identifier")),
         }
     }
 
@@ -620,6 +1193,41 @@ impl Core {
         }
     }
 
+    fn ast_sgl_reload(&mut self, ident: Option<Box<ASTNode>>) -> Result<ExpressionResponse, Error> {
+        match ident.as_deref() {
+            Some(ASTNode::Identifier(ident)) if ident == "config" => {}
+            _ => return Err(query_error("reload must be followed by config")),
+        }
+
+        self.authorize_admin()?;
+
+        let live_config = match &self.live_config {
+            Some(live_config) => live_config,
+            None => {
+                return Err(Error {
+                    message: "the configuration cannot be reloaded".to_string(),
+                    query_message: None,
+                    status: Status::InternalError,
+                })
+            }
+        };
+
+        let result = live_config.reload();
+        server::main::print_reload(&result);
+
+        match result {
+            Ok(changed) => Ok(ExpressionResponse(Some(Bson::Array(
+                changed.into_iter().map(Bson::String).collect(),
+            )))),
+
+            Err(message) => Err(Error {
+                message,
+                query_message: None,
+                status: Status::InternalError,
+            }),
+        }
+    }
+
     // error
     fn dd_error(&self, error: TransactionError) -> Error {
         match error {
@@ -0,0 +1,469 @@
+use bson::{Binary, Bson};
+use std::cmp::Ordering;
+
+use crate::query::parser::{Expression, Operator};
+use crate::server::validation::type_name;
+
+use super::var_manager::VariableManager;
+
+/// Computes an expression with the variables of the query
+pub fn evaluate(expression: &Expression, variables: &VariableManager) -> Result<Bson, String> {
+    match expression {
+        Expression::Literal(value) => Ok(value.clone()),
+
+        Expression::Variable(name) => variables
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("variable {} not found", name)),
+
+        Expression::Document(fields) => fields
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), evaluate(value, variables)?)))
+            .collect::<Result<bson::Document, String>>()
+            .map(Bson::Document),
+
+        Expression::Array(items) => items
+            .iter()
+            .map(|item| evaluate(item, variables))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Bson::Array),
+
+        Expression::Field(value, field) => match evaluate(value, variables)? {
+            Bson::Document(doc) => Ok(doc.get(field).cloned().unwrap_or(Bson::Null)),
+            // a missing document has no fields either, `$user.address.city` is null
+            Bson::Null => Ok(Bson::Null),
+            value => Err(format!("cannot read .{} of a {}", field, type_name(&value))),
+        },
+
+        Expression::Index(value, index) => {
+            let value = evaluate(value, variables)?;
+            let index = evaluate(index, variables)?;
+
+            match (value, index) {
+                (Bson::Array(items), index) => match integer(&index) {
+                    Some(index) => Ok(usize::try_from(index)
+                        .ok()
+                        .and_then(|index| items.get(index).cloned())
+                        .unwrap_or(Bson::Null)),
+                    None => Err(format!(
+                        "an array is indexed by an integer, not a {}",
+                        type_name(&index)
+                    )),
+                },
+                (Bson::Document(doc), Bson::String(key)) => {
+                    Ok(doc.get(key).cloned().unwrap_or(Bson::Null))
+                }
+                (Bson::Null, _) => Ok(Bson::Null),
+                (value, index) => Err(format!(
+                    "cannot index a {} with a {}",
+                    type_name(&value),
+                    type_name(&index)
+                )),
+            }
+        }
+
+        Expression::Unary(operator, value) => {
+            let value = evaluate(value, variables)?;
+
+            match (operator, value) {
+                (Operator::Not, Bson::Boolean(value)) => Ok(Bson::Boolean(!value)),
+                (Operator::Neg, Bson::Int32(value)) => value
+                    .checked_neg()
+                    .map(Bson::Int32)
+                    .ok_or_else(|| "integer overflow".to_string()),
+                (Operator::Neg, Bson::Int64(value)) => value
+                    .checked_neg()
+                    .map(Bson::Int64)
+                    .ok_or_else(|| "integer overflow".to_string()),
+                (Operator::Neg, Bson::Double(value)) => Ok(Bson::Double(-value)),
+                (operator, value) => Err(format!(
+                    "cannot apply {} to a {}",
+                    symbol(*operator),
+                    type_name(&value)
+                )),
+            }
+        }
+
+        // the boolean operators only evaluate their right side when needed
+        Expression::Binary(operator @ (Operator::And | Operator::Or), left, right) => {
+            let left = boolean(*operator, evaluate(left, variables)?)?;
+
+            match (operator, left) {
+                (Operator::And, false) => Ok(Bson::Boolean(false)),
+                (Operator::Or, true) => Ok(Bson::Boolean(true)),
+                _ => boolean(*operator, evaluate(right, variables)?).map(Bson::Boolean),
+            }
+        }
+
+        Expression::Binary(operator, left, right) => binary(
+            *operator,
+            evaluate(left, variables)?,
+            evaluate(right, variables)?,
+        ),
+
+        Expression::Call(name, args) => {
+            let args = args
+                .iter()
+                .map(|arg| evaluate(arg, variables))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            call(name, args)
+        }
+    }
+}
+
+fn binary(operator: Operator, left: Bson, right: Bson) -> Result<Bson, String> {
+    let mismatch = |left: &Bson, right: &Bson| {
+        format!(
+            "cannot apply {} to a {} and a {}",
+            symbol(operator),
+            type_name(left),
+            type_name(right)
+        )
+    };
+
+    match operator {
+        Operator::Eq => Ok(Bson::Boolean(equals(&left, &right))),
+        Operator::Ne => Ok(Bson::Boolean(!equals(&left, &right))),
+
+        Operator::Lt | Operator::Le | Operator::Gt | Operator::Ge => {
+            let ordering = compare(&left, &right).ok_or_else(|| mismatch(&left, &right))?;
+
+            Ok(Bson::Boolean(match operator {
+                Operator::Lt => ordering == Ordering::Less,
+                Operator::Le => ordering != Ordering::Greater,
+                Operator::Gt => ordering == Ordering::Greater,
+                _ => ordering != Ordering::Less,
+            }))
+        }
+
+        // a string and a scalar are concatenated, e.g. `"order-" + $id`
+        Operator::Add if is_string(&left) || is_string(&right) => {
+            match (to_text(&left), to_text(&right)) {
+                (Some(left), Some(right)) => Ok(Bson::String(left + &right)),
+                _ => Err(mismatch(&left, &right)),
+            }
+        }
+
+        Operator::Add | Operator::Sub | Operator::Mul | Operator::Div | Operator::Rem => {
+            match (integer(&left), integer(&right)) {
+                (Some(l), Some(r)) => {
+                    integer_arithmetic(operator, l, r).map(|result| narrow(result, &left, &right))
+                }
+                _ => match (number(&left), number(&right)) {
+                    (Some(l), Some(r)) => Ok(Bson::Double(match operator {
+                        Operator::Add => l + r,
+                        Operator::Sub => l - r,
+                        Operator::Mul => l * r,
+                        Operator::Div => l / r,
+                        _ => l % r,
+                    })),
+                    _ => Err(mismatch(&left, &right)),
+                },
+            }
+        }
+
+        _ => Err(mismatch(&left, &right)),
+    }
+}
+
+/// The integers stay integers, except for a division with a remainder
+fn integer_arithmetic(operator: Operator, left: i64, right: i64) -> Result<Bson, String> {
+    let result = match operator {
+        Operator::Add => left.checked_add(right),
+        Operator::Sub => left.checked_sub(right),
+        Operator::Mul => left.checked_mul(right),
+        Operator::Div | Operator::Rem if right == 0 => return Err("division by zero".to_string()),
+        Operator::Div if left.checked_rem(right).is_some_and(|rem| rem != 0) => {
+            return Ok(Bson::Double(left as f64 / right as f64))
+        }
+        Operator::Div => left.checked_div(right),
+        _ => left.checked_rem(right),
+    };
+
+    result
+        .map(Bson::Int64)
+        .ok_or_else(|| "integer overflow".to_string())
+}
+
+/// Two Int32 give an Int32, unless the result only fits in an Int64
+fn narrow(result: Bson, left: &Bson, right: &Bson) -> Bson {
+    match (result, left, right) {
+        (Bson::Int64(result), Bson::Int32(_), Bson::Int32(_)) => i32::try_from(result)
+            .map(Bson::Int32)
+            .unwrap_or(Bson::Int64(result)),
+        (result, _, _) => result,
+    }
+}
+
+fn boolean(operator: Operator, value: Bson) -> Result<bool, String> {
+    match value {
+        Bson::Boolean(value) => Ok(value),
+        value => Err(format!(
+            "{} expects booleans, not a {}",
+            symbol(operator),
+            type_name(&value)
+        )),
+    }
+}
+
+fn integer(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::Int32(value) => Some(*value as i64),
+        Bson::Int64(value) => Some(*value),
+        _ => None,
+    }
+}
+
+fn number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Double(value) => Some(*value),
+        value => integer(value).map(|value| value as f64),
+    }
+}
+
+fn is_string(value: &Bson) -> bool {
+    matches!(value, Bson::String(_))
+}
+
+fn to_text(value: &Bson) -> Option<String> {
+    match value {
+        Bson::String(value) => Some(value.clone()),
+        Bson::Boolean(value) => Some(value.to_string()),
+        Bson::Double(value) => Some(value.to_string()),
+        value => integer(value).map(|value| value.to_string()),
+    }
+}
+
+/// The numbers are equal whatever their type, `1 == 1.0`
+fn equals(left: &Bson, right: &Bson) -> bool {
+    match (number(left), number(right)) {
+        (Some(left), Some(right)) => left == right,
+        _ => left == right,
+    }
+}
+
+fn compare(left: &Bson, right: &Bson) -> Option<Ordering> {
+    match (left, right) {
+        (Bson::String(left), Bson::String(right)) => Some(left.cmp(right)),
+        (Bson::DateTime(left), Bson::DateTime(right)) => Some(left.cmp(right)),
+        (Bson::Boolean(left), Bson::Boolean(right)) => Some(left.cmp(right)),
+        _ => match (integer(left), integer(right)) {
+            (Some(left), Some(right)) => Some(left.cmp(&right)),
+            _ => number(left)?.partial_cmp(&number(right)?),
+        },
+    }
+}
+
+fn symbol(operator: Operator) -> &'static str {
+    match operator {
+        Operator::Add => "+",
+        Operator::Sub => "-",
+        Operator::Mul => "*",
+        Operator::Div => "/",
+        Operator::Rem => "%",
+        Operator::Eq => "==",
+        Operator::Ne => "!=",
+        Operator::Lt => "<",
+        Operator::Le => "<=",
+        Operator::Gt => ">",
+        Operator::Ge => ">=",
+        Operator::And => "&&",
+        Operator::Or => "||",
+        Operator::Not => "!",
+        Operator::Neg => "-",
+    }
+}
+
+/// The built-in functions
+fn call(name: &str, args: Vec<Bson>) -> Result<Bson, String> {
+    match (name, args.as_slice()) {
+        ("now", []) => Ok(Bson::DateTime(bson::DateTime::now())),
+
+        ("len", [value]) => match value {
+            Bson::String(value) => Ok(value.chars().count()),
+            Bson::Array(items) => Ok(items.len()),
+            Bson::Document(doc) => Ok(doc.len()),
+            Bson::Binary(binary) => Ok(binary.bytes.len()),
+            value => Err(format!("len() of a {}", type_name(value))),
+        }
+        .map(|len| Bson::Int64(len as i64)),
+
+        ("upper", [Bson::String(value)]) => Ok(Bson::String(value.to_uppercase())),
+
+        ("uuid", []) => Ok(Bson::Binary(Binary::from_uuid(bson::Uuid::new()))),
+        ("uuid", [Bson::String(value)]) => bson::Uuid::parse_str(value)
+            .map(|uuid| Bson::Binary(Binary::from_uuid(uuid)))
+            .map_err(|e| format!("invalid UUID: {}", e)),
+
+        ("keys", [Bson::Document(doc)]) => Ok(Bson::Array(
+            doc.keys().map(|key| Bson::String(key.clone())).collect(),
+        )),
+
+        ("now" | "len" | "upper" | "uuid" | "keys", args) => Err(format!(
+            "invalid arguments for {}(): ({})",
+            name,
+            args.iter().map(type_name).collect::<Vec<_>>().join(", ")
+        )),
+
+        _ => Err(format!(
+            "unknown function {}(), expected now, len, upper, uuid or keys",
+            name
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::parser::{self, ASTNode};
+    use bson::doc;
+
+    /// Evaluates the expression of `get <input>`, with `$n` set to 7 and `$user` to a document
+    fn eval(input: &str) -> Result<Bson, String> {
+        let mut variables = VariableManager::new();
+        variables.set("n", Bson::Int64(7));
+        variables.set("small", Bson::Int32(3));
+        variables.set("min", Bson::Int64(i64::MIN));
+        variables.set(
+            "user",
+            Bson::Document(doc! { "name": "ada", "tags": ["a", "b"], "address": Bson::Null }),
+        );
+
+        let expression = match parser::parse(&format!("get {}", input)).unwrap().remove(0) {
+            ASTNode::SingleExpression {
+                ident: Some(ident), ..
+            } => match *ident {
+                ASTNode::Expression(expression) => expression,
+                node => panic!("expected an expression, got {:?}", node),
+            },
+            node => panic!("expected a get, got {:?}", node),
+        };
+
+        evaluate(&expression, &variables)
+    }
+
+    #[test]
+    fn keeps_the_integers() {
+        assert_eq!(eval("$n + 1"), Ok(Bson::Int64(8)));
+        assert_eq!(eval("$n * -2"), Ok(Bson::Int64(-14)));
+        assert_eq!(eval("$n / 7"), Ok(Bson::Int64(1)));
+        assert_eq!(eval("$n / 2"), Ok(Bson::Double(3.5)));
+        assert_eq!(eval("$n % 4"), Ok(Bson::Int64(3)));
+        assert_eq!(eval("-$n"), Ok(Bson::Int64(-7)));
+    }
+
+    #[test]
+    fn keeps_the_int32_while_they_fit() {
+        assert_eq!(eval("$small + int32(4)"), Ok(Bson::Int32(7)));
+        assert_eq!(eval("-$small"), Ok(Bson::Int32(-3)));
+        assert_eq!(
+            eval("int32(2147483647) + $small"),
+            Ok(Bson::Int64(2147483650))
+        );
+        // an Int64 side gives an Int64
+        assert_eq!(eval("$small + 1"), Ok(Bson::Int64(4)));
+    }
+
+    #[test]
+    fn refuses_integer_overflows() {
+        assert_eq!(
+            eval("9223372036854775807 + 1"),
+            Err("integer overflow".to_string())
+        );
+        assert_eq!(eval("$min - 1"), Err("integer overflow".to_string()));
+        assert_eq!(eval("$min * 2"), Err("integer overflow".to_string()));
+        assert_eq!(eval("-$min"), Err("integer overflow".to_string()));
+        assert_eq!(eval("$min / -1"), Err("integer overflow".to_string()));
+        assert_eq!(eval("$min % -1"), Err("integer overflow".to_string()));
+        assert_eq!(
+            eval("-int32(-2147483648)"),
+            Err("integer overflow".to_string())
+        );
+    }
+
+    #[test]
+    fn refuses_divisions_by_zero() {
+        assert_eq!(eval("$n / 0"), Err("division by zero".to_string()));
+        assert_eq!(eval("$n % 0"), Err("division by zero".to_string()));
+        assert_eq!(eval("$n / 0.0"), Ok(Bson::Double(f64::INFINITY)));
+    }
+
+    #[test]
+    fn mixes_integers_and_doubles() {
+        assert_eq!(eval("$n + 0.5"), Ok(Bson::Double(7.5)));
+        assert_eq!(eval("$small * 1.5"), Ok(Bson::Double(4.5)));
+        assert_eq!(eval("$n == 7.0"), Ok(Bson::Boolean(true)));
+        assert_eq!(eval("$small < 3.5"), Ok(Bson::Boolean(true)));
+        assert_eq!(eval("$n > $small"), Ok(Bson::Boolean(true)));
+    }
+
+    #[test]
+    fn concatenates_strings() {
+        assert_eq!(
+            eval(r#""order-" + $n"#),
+            Ok(Bson::String("order-7".to_string()))
+        );
+        assert_eq!(
+            eval(r#"$user.name + "!" + true"#),
+            Ok(Bson::String("ada!true".to_string()))
+        );
+        assert_eq!(eval(r#"1.5 + "x""#), Ok(Bson::String("1.5x".to_string())));
+        assert!(eval(r#""x" + $user.tags"#).is_err());
+        assert!(eval(r#""x" - "y""#).is_err());
+    }
+
+    #[test]
+    fn short_circuits_the_boolean_operators() {
+        // the right side would fail, as $missing is not set
+        assert_eq!(eval("false && $missing"), Ok(Bson::Boolean(false)));
+        assert_eq!(eval("true || $missing"), Ok(Bson::Boolean(true)));
+        assert!(eval("true && $missing").is_err());
+        assert!(eval("false || $missing").is_err());
+        assert!(eval("1 && true").is_err());
+        assert!(eval("true && 1").is_err());
+    }
+
+    #[test]
+    fn reads_missing_values_as_null() {
+        assert_eq!(eval("$user.age"), Ok(Bson::Null));
+        assert_eq!(eval("$user.address.city"), Ok(Bson::Null));
+        assert_eq!(eval("$user.tags[5]"), Ok(Bson::Null));
+        assert_eq!(eval("$user.tags[-1]"), Ok(Bson::Null));
+        assert_eq!(
+            eval(r#"$user["name"]"#),
+            Ok(Bson::String("ada".to_string()))
+        );
+        assert_eq!(eval(r#"$user.age["x"]"#), Ok(Bson::Null));
+        assert!(eval("$user.name.first").is_err());
+        assert!(eval(r#"$user.tags["a"]"#).is_err());
+        assert!(eval("$n[0]").is_err());
+        assert!(eval("$missing.field").is_err());
+    }
+
+    #[test]
+    fn checks_the_arguments_of_the_built_ins() {
+        assert_eq!(eval(r#"len("café")"#), Ok(Bson::Int64(4)));
+        assert_eq!(eval("len($user.tags)"), Ok(Bson::Int64(2)));
+        assert_eq!(
+            eval("keys($user)").map(|keys| keys.as_array().unwrap().len()),
+            Ok(3)
+        );
+
+        for input in [
+            "now(1)",
+            "len()",
+            "len($n)",
+            r#"len("a", "b")"#,
+            "upper($n)",
+            "upper()",
+            "uuid($n)",
+            "uuid($user.name)",
+            "keys($user.tags)",
+            "keys()",
+            "nope()",
+        ] {
+            assert!(eval(input).is_err(), "{} was evaluated", input);
+        }
+    }
+}
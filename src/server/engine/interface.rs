@@ -1,9 +1,8 @@
 use bson::Bson;
 use dustdata::DustData;
 use dustdata::Error as DustDataError;
-use rand::Rng;
-use rustbase_scram::hash_password;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 
 use crate::config;
@@ -11,13 +10,25 @@ use crate::server;
 
 use config::schema;
 use server::cache;
+use server::oplog;
+use server::quota;
+use server::raft;
 use server::route;
+use server::shard;
+use server::validation;
 use server::wirewave;
 
 use cache::Cache;
+use oplog::{OpLog, Operation};
+use quota::{Change, Counters, Quota, Scope, Usage};
+use raft::{Command, ProposeError, Raft};
+use shard::{ShardOperation, ShardRequest, ShardRouter};
+use validation::{DatabaseSchema, Mode};
+use wirewave::authentication::{configured_iterations, new_credentials, stored_iterations};
 use wirewave::authorization::UserPermission;
 use wirewave::server::Status;
 
+#[derive(Debug)]
 pub enum TransactionError {
     InternalError(DustDataError),
     ExternalError(Status, String),
@@ -29,7 +40,13 @@ pub struct DustDataInterface {
     config: Arc<schema::RustbaseConfig>,
     pub current_database: String,
     system_db: Arc<RwLock<DustData>>,
-    current_user: Option<String>,
+    pub current_user: Option<String>,
+    /// The user charged by the quotas, the current user unless a router forwarded the write
+    quota_user: Option<String>,
+    counters: Arc<Counters>,
+    oplog: Option<Arc<OpLog>>,
+    shards: Option<Arc<ShardRouter>>,
+    raft: Option<Arc<Raft>>,
 }
 
 impl DustDataInterface {
@@ -40,22 +57,273 @@ impl DustDataInterface {
         system_db: Arc<RwLock<DustData>>,
         current_database: String,
         current_user: Option<String>,
+        oplog: Option<Arc<OpLog>>,
     ) -> Self {
         Self {
             cache,
             routers,
-            config,
+            config: config.clone(),
             current_database,
             system_db,
+            quota_user: current_user.clone(),
             current_user,
+            counters: Arc::new(Counters::new(config.clone())),
+            oplog,
+            shards: None,
+            raft: None,
         }
     }
 
+    /// Shares the usage of the databases, otherwise counted again by each interface
+    pub fn set_counters(&mut self, counters: Arc<Counters>) {
+        self.counters = counters;
+    }
+
+    /// Charges the writes to the user a router runs them for
+    pub fn set_quota_user(&mut self, user: Option<String>) {
+        self.quota_user = user;
+    }
+
+    /// Commits the writes to the Raft log before they are applied
+    pub fn set_raft(&mut self, raft: Arc<Raft>) {
+        self.raft = Some(raft);
+    }
+
+    /// Proposes a write to the Raft log and waits until it is applied. The preconditions
+    /// are checked on the applied state, as the local write would.
+    fn replicate(
+        &self,
+        database: &str,
+        operation: Operation,
+        key: Option<String>,
+        value: Option<Bson>,
+    ) -> Option<Result<(), TransactionError>> {
+        let raft = self.raft.as_ref()?;
+
+        let exists = {
+            let routers = self.routers.read().unwrap();
+
+            match (routers.get(database), &key) {
+                (Some(dd), Some(key)) => dd.get(key).ok().flatten().is_some(),
+                (Some(_), None) => true,
+                (None, _) => false,
+            }
+        };
+
+        let precondition = match operation {
+            Operation::Insert if exists => Some((Status::AlreadyExists, "key already exists")),
+            Operation::Update | Operation::Delete if !exists => {
+                Some((Status::AlreadyExists, "key not exists"))
+            }
+            Operation::DropDatabase if !exists => Some((Status::NotFound, "database not found")),
+            _ => None,
+        };
+
+        if let Some((status, message)) = precondition {
+            return Some(Err(TransactionError::ExternalError(
+                status,
+                message.to_string(),
+            )));
+        }
+
+        let command = Command {
+            database: database.to_string(),
+            operation,
+            key,
+            value,
+            user: self.quota_user.clone(),
+        };
+
+        Some(raft.propose_blocking(command).map(|_| ()).map_err(|e| {
+            let message = match e {
+                ProposeError::NotLeader(Some(leader)) => {
+                    return TransactionError::ExternalError(
+                        Status::NotLeader,
+                        format!("not the leader, send the writes to {}", leader),
+                    )
+                }
+                ProposeError::NotLeader(None) => {
+                    return TransactionError::ExternalError(
+                        Status::NotLeader,
+                        "not the leader, no leader is elected yet".to_string(),
+                    )
+                }
+                ProposeError::Timeout => "the write was not committed in time",
+                ProposeError::Lost => "the write was lost by a leader change",
+                ProposeError::Storage(e) => {
+                    return TransactionError::ExternalError(
+                        Status::InternalError,
+                        format!("cannot save the write to the log: {}", e),
+                    )
+                }
+            };
+
+            TransactionError::ExternalError(Status::InternalError, message.to_string())
+        }))
+    }
+
+    /// Forwards the key operations to the nodes of a sharded cluster instead of the local databases
+    pub fn set_shards(&mut self, shards: Arc<ShardRouter>) {
+        self.shards = Some(shards);
+    }
+
+    fn forward(
+        &self,
+        database: &str,
+        operation: ShardOperation,
+        key: Option<String>,
+        value: Option<Bson>,
+    ) -> Option<Result<Bson, TransactionError>> {
+        let shards = self.shards.as_ref()?;
+
+        Some(shards.forward(ShardRequest {
+            database: database.to_string(),
+            operation,
+            key,
+            value,
+            user: self.quota_user.clone(),
+        }))
+    }
+
+    fn log_operation(
+        &self,
+        database: &str,
+        operation: Operation,
+        key: Option<String>,
+        value: Option<Bson>,
+    ) -> Result<(), TransactionError> {
+        if let Some(oplog) = &self.oplog {
+            oplog.append(database, operation, key, value).map_err(|e| {
+                TransactionError::ExternalError(Status::InternalError, e.to_string())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Replicas only apply the writes of their primary
+    fn check_writable(&self) -> Result<(), TransactionError> {
+        let replica = self
+            .config
+            .cluster
+            .as_ref()
+            .is_some_and(|cluster| cluster.role == schema::ClusterRole::Replica);
+
+        if replica {
+            return Err(TransactionError::ExternalError(
+                Status::NotAuthorized,
+                "read-only replica".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Checks a document against the schema of the current database, if it has one
+    fn check_document(&self, key: &str, value: &Bson) -> Result<(), TransactionError> {
+        let schema = validation::get(&self.system_db.read().unwrap(), &self.current_database);
+
+        let schema = match schema {
+            Some(schema) => schema,
+            None => return Ok(()),
+        };
+
+        let errors = validation::validate(&schema.schema, value);
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+
+        let message = format!(
+            "{} does not match the schema of {}: {}",
+            key,
+            self.current_database,
+            errors.join("; ")
+        );
+
+        match schema.mode {
+            Mode::Strict => Err(TransactionError::ExternalError(
+                Status::SchemaViolation,
+                message,
+            )),
+            Mode::Warn => {
+                println!("[Engine] {}", message);
+                Ok(())
+            }
+        }
+    }
+
+    /// Checks a write against the quotas of the current database and of its user, counting
+    /// the database first if it has never been
+    fn check_quota(
+        &self,
+        dd: Option<&DustData>,
+        key: &str,
+        change: &Change,
+    ) -> Result<(), TransactionError> {
+        let usage = self
+            .counters
+            .usage(&self.current_database, dd)
+            .map_err(TransactionError::InternalError)?;
+
+        quota::check(
+            &self.system_db.read().unwrap(),
+            &self.counters,
+            &self.current_database,
+            usage,
+            key,
+            self.quota_user.as_deref(),
+            change,
+        )
+        .map_err(|message| TransactionError::ExternalError(Status::QuotaExceeded, message))
+    }
+
+    /// Counts a write made to the current database, while it is still locked
+    fn record_quota(&self, key: &str, change: &Change) -> Result<(), TransactionError> {
+        self.counters.record(&self.current_database, change);
+
+        quota::charge(
+            &self.system_db,
+            &self.counters,
+            &self.current_database,
+            key,
+            self.quota_user.as_deref(),
+            change,
+        )
+        .map_err(TransactionError::InternalError)
+    }
+
+    /// Proposes a key write to the Raft log once it is within the quotas. Every node counts
+    /// it when applying it.
+    fn replicate_within_quota(
+        &self,
+        operation: Operation,
+        key: String,
+        value: Option<Bson>,
+    ) -> Option<Result<(), TransactionError>> {
+        self.raft.as_ref()?;
+
+        {
+            let routers = self.routers.read().unwrap();
+            let dd = routers.get(&self.current_database);
+            let previous = dd.and_then(|dd| dd.get(&key).ok().flatten());
+            let change = Change::of(&operation, &key, previous.as_ref(), value.as_ref());
+
+            if let Err(e) = self.check_quota(dd, &key, &change) {
+                return Some(Err(e));
+            }
+        }
+
+        self.replicate(&self.current_database, operation, Some(key), value)
+    }
+
     pub fn insert_into_dustdata(
         &mut self,
         key: String,
         value: Bson,
     ) -> Result<Bson, TransactionError> {
+        self.check_writable()?;
+
         if self.current_database == "_default" {
             return Err(TransactionError::ExternalError(
                 Status::Reserved,
@@ -72,9 +340,33 @@ impl DustDataInterface {
             }
         }
 
+        self.check_document(&key, &value)?;
+
+        if let Some(result) = self.forward(
+            &self.current_database,
+            ShardOperation::Insert,
+            Some(key.clone()),
+            Some(value.clone()),
+        ) {
+            return result;
+        }
+
+        if let Some(result) =
+            self.replicate_within_quota(Operation::Insert, key.clone(), Some(value.clone()))
+        {
+            return result.map(|_| value);
+        }
+
         let mut routers = self.routers.write().unwrap();
 
         if !routers.contains_key(&self.current_database) {
+            if route::is_reserved_directory(&self.current_database) {
+                return Err(TransactionError::ExternalError(
+                    Status::Reserved,
+                    "database reserved".to_string(),
+                ));
+            }
+
             let dd = route::create_dustdata(&self.config, Some(&self.current_database));
 
             routers.insert(self.current_database.clone(), dd);
@@ -83,13 +375,38 @@ impl DustDataInterface {
 
         let dd = routers.get_mut(&self.current_database).unwrap();
 
+        let change = Change::insert(&key, &value);
+        self.check_quota(Some(&*dd), &key, &change)?;
+
+        // the operation is logged before the write, which must not fail once logged
+        if dd
+            .get(&key)
+            .map_err(TransactionError::InternalError)?
+            .is_some()
+        {
+            return Err(TransactionError::InternalError(dustdata::Error {
+                code: dustdata::ErrorCode::KeyExists,
+            }));
+        }
+
+        self.log_operation(
+            &self.current_database,
+            Operation::Insert,
+            Some(key.clone()),
+            Some(value.clone()),
+        )?;
+
         dd.insert(&key, value.clone())
             .map_err(TransactionError::InternalError)?;
 
+        self.record_quota(&key, &change)?;
+
         Ok(value)
     }
 
     pub fn update_dustdata(&mut self, key: String, value: Bson) -> Result<Bson, TransactionError> {
+        self.check_writable()?;
+
         if self.current_database == "_default" {
             return Err(TransactionError::ExternalError(
                 Status::Reserved,
@@ -106,6 +423,23 @@ impl DustDataInterface {
             }
         }
 
+        self.check_document(&key, &value)?;
+
+        if let Some(result) = self.forward(
+            &self.current_database,
+            ShardOperation::Update,
+            Some(key.clone()),
+            Some(value.clone()),
+        ) {
+            return result;
+        }
+
+        if let Some(result) =
+            self.replicate_within_quota(Operation::Update, key.clone(), Some(value.clone()))
+        {
+            return result.map(|_| value);
+        }
+
         let mut cache = self.cache.write().unwrap();
         let cache_key = format!("{}:{}", self.current_database, key);
         cache.remove(&cache_key).ok();
@@ -114,9 +448,29 @@ impl DustDataInterface {
         let dd = routers.get_mut(&self.current_database);
 
         if let Some(dd) = dd {
+            let previous = dd.get(&key).map_err(TransactionError::InternalError)?;
+
+            if previous.is_none() {
+                return Err(TransactionError::InternalError(dustdata::Error {
+                    code: dustdata::ErrorCode::KeyNotExists,
+                }));
+            }
+
+            let change = Change::update(previous.as_ref(), &value);
+            self.check_quota(Some(&*dd), &key, &change)?;
+
+            self.log_operation(
+                &self.current_database,
+                Operation::Update,
+                Some(key.clone()),
+                Some(value.clone()),
+            )?;
+
             dd.update(&key, value.clone())
                 .map_err(TransactionError::InternalError)?;
 
+            self.record_quota(&key, &change)?;
+
             Ok(value)
         } else {
             Err(TransactionError::ExternalError(
@@ -127,6 +481,8 @@ impl DustDataInterface {
     }
 
     pub fn delete_from_dustdata(&mut self, key: String) -> Result<Bson, TransactionError> {
+        self.check_writable()?;
+
         if self.current_database == "_default" {
             return Err(TransactionError::ExternalError(
                 Status::Reserved,
@@ -143,6 +499,19 @@ impl DustDataInterface {
             }
         }
 
+        if let Some(result) = self.forward(
+            &self.current_database,
+            ShardOperation::Delete,
+            Some(key.clone()),
+            None,
+        ) {
+            return result;
+        }
+
+        if let Some(result) = self.replicate_within_quota(Operation::Delete, key.clone(), None) {
+            return result.map(|_| Bson::String(key));
+        }
+
         let mut cache = self.cache.write().unwrap();
         let cache_key = format!("{}:{}", self.current_database, key);
         cache.remove(&cache_key).ok();
@@ -151,8 +520,27 @@ impl DustDataInterface {
         let dd = routers.get_mut(&self.current_database);
 
         if let Some(dd) = dd {
+            let previous = dd.get(&key).map_err(TransactionError::InternalError)?;
+
+            if previous.is_none() {
+                return Err(TransactionError::InternalError(dustdata::Error {
+                    code: dustdata::ErrorCode::KeyNotExists,
+                }));
+            }
+
+            let change = Change::delete(&key, previous.as_ref());
+
+            self.log_operation(
+                &self.current_database,
+                Operation::Delete,
+                Some(key.clone()),
+                None,
+            )?;
+
             dd.delete(&key).map_err(TransactionError::InternalError)?;
 
+            self.record_quota(&key, &change)?;
+
             Ok(Bson::String(key))
         } else {
             Err(TransactionError::ExternalError(
@@ -179,6 +567,15 @@ impl DustDataInterface {
             }
         }
 
+        if let Some(result) = self.forward(
+            &self.current_database,
+            ShardOperation::Get,
+            Some(key.clone()),
+            None,
+        ) {
+            return result;
+        }
+
         let mut cache = self.cache.write().unwrap();
 
         let cache_key = format!("{}:{}", self.current_database, key);
@@ -228,13 +625,35 @@ impl DustDataInterface {
             }
         }
 
+        if let Some(result) = self.forward(&self.current_database, ShardOperation::List, None, None)
+        {
+            return result.map(|keys| match keys {
+                Bson::Array(keys) => keys
+                    .into_iter()
+                    .filter_map(|key| key.as_str().map(|key| key.to_string()))
+                    .collect(),
+                _ => Vec::new(),
+            });
+        }
+
         let routers = self.routers.read().unwrap();
-        let dd = routers.get(&self.current_database).unwrap();
 
-        dd.list_keys().map_err(TransactionError::InternalError)
+        match routers.get(&self.current_database) {
+            Some(dd) => dd.list_keys().map_err(TransactionError::InternalError),
+            None => Err(TransactionError::ExternalError(
+                Status::NotFound,
+                "database not found".to_string(),
+            )),
+        }
+    }
+
+    pub fn list_databases(&self) -> Vec<String> {
+        self.routers.read().unwrap().keys().cloned().collect()
     }
 
     pub fn delete_database(&mut self, database: String) -> Result<Bson, TransactionError> {
+        self.check_writable()?;
+
         if self.current_database == "_default" {
             return Err(TransactionError::ExternalError(
                 Status::Reserved,
@@ -251,12 +670,30 @@ impl DustDataInterface {
             }
         }
 
+        if let Some(result) = self.forward(&database, ShardOperation::DropDatabase, None, None) {
+            return result;
+        }
+
+        // the usage is forgotten by every node when applying the drop
+        if let Some(result) = self.replicate(&database, Operation::DropDatabase, None, None) {
+            result?;
+
+            return Ok(Bson::String(database));
+        }
+
         let mut routers = self.routers.write().unwrap();
 
-        if let Some(mut dd) = routers.remove(&database) {
+        if routers.contains_key(&database) {
+            // the log of the database is kept, so it can be recovered
+            self.log_operation(&database, Operation::DropDatabase, None, None)?;
+
+            let mut dd = routers.remove(&database).unwrap();
             dd.flush().unwrap();
             drop(dd);
 
+            quota::drop_database(&self.system_db, &self.counters, &database)
+                .map_err(TransactionError::InternalError)?;
+
             let database = database.clone();
 
             // using thread to delete database because it's a blocking operation
@@ -277,6 +714,77 @@ impl DustDataInterface {
         }
     }
 
+    pub fn snapshot_database(
+        &mut self,
+        database: String,
+        path: PathBuf,
+    ) -> Result<Bson, TransactionError> {
+        if database == "_default" {
+            return Err(TransactionError::ExternalError(
+                Status::Reserved,
+                "database reserved".to_string(),
+            ));
+        }
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Admin)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        let mut routers = self.routers.write().unwrap();
+        let dd = routers.get_mut(&database);
+
+        if let Some(dd) = dd {
+            route::snapshot_dustdata(dd, &path).map_err(|message| {
+                TransactionError::ExternalError(Status::InternalError, message)
+            })?;
+
+            println!(
+                "[Engine] snapshot of {} created at {}",
+                database,
+                path.display()
+            );
+
+            Ok(Bson::String(database))
+        } else {
+            Err(TransactionError::ExternalError(
+                Status::NotFound,
+                "database not found".to_string(),
+            ))
+        }
+    }
+
+    /// Snapshots every database (and the system database) into its own directory under `path`.
+    /// The router lock is taken once per database, so writes are only blocked while
+    /// that database is being snapshotted.
+    pub fn snapshot_all(&mut self, path: PathBuf) -> Result<Bson, TransactionError> {
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Admin)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        let snapshotted = route::snapshot_all_dustdata(&self.routers, &self.system_db, &path)
+            .map_err(|message| TransactionError::ExternalError(Status::InternalError, message))?;
+
+        println!(
+            "[Engine] snapshot of {} databases created at {}",
+            snapshotted.len(),
+            path.display()
+        );
+
+        Ok(Bson::Array(
+            snapshotted.into_iter().map(Bson::String).collect(),
+        ))
+    }
+
     pub fn create_user(
         &mut self,
         username: String,
@@ -292,32 +800,78 @@ impl DustDataInterface {
             }
         }
 
+        check_username(&username)?;
+
         let mut dd = self.system_db.write().unwrap();
 
-        let salt = rand::thread_rng().gen::<[u8; 32]>().to_vec();
-        let hash_password =
-            hash_password(&password, std::num::NonZeroU32::new(4096).unwrap(), &salt).to_vec();
+        let mut doc = new_credentials(&password, configured_iterations(self.config.auth.as_ref()));
+        doc.insert("permission", user_permission as i32);
 
-        let hash_password = bson::Binary {
-            subtype: bson::spec::BinarySubtype::Generic,
-            bytes: hash_password,
-        };
+        dd.insert(&username, Bson::Document(doc))
+            .map_err(TransactionError::InternalError)?;
 
-        let salt = bson::Binary {
-            subtype: bson::spec::BinarySubtype::Generic,
-            bytes: salt,
-        };
+        Ok(bson::Bson::String(username))
+    }
 
-        let doc = bson::doc! {
-            "password": hash_password,
-            "salt": salt,
-            "permission": user_permission as i32,
+    /// The users, or only `username`, with their permission and the iterations of their
+    /// password. `rehash_pending` marks the ones hashed with fewer than `auth.iterations`:
+    /// SCRAM never sends the password, so only an HTTP Basic login or a new password
+    /// hashes them again.
+    pub fn show_users(&self, username: Option<String>) -> Result<Bson, TransactionError> {
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Admin)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        if let Some(username) = &username {
+            check_username(username)?;
+        }
+
+        let iterations = configured_iterations(self.config.auth.as_ref());
+        let dd = self.system_db.read().unwrap();
+
+        let usernames = match username {
+            Some(username) => vec![username],
+            None => dd
+                .list_keys()
+                .map_err(TransactionError::InternalError)?
+                .into_iter()
+                .filter(|key| !route::is_catalog_key(key))
+                .collect(),
         };
 
-        dd.insert(&username, Bson::Document(doc))
-            .map_err(TransactionError::InternalError)?;
+        let mut users = Vec::new();
+
+        for username in usernames {
+            let user = match dd.get(&username).map_err(TransactionError::InternalError)? {
+                Some(Bson::Document(user)) => user,
+                _ => {
+                    return Err(TransactionError::ExternalError(
+                        Status::NotFound,
+                        "user not found".to_string(),
+                    ))
+                }
+            };
 
-        Ok(bson::Bson::String(username))
+            let permission = user
+                .get_i32("permission")
+                .ok()
+                .and_then(|permission| UserPermission::from_i32(permission).ok())
+                .map(|permission| permission.as_str());
+
+            users.push(Bson::Document(bson::doc! {
+                "user": username,
+                "permission": permission,
+                "iterations": stored_iterations(&user) as i32,
+                "rehash_pending": stored_iterations(&user) < iterations,
+            }));
+        }
+
+        Ok(Bson::Array(users))
     }
 
     pub fn delete_user(&mut self, username: String) -> Result<Bson, TransactionError> {
@@ -330,11 +884,16 @@ impl DustDataInterface {
             }
         }
 
+        check_username(&username)?;
+
         let mut dd = self.system_db.write().unwrap();
 
         dd.delete(&username)
             .map_err(TransactionError::InternalError)?;
 
+        quota::remove(&mut dd, &self.counters, &Scope::User(username.clone()))
+            .map_err(TransactionError::InternalError)?;
+
         Ok(bson::Bson::String(username))
     }
 
@@ -353,6 +912,8 @@ impl DustDataInterface {
             }
         }
 
+        check_username(&username)?;
+
         let mut dd = self.system_db.write().unwrap();
 
         let user = dd.get(&username).map_err(TransactionError::InternalError)?;
@@ -368,26 +929,10 @@ impl DustDataInterface {
         let user = user.as_document_mut().unwrap();
 
         if let Some(password) = password {
-            let salt = rand::thread_rng().gen::<[u8; 32]>().to_vec();
-            let hash_password =
-                hash_password(&password, std::num::NonZeroU32::new(4096).unwrap(), &salt).to_vec();
-
-            let hash_password = bson::Binary {
-                subtype: bson::spec::BinarySubtype::Generic,
-                bytes: hash_password,
-            };
-
-            let salt = bson::Binary {
-                subtype: bson::spec::BinarySubtype::Generic,
-                bytes: salt,
-            };
-
-            let doc = bson::doc! {
-                "password": hash_password,
-                "salt": salt,
-            };
-
-            user.extend(doc);
+            user.extend(new_credentials(
+                &password,
+                configured_iterations(self.config.auth.as_ref()),
+            ));
         }
 
         if let Some(user_permission) = user_permission {
@@ -402,6 +947,222 @@ impl DustDataInterface {
         Ok(bson::Bson::String(username))
     }
 
+    /// The schema of a database, `Null` if it has none
+    pub fn show_schema(&mut self, database: String) -> Result<Bson, TransactionError> {
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Admin)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        let catalog = self.system_db.read().unwrap();
+
+        Ok(validation::get(&catalog, &database)
+            .map(|schema| schema.to_bson(&database))
+            .unwrap_or(Bson::Null))
+    }
+
+    /// Attaches a schema to a database, or removes it with `None`. The existing documents
+    /// are not checked, see `validate_database`.
+    pub fn set_schema(
+        &mut self,
+        database: String,
+        schema: Option<DatabaseSchema>,
+    ) -> Result<Bson, TransactionError> {
+        if database == "_default" {
+            return Err(TransactionError::ExternalError(
+                Status::Reserved,
+                "database reserved".to_string(),
+            ));
+        }
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Admin)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        if let Some(schema) = &schema {
+            validation::check_schema(&schema.schema).map_err(|message| {
+                TransactionError::ExternalError(Status::InvalidQuery, message)
+            })?;
+        }
+
+        let mut catalog = self.system_db.write().unwrap();
+
+        validation::set(&mut catalog, &database, schema.as_ref())
+            .map_err(TransactionError::InternalError)?;
+
+        match &schema {
+            Some(schema) => println!(
+                "[Engine] schema of {} set, in {} mode",
+                database, schema.mode
+            ),
+            None => println!("[Engine] schema of {} removed", database),
+        }
+
+        Ok(schema
+            .map(|schema| schema.to_bson(&database))
+            .unwrap_or(Bson::Null))
+    }
+
+    /// Checks the documents of a database against a schema, the attached one if `None`, e.g.
+    /// before attaching it. Only the keys stored on this node are checked.
+    pub fn validate_database(
+        &mut self,
+        database: String,
+        schema: Option<bson::Document>,
+    ) -> Result<Bson, TransactionError> {
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Admin)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        let schema = match schema {
+            Some(schema) => {
+                validation::check_schema(&schema).map_err(|message| {
+                    TransactionError::ExternalError(Status::InvalidQuery, message)
+                })?;
+
+                schema
+            }
+            None => match validation::get(&self.system_db.read().unwrap(), &database) {
+                Some(schema) => schema.schema,
+                None => {
+                    return Err(TransactionError::ExternalError(
+                        Status::NotFound,
+                        format!("{} has no schema", database),
+                    ))
+                }
+            },
+        };
+
+        let routers = self.routers.read().unwrap();
+
+        let dd = match routers.get(&database) {
+            Some(dd) => dd,
+            None => {
+                return Err(TransactionError::ExternalError(
+                    Status::NotFound,
+                    "database not found".to_string(),
+                ))
+            }
+        };
+
+        let keys = dd.list_keys().map_err(TransactionError::InternalError)?;
+        let mut invalid = Vec::new();
+
+        for key in &keys {
+            let value = match dd.get(key).map_err(TransactionError::InternalError)? {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let errors = validation::validate(&schema, &value);
+
+            if !errors.is_empty() {
+                invalid.push(Bson::Document(bson::doc! {
+                    "key": key,
+                    "errors": errors,
+                }));
+            }
+        }
+
+        Ok(Bson::Document(bson::doc! {
+            "database": database,
+            "checked": keys.len() as i64,
+            "invalid": invalid,
+        }))
+    }
+
+    /// The limits and usage of a database or a user
+    pub fn show_quota(&mut self, scope: Scope) -> Result<Bson, TransactionError> {
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Admin)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        // released before counting, the writes lock the databases before the catalog
+        let quota = {
+            let catalog = self.system_db.read().unwrap();
+
+            check_quota_user(&catalog, &scope)?;
+
+            quota::get(&catalog, &scope)
+        };
+
+        let total = self.total_usage(&scope, &quota)?;
+
+        Ok(quota.to_bson(&scope, total))
+    }
+
+    /// Changes some limits of a database or a user, `None` removing the limit
+    pub fn set_quota(
+        &mut self,
+        scope: Scope,
+        limits: Vec<(String, Option<i64>)>,
+    ) -> Result<Bson, TransactionError> {
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Admin)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        let quota = {
+            let mut catalog = self.system_db.write().unwrap();
+
+            check_quota_user(&catalog, &scope)?;
+
+            let mut current = quota::get(&catalog, &scope).limits;
+
+            for (field, value) in limits {
+                current.set(&field, value).map_err(|message| {
+                    TransactionError::ExternalError(Status::InvalidQuery, message)
+                })?;
+            }
+
+            quota::set_limits(&mut catalog, &self.counters, &scope, current)
+                .map_err(TransactionError::InternalError)?
+        };
+
+        println!("[Engine] quota of {} changed", scope);
+
+        let total = self.total_usage(&scope, &quota)?;
+
+        Ok(quota.to_bson(&scope, total))
+    }
+
+    /// What a scope uses: a database is counted in memory, a user in its quota
+    fn total_usage(&self, scope: &Scope, quota: &Quota) -> Result<Usage, TransactionError> {
+        match scope {
+            Scope::Database(database) => {
+                let routers = self.routers.read().unwrap();
+
+                self.counters
+                    .usage(database, routers.get(database))
+                    .map_err(TransactionError::InternalError)
+            }
+            Scope::User(_) => Ok(quota.total()),
+        }
+    }
+
     pub fn user_has_perm(
         &self,
         username: String,
@@ -427,3 +1188,36 @@ impl DustDataInterface {
         Ok(user_permission.cmp(&perm))
     }
 }
+
+/// The users share the system database with the catalog, whose keys a quoted name such as
+/// `` `$quota:x` `` could otherwise reach
+fn check_username(username: &str) -> Result<(), TransactionError> {
+    if route::is_catalog_key(username) {
+        return Err(TransactionError::ExternalError(
+            Status::Reserved,
+            "a username cannot start with $".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// A user quota is only kept while the user exists
+fn check_quota_user(catalog: &DustData, scope: &Scope) -> Result<(), TransactionError> {
+    if let Scope::User(username) = scope {
+        check_username(username)?;
+
+        if catalog
+            .get(username)
+            .map_err(TransactionError::InternalError)?
+            .is_none()
+        {
+            return Err(TransactionError::ExternalError(
+                Status::NotFound,
+                "user not found".to_string(),
+            ));
+        }
+    }
+
+    Ok(())
+}
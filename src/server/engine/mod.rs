@@ -1,3 +1,4 @@
 pub mod core;
-mod interface;
+mod evaluator;
+pub mod interface;
 mod var_manager;
@@ -0,0 +1,796 @@
+use async_trait::async_trait;
+use base64::Engine;
+use colored::Colorize;
+use hyper::body::HttpBody;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::http::request::Parts;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, StatusCode};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+
+use super::shard::{ShardOperation, ShardRequest};
+use super::wirewave;
+use crate::config::schema;
+use crate::utils::extjson;
+
+use wirewave::authentication::DefaultAuthenticationProvider;
+use wirewave::limits::ConnectionSlot;
+use wirewave::server::{
+    request_timed_out, within, Error, ReqHeader, Request, Response, Shutdown, Status, Type,
+    Wirewave,
+};
+
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+const DEFAULT_TOKEN_TTL: u64 = 60 * 60;
+const TOKEN_LENGTH: usize = 40;
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+// how long a refused connection may take to send its request
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(5);
+// the seconds a refused client should wait before connecting again
+const RETRY_AFTER: &str = "1";
+
+/// What the gateway runs besides the queries of `Wirewave`
+#[async_trait]
+pub trait Gateway: Wirewave {
+    /// Runs a single key operation on `request.database`
+    async fn key_operation(
+        &self,
+        request: ShardRequest,
+        username: Option<String>,
+    ) -> Result<Response, Error>;
+}
+
+struct State<T> {
+    svc: T,
+    provider: DefaultAuthenticationProvider,
+    tokens: Mutex<HashMap<String, Token>>,
+    token_ttl: Duration,
+}
+
+struct Token {
+    username: String,
+    expires: Instant,
+}
+
+/// Serves the HTTP gateway until the shutdown signal, then drains the connections
+pub async fn serve<T: Gateway>(
+    config: schema::Http,
+    svc: T,
+    provider: DefaultAuthenticationProvider,
+    shutdown: Shutdown,
+) {
+    let address = format!("{}:{}", config.host, config.port);
+
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("[HTTP] cannot listen on {}: {}", address, e);
+            std::process::exit(1);
+        }
+    };
+
+    let acceptor = match config.tls.as_ref().map(wirewave::server::tls_acceptor) {
+        Some(Ok(acceptor)) => Some(acceptor),
+        Some(Err(e)) => {
+            println!("[HTTP] cannot load the TLS certificate: {}", e);
+            std::process::exit(1);
+        }
+        None => None,
+    };
+
+    println!(
+        "[HTTP] listening on {}",
+        format!(
+            "{}://{}",
+            if acceptor.is_some() { "https" } else { "http" },
+            address
+        )
+        .yellow()
+    );
+
+    let state = Arc::new(State {
+        svc,
+        provider,
+        tokens: Mutex::new(HashMap::new()),
+        token_ttl: Duration::from_secs(config.token_ttl.unwrap_or(DEFAULT_TOKEN_TTL)),
+    });
+
+    let mut signal = shutdown.signal.clone();
+    let mut connections = JoinSet::new();
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    println!("[HTTP] accept failed: {}", e);
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            },
+            _ = connections.join_next(), if !connections.is_empty() => continue,
+            _ = signal.changed() => break,
+        };
+
+        // counted with the Wirewave connections, before the TLS handshake
+        let server_context = state.svc.server_context().await;
+
+        let slot = match server_context
+            .limiter
+            .connect(&server_context.limits, Some(peer.ip()))
+        {
+            Ok(slot) => slot,
+            Err(error) => {
+                println!("[HTTP] refused {}: {}", peer, error.message);
+
+                connections.spawn(refuse_connection(
+                    stream,
+                    peer,
+                    acceptor.clone(),
+                    error.message,
+                ));
+                continue;
+            }
+        };
+
+        connections.spawn(accept_connection(
+            state.clone(),
+            stream,
+            peer,
+            acceptor.clone(),
+            shutdown.signal.clone(),
+            slot,
+        ));
+    }
+
+    drop(listener);
+
+    let finished = tokio::time::timeout(shutdown.timeout, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+
+    if finished.is_err() {
+        println!(
+            "[HTTP] {} connections did not finish in time, closing them",
+            connections.len()
+        );
+
+        connections.shutdown().await;
+    }
+}
+
+async fn accept_connection<T: Gateway>(
+    state: Arc<State<T>>,
+    stream: TcpStream,
+    peer: SocketAddr,
+    acceptor: Option<TlsAcceptor>,
+    signal: watch::Receiver<bool>,
+    // released when the connection ends
+    _slot: ConnectionSlot,
+) {
+    match acceptor {
+        Some(acceptor) => match acceptor.accept(stream).await {
+            Ok(stream) => serve_connection(state, stream, peer, signal).await,
+            Err(e) => println!("[HTTP] TLS handshake with {} failed: {}", peer, e),
+        },
+        None => serve_connection(state, stream, peer, signal).await,
+    }
+}
+
+/// Answers the requests of a connection over the limits with a 503, then closes it
+async fn refuse_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    acceptor: Option<TlsAcceptor>,
+    message: String,
+) {
+    let refused = async {
+        match acceptor {
+            Some(acceptor) => match acceptor.accept(stream).await {
+                Ok(stream) => refuse_requests(stream, message).await,
+                Err(e) => println!("[HTTP] TLS handshake with {} failed: {}", peer, e),
+            },
+            None => refuse_requests(stream, message).await,
+        }
+    };
+
+    if tokio::time::timeout(REFUSE_TIMEOUT, refused).await.is_err() {
+        println!("[HTTP] {} did not send its request in time", peer);
+    }
+}
+
+async fn refuse_requests<IO>(stream: IO, message: String)
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |_| {
+        let mut response = error_response(Error {
+            message: message.clone(),
+            query_message: None,
+            status: Status::TooManyConnections,
+        });
+
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from_static(RETRY_AFTER));
+
+        async move { Ok::<_, Infallible>(response) }
+    });
+
+    let _ = Http::new()
+        .http1_keep_alive(false)
+        .serve_connection(stream, service)
+        .await;
+}
+
+async fn serve_connection<T, IO>(
+    state: Arc<State<T>>,
+    stream: IO,
+    peer: SocketAddr,
+    mut signal: watch::Receiver<bool>,
+) where
+    T: Gateway,
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |request| {
+        let state = state.clone();
+
+        async move { Ok::<_, Infallible>(handle(&state, request, peer).await) }
+    });
+
+    let connection = Http::new().serve_connection(stream, service);
+    tokio::pin!(connection);
+
+    // on shutdown, the request in flight is answered before the connection is closed
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = signal.changed() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+
+    if let Err(e) = result {
+        println!("[HTTP] connection with {} failed: {}", peer, e);
+    }
+}
+
+async fn handle<T: Gateway>(
+    state: &State<T>,
+    request: hyper::Request<Body>,
+    peer: SocketAddr,
+) -> hyper::Response<Body> {
+    match route(state, request, peer).await {
+        Ok(response) => response,
+        Err(error) => error_response(error),
+    }
+}
+
+async fn route<T: Gateway>(
+    state: &State<T>,
+    request: hyper::Request<Body>,
+    peer: SocketAddr,
+) -> Result<hyper::Response<Body>, Error> {
+    let (parts, body) = request.into_parts();
+
+    let segments = parts
+        .uri
+        .path()
+        .trim_matches('/')
+        .split('/')
+        .map(percent_decode)
+        .collect::<Result<Vec<String>, Error>>()?;
+
+    let server_context = state.svc.server_context().await;
+    let limits = server_context.limits;
+
+    // the address is charged before the credentials are checked, so hashing the passwords
+    // of failed logins is limited too
+    server_context
+        .limiter
+        .allow_request(&limits, &peer.ip().to_string())?;
+
+    let username = authenticate(state, &parts.headers, peer).await?;
+
+    // the anonymous clients only use the bucket of their address
+    if let Some(username) = &username {
+        server_context.limiter.allow_request(&limits, username)?;
+    }
+
+    within(
+        limits.request_timeout.map(Duration::from_secs),
+        endpoint(state, parts, body, segments, username),
+    )
+    .await
+    .unwrap_or_else(|| {
+        Err(request_timed_out(
+            limits.request_timeout.unwrap_or_default(),
+        ))
+    })
+}
+
+async fn endpoint<T: Gateway>(
+    state: &State<T>,
+    parts: Parts,
+    body: Body,
+    segments: Vec<String>,
+    username: Option<String>,
+) -> Result<hyper::Response<Body>, Error> {
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+    match (parts.method.as_str(), segments.as_slice()) {
+        ("POST", ["auth", "token"]) => issue_token(state, username),
+
+        ("POST", ["db", database, "query"]) => {
+            let json = parts
+                .headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.starts_with("application/json"));
+
+            let body = read_body(body).await?;
+            let mut query = query_body(&body, json)?;
+            query.insert("database", *database);
+
+            let request = Request {
+                body: query,
+                header: ReqHeader {
+                    type_: Type::Query,
+                    auth: None,
+                },
+            };
+
+            let response = state.svc.request(request, username).await?;
+
+            Ok(json_response(response))
+        }
+
+        ("GET", ["db", database, "keys"]) => {
+            key_operation(state, database, ShardOperation::List, None, None, username).await
+        }
+
+        ("GET", ["db", database, "keys", key]) => {
+            key_operation(
+                state,
+                database,
+                ShardOperation::Get,
+                Some(key),
+                None,
+                username,
+            )
+            .await
+        }
+
+        ("PUT", ["db", database, "keys", key]) => {
+            let body = read_body(body).await?;
+            let value: serde_json::Value = serde_json::from_slice(&body)
+                .map_err(|e| bad_body(format!("the body is not JSON: {}", e)))?;
+            let value = extjson::from_value(value).map_err(bad_body)?;
+
+            put_key(state, database, key, value, username).await
+        }
+
+        ("DELETE", ["db", database, "keys", key]) => {
+            key_operation(
+                state,
+                database,
+                ShardOperation::Delete,
+                Some(key),
+                None,
+                username,
+            )
+            .await
+        }
+
+        _ => Err(Error {
+            message: format!("no endpoint for {} {}", parts.method, parts.uri.path()),
+            query_message: None,
+            status: Status::NotFound,
+        }),
+    }
+}
+
+/// The user of the Basic credentials or of the bearer token. Without them, the request is
+/// anonymous unless the server requires authentication.
+async fn authenticate<T: Gateway>(
+    state: &State<T>,
+    headers: &HeaderMap,
+    peer: SocketAddr,
+) -> Result<Option<String>, Error> {
+    let authorization = match headers.get(header::AUTHORIZATION) {
+        Some(authorization) => authorization
+            .to_str()
+            .map_err(|_| bad_auth("invalid Authorization header"))?,
+        // the localhost bypass of Wirewave is not honoured, any web page can
+        // make a browser send a simple POST to a local port
+        None => {
+            if state.svc.server_context().await.require_authentication {
+                return Err(bad_auth("authentication required"));
+            }
+
+            return Ok(None);
+        }
+    };
+
+    let (scheme, credentials) = authorization.split_once(' ').unwrap_or((authorization, ""));
+
+    if scheme.eq_ignore_ascii_case("Bearer") {
+        let mut tokens = state.tokens.lock().unwrap();
+
+        return match tokens.get(credentials.trim()) {
+            Some(token) if token.expires > Instant::now() => Ok(Some(token.username.clone())),
+            Some(_) => {
+                tokens.remove(credentials.trim());
+                Err(bad_auth("token expired"))
+            }
+            None => Err(bad_auth("unknown token")),
+        };
+    }
+
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return Err(bad_auth("unsupported authorization scheme"));
+    }
+
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(credentials.trim())
+        .ok()
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .ok_or_else(|| bad_auth("invalid Basic credentials"))?;
+
+    let (username, password) = decoded
+        .split_once(':')
+        .ok_or_else(|| bad_auth("invalid Basic credentials"))?;
+
+    let provider = state.provider.clone();
+    let (username, password) = (username.to_string(), password.to_string());
+    let iterations = state.svc.server_context().await.iterations;
+
+    // hashing the password takes a while, it runs off the async threads
+    tokio::task::spawn_blocking(move || {
+        if provider.verify_password(&username, &password) {
+            // the password is known here, so a weaker hash is replaced
+            provider.rehash(&username, &password, iterations);
+
+            Ok(Some(username))
+        } else {
+            println!("[HTTP] authentication of {} from {} failed", username, peer);
+            Err(bad_auth("invalid username or password"))
+        }
+    })
+    .await
+    .unwrap_or_else(|e| Err(bad_auth(&e.to_string())))
+}
+
+fn issue_token<T>(
+    state: &State<T>,
+    username: Option<String>,
+) -> Result<hyper::Response<Body>, Error> {
+    let username =
+        username.ok_or_else(|| bad_auth("a token is issued to an authenticated user"))?;
+
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect();
+
+    let now = Instant::now();
+    let mut tokens = state.tokens.lock().unwrap();
+
+    tokens.retain(|_, token| token.expires > now);
+    tokens.insert(
+        token.clone(),
+        Token {
+            username,
+            expires: now + state.token_ttl,
+        },
+    );
+
+    Ok(respond(
+        StatusCode::CREATED,
+        serde_json::json!({
+            "token": token,
+            "expires_in": state.token_ttl.as_secs(),
+        }),
+    ))
+}
+
+async fn key_operation<T: Gateway>(
+    state: &State<T>,
+    database: &str,
+    operation: ShardOperation,
+    key: Option<&str>,
+    value: Option<bson::Bson>,
+    username: Option<String>,
+) -> Result<hyper::Response<Body>, Error> {
+    let request = ShardRequest {
+        database: database.to_string(),
+        operation,
+        key: key.map(|key| key.to_string()),
+        value,
+        user: None,
+    };
+
+    let response = state
+        .svc
+        .key_operation(request, username)
+        .await
+        .map_err(missing_key)?;
+
+    Ok(json_response(response))
+}
+
+/// The engine reports a missing key as `AlreadyExists`, a REST client expects a 404
+fn missing_key(error: Error) -> Error {
+    match error.status {
+        Status::AlreadyExists if error.message == "key not exists" => Error {
+            status: Status::NotFound,
+            ..error
+        },
+        _ => error,
+    }
+}
+
+/// Inserts the key, or replaces its value when it exists
+async fn put_key<T: Gateway>(
+    state: &State<T>,
+    database: &str,
+    key: &str,
+    value: bson::Bson,
+    username: Option<String>,
+) -> Result<hyper::Response<Body>, Error> {
+    let request = |operation| ShardRequest {
+        database: database.to_string(),
+        operation,
+        key: Some(key.to_string()),
+        value: Some(value.clone()),
+        user: None,
+    };
+
+    let (mut response, status) = match state
+        .svc
+        .key_operation(request(ShardOperation::Insert), username.clone())
+        .await
+    {
+        Ok(response) => (response, Status::Inserted),
+        Err(Error {
+            status: Status::AlreadyExists,
+            ..
+        }) => {
+            let response = state
+                .svc
+                .key_operation(request(ShardOperation::Update), username)
+                .await?;
+
+            (response, Status::Updated)
+        }
+        Err(e) => return Err(e),
+    };
+
+    response.header.status = status;
+
+    Ok(json_response(response))
+}
+
+/// An RBQL query as plain text, or `{ "query": ..., "variables": ... }` as JSON
+fn query_body(body: &[u8], json: bool) -> Result<bson::Document, Error> {
+    if !json {
+        let query = String::from_utf8(body.to_vec())
+            .map_err(|_| bad_body("the query is not UTF-8".to_string()))?;
+
+        return Ok(bson::doc! { "query": query });
+    }
+
+    let body: serde_json::Value = serde_json::from_slice(body)
+        .map_err(|e| bad_body(format!("the body is not JSON: {}", e)))?;
+
+    let query = body
+        .get("query")
+        .and_then(|query| query.as_str())
+        .ok_or_else(|| bad_body("Missing query".to_string()))?;
+
+    let mut document = bson::doc! { "query": query };
+
+    if let Some(variables) = body.get("variables") {
+        match extjson::from_value(variables.clone()) {
+            Ok(bson::Bson::Document(variables)) => {
+                document.insert("variables", variables);
+            }
+            _ => return Err(bad_body("variables must be an object".to_string())),
+        }
+    }
+
+    Ok(document)
+}
+
+async fn read_body(mut body: Body) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| bad_body(e.to_string()))?;
+
+        if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(bad_body(format!(
+                "the body is larger than {} bytes",
+                MAX_BODY_SIZE
+            )));
+        }
+
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes)
+}
+
+fn percent_decode(segment: &str) -> Result<String, Error> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let byte = segment
+                .get(index + 1..index + 3)
+                // from_str_radix would take a sign, as in %+1
+                .filter(|hex| hex.bytes().all(|byte| byte.is_ascii_hexdigit()))
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| bad_body(format!("invalid escape in {}", segment)))?;
+
+            decoded.push(byte);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| bad_body(format!("{} is not UTF-8", segment)))
+}
+
+pub fn http_status(status: &Status) -> StatusCode {
+    match status {
+        Status::Ok | Status::Updated => StatusCode::OK,
+        Status::Inserted => StatusCode::CREATED,
+        Status::InvalidQuery | Status::BadBson | Status::BadBody | Status::SyntaxError => {
+            StatusCode::BAD_REQUEST
+        }
+        Status::NotFound => StatusCode::NOT_FOUND,
+        Status::AlreadyExists => StatusCode::CONFLICT,
+        Status::BadAuth => StatusCode::UNAUTHORIZED,
+        Status::NotAuthorized | Status::Reserved => StatusCode::FORBIDDEN,
+        Status::NotLeader => StatusCode::MISDIRECTED_REQUEST,
+        Status::SchemaViolation => StatusCode::UNPROCESSABLE_ENTITY,
+        Status::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+        Status::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+        Status::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        Status::ShuttingDown | Status::TooManyConnections => StatusCode::SERVICE_UNAVAILABLE,
+        Status::Timeout => StatusCode::GATEWAY_TIMEOUT,
+    }
+}
+
+fn json_response(response: Response) -> hyper::Response<Body> {
+    respond(
+        http_status(&response.header.status),
+        serde_json::json!({
+            "status": response.header.status,
+            "messages": response.header.messages,
+            "body": response.body.map(extjson::to_relaxed),
+        }),
+    )
+}
+
+fn error_response(error: Error) -> hyper::Response<Body> {
+    let mut response = respond(
+        http_status(&error.status),
+        serde_json::json!({
+            "status": error.status,
+            "message": error.message,
+            "query_message": error.query_message,
+        }),
+    );
+
+    if matches!(error.status, Status::BadAuth) {
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"rustbase\""),
+        );
+    }
+
+    response
+}
+
+fn respond(status: StatusCode, body: serde_json::Value) -> hyper::Response<Body> {
+    hyper::Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn bad_body(message: String) -> Error {
+    Error {
+        message,
+        query_message: None,
+        status: Status::BadBody,
+    }
+}
+
+fn bad_auth(message: &str) -> Error {
+    Error {
+        message: message.to_string(),
+        query_message: None,
+        status: Status::BadAuth,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_the_escapes_of_a_segment() {
+        assert_eq!(percent_decode("plain").unwrap(), "plain");
+        assert_eq!(percent_decode("a%20b").unwrap(), "a b");
+        assert_eq!(percent_decode("caf%C3%A9").unwrap(), "café");
+        assert_eq!(percent_decode("%2f%2F").unwrap(), "//");
+    }
+
+    #[test]
+    fn refuses_an_invalid_segment() {
+        for segment in ["%zz", "%2", "%", "%+1", "%FF"] {
+            let error = percent_decode(segment).unwrap_err();
+
+            assert!(matches!(error.status, Status::BadBody), "{}", segment);
+        }
+    }
+
+    #[test]
+    fn maps_the_statuses_to_http_codes() {
+        assert_eq!(http_status(&Status::Ok), StatusCode::OK);
+        assert_eq!(http_status(&Status::Inserted), StatusCode::CREATED);
+        assert_eq!(http_status(&Status::SyntaxError), StatusCode::BAD_REQUEST);
+        assert_eq!(http_status(&Status::BadAuth), StatusCode::UNAUTHORIZED);
+        assert_eq!(http_status(&Status::Reserved), StatusCode::FORBIDDEN);
+        assert_eq!(http_status(&Status::NotFound), StatusCode::NOT_FOUND);
+        assert_eq!(http_status(&Status::AlreadyExists), StatusCode::CONFLICT);
+        assert_eq!(
+            http_status(&Status::TooManyConnections),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(http_status(&Status::Timeout), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn reports_a_missing_key_as_not_found() {
+        let missing = missing_key(Error {
+            message: "key not exists".to_string(),
+            query_message: None,
+            status: Status::AlreadyExists,
+        });
+
+        assert!(matches!(missing.status, Status::NotFound));
+
+        let exists = missing_key(Error {
+            message: "key already exists".to_string(),
+            query_message: None,
+            status: Status::AlreadyExists,
+        });
+
+        assert!(matches!(exists.status, Status::AlreadyExists));
+    }
+}
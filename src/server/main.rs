@@ -1,33 +1,127 @@
 use async_trait::async_trait;
 use colored::Colorize;
 use dustdata::{DustData, DustDataConfig, LsmConfig, Size};
-use rayon::{ThreadPool, ThreadPoolBuilder};
+use rayon::ThreadPoolBuilder;
 
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
 
+use super::backup;
 use super::cache;
+use super::cluster;
 use super::engine;
+use super::http;
+use super::oplog;
+use super::quota;
+use super::raft;
+use super::reload;
+use super::shard;
 use super::wirewave;
 use crate::config;
 use crate::query;
 use crate::server;
 
 use cache::Cache;
+use cluster::ReplicaState;
 use config::schema;
 use engine::core::Core;
+use engine::interface::TransactionError;
+use http::Gateway;
+use oplog::{ChangeStream, OpLog};
+use quota::Counters;
+use raft::transport::WirewaveTransport;
+use raft::{DustDataMachine, Raft, RaftMessage};
+use reload::LiveConfig;
 use server::route;
+use shard::{ShardRequest, ShardRouter};
+use wirewave::authentication::{self, DefaultAuthenticationProvider};
+use wirewave::authorization::UserPermission;
+use wirewave::limits::Limiter;
 use wirewave::server::{
-    Error, Request, Response, Server, ServerContext, Status, Wirewave, WirewaveServer,
+    Address, Endpoint, Error, Peer, Request, ResHeader, Response, Server, ServerContext, Shutdown,
+    Status, Wirewave, WirewaveServer,
 };
 
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+
+#[derive(Clone)]
 pub struct RustbaseServer {
-    pool: ThreadPool,
+    limiter: Arc<Limiter>,
+    live: Arc<LiveConfig>,
     routers: Arc<RwLock<HashMap<String, DustData>>>,
-    config: Arc<schema::RustbaseConfig>,
     cache: Arc<RwLock<Cache>>,
     system_db: Arc<RwLock<dustdata::DustData>>,
+    oplog: Option<Arc<OpLog>>,
+    replica: Option<Arc<ReplicaState>>,
+    shards: Option<Arc<ShardRouter>>,
+    raft: Option<Arc<Raft>>,
+    counters: Arc<Counters>,
+}
+
+impl RustbaseServer {
+    fn run_query(
+        &self,
+        database: &str,
+        query: &str,
+        variables: Option<&bson::Document>,
+        username: Option<String>,
+    ) -> Result<Response, Error> {
+        self.live.pool().install(|| {
+            let query = query::parser::parse(query)?;
+
+            let mut core = self.core(database.to_string(), username);
+
+            if let Some(variables) = variables {
+                core.set_variables(variables);
+            }
+
+            core.set_live_config(self.live.clone());
+
+            core.run_ast(query)
+        })
+    }
+
+    /// A core running its writes through the shards or the Raft log, when there are
+    fn core(&self, database: String, username: Option<String>) -> Core {
+        let mut core = Core::new(
+            self.cache.clone(),
+            self.routers.clone(),
+            self.live.config(),
+            self.system_db.clone(),
+            database,
+            username,
+            self.oplog.clone(),
+        );
+
+        core.set_counters(self.counters.clone());
+
+        if let Some(shards) = &self.shards {
+            core.set_shards(shards.clone());
+        }
+
+        if let Some(raft) = &self.raft {
+            core.set_raft(raft.clone());
+        }
+
+        core
+    }
+}
+
+/// Runs `f` on a blocking thread, the async threads stay free for the other connections
+async fn run_blocking<F>(f: F) -> Result<Response, Error>
+where
+    F: FnOnce() -> Result<Response, Error> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await.unwrap_or_else(|e| {
+        Err(Error {
+            message: e.to_string(),
+            query_message: None,
+            status: Status::InternalError,
+        })
+    })
 }
 
 #[async_trait]
@@ -55,106 +149,705 @@ impl Wirewave for RustbaseServer {
             return Err(error);
         }
 
-        let database = body.get_str("database").unwrap();
-        let query = body.get_str("query").unwrap();
+        let database = body.get_str("database").unwrap().to_string();
+        let query = body.get_str("query").unwrap().to_string();
+        let variables = body.get_document("variables").ok().cloned();
 
-        self.pool.install(move || {
-            let query = query::parser::parse(query)?;
+        let server = self.clone();
+
+        // off the async threads, so a request timeout can answer while the query runs
+        run_blocking(move || server.run_query(&database, &query, variables.as_ref(), username))
+            .await
+    }
 
-            let mut core = Core::new(
+    async fn subscribe(
+        &self,
+        request: Request,
+        username: Option<String>,
+    ) -> Result<mpsc::Receiver<Response>, Error> {
+        let body = request.body;
+
+        let database = match body.get_str("database") {
+            Ok(database) => database.to_string(),
+            Err(_) => {
+                return Err(Error {
+                    message: "Missing database".to_string(),
+                    query_message: None,
+                    status: Status::BadBody,
+                })
+            }
+        };
+
+        let oplog = match &self.oplog {
+            Some(oplog) => oplog.clone(),
+            None => {
+                return Err(Error {
+                    message: "change streams require the operation log (storage.oplog)".to_string(),
+                    query_message: None,
+                    status: Status::InternalError,
+                })
+            }
+        };
+
+        let prefix = body.get_str("prefix").ok().map(|prefix| prefix.to_string());
+        let resume_after = body.get_i64("resume_after").ok();
+
+        self.live.pool().install(|| {
+            Core::new(
                 self.cache.clone(),
                 self.routers.clone(),
-                self.config.clone(),
+                self.live.config(),
                 self.system_db.clone(),
-                database.to_string(),
+                database.clone(),
                 username,
-            );
+                self.oplog.clone(),
+            )
+            .authorize_read()
+        })?;
+
+        let mut stream = ChangeStream::new(oplog, Some(database), prefix, resume_after);
+        let (sender, receiver) = mpsc::channel(64);
+
+        tokio::spawn(async move {
+            while let Some(entry) = stream.next().await {
+                let event = Response {
+                    header: ResHeader {
+                        status: Status::Ok,
+                        messages: None,
+                        is_error: false,
+                    },
+                    body: Some(bson::to_bson(&entry).unwrap()),
+                };
+
+                // the client is gone
+                if sender.send(event).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(receiver)
+    }
 
-            core.run_ast(query)
-        })
+    async fn cluster(
+        &self,
+        request: Request,
+        username: Option<String>,
+    ) -> Result<mpsc::Receiver<Response>, Error> {
+        let body = request.body;
+
+        self.live.pool().install(|| {
+            Core::new(
+                self.cache.clone(),
+                self.routers.clone(),
+                self.live.config(),
+                self.system_db.clone(),
+                String::new(),
+                username.clone(),
+                self.oplog.clone(),
+            )
+            .authorize_admin()
+        })?;
+
+        let respond = |response: Response| {
+            let (sender, receiver) = mpsc::channel(1);
+            sender.try_send(response).ok();
+
+            receiver
+        };
+
+        match body.get_str("action") {
+            Ok("shard") => {
+                let request: ShardRequest = bson::from_document(body).map_err(|e| Error {
+                    message: e.to_string(),
+                    query_message: None,
+                    status: Status::BadBody,
+                })?;
+
+                let response = self.live.pool().install(|| {
+                    let mut core = Core::new(
+                        self.cache.clone(),
+                        self.routers.clone(),
+                        self.live.config(),
+                        self.system_db.clone(),
+                        request.database.clone(),
+                        username,
+                        self.oplog.clone(),
+                    );
+
+                    // run as the cluster user, charged to the user of the router
+                    core.set_counters(self.counters.clone());
+                    core.set_quota_user(request.user.clone());
+
+                    core.run_shard_operation(request)
+                })?;
+
+                Ok(respond(response))
+            }
+
+            Ok("raft") => {
+                let raft = match &self.raft {
+                    Some(raft) => raft,
+                    None => {
+                        return Err(Error {
+                            message: "the server is not a Raft node".to_string(),
+                            query_message: None,
+                            status: Status::InternalError,
+                        })
+                    }
+                };
+
+                let message = body
+                    .get_document("message")
+                    .ok()
+                    .and_then(|message| bson::from_document::<RaftMessage>(message.clone()).ok())
+                    .ok_or_else(|| Error {
+                        message: "Missing or invalid message".to_string(),
+                        query_message: None,
+                        status: Status::BadBody,
+                    })?;
+
+                let reply = raft.handle(message).await;
+
+                Ok(respond(Response {
+                    header: ResHeader {
+                        status: Status::Ok,
+                        messages: None,
+                        is_error: false,
+                    },
+                    body: Some(bson::to_bson(&reply).unwrap()),
+                }))
+            }
+
+            Ok("rebalance") => {
+                let shards = match &self.shards {
+                    Some(shards) => shards,
+                    None => {
+                        return Err(Error {
+                            message: "rebalance requires a router".to_string(),
+                            query_message: None,
+                            status: Status::InternalError,
+                        })
+                    }
+                };
+
+                println!(
+                    "[Cluster] rebalancing the keys across {}",
+                    shards.nodes().join(", ").yellow()
+                );
+
+                let moved = shards.rebalance().await.map_err(|e| match e {
+                    TransactionError::ExternalError(status, message) => Error {
+                        message,
+                        query_message: None,
+                        status,
+                    },
+                    TransactionError::InternalError(e) => Error {
+                        message: format!("{:?}", e.code),
+                        query_message: None,
+                        status: Status::InternalError,
+                    },
+                })?;
+
+                println!("[Cluster] rebalance done, {} keys moved", moved);
+
+                Ok(respond(Response {
+                    header: ResHeader {
+                        status: Status::Ok,
+                        messages: None,
+                        is_error: false,
+                    },
+                    body: Some(bson::Bson::Document(bson::doc! { "moved": moved as i64 })),
+                }))
+            }
+
+            Ok("status") => {
+                let status = match (&self.replica, &self.oplog) {
+                    _ if self.raft.is_some() => self.raft.as_ref().unwrap().status(),
+                    (Some(replica), _) => replica.status(),
+                    (None, Some(oplog)) => cluster::primary_status(oplog),
+                    (None, None) => bson::doc! { "role": "standalone" },
+                };
+
+                Ok(respond(Response {
+                    header: ResHeader {
+                        status: Status::Ok,
+                        messages: None,
+                        is_error: false,
+                    },
+                    body: Some(bson::Bson::Document(status)),
+                }))
+            }
+
+            Ok("replicate") => {
+                let config = self.live.config();
+                let primary = config
+                    .cluster
+                    .as_ref()
+                    .filter(|cluster| cluster.role == schema::ClusterRole::Primary);
+
+                let (primary, oplog) = match (primary, &self.oplog) {
+                    (Some(primary), Some(oplog)) => (primary, oplog.clone()),
+                    _ => return Err(Error {
+                        message:
+                            "replication requires a primary with the operation log (storage.oplog)"
+                                .to_string(),
+                        query_message: None,
+                        status: Status::InternalError,
+                    }),
+                };
+
+                let after = body.get_i64("after").ok();
+                let max_lag = primary.max_lag.unwrap_or(cluster::DEFAULT_MAX_LAG);
+
+                Ok(cluster::replication_stream(
+                    oplog,
+                    self.routers.clone(),
+                    self.system_db.clone(),
+                    after,
+                    max_lag,
+                ))
+            }
+
+            _ => Err(Error {
+                message: "Missing or unknown action".to_string(),
+                query_message: None,
+                status: Status::BadBody,
+            }),
+        }
     }
 
-    async fn new_connection(&self, username: Option<String>, addr: SocketAddr) {
+    async fn new_connection(&self, username: Option<String>, peer: Peer) {
         println!(
             "[Server] new connection from {} as {}",
-            addr,
+            peer,
             username.unwrap_or("anonymous".to_string()).yellow(),
         )
     }
 
     async fn server_context(&self) -> ServerContext {
-        let require_auth = current_users(self.system_db.clone()) > 0;
+        let auth = self.live.config().auth.clone();
+        let required = auth
+            .as_ref()
+            .and_then(|auth| auth.required)
+            .unwrap_or(false);
+
+        // without `auth.required`, authentication starts once a user exists
+        let require_auth = required || current_users(self.system_db.clone()) > 0;
 
         ServerContext {
             require_authentication: require_auth,
+            auth_bypass: auth
+                .as_ref()
+                .and_then(|auth| auth.enable_auth_bypass)
+                .unwrap_or(false),
+            iterations: authentication::configured_iterations(auth.as_ref()),
+            auth_type: auth.and_then(|auth| auth.auth_type).unwrap_or_default(),
+            limits: self.live.config().net.limits.clone().unwrap_or_default(),
+            limiter: self.limiter.clone(),
+            cluster_user: self
+                .live
+                .config()
+                .cluster
+                .as_ref()
+                .and_then(|cluster| cluster.username.clone()),
         }
     }
 }
 
+#[async_trait]
+impl Gateway for RustbaseServer {
+    async fn key_operation(
+        &self,
+        request: ShardRequest,
+        username: Option<String>,
+    ) -> Result<Response, Error> {
+        let server = self.clone();
+
+        run_blocking(move || {
+            server.live.pool().install(|| {
+                server
+                    .core(request.database.clone(), username)
+                    .run_shard_operation(request)
+            })
+        })
+        .await
+    }
+}
+
 fn current_users(system_db: Arc<RwLock<DustData>>) -> usize {
     let dd = system_db.read().unwrap();
 
-    dd.list_keys().unwrap().len()
+    dd.list_keys()
+        .unwrap()
+        .iter()
+        .filter(|key| !route::is_catalog_key(key))
+        .count()
 }
 
-pub async fn initalize_server(config: schema::RustbaseConfig) {
+pub async fn initalize_server(config: schema::RustbaseConfig, config_path: Option<PathBuf>) {
     let config = Arc::new(config);
-    let addr = format!("{}:{}", config.net.host, config.net.port);
 
     let routers = route::initialize_dustdata(&config);
     let cache = Arc::new(RwLock::new(Cache::new(config.cache_size)));
+    let oplog = OpLog::open(&config).map(Arc::new);
 
     let system_db = Arc::new(RwLock::new(DustData::new(default_dustdata_config(
         &config,
         Some("_default"),
     ))));
 
-    let c_routers = routers.clone();
-    let c_system_db = system_db.clone();
-    ctrlc::set_handler(move || {
-        c_routers
-            .write()
-            .unwrap()
-            .iter_mut()
-            .for_each(|(route, dd)| {
-                println!("[Server] flushing {} to exit", route.yellow());
-                dd.flush().unwrap();
-            });
-
-        c_system_db.write().unwrap().flush().unwrap();
-
-        std::process::exit(0);
-    })
-    .expect("Error setting Ctrl-C handler");
+    if let Some(auth) = &config.auth {
+        bootstrap_admin(auth, &system_db);
+    }
+
+    let (shutdown_sender, signal) = watch::channel(false);
+
+    let counters = Arc::new(Counters::new(config.clone()));
+
+    let forced_routers = routers.clone();
+    let forced_system_db = system_db.clone();
+    let forced_cache = cache.clone();
+    let forced_oplog = oplog.clone();
+    let forced_counters = counters.clone();
+
+    tokio::spawn(async move {
+        wait_for_termination().await;
+
+        println!("[Server] shutting down, no new connection is accepted");
+        shutdown_sender.send_replace(true);
+
+        // a second signal skips the wait for the connections and the background tasks,
+        // what is written so far is still flushed
+        wait_for_termination().await;
+        println!("[Server] forced shutdown");
+
+        flush_all(
+            &forced_routers,
+            &forced_system_db,
+            &forced_cache,
+            &forced_oplog,
+            &forced_counters,
+        );
+        std::process::exit(1);
+    });
+
+    // stops the tasks writing in the background, once the connections are closed
+    let (stop_sender, stop) = watch::channel(false);
+    let mut background = Vec::new();
+
+    let shutdown = Shutdown {
+        signal,
+        timeout: Duration::from_secs(
+            config
+                .net
+                .shutdown_timeout
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+        ),
+    };
+
+    let http_shutdown = Shutdown {
+        signal: shutdown.signal.clone(),
+        timeout: shutdown.timeout,
+    };
+
+    if let Some(backup) = &config.backup {
+        background.push(tokio::spawn(backup::run_scheduler(
+            backup.clone(),
+            routers.clone(),
+            system_db.clone(),
+            stop.clone(),
+        )));
+    }
+
+    let replica = config
+        .cluster
+        .as_ref()
+        .filter(|cluster| cluster.role == schema::ClusterRole::Replica)
+        .map(|_| Arc::new(ReplicaState::load(&config)));
+
+    if let Some(replica) = &replica {
+        background.push(tokio::spawn(cluster::run_replica(
+            cluster::Replica {
+                config: config.clone(),
+                routers: routers.clone(),
+                system_db: system_db.clone(),
+                cache: cache.clone(),
+                state: replica.clone(),
+            },
+            stop.clone(),
+        )));
+    }
+
+    let shards = config
+        .cluster
+        .as_ref()
+        .filter(|cluster| cluster.role == schema::ClusterRole::Router)
+        .and_then(|cluster| ShardRouter::new(cluster, tokio::runtime::Handle::current()))
+        .map(Arc::new);
+
+    if let Some(shards) = &shards {
+        println!(
+            "[Cluster] routing the keys to {}",
+            shards.nodes().join(", ").yellow()
+        );
+    }
+
+    let raft = config
+        .cluster
+        .as_ref()
+        .filter(|cluster| cluster.role == schema::ClusterRole::Raft)
+        .and_then(|cluster| {
+            let transport = Arc::new(WirewaveTransport::new(cluster));
+            let machine = Arc::new(DustDataMachine::new(
+                config.clone(),
+                routers.clone(),
+                cache.clone(),
+                oplog.clone(),
+                system_db.clone(),
+                counters.clone(),
+            ));
+
+            Raft::from_config(&config, transport, machine)
+                .inspect_err(|e| println!("[Raft] disabled: {}", e))
+                .ok()
+        })
+        .map(Arc::new);
+
+    if let Some(raft) = &raft {
+        background.push(tokio::spawn(raft.clone().run(stop.clone())));
+    }
 
     let pool = ThreadPoolBuilder::new()
         .num_threads(config.threads)
         .build()
         .unwrap();
 
-    let database = RustbaseServer {
+    let mut endpoints = Vec::new();
+    let mut tls_senders = Vec::new();
+
+    for listener in config::listeners(&config) {
+        let (sender, receiver) = match &listener.tls {
+            Some(tls) => {
+                let (sender, receiver) =
+                    watch::channel(wirewave::server::tls_acceptor(tls).unwrap());
+
+                (Some(sender), Some(receiver))
+            }
+            None => (None, None),
+        };
+
+        let address = match &listener.path {
+            Some(path) => Address::Unix {
+                path: path.clone(),
+                mode: listener
+                    .mode
+                    .as_deref()
+                    .map(|mode| config::socket_mode(mode).unwrap()),
+            },
+            None if listener.websocket.unwrap_or(false) => {
+                Address::WebSocket(config::listener_name(&listener))
+            }
+            None => Address::Tcp(config::listener_name(&listener)),
+        };
+
+        tls_senders.push(sender);
+        endpoints.push(Endpoint {
+            address,
+            tls: receiver,
+            auth_required: listener.auth_required,
+            peer_credentials: listener.peer_credentials.unwrap_or(false),
+        });
+    }
+
+    let live = Arc::new(LiveConfig::new(
+        config_path,
+        config.clone(),
         pool,
+        cache.clone(),
+        tls_senders,
+    ));
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(live.clone()));
+
+    let routers_to_flush = routers.clone();
+    let cache_to_clear = cache.clone();
+    let oplog_to_sync = oplog.clone();
+    let counters_to_flush = counters.clone();
+
+    let database = RustbaseServer {
+        limiter: Arc::new(Limiter::default()),
+        live,
         routers,
         cache,
-        config: Arc::clone(&config),
         system_db: Arc::clone(&system_db),
+        oplog,
+        replica,
+        shards,
+        raft,
+        counters,
     };
+    let gateway = config.net.http.clone().map(|http| {
+        let provider = DefaultAuthenticationProvider::new(system_db.clone());
+
+        http::serve(http, database.clone(), provider, http_shutdown)
+    });
+
     let svc = WirewaveServer::new(database);
 
-    println!(
-        "[Server] listening on {}",
-        format!("rustbase://{}", addr).yellow()
+    for endpoint in &endpoints {
+        println!(
+            "[Server] listening on {}{}",
+            endpoint.address.to_string().yellow(),
+            if endpoint.tls.is_some() { " (TLS)" } else { "" }
+        );
+    }
+
+    let server = Server::new(svc, system_db.clone());
+
+    let serve = server.serve(endpoints, shutdown);
+
+    // both stop on the same signal, the data is flushed once neither can write
+    match gateway {
+        Some(gateway) => {
+            tokio::join!(serve, gateway);
+        }
+        None => serve.await,
+    }
+
+    // each finishes the step it is in, e.g. the entry or the backup being written
+    stop_sender.send_replace(true);
+
+    for task in background {
+        if let Err(e) = task.await {
+            println!("[Server] a background task failed: {}", e);
+        }
+    }
+
+    flush_all(
+        &routers_to_flush,
+        &system_db,
+        &cache_to_clear,
+        &oplog_to_sync,
+        &counters_to_flush,
     );
 
-    let server = Server::new(svc, system_db);
+    println!("[Server] stopped");
+}
 
-    if let Some(tls) = &config.net.tls {
-        server.serve_tls(addr, tls).await;
-    } else {
-        server.serve(addr).await;
+/// Creates the admin of the configuration if it does not exist yet, and refuses to start
+/// when authentication is required but nobody could log in
+fn bootstrap_admin(auth: &schema::Auth, system_db: &Arc<RwLock<DustData>>) {
+    if let Some(admin) = &auth.admin {
+        let mut dd = system_db.write().unwrap();
+
+        if dd.get(&admin.username).unwrap().is_none() {
+            let iterations = authentication::configured_iterations(Some(auth));
+            let mut user = authentication::new_credentials(&admin.password, iterations);
+            user.insert("permission", UserPermission::Admin as i32);
+
+            dd.insert(&admin.username, bson::Bson::Document(user))
+                .unwrap();
+            dd.flush().unwrap();
+
+            println!("[Auth] admin {} created", admin.username.yellow());
+        }
+    }
+
+    let required = auth.required.unwrap_or(false);
+    let bypass = auth.enable_auth_bypass.unwrap_or(false);
+
+    if required && !bypass && current_users(system_db.clone()) == 0 {
+        println!(
+            "[Auth] {}, set auth.admin (or RUSTBASE_AUTH_ADMIN_USERNAME and RUSTBASE_AUTH_ADMIN_PASSWORD)",
+            "authentication is required but there is no user".red()
+        );
+        std::process::exit(1);
+    }
+}
+
+/// Flushes every database, then the system database and the owners of the quotas, then
+/// syncs the operation log and empties the cache. Called once no request can write anymore.
+fn flush_all(
+    routers: &RwLock<HashMap<String, DustData>>,
+    system_db: &RwLock<DustData>,
+    cache: &RwLock<Cache>,
+    oplog: &Option<Arc<OpLog>>,
+    counters: &Counters,
+) {
+    for (route, dd) in routers.write().unwrap().iter_mut() {
+        println!("[Server] flushing {} to exit", route.yellow());
+
+        if let Err(e) = dd.flush() {
+            println!("[Server] failed to flush {}: {:?}", route.red(), e.code);
+        }
+    }
+
+    if let Err(e) = system_db.write().unwrap().flush() {
+        println!("[Server] failed to flush the system database: {:?}", e.code);
+    }
+
+    if let Err(e) = counters.flush() {
+        println!(
+            "[Server] failed to flush the owners of the quotas: {:?}",
+            e.code
+        );
+    }
+
+    if let Some(oplog) = oplog {
+        if let Err(e) = oplog.sync() {
+            println!("[Server] failed to sync the operation log: {}", e);
+        }
+    }
+
+    let mut cache = cache.write().unwrap();
+    *cache = Cache::new(0);
+}
+
+#[cfg(unix)]
+async fn reload_on_hangup(live: Arc<LiveConfig>) {
+    let mut hangup =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).unwrap();
+
+    while hangup.recv().await.is_some() {
+        let reload_live = live.clone();
+
+        // the new thread pool is built on a blocking thread
+        let result = tokio::task::spawn_blocking(move || reload_live.reload())
+            .await
+            .unwrap_or_else(|e| Err(e.to_string()));
+
+        print_reload(&result);
     }
 }
 
+pub fn print_reload(result: &Result<Vec<String>, String>) {
+    match result {
+        Ok(changed) if changed.is_empty() => {
+            println!("[Config] configuration reloaded, nothing changed")
+        }
+        Ok(changed) => println!("[Config] configuration reloaded: {}", changed.join(", ")),
+        Err(e) => println!("[Config] reload rejected: {}", e.red()),
+    }
+}
+
+async fn wait_for_termination() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.unwrap();
+}
+
 pub fn default_dustdata_config(
     config: &schema::RustbaseConfig,
     database: Option<&str>,
@@ -1,11 +1,21 @@
+pub mod backup;
 pub mod cache;
+pub mod cluster;
 pub mod engine;
+pub mod http;
 pub mod main;
+pub mod oplog;
+pub mod quota;
+pub mod raft;
+pub mod reload;
 pub mod route;
+pub mod shard;
+pub mod validation;
 pub mod wirewave;
 
 use crate::config::schema;
+use std::path::PathBuf;
 
-pub async fn initalize_server(config: schema::RustbaseConfig) {
-    main::initalize_server(config).await;
+pub async fn initalize_server(config: schema::RustbaseConfig, config_path: Option<PathBuf>) {
+    main::initalize_server(config, config_path).await;
 }
@@ -0,0 +1,479 @@
+use bson::{Bson, Document};
+use dustdata::DustData;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+use crate::config::schema;
+
+pub const OPLOG_EXTENSION: &str = "oplog";
+pub const OPLOG_DIRECTORY: &str = "_oplog";
+const EVENTS_CAPACITY: usize = 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Insert,
+    Update,
+    Delete,
+    DropDatabase,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OpEntry {
+    pub seq: i64,
+    pub timestamp: bson::DateTime,
+    pub database: String,
+    pub operation: Operation,
+    pub key: Option<String>,
+    pub value: Option<Bson>,
+}
+
+/// Append-only log of every write, one file per database. Entries are numbered with a
+/// sequence shared by all databases, so the logs of several databases can be merged in order.
+pub struct OpLog {
+    path: PathBuf,
+    sync: bool,
+    inner: Mutex<OpLogInner>,
+    events: broadcast::Sender<OpEntry>,
+}
+
+struct OpLogInner {
+    seq: i64,
+    files: HashMap<String, File>,
+}
+
+impl OpLog {
+    pub fn open(config: &schema::RustbaseConfig) -> Option<Self> {
+        let oplog = config.storage.oplog.as_ref()?;
+
+        let path = oplog_path(config)?;
+
+        if !path.exists() {
+            fs::create_dir_all(&path).unwrap();
+        }
+
+        // recover the sequence from the last entry of every log, dropping the entry a crash
+        // left partially written so that the next ones are not appended after it
+        let mut seq = 0;
+
+        for path in log_files(&path) {
+            match repair(&path) {
+                Ok(last) => seq = seq.max(last.unwrap_or(0)),
+                Err(e) => println!("[OpLog] cannot repair {}: {}", path.display(), e),
+            }
+        }
+
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+
+        Some(Self {
+            path,
+            sync: oplog.sync.unwrap_or(true),
+            inner: Mutex::new(OpLogInner {
+                seq,
+                files: HashMap::new(),
+            }),
+            events,
+        })
+    }
+
+    pub fn append(
+        &self,
+        database: &str,
+        operation: Operation,
+        key: Option<String>,
+        value: Option<Bson>,
+    ) -> io::Result<OpEntry> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let entry = OpEntry {
+            seq: inner.seq + 1,
+            timestamp: bson::DateTime::now(),
+            database: database.to_string(),
+            operation,
+            key,
+            value,
+        };
+
+        let bytes = bson::to_vec(&entry).map_err(io::Error::other)?;
+
+        if !inner.files.contains_key(database) {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(database_log_path(&self.path, database))?;
+
+            inner.files.insert(database.to_string(), file);
+        }
+
+        let file = inner.files.get_mut(database).unwrap();
+        let length = file.metadata()?.len();
+
+        if let Err(e) = file.write_all(&bytes) {
+            // a partial entry would hide the next ones from the readers
+            file.set_len(length).ok();
+            return Err(e);
+        }
+
+        if self.sync {
+            file.sync_data()?;
+        }
+
+        inner.seq = entry.seq;
+
+        // no subscribers is not an error
+        self.events.send(entry.clone()).ok();
+
+        Ok(entry)
+    }
+
+    /// Syncs every open log to disk, e.g. before exiting when `sync` is disabled
+    pub fn sync(&self) -> io::Result<()> {
+        let inner = self.inner.lock().unwrap();
+
+        for file in inner.files.values() {
+            file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    /// The sequence of the last logged entry
+    pub fn seq(&self) -> i64 {
+        self.inner.lock().unwrap().seq
+    }
+
+    /// Reads the logged entries with a sequence greater than `after`, of one database
+    /// or, when `database` is `None`, of every database merged in order.
+    pub fn entries_after(&self, database: Option<&str>, after: i64) -> Vec<OpEntry> {
+        let files = match database {
+            Some(database) => vec![database_log_path(&self.path, database)],
+            None => log_files(&self.path),
+        };
+
+        let mut entries: Vec<OpEntry> = files
+            .iter()
+            .filter_map(|path| OpLogReader::open(path).ok())
+            .flatten()
+            .filter(|entry| entry.seq > after)
+            .collect();
+
+        entries.sort_by_key(|entry| entry.seq);
+
+        entries
+    }
+}
+
+/// The changes of a database (or of every database when `database` is `None`), starting
+/// after a resume token (the `seq` of the last entry received) or at the time of the subscription.
+pub struct ChangeStream {
+    oplog: Arc<OpLog>,
+    receiver: broadcast::Receiver<OpEntry>,
+    backlog: std::vec::IntoIter<OpEntry>,
+    database: Option<String>,
+    prefix: Option<String>,
+    last_seq: i64,
+}
+
+impl ChangeStream {
+    pub fn new(
+        oplog: Arc<OpLog>,
+        database: Option<String>,
+        prefix: Option<String>,
+        resume_after: Option<i64>,
+    ) -> Self {
+        // subscribe before reading the log, so nothing is missed in between
+        let receiver = oplog.events.subscribe();
+
+        let (backlog, last_seq) = match resume_after {
+            Some(after) => (oplog.entries_after(database.as_deref(), after), after),
+            None => (Vec::new(), oplog.seq()),
+        };
+
+        Self {
+            oplog,
+            receiver,
+            backlog: backlog.into_iter(),
+            database,
+            prefix,
+            last_seq,
+        }
+    }
+
+    pub async fn next(&mut self) -> Option<OpEntry> {
+        loop {
+            let entry = match self.backlog.next() {
+                Some(entry) => entry,
+                None => match self.receiver.recv().await {
+                    Ok(entry) => entry,
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        // too slow to keep up with the live events, catch up from the log
+                        self.backlog = self
+                            .oplog
+                            .entries_after(self.database.as_deref(), self.last_seq)
+                            .into_iter();
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                },
+            };
+
+            let other_database = self
+                .database
+                .as_ref()
+                .is_some_and(|database| *database != entry.database);
+
+            if entry.seq <= self.last_seq || other_database {
+                continue;
+            }
+
+            self.last_seq = entry.seq;
+
+            let matches_prefix = match (&self.prefix, &entry.key) {
+                (Some(prefix), Some(key)) => key.starts_with(prefix),
+                _ => true,
+            };
+
+            if matches_prefix {
+                return Some(entry);
+            }
+        }
+    }
+}
+
+/// Applies a logged operation to a database. Inserts and updates overwrite the key and
+/// missing keys are ignored on delete, so replaying an entry more than once is harmless.
+pub fn apply_entry(dd: &mut DustData, entry: &OpEntry) -> dustdata::Result<()> {
+    match entry.operation {
+        Operation::Insert | Operation::Update => {
+            let key = entry.key.as_deref().unwrap_or_default();
+            let value = entry.value.clone().unwrap_or(Bson::Null);
+
+            match dd.insert(key, value.clone()) {
+                Err(e) if matches!(e.code, dustdata::ErrorCode::KeyExists) => dd.update(key, value),
+                result => result,
+            }
+        }
+
+        Operation::Delete => {
+            if let Some(key) = &entry.key {
+                dd.delete(key).ok();
+            }
+
+            Ok(())
+        }
+
+        Operation::DropDatabase => {
+            for key in dd.list_keys()? {
+                dd.delete(&key).ok();
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// Returns the directory of the operation logs, if they are enabled
+pub fn oplog_path(config: &schema::RustbaseConfig) -> Option<PathBuf> {
+    let oplog = config.storage.oplog.as_ref()?;
+
+    Some(
+        oplog
+            .path
+            .clone()
+            .unwrap_or_else(|| config.storage.path.join(OPLOG_DIRECTORY)),
+    )
+}
+
+pub fn database_log_path(oplog_path: &Path, database: &str) -> PathBuf {
+    oplog_path.join(format!("{}.{}", database, OPLOG_EXTENSION))
+}
+
+fn log_files(oplog_path: &Path) -> Vec<PathBuf> {
+    match fs::read_dir(oplog_path) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == OPLOG_EXTENSION))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Truncates a log after its last readable entry, returning the sequence of that entry
+fn repair(path: &Path) -> io::Result<Option<i64>> {
+    let mut reader = OpLogReader::open(path)?;
+    let last = reader.by_ref().last().map(|entry| entry.seq);
+
+    let file = OpenOptions::new().write(true).open(path)?;
+    let length = file.metadata()?.len();
+
+    if reader.valid_length < length {
+        println!(
+            "[OpLog] dropping {} bytes of a partial entry at the end of {}",
+            length - reader.valid_length,
+            path.display()
+        );
+
+        file.set_len(reader.valid_length)?;
+        file.sync_data()?;
+    }
+
+    Ok(last)
+}
+
+/// Reads the entries of an operation log in order. A partially written entry at the end
+/// of the log (e.g. after a crash) ends the iteration.
+pub struct OpLogReader {
+    reader: BufReader<File>,
+    /// The end of the last entry read
+    valid_length: u64,
+}
+
+impl OpLogReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            reader: BufReader::new(File::open(path)?),
+            valid_length: 0,
+        })
+    }
+}
+
+impl Iterator for OpLogReader {
+    type Item = OpEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.fill_buf() {
+            Ok([]) | Err(_) => return None,
+            Ok(_) => {}
+        }
+
+        let doc = Document::from_reader(&mut self.reader).ok()?;
+        let entry = bson::from_document(doc).ok()?;
+
+        self.valid_length = self.reader.stream_position().ok()?;
+
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    fn open(name: &str, fresh: bool) -> OpLog {
+        let path =
+            std::env::temp_dir().join(format!("rustbase-oplog-{}-{}", name, std::process::id()));
+
+        if fresh {
+            fs::remove_dir_all(&path).ok();
+        }
+
+        let mut config = config::default_configuration();
+        config.storage.path = path;
+        config.storage.oplog = Some(schema::OpLogStorageConfig {
+            path: None,
+            sync: Some(false),
+        });
+
+        OpLog::open(&config).unwrap()
+    }
+
+    fn insert(oplog: &OpLog, database: &str, key: &str) -> OpEntry {
+        oplog
+            .append(
+                database,
+                Operation::Insert,
+                Some(key.to_string()),
+                Some(Bson::Int32(1)),
+            )
+            .unwrap()
+    }
+
+    fn seqs(entries: Vec<OpEntry>) -> Vec<i64> {
+        entries.into_iter().map(|entry| entry.seq).collect()
+    }
+
+    #[test]
+    fn numbers_the_entries_across_the_databases() {
+        let oplog = open("seq", true);
+
+        assert_eq!(insert(&oplog, "a", "k1").seq, 1);
+        assert_eq!(insert(&oplog, "b", "k2").seq, 2);
+        assert_eq!(insert(&oplog, "a", "k3").seq, 3);
+
+        assert_eq!(seqs(oplog.entries_after(None, 0)), [1, 2, 3]);
+        assert_eq!(seqs(oplog.entries_after(Some("a"), 0)), [1, 3]);
+        assert_eq!(seqs(oplog.entries_after(Some("a"), 1)), [3]);
+        assert_eq!(seqs(oplog.entries_after(None, 3)), Vec::<i64>::new());
+        assert!(oplog.entries_after(Some("c"), 0).is_empty());
+
+        // the sequence goes on after a restart
+        drop(oplog);
+        let oplog = open("seq", false);
+
+        assert_eq!(oplog.seq(), 3);
+        assert_eq!(insert(&oplog, "b", "k4").seq, 4);
+    }
+
+    #[test]
+    fn replays_the_entries() {
+        let oplog = open("replay", true);
+
+        insert(&oplog, "a", "k1");
+        insert(&oplog, "a", "k2");
+        oplog
+            .append(
+                "a",
+                Operation::Update,
+                Some("k1".to_string()),
+                Some(Bson::Int32(2)),
+            )
+            .unwrap();
+        oplog
+            .append("a", Operation::Delete, Some("k2".to_string()), None)
+            .unwrap();
+
+        let mut config = config::default_configuration();
+        config.storage.path = oplog.path.join("replayed");
+        let mut dd = crate::server::route::create_dustdata(&config, Some("a"));
+
+        // replaying twice gives the same state
+        for _ in 0..2 {
+            for entry in oplog.entries_after(Some("a"), 0) {
+                apply_entry(&mut dd, &entry).unwrap();
+            }
+        }
+
+        assert_eq!(dd.get("k1").unwrap(), Some(Bson::Int32(2)));
+        assert_eq!(dd.get("k2").unwrap(), None);
+    }
+
+    #[test]
+    fn drops_a_partial_entry_left_by_a_crash() {
+        let oplog = open("crash", true);
+
+        insert(&oplog, "a", "k1");
+        insert(&oplog, "a", "k2");
+
+        let path = database_log_path(&oplog.path, "a");
+        drop(oplog);
+
+        // the first bytes of a third entry
+        let partial =
+            bson::to_vec(&bson::doc! { "seq": 3_i64, "padding": "x".repeat(64) }).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&partial[..partial.len() / 2]).unwrap();
+        drop(file);
+
+        let oplog = open("crash", false);
+
+        assert_eq!(oplog.seq(), 2);
+        assert_eq!(insert(&oplog, "a", "k3").seq, 3);
+        assert_eq!(seqs(oplog.entries_after(Some("a"), 0)), [1, 2, 3]);
+    }
+}
@@ -0,0 +1,788 @@
+use bson::Bson;
+use dustdata::DustData;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, Mutex, RwLock};
+
+use super::oplog::Operation;
+use super::route;
+use crate::config::schema;
+
+/// The quotas are stored in the system database next to the users, see `route::is_catalog_key`
+pub const CATALOG_PREFIX: &str = "$quota:";
+
+/// Who inserted the keys counted for a user, a store per database in this directory of the
+/// data path. Kept out of the catalog, which is sent to the replicas and read on every
+/// connection.
+pub const OWNERS_DIRECTORY: &str = "_owners";
+
+/// What a quota applies to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scope {
+    Database(String),
+    User(String),
+}
+
+impl Scope {
+    fn key(&self) -> String {
+        match self {
+            Scope::Database(database) => format!("{}database:{}", CATALOG_PREFIX, database),
+            Scope::User(username) => format!("{}user:{}", CATALOG_PREFIX, username),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scope::Database(database) => write!(f, "database {}", database),
+            Scope::User(username) => write!(f, "user {}", username),
+        }
+    }
+}
+
+/// The limits of a scope, unlimited when unset
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Limits {
+    pub max_keys: Option<i64>,
+    pub max_bytes: Option<i64>,
+    pub max_document_size: Option<i64>,
+}
+
+impl Limits {
+    pub const FIELDS: [&'static str; 3] = ["max_keys", "max_bytes", "max_document_size"];
+
+    /// Sets a limit by its name, `None` removing it
+    pub fn set(&mut self, field: &str, value: Option<i64>) -> Result<(), String> {
+        let limit = match field {
+            "max_keys" => &mut self.max_keys,
+            "max_bytes" => &mut self.max_bytes,
+            "max_document_size" => &mut self.max_document_size,
+            _ => {
+                return Err(format!(
+                    "unknown limit {}, expected one of {}",
+                    field,
+                    Self::FIELDS.join(", ")
+                ))
+            }
+        };
+
+        *limit = value;
+
+        Ok(())
+    }
+
+    /// Whether the usage must be counted to enforce the limits
+    pub fn counts_usage(&self) -> bool {
+        self.max_keys.is_some() || self.max_bytes.is_some()
+    }
+}
+
+/// The keys and bytes used in a database
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub keys: i64,
+    pub bytes: i64,
+}
+
+/// A catalog entry: the limits of a database or a user, and for a user what it uses in each
+/// database. The usage of a database is counted in memory, see `Counters`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Quota {
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub usage: HashMap<String, Usage>,
+}
+
+impl Quota {
+    pub fn total(&self) -> Usage {
+        self.usage
+            .values()
+            .fold(Usage::default(), |total, usage| Usage {
+                keys: total.keys + usage.keys,
+                bytes: total.bytes + usage.bytes,
+            })
+    }
+
+    /// The quota as returned by `show quota`, `total` being the usage of a database
+    pub fn to_bson(&self, scope: &Scope, total: Usage) -> Bson {
+        let (name, value) = match scope {
+            Scope::Database(database) => ("database", database),
+            Scope::User(username) => ("user", username),
+        };
+
+        let mut doc = bson::doc! {
+            name: value,
+            "max_keys": self.limits.max_keys,
+            "max_bytes": self.limits.max_bytes,
+            "max_document_size": self.limits.max_document_size,
+            "keys": total.keys,
+            "bytes": total.bytes,
+        };
+
+        if let Scope::User(_) = scope {
+            let databases: bson::Document = self
+                .usage
+                .iter()
+                .map(|(database, usage)| {
+                    (
+                        database.clone(),
+                        Bson::Document(bson::doc! { "keys": usage.keys, "bytes": usage.bytes }),
+                    )
+                })
+                .collect();
+
+            doc.insert("databases", databases);
+        }
+
+        Bson::Document(doc)
+    }
+}
+
+/// What a write changes in the usage of a database
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Change {
+    pub keys: i64,
+    pub bytes: i64,
+    /// The size of the written document, 0 for a delete
+    pub document_size: i64,
+}
+
+impl Change {
+    pub fn insert(key: &str, value: &Bson) -> Self {
+        let size = document_size(value);
+
+        Self {
+            keys: 1,
+            bytes: key.len() as i64 + size,
+            document_size: size,
+        }
+    }
+
+    pub fn update(previous: Option<&Bson>, value: &Bson) -> Self {
+        let size = document_size(value);
+
+        Self {
+            keys: 0,
+            bytes: size - previous.map(document_size).unwrap_or(size),
+            document_size: size,
+        }
+    }
+
+    pub fn delete(key: &str, previous: Option<&Bson>) -> Self {
+        match previous {
+            Some(previous) => Self {
+                keys: -1,
+                bytes: -(key.len() as i64 + document_size(previous)),
+                document_size: 0,
+            },
+            None => Self::default(),
+        }
+    }
+
+    /// The change of a key operation, given the value it replaces
+    pub fn of(
+        operation: &Operation,
+        key: &str,
+        previous: Option<&Bson>,
+        value: Option<&Bson>,
+    ) -> Self {
+        match (operation, value) {
+            (Operation::Insert, Some(value)) => Self::insert(key, value),
+            (Operation::Update, Some(value)) => Self::update(previous, value),
+            (Operation::Delete, _) => Self::delete(key, previous),
+            _ => Self::default(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.keys == 0 && self.bytes == 0
+    }
+}
+
+/// The encoded size of a value, as a document field when it is not a document
+pub fn document_size(value: &Bson) -> i64 {
+    let encoded = match value {
+        Bson::Document(doc) => bson::to_vec(doc),
+        value => bson::to_vec(&bson::doc! { "": value.clone() }),
+    };
+
+    encoded.map(|encoded| encoded.len() as i64).unwrap_or(0)
+}
+
+/// The usage of the databases, counted from their keys the first time it is needed and then
+/// kept up to date by the writes, so that counting costs nothing to the catalog. Also keeps
+/// the owners of the keys, see `OWNERS_DIRECTORY`.
+pub struct Counters {
+    config: Arc<schema::RustbaseConfig>,
+    databases: Mutex<HashMap<String, Usage>>,
+    owners: Mutex<HashMap<String, DustData>>,
+}
+
+impl Counters {
+    pub fn new(config: Arc<schema::RustbaseConfig>) -> Self {
+        Self {
+            config,
+            databases: Mutex::default(),
+            owners: Mutex::default(),
+        }
+    }
+
+    /// The usage of a database, counting its keys if it has not been yet. A database that
+    /// does not exist uses nothing.
+    pub fn usage(&self, database: &str, dd: Option<&DustData>) -> dustdata::Result<Usage> {
+        if let Some(usage) = self.databases.lock().unwrap().get(database) {
+            return Ok(*usage);
+        }
+
+        let mut usage = Usage::default();
+
+        if let Some(dd) = dd {
+            for key in dd.list_keys()? {
+                if let Some(value) = dd.get(&key)? {
+                    usage.keys += 1;
+                    usage.bytes += key.len() as i64 + document_size(&value);
+                }
+            }
+        }
+
+        self.databases
+            .lock()
+            .unwrap()
+            .insert(database.to_string(), usage);
+
+        Ok(usage)
+    }
+
+    /// Adds a write to a database, if it is counted. Called while the database is still locked
+    /// for the write, so that a count running meanwhile cannot see it twice.
+    pub fn record(&self, database: &str, change: &Change) {
+        if let Some(usage) = self.databases.lock().unwrap().get_mut(database) {
+            usage.keys += change.keys;
+            usage.bytes += change.bytes;
+        }
+    }
+
+    /// Forgets a database, counted again when needed, e.g. once dropped
+    pub fn forget(&self, database: &str) {
+        self.databases.lock().unwrap().remove(database);
+    }
+
+    pub fn clear(&self) {
+        self.databases.lock().unwrap().clear();
+    }
+
+    /// The user a key of `database` is counted for, if it has one
+    fn owner(&self, database: &str, key: &str) -> dustdata::Result<Option<String>> {
+        let mut owners = self.owners.lock().unwrap();
+
+        let store = match self.owner_store(&mut owners, database, false) {
+            Some(store) => store,
+            None => return Ok(None),
+        };
+
+        Ok(store
+            .get(key)?
+            .and_then(|owner| owner.as_str().map(|owner| owner.to_string())))
+    }
+
+    /// Records the user a key is counted for, or forgets it with `None`
+    fn set_owner(&self, database: &str, key: &str, owner: Option<&str>) -> dustdata::Result<()> {
+        let mut owners = self.owners.lock().unwrap();
+
+        match owner {
+            Some(owner) => {
+                let store = self.owner_store(&mut owners, database, true).unwrap();
+                let owner = Bson::String(owner.to_string());
+
+                match store.insert(key, owner.clone()) {
+                    Err(e) if matches!(e.code, dustdata::ErrorCode::KeyExists) => {
+                        store.update(key, owner)
+                    }
+                    result => result,
+                }
+            }
+            None => match self.owner_store(&mut owners, database, false) {
+                Some(store) if store.get(key)?.is_some() => store.delete(key),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    /// Forgets the keys counted for `username`, in every database
+    fn remove_owner(&self, username: &str) -> dustdata::Result<()> {
+        let mut owners = self.owners.lock().unwrap();
+
+        for database in self.owner_databases() {
+            let store = match self.owner_store(&mut owners, &database, false) {
+                Some(store) => store,
+                None => continue,
+            };
+
+            for key in store.list_keys()? {
+                if store.get(&key)?.as_ref().and_then(|owner| owner.as_str()) == Some(username) {
+                    store.delete(&key)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the owners of the keys of `database`, or of every database
+    fn drop_owners(&self, database: Option<&str>) {
+        let mut owners = self.owners.lock().unwrap();
+        let path = self.config.storage.path.join(OWNERS_DIRECTORY);
+
+        let path = match database {
+            Some(database) => {
+                owners.remove(database);
+                path.join(database)
+            }
+            None => {
+                owners.clear();
+                path
+            }
+        };
+
+        if let Err(e) = fs::remove_dir_all(&path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                println!("[Quota] cannot remove {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Flushes the owners of the keys, e.g. on shutdown
+    pub fn flush(&self) -> dustdata::Result<()> {
+        for store in self.owners.lock().unwrap().values_mut() {
+            store.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// The store of the owners of a database, opened on first use. One that does not exist
+    /// is only created with `create`.
+    fn owner_store<'a>(
+        &self,
+        owners: &'a mut HashMap<String, DustData>,
+        database: &str,
+        create: bool,
+    ) -> Option<&'a mut DustData> {
+        if !owners.contains_key(database) {
+            let path = self
+                .config
+                .storage
+                .path
+                .join(OWNERS_DIRECTORY)
+                .join(database);
+
+            if !create && !path.exists() {
+                return None;
+            }
+
+            // so that `owner_databases` finds it before the store writes its first file
+            if let Err(e) = fs::create_dir_all(&path) {
+                println!("[Quota] cannot create {}: {}", path.display(), e);
+            }
+
+            owners.insert(
+                database.to_string(),
+                route::create_dustdata_at(&self.config, &path),
+            );
+        }
+
+        owners.get_mut(database)
+    }
+
+    /// The databases with owners on disk
+    fn owner_databases(&self) -> Vec<String> {
+        fs::read_dir(self.config.storage.path.join(OWNERS_DIRECTORY))
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Returns the quota of `scope`, or an unlimited one without usage if none is stored
+pub fn get(catalog: &DustData, scope: &Scope) -> Quota {
+    catalog
+        .get(&scope.key())
+        .ok()
+        .flatten()
+        .and_then(|quota| bson::from_bson(quota).ok())
+        .unwrap_or_default()
+}
+
+fn save(catalog: &mut DustData, scope: &Scope, quota: &Quota) -> dustdata::Result<()> {
+    let key = scope.key();
+    let value = bson::to_bson(quota).unwrap();
+
+    match catalog.insert(&key, value.clone()) {
+        Err(e) if matches!(e.code, dustdata::ErrorCode::KeyExists) => catalog.update(&key, value),
+        result => result,
+    }
+}
+
+/// Changes the limits of `scope`. A user starts counting its usage when it gets a key or
+/// byte limit, and forgets it once it has none.
+pub fn set_limits(
+    catalog: &mut DustData,
+    counters: &Counters,
+    scope: &Scope,
+    limits: Limits,
+) -> dustdata::Result<Quota> {
+    let mut quota = get(catalog, scope);
+    quota.limits = limits;
+
+    if let Scope::User(username) = scope {
+        if !limits.counts_usage() {
+            quota.usage.clear();
+            counters.remove_owner(username)?;
+        }
+    }
+
+    save(catalog, scope, &quota)?;
+
+    Ok(quota)
+}
+
+/// Removes a quota, and for a user the keys counted for it
+pub fn remove(catalog: &mut DustData, counters: &Counters, scope: &Scope) -> dustdata::Result<()> {
+    catalog.delete(&scope.key()).ok();
+
+    match scope {
+        Scope::User(username) => counters.remove_owner(username),
+        Scope::Database(_) => Ok(()),
+    }
+}
+
+/// The user a write is charged to: the one inserting the key, or the one who inserted it
+/// for an update or a delete
+fn charged_user(
+    counters: &Counters,
+    database: &str,
+    key: &str,
+    writer: Option<&str>,
+    change: &Change,
+) -> Option<String> {
+    if change.keys > 0 {
+        return writer.map(|writer| writer.to_string());
+    }
+
+    counters.owner(database, key).ok().flatten()
+}
+
+/// Checks a write against the quotas of the database, of the user making it for the size of
+/// the document, and of the user it is charged to for the keys and bytes
+pub fn check(
+    catalog: &DustData,
+    counters: &Counters,
+    database: &str,
+    database_usage: Usage,
+    key: &str,
+    writer: Option<&str>,
+    change: &Change,
+) -> Result<(), String> {
+    let database_scope = Scope::Database(database.to_string());
+    let limits = get(catalog, &database_scope).limits;
+
+    check_document_size(&database_scope, &limits, change)?;
+    check_usage(&database_scope, &limits, database_usage, change)?;
+
+    if let Some(writer) = writer {
+        let scope = Scope::User(writer.to_string());
+
+        check_document_size(&scope, &get(catalog, &scope).limits, change)?;
+    }
+
+    if let Some(charged) = charged_user(counters, database, key, writer, change) {
+        let scope = Scope::User(charged);
+        let quota = get(catalog, &scope);
+
+        check_usage(&scope, &quota.limits, quota.total(), change)?;
+    }
+
+    Ok(())
+}
+
+fn check_document_size(scope: &Scope, limits: &Limits, change: &Change) -> Result<(), String> {
+    match limits.max_document_size {
+        Some(max) if change.document_size > max => Err(format!(
+            "the document is {} bytes, the limit of {} is {}",
+            change.document_size, scope, max
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_usage(
+    scope: &Scope,
+    limits: &Limits,
+    total: Usage,
+    change: &Change,
+) -> Result<(), String> {
+    if let Some(max) = limits.max_keys {
+        if change.keys > 0 && total.keys + change.keys > max {
+            return Err(format!("{} is at its limit of {} keys", scope, max));
+        }
+    }
+
+    if let Some(max) = limits.max_bytes {
+        if change.bytes > 0 && total.bytes + change.bytes > max {
+            return Err(format!("{} is at its limit of {} bytes", scope, max));
+        }
+    }
+
+    Ok(())
+}
+
+/// Counts a write once it is made in the usage of the user it is charged to, when that user
+/// has a key or byte limit. The catalog is only written then.
+pub fn charge(
+    catalog: &RwLock<DustData>,
+    counters: &Counters,
+    database: &str,
+    key: &str,
+    writer: Option<&str>,
+    change: &Change,
+) -> dustdata::Result<()> {
+    if change.is_empty() {
+        return Ok(());
+    }
+
+    let scope = match charged_user(counters, database, key, writer, change) {
+        Some(username) => Scope::User(username),
+        None => return Ok(()),
+    };
+
+    let mut catalog = catalog.write().unwrap();
+    let mut quota = get(&catalog, &scope);
+
+    if !quota.limits.counts_usage() {
+        return Ok(());
+    }
+
+    let usage = quota.usage.entry(database.to_string()).or_default();
+
+    usage.keys = (usage.keys + change.keys).max(0);
+    usage.bytes = (usage.bytes + change.bytes).max(0);
+
+    save(&mut catalog, &scope, &quota)?;
+
+    match (change.keys, &scope) {
+        (1, Scope::User(username)) => counters.set_owner(database, key, Some(username)),
+        (-1, _) => counters.set_owner(database, key, None),
+        _ => Ok(()),
+    }
+}
+
+/// Forgets the usage of a dropped database, for the database and every user
+pub fn drop_database(
+    catalog: &RwLock<DustData>,
+    counters: &Counters,
+    database: &str,
+) -> dustdata::Result<()> {
+    counters.forget(database);
+    counters.drop_owners(Some(database));
+
+    let mut catalog = catalog.write().unwrap();
+    let user_prefix = Scope::User(String::new()).key();
+
+    let scopes: Vec<Scope> = catalog
+        .list_keys()?
+        .into_iter()
+        .filter_map(|key| {
+            key.strip_prefix(&user_prefix)
+                .map(|username| Scope::User(username.to_string()))
+        })
+        .collect();
+
+    for scope in scopes {
+        let mut quota = get(&catalog, &scope);
+
+        if quota.usage.remove(database).is_some() {
+            save(&mut catalog, &scope, &quota)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Forgets the usage of every database and user, e.g. when the databases are replaced by a
+/// snapshot whose keys were counted elsewhere
+pub fn reset_usage(catalog: &RwLock<DustData>, counters: &Counters) -> dustdata::Result<()> {
+    counters.clear();
+    counters.drop_owners(None);
+
+    let mut catalog = catalog.write().unwrap();
+    let user_prefix = Scope::User(String::new()).key();
+
+    for key in catalog.list_keys()? {
+        if let Some(username) = key.strip_prefix(&user_prefix) {
+            let scope = Scope::User(username.to_string());
+            let mut quota = get(&catalog, &scope);
+
+            quota.usage.clear();
+            save(&mut catalog, &scope, &quota)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::server::route;
+
+    fn stores(name: &str) -> (RwLock<DustData>, Counters) {
+        let path =
+            std::env::temp_dir().join(format!("rustbase-quota-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&path).ok();
+
+        let mut config = config::default_configuration();
+        config.storage.path = path;
+
+        let catalog = RwLock::new(route::create_dustdata(&config, Some("_default")));
+
+        (catalog, Counters::new(Arc::new(config)))
+    }
+
+    fn limit_keys(
+        catalog: &RwLock<DustData>,
+        counters: &Counters,
+        username: &str,
+        max: Option<i64>,
+    ) {
+        let limits = Limits {
+            max_keys: max,
+            ..Limits::default()
+        };
+
+        set_limits(
+            &mut catalog.write().unwrap(),
+            counters,
+            &Scope::User(username.to_string()),
+            limits,
+        )
+        .unwrap();
+    }
+
+    fn keys_of(catalog: &RwLock<DustData>, username: &str) -> i64 {
+        get(&catalog.read().unwrap(), &Scope::User(username.to_string()))
+            .total()
+            .keys
+    }
+
+    #[test]
+    fn a_key_is_uncounted_from_the_user_who_inserted_it() {
+        let (catalog, counters) = stores("owner");
+        let value = Bson::Int32(1);
+
+        limit_keys(&catalog, &counters, "alice", Some(1));
+        limit_keys(&catalog, &counters, "bob", Some(1));
+
+        let insert = Change::insert("k", &value);
+        charge(&catalog, &counters, "shop", "k", Some("alice"), &insert).unwrap();
+
+        assert_eq!(keys_of(&catalog, "alice"), 1);
+        assert!(check(
+            &catalog.read().unwrap(),
+            &counters,
+            "shop",
+            Usage::default(),
+            "l",
+            Some("alice"),
+            &Change::insert("l", &value)
+        )
+        .is_err());
+
+        let delete = Change::delete("k", Some(&value));
+        charge(&catalog, &counters, "shop", "k", Some("bob"), &delete).unwrap();
+
+        assert_eq!(keys_of(&catalog, "alice"), 0);
+        assert_eq!(keys_of(&catalog, "bob"), 0);
+    }
+
+    #[test]
+    fn the_owners_are_kept_out_of_the_catalog() {
+        let (catalog, counters) = stores("side");
+        let value = Bson::Int32(1);
+
+        limit_keys(&catalog, &counters, "alice", Some(10));
+
+        for key in ["a", "b"] {
+            let insert = Change::insert(key, &value);
+            charge(&catalog, &counters, "shop", key, Some("alice"), &insert).unwrap();
+        }
+
+        assert_eq!(
+            catalog.read().unwrap().list_keys().unwrap(),
+            vec!["$quota:user:alice".to_string()]
+        );
+        assert_eq!(
+            counters.owner("shop", "a").unwrap().as_deref(),
+            Some("alice")
+        );
+
+        drop_database(&catalog, &counters, "shop").unwrap();
+
+        assert_eq!(counters.owner("shop", "a").unwrap(), None);
+        assert_eq!(keys_of(&catalog, "alice"), 0);
+
+        let insert = Change::insert("c", &value);
+        charge(&catalog, &counters, "shop", "c", Some("alice"), &insert).unwrap();
+        limit_keys(&catalog, &counters, "alice", None);
+
+        assert_eq!(counters.owner("shop", "c").unwrap(), None);
+    }
+
+    #[test]
+    fn a_user_without_limits_writes_nothing_to_the_catalog() {
+        let (catalog, counters) = stores("unlimited");
+        let value = Bson::Int32(1);
+
+        charge(
+            &catalog,
+            &counters,
+            "shop",
+            "k",
+            Some("alice"),
+            &Change::insert("k", &value),
+        )
+        .unwrap();
+
+        assert!(catalog.read().unwrap().list_keys().unwrap().is_empty());
+        assert_eq!(counters.owner("shop", "k").unwrap(), None);
+    }
+
+    #[test]
+    fn the_databases_are_counted_once_then_kept_by_the_writes() {
+        let (catalog, counters) = stores("counters");
+        let value = Bson::Int32(1);
+
+        {
+            let mut dd = catalog.write().unwrap();
+            dd.insert("a", value.clone()).unwrap();
+        }
+
+        let usage = counters
+            .usage("shop", Some(&catalog.read().unwrap()))
+            .unwrap();
+        assert_eq!(usage.keys, 1);
+
+        counters.record("shop", &Change::insert("b", &value));
+        assert_eq!(counters.usage("shop", None).unwrap().keys, 2);
+
+        counters.forget("shop");
+        assert_eq!(counters.usage("shop", None).unwrap().keys, 0);
+    }
+}
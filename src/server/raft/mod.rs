@@ -0,0 +1,1979 @@
+pub mod transport;
+
+use async_trait::async_trait;
+use bson::{Bson, Document};
+use colored::Colorize;
+use dustdata::DustData;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinSet;
+
+use super::cache::Cache;
+use super::cluster;
+use super::oplog::{OpEntry, OpLog, Operation};
+use super::quota::{self, Counters};
+use crate::config::schema;
+
+pub const DEFAULT_ELECTION_TIMEOUT: u64 = 1000;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(150);
+const TICK: Duration = Duration::from_millis(50);
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_ENTRIES_PER_APPEND: usize = 256;
+const MAX_ENTRIES_PER_APPLY: usize = 1024;
+/// The applied entries are flushed every `FLUSH_EVERY` entries, or after `FLUSH_INTERVAL`
+const FLUSH_EVERY: u64 = 1000;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_LOG_ENTRIES: u64 = 10_000;
+const SNAPSHOT_CHUNK: usize = 1024;
+pub const RAFT_DIRECTORY: &str = "_raft";
+const STATE_NAME: &str = "state.json";
+const LOG_NAME: &str = "log";
+
+/// A write, replicated to the majority of the nodes before being applied
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Command {
+    pub database: String,
+    pub operation: Operation,
+    pub key: Option<String>,
+    pub value: Option<Bson>,
+    /// The user who made the write, charged for it by the quotas of every node
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogEntry {
+    pub term: u64,
+    pub index: u64,
+    /// `None` for the entry a new leader appends to commit the entries of previous terms
+    pub command: Option<Command>,
+}
+
+/// A key of a snapshot
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Record {
+    pub database: String,
+    pub key: String,
+    pub value: Bson,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RaftMessage {
+    RequestVote {
+        term: u64,
+        candidate: String,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        leader: String,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    },
+    AppendResult {
+        term: u64,
+        success: bool,
+        last_log_index: u64,
+    },
+    /// A chunk of the state of the leader, sent to a follower missing compacted entries.
+    /// Answered with an `AppendResult`.
+    InstallSnapshot {
+        term: u64,
+        leader: String,
+        last_included_index: u64,
+        last_included_term: u64,
+        records: Vec<Record>,
+        first: bool,
+        done: bool,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug)]
+pub enum ProposeError {
+    /// Only the leader accepts writes, with the address of the leader if one is known
+    NotLeader(Option<String>),
+    Timeout,
+    /// The leader changed before the write was committed
+    Lost,
+    /// The entry could not be saved to the log
+    Storage(String),
+}
+
+/// How the nodes reach each other. Implemented over Wirewave by the server, and in memory
+/// by the tests to run several nodes in one process and simulate partitions.
+#[async_trait]
+pub trait Transport: Send + Sync + 'static {
+    /// Sends a message to a peer and waits for its reply, `None` if the peer is unreachable
+    async fn call(&self, peer: &str, message: RaftMessage) -> Option<RaftMessage>;
+}
+
+/// Where the committed commands are applied, in the order of the log
+pub trait StateMachine: Send + Sync + 'static {
+    fn apply(&self, index: u64, command: &Command);
+
+    /// Makes the applied commands durable
+    fn flush(&self) -> Result<(), String>;
+
+    /// Every record of the state
+    fn snapshot(&self) -> Vec<Record>;
+
+    /// Replaces the state with the records of a snapshot
+    fn restore(&self, records: Vec<Record>) -> Result<(), String>;
+}
+
+/// The chunks of a snapshot received so far
+struct PendingSnapshot {
+    term: u64,
+    index: u64,
+    records: Vec<Record>,
+}
+
+struct RaftState {
+    role: Role,
+    term: u64,
+    voted_for: Option<String>,
+    /// The entries after `log_start`, the ones before are compacted
+    log: Vec<LogEntry>,
+    log_start: u64,
+    start_term: u64,
+    commit_index: u64,
+    last_applied: u64,
+    /// The last applied entry flushed by the machine, the one saved in the hard state
+    flushed: u64,
+    leader: Option<String>,
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    election_deadline: Instant,
+    snapshot: Option<PendingSnapshot>,
+}
+
+impl RaftState {
+    fn last_index(&self) -> u64 {
+        self.log_start + self.log.len() as u64
+    }
+
+    /// `None` for a compacted or missing entry
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.log_start {
+            return Some(self.start_term);
+        }
+
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&LogEntry> {
+        if index <= self.log_start {
+            return None;
+        }
+
+        self.log.get((index - self.log_start - 1) as usize)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct HardState {
+    term: u64,
+    voted_for: Option<String>,
+    /// The last entry applied and flushed
+    last_applied: u64,
+    #[serde(default)]
+    log_start: u64,
+    #[serde(default)]
+    start_term: u64,
+}
+
+pub struct Raft {
+    id: String,
+    peers: Vec<String>,
+    /// `None` keeps everything in memory
+    path: Option<PathBuf>,
+    election_timeout: Duration,
+    /// Flushed entries kept in the log before it is compacted
+    max_log_entries: u64,
+    state: Mutex<RaftState>,
+    transport: Arc<dyn Transport>,
+    machine: Arc<dyn StateMachine>,
+    applied: watch::Sender<u64>,
+    wake: Notify,
+    /// Wakes the apply loop when the commit index advances
+    committed: Notify,
+    /// Held while entries or a snapshot are applied to the machine
+    applying: tokio::sync::Mutex<()>,
+    /// The peers a snapshot is being sent to
+    installing: Mutex<HashSet<String>>,
+    runtime: Handle,
+}
+
+impl Raft {
+    pub fn new(
+        id: String,
+        peers: Vec<String>,
+        path: Option<PathBuf>,
+        election_timeout: Duration,
+        transport: Arc<dyn Transport>,
+        machine: Arc<dyn StateMachine>,
+        runtime: Handle,
+    ) -> io::Result<Self> {
+        let (hard_state, log) = match &path {
+            Some(path) => {
+                let (hard_state, log, torn) = load(path)?;
+
+                // the next entries would be appended after the unreadable ones, and lost
+                // on the following restart
+                if torn {
+                    println!("[Raft] the log ends with a partial entry, it is rewritten");
+                    write_entries(&path.join(LOG_NAME), &log, false)?;
+                }
+
+                (hard_state, log)
+            }
+            None => (HardState::default(), Vec::new()),
+        };
+
+        let (applied, _) = watch::channel(hard_state.last_applied);
+
+        let raft = Self {
+            id,
+            peers,
+            path,
+            election_timeout,
+            max_log_entries: DEFAULT_MAX_LOG_ENTRIES,
+            state: Mutex::new(RaftState {
+                role: Role::Follower,
+                term: hard_state.term,
+                voted_for: hard_state.voted_for,
+                log,
+                log_start: hard_state.log_start,
+                start_term: hard_state.start_term,
+                // everything applied was committed, the entries applied but not flushed
+                // are applied again once committed
+                commit_index: hard_state.last_applied,
+                last_applied: hard_state.last_applied,
+                flushed: hard_state.last_applied,
+                leader: None,
+                next_index: HashMap::new(),
+                match_index: HashMap::new(),
+                election_deadline: Instant::now(),
+                snapshot: None,
+            }),
+            transport,
+            machine,
+            applied,
+            wake: Notify::new(),
+            committed: Notify::new(),
+            applying: tokio::sync::Mutex::new(()),
+            installing: Mutex::new(HashSet::new()),
+            runtime,
+        };
+
+        raft.reset_election_deadline(&mut raft.state.lock().unwrap());
+
+        Ok(raft)
+    }
+
+    /// The node of the configuration
+    pub fn from_config(
+        config: &schema::RustbaseConfig,
+        transport: Arc<dyn Transport>,
+        machine: Arc<dyn StateMachine>,
+    ) -> Result<Self, String> {
+        let cluster = config
+            .cluster
+            .as_ref()
+            .ok_or_else(|| "the cluster is not configured".to_string())?;
+
+        let address = cluster
+            .address
+            .clone()
+            .ok_or_else(|| "the address of this node (cluster.address) is missing".to_string())?;
+
+        let peers = cluster
+            .nodes
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|node| *node != address)
+            .collect();
+
+        let election_timeout = cluster.election_timeout.unwrap_or(DEFAULT_ELECTION_TIMEOUT);
+        let path = config.storage.path.join(RAFT_DIRECTORY);
+
+        Self::new(
+            address,
+            peers,
+            Some(path.clone()),
+            Duration::from_millis(election_timeout),
+            transport,
+            machine,
+            Handle::current(),
+        )
+        .map_err(|e| format!("cannot load {}: {}", path.display(), e))
+    }
+
+    pub fn status(&self) -> Document {
+        let state = self.state.lock().unwrap();
+
+        let role = match state.role {
+            Role::Follower => "follower",
+            Role::Candidate => "candidate",
+            Role::Leader => "leader",
+        };
+
+        bson::doc! {
+            "role": role,
+            "term": state.term as i64,
+            "leader": state.leader.clone(),
+            "commit": state.commit_index as i64,
+            "applied": state.last_applied as i64,
+        }
+    }
+
+    /// Runs until `stop` changes: sends the heartbeats while leader, starts an election when
+    /// the leader is silent for too long, and applies the committed entries. The entries
+    /// applied are flushed before returning.
+    pub async fn run(self: Arc<Self>, stop: watch::Receiver<bool>) {
+        let mut tick_stop = stop.clone();
+
+        let tick = async {
+            tokio::select! {
+                _ = self.clone().tick() => {}
+                _ = tick_stop.changed() => {}
+            }
+        };
+
+        tokio::join!(tick, self.clone().apply_loop(stop));
+    }
+
+    async fn tick(self: Arc<Self>) {
+        let mut last_heartbeat = Instant::now();
+
+        loop {
+            let woken = tokio::select! {
+                _ = tokio::time::sleep(TICK) => false,
+                _ = self.wake.notified() => true,
+            };
+
+            let (role, deadline) = {
+                let state = self.state.lock().unwrap();
+                (state.role, state.election_deadline)
+            };
+
+            match role {
+                Role::Leader => {
+                    if woken || last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+                        last_heartbeat = Instant::now();
+                        self.clone().replicate_to_peers().await;
+                    }
+                }
+
+                _ => {
+                    if Instant::now() >= deadline {
+                        self.clone().start_election().await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Applies the committed entries outside of the state lock, and flushes them from time to time
+    async fn apply_loop(self: Arc<Self>, mut stop: watch::Receiver<bool>) {
+        let mut last_flush = Instant::now();
+
+        loop {
+            // a batch being applied is finished before stopping
+            tokio::select! {
+                _ = self.committed.notified() => {}
+                _ = tokio::time::sleep(FLUSH_INTERVAL) => {}
+                _ = stop.changed() => break,
+            }
+
+            while self.apply_batch().await {}
+
+            let unflushed = {
+                let state = self.state.lock().unwrap();
+                state.last_applied - state.flushed
+            };
+
+            if unflushed >= FLUSH_EVERY || (unflushed > 0 && last_flush.elapsed() >= FLUSH_INTERVAL)
+            {
+                self.flush().await;
+                last_flush = Instant::now();
+            }
+        }
+
+        self.flush().await;
+    }
+
+    /// Applies the next committed entries, `false` once none are left
+    async fn apply_batch(&self) -> bool {
+        let _applying = self.applying.lock().await;
+
+        let entries: Vec<LogEntry> = {
+            let state = self.state.lock().unwrap();
+
+            (state.last_applied + 1..=state.commit_index)
+                .take(MAX_ENTRIES_PER_APPLY)
+                .map_while(|index| state.entry(index).cloned())
+                .collect()
+        };
+
+        let last = match entries.last() {
+            Some(entry) => entry.index,
+            None => return false,
+        };
+
+        let machine = self.machine.clone();
+
+        let applied = tokio::task::spawn_blocking(move || {
+            for entry in &entries {
+                if let Some(command) = &entry.command {
+                    machine.apply(entry.index, command);
+                }
+            }
+        })
+        .await;
+
+        if applied.is_err() {
+            println!("[Raft] failed to apply the entries up to {}", last);
+            return false;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        state.last_applied = state.last_applied.max(last);
+        self.applied.send_replace(state.last_applied);
+
+        true
+    }
+
+    /// Flushes the machine before saving the applied index, so a restart never skips an
+    /// entry, then compacts the log
+    pub async fn flush(&self) {
+        let _applying = self.applying.lock().await;
+
+        let applied = self.state.lock().unwrap().last_applied;
+        let machine = self.machine.clone();
+
+        match tokio::task::spawn_blocking(move || machine.flush()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                println!("[Raft] cannot flush the applied entries: {}", e);
+                return;
+            }
+            Err(_) => {
+                println!("[Raft] cannot flush the applied entries");
+                return;
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let flushed = state.flushed;
+
+        state.flushed = applied;
+
+        if let Err(e) = self.save_hard_state(&state) {
+            println!("[Raft] cannot save the state: {}", e);
+            state.flushed = flushed;
+            return;
+        }
+
+        self.compact(&mut state);
+    }
+
+    /// Appends a command to the log and waits until it is committed and applied
+    pub async fn propose(&self, command: Command) -> Result<u64, ProposeError> {
+        let (index, term) = {
+            let mut state = self.state.lock().unwrap();
+
+            if state.role != Role::Leader {
+                return Err(ProposeError::NotLeader(state.leader.clone()));
+            }
+
+            let entry = LogEntry {
+                term: state.term,
+                index: state.last_index() + 1,
+                command: Some(command),
+            };
+
+            self.persist_entries(&state, std::slice::from_ref(&entry))
+                .map_err(|e| ProposeError::Storage(e.to_string()))?;
+
+            state.log.push(entry);
+
+            // a single node is its own majority
+            self.advance_commit(&mut state);
+
+            (state.last_index(), state.term)
+        };
+
+        self.wake.notify_one();
+
+        let mut applied = self.applied.subscribe();
+
+        let wait = async {
+            while *applied.borrow_and_update() < index {
+                if applied.changed().await.is_err() {
+                    break;
+                }
+            }
+        };
+
+        tokio::time::timeout(PROPOSE_TIMEOUT, wait)
+            .await
+            .map_err(|_| ProposeError::Timeout)?;
+
+        // a compacted entry was applied long ago, by this leader
+        match self.state.lock().unwrap().term_at(index) {
+            Some(entry_term) if entry_term != term => Err(ProposeError::Lost),
+            _ => Ok(index),
+        }
+    }
+
+    /// Same as `propose`, blocking the current thread, which must not be a thread of the runtime
+    pub fn propose_blocking(&self, command: Command) -> Result<u64, ProposeError> {
+        self.runtime.block_on(self.propose(command))
+    }
+
+    /// Answers a message of another node
+    pub async fn handle(self: &Arc<Self>, message: RaftMessage) -> RaftMessage {
+        match message {
+            RaftMessage::InstallSnapshot {
+                term,
+                leader,
+                last_included_index,
+                last_included_term,
+                records,
+                first,
+                done,
+            } => {
+                let records = {
+                    let mut state = self.state.lock().unwrap();
+
+                    match self.receive_snapshot(
+                        &mut state,
+                        term,
+                        leader,
+                        last_included_index,
+                        records,
+                        first,
+                        done,
+                    ) {
+                        Ok(records) => records,
+                        Err(success) => return append_result(&state, success),
+                    }
+                };
+
+                // restored by its own task, so a caller giving up does not leave it halfway
+                let raft = self.clone();
+
+                tokio::spawn(async move {
+                    raft.install_snapshot(last_included_index, last_included_term, records)
+                        .await
+                })
+                .await
+                .unwrap_or_else(|_| append_result(&self.state.lock().unwrap(), false))
+            }
+
+            message => self.handle_message(message),
+        }
+    }
+
+    fn handle_message(&self, message: RaftMessage) -> RaftMessage {
+        let mut state = self.state.lock().unwrap();
+
+        match message {
+            RaftMessage::RequestVote {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => {
+                if term > state.term {
+                    self.step_down(&mut state, term, None);
+                }
+
+                let my_last_term = state.term_at(state.last_index()).unwrap_or(0);
+                let up_to_date = last_log_term > my_last_term
+                    || (last_log_term == my_last_term && last_log_index >= state.last_index());
+
+                let mut granted = term == state.term
+                    && state
+                        .voted_for
+                        .as_ref()
+                        .is_none_or(|voted_for| *voted_for == candidate)
+                    && up_to_date;
+
+                if granted {
+                    let previous = state.voted_for.replace(candidate);
+
+                    // a vote is only granted once saved
+                    if let Err(e) = self.save_hard_state(&state) {
+                        println!("[Raft] cannot save the vote: {}", e);
+                        state.voted_for = previous;
+                        granted = false;
+                    } else {
+                        self.reset_election_deadline(&mut state);
+                    }
+                }
+
+                RaftMessage::Vote {
+                    term: state.term,
+                    granted,
+                }
+            }
+
+            RaftMessage::AppendEntries {
+                term,
+                leader,
+                mut prev_log_index,
+                mut prev_log_term,
+                mut entries,
+                leader_commit,
+            } => {
+                if term < state.term {
+                    return append_result(&state, false);
+                }
+
+                self.step_down(&mut state, term, Some(leader));
+
+                let last_new_index = prev_log_index + entries.len() as u64;
+
+                // the compacted entries were committed, so they match
+                if prev_log_index < state.log_start {
+                    let log_start = state.log_start;
+
+                    entries.retain(|entry| entry.index > log_start);
+                    prev_log_index = log_start;
+                    prev_log_term = state.start_term;
+                }
+
+                if prev_log_index > state.last_index()
+                    || state.term_at(prev_log_index) != Some(prev_log_term)
+                {
+                    return RaftMessage::AppendResult {
+                        term: state.term,
+                        success: false,
+                        last_log_index: state.last_index().min(prev_log_index.saturating_sub(1)),
+                    };
+                }
+
+                let mut conflict = None;
+                let mut appended = Vec::new();
+
+                for entry in entries {
+                    if appended.is_empty() && entry.index <= state.last_index() {
+                        if state.term_at(entry.index) == Some(entry.term) {
+                            continue;
+                        }
+
+                        conflict = Some(entry.index);
+                    }
+
+                    appended.push(entry);
+                }
+
+                // the entries are saved before being acknowledged
+                if let Some(index) = conflict {
+                    // a conflicting entry and everything after it were never committed
+                    let mut log = state.log[..(index - state.log_start - 1) as usize].to_vec();
+                    log.extend(appended);
+
+                    if let Err(e) = self.rewrite_log(&log) {
+                        println!("[Raft] cannot save the log: {}", e);
+                        return append_result(&state, false);
+                    }
+
+                    state.log = log;
+                } else if !appended.is_empty() {
+                    if let Err(e) = self.persist_entries(&state, &appended) {
+                        println!("[Raft] cannot save the log: {}", e);
+                        return append_result(&state, false);
+                    }
+
+                    state.log.extend(appended);
+                }
+
+                let commit_index = leader_commit.min(last_new_index);
+
+                if commit_index > state.commit_index {
+                    state.commit_index = commit_index;
+                    self.committed.notify_one();
+                }
+
+                RaftMessage::AppendResult {
+                    term: state.term,
+                    success: true,
+                    last_log_index: last_new_index,
+                }
+            }
+
+            // handled by `handle`
+            RaftMessage::InstallSnapshot { .. } => append_result(&state, false),
+
+            // replies are only expected as the result of a call
+            RaftMessage::Vote { .. } | RaftMessage::AppendResult { .. } => RaftMessage::Vote {
+                term: state.term,
+                granted: false,
+            },
+        }
+    }
+
+    /// Buffers a chunk of a snapshot and returns every record once the last one is received,
+    /// otherwise the success of the chunk
+    #[allow(clippy::too_many_arguments)]
+    fn receive_snapshot(
+        &self,
+        state: &mut RaftState,
+        term: u64,
+        leader: String,
+        index: u64,
+        records: Vec<Record>,
+        first: bool,
+        done: bool,
+    ) -> Result<Vec<Record>, bool> {
+        if term < state.term {
+            return Err(false);
+        }
+
+        self.step_down(state, term, Some(leader));
+
+        if first {
+            state.snapshot = Some(PendingSnapshot {
+                term,
+                index,
+                records: Vec::new(),
+            });
+        }
+
+        match &mut state.snapshot {
+            Some(pending) if pending.term == term && pending.index == index => {
+                pending.records.extend(records)
+            }
+            // a chunk of another snapshot, the leader starts again
+            _ => {
+                state.snapshot = None;
+                return Err(false);
+            }
+        }
+
+        if !done {
+            return Err(true);
+        }
+
+        let pending = state.snapshot.take().ok_or(false)?;
+
+        // already applied, e.g. the leader retrying a snapshot that timed out
+        if index <= state.last_applied {
+            return Err(true);
+        }
+
+        Ok(pending.records)
+    }
+
+    async fn install_snapshot(
+        &self,
+        index: u64,
+        last_term: u64,
+        records: Vec<Record>,
+    ) -> RaftMessage {
+        let _applying = self.applying.lock().await;
+        let machine = self.machine.clone();
+
+        let restored = tokio::task::spawn_blocking(move || {
+            machine.restore(records)?;
+            machine.flush()
+        })
+        .await
+        .unwrap_or_else(|_| Err("the restore panicked".to_string()));
+
+        let mut state = self.state.lock().unwrap();
+
+        if let Err(e) = restored {
+            println!("[Raft] cannot install the snapshot: {}", e);
+            return append_result(&state, false);
+        }
+
+        // the entries following the snapshot are kept if the log has it
+        let log = match state.term_at(index) {
+            Some(term) if term == last_term => {
+                let log_start = state.log_start;
+                state.log.split_off((index - log_start) as usize)
+            }
+            _ => Vec::new(),
+        };
+
+        state.log = log;
+        state.log_start = index;
+        state.start_term = last_term;
+        state.commit_index = state.commit_index.max(index);
+        state.last_applied = index;
+        state.flushed = index;
+
+        // the new start is saved first, the entries before it are skipped when loading
+        if let Err(e) = self.save_hard_state(&state) {
+            println!("[Raft] cannot save the state: {}", e);
+        } else if let Err(e) = self.rewrite_log(&state.log) {
+            println!("[Raft] cannot save the log: {}", e);
+        }
+
+        self.applied.send_replace(index);
+        self.committed.notify_one();
+
+        println!(
+            "[Raft] installed a snapshot up to entry {}",
+            index.to_string().yellow()
+        );
+
+        append_result(&state, true)
+    }
+
+    async fn start_election(self: Arc<Self>) {
+        let (message, term) = {
+            let mut state = self.state.lock().unwrap();
+
+            let previous = (state.term, state.voted_for.clone(), state.role);
+
+            state.term += 1;
+            state.role = Role::Candidate;
+            state.voted_for = Some(self.id.clone());
+            state.leader = None;
+
+            self.reset_election_deadline(&mut state);
+
+            // a term is only started once saved, so a restart never votes twice in it
+            if let Err(e) = self.save_hard_state(&state) {
+                println!("[Raft] cannot save the state: {}", e);
+                (state.term, state.voted_for, state.role) = previous;
+                return;
+            }
+
+            if self.peers.is_empty() {
+                self.become_leader(&mut state);
+                return;
+            }
+
+            let message = RaftMessage::RequestVote {
+                term: state.term,
+                candidate: self.id.clone(),
+                last_log_index: state.last_index(),
+                last_log_term: state.term_at(state.last_index()).unwrap_or(0),
+            };
+
+            (message, state.term)
+        };
+
+        let mut calls = JoinSet::new();
+
+        for peer in &self.peers {
+            let transport = self.transport.clone();
+            let peer = peer.clone();
+            let message = message.clone();
+
+            calls.spawn(async move { transport.call(&peer, message).await });
+        }
+
+        let mut votes = 1;
+
+        while let Some(reply) = calls.join_next().await {
+            let (reply_term, granted) = match reply {
+                Ok(Some(RaftMessage::Vote { term, granted })) => (term, granted),
+                _ => continue,
+            };
+
+            let mut state = self.state.lock().unwrap();
+
+            if reply_term > state.term {
+                self.step_down(&mut state, reply_term, None);
+                return;
+            }
+
+            if state.role != Role::Candidate || state.term != term {
+                return;
+            }
+
+            if granted {
+                votes += 1;
+
+                if votes > self.peers.len().div_ceil(2) {
+                    self.become_leader(&mut state);
+                    drop(state);
+
+                    self.wake.notify_one();
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn replicate_to_peers(self: Arc<Self>) {
+        let mut calls = JoinSet::new();
+
+        for peer in self.peers.clone() {
+            let raft = self.clone();
+
+            calls.spawn(async move { raft.replicate_to(peer).await });
+        }
+
+        while calls.join_next().await.is_some() {}
+    }
+
+    async fn replicate_to(&self, peer: String) {
+        let append = {
+            let state = self.state.lock().unwrap();
+
+            if state.role != Role::Leader {
+                return;
+            }
+
+            let next_index = state.next_index.get(&peer).copied().unwrap_or(1);
+
+            // the entries the follower needs are compacted
+            if next_index <= state.log_start {
+                None
+            } else {
+                let prev_log_index = next_index - 1;
+
+                let entries: Vec<LogEntry> = state
+                    .log
+                    .iter()
+                    .skip((prev_log_index - state.log_start) as usize)
+                    .take(MAX_ENTRIES_PER_APPEND)
+                    .cloned()
+                    .collect();
+
+                let sent = entries.len() as u64;
+
+                let message = RaftMessage::AppendEntries {
+                    term: state.term,
+                    leader: self.id.clone(),
+                    prev_log_index,
+                    prev_log_term: state.term_at(prev_log_index).unwrap_or(0),
+                    entries,
+                    leader_commit: state.commit_index,
+                };
+
+                Some((message, state.term, prev_log_index, sent))
+            }
+        };
+
+        let (message, term, prev_log_index, sent) = match append {
+            Some(append) => append,
+            None => return self.send_snapshot(peer).await,
+        };
+
+        let (reply_term, success, last_log_index) = match self.transport.call(&peer, message).await
+        {
+            Some(RaftMessage::AppendResult {
+                term,
+                success,
+                last_log_index,
+            }) => (term, success, last_log_index),
+            _ => return,
+        };
+
+        let mut state = self.state.lock().unwrap();
+
+        if reply_term > state.term {
+            self.step_down(&mut state, reply_term, None);
+            return;
+        }
+
+        // the reply of a previous term
+        if state.role != Role::Leader || state.term != term {
+            return;
+        }
+
+        if success {
+            self.matched(&mut state, peer, prev_log_index + sent);
+        } else {
+            // go back to the last entry the follower may have
+            let next_index = (prev_log_index).min(last_log_index + 1).max(1);
+            state.next_index.insert(peer, next_index);
+        }
+    }
+
+    /// Sends the whole state to a peer missing compacted entries, one at a time per peer
+    async fn send_snapshot(&self, peer: String) {
+        if !self.installing.lock().unwrap().insert(peer.clone()) {
+            return;
+        }
+
+        self.send_snapshot_chunks(&peer).await;
+        self.installing.lock().unwrap().remove(&peer);
+    }
+
+    async fn send_snapshot_chunks(&self, peer: &str) {
+        let (term, index, last_term, records) = {
+            // nothing is applied while the records are copied
+            let _applying = self.applying.lock().await;
+
+            let (term, index, last_term) = {
+                let state = self.state.lock().unwrap();
+
+                if state.role != Role::Leader {
+                    return;
+                }
+
+                match state.term_at(state.last_applied) {
+                    Some(last_term) => (state.term, state.last_applied, last_term),
+                    None => return,
+                }
+            };
+
+            let machine = self.machine.clone();
+
+            match tokio::task::spawn_blocking(move || machine.snapshot()).await {
+                Ok(records) => (term, index, last_term, records),
+                Err(_) => return,
+            }
+        };
+
+        println!(
+            "[Raft] sending a snapshot up to entry {} to {}",
+            index,
+            peer.yellow()
+        );
+
+        let chunks: Vec<&[Record]> = match records.is_empty() {
+            true => vec![&[]],
+            false => records.chunks(SNAPSHOT_CHUNK).collect(),
+        };
+
+        for (i, chunk) in chunks.iter().enumerate() {
+            let message = RaftMessage::InstallSnapshot {
+                term,
+                leader: self.id.clone(),
+                last_included_index: index,
+                last_included_term: last_term,
+                records: chunk.to_vec(),
+                first: i == 0,
+                done: i == chunks.len() - 1,
+            };
+
+            match self.transport.call(peer, message).await {
+                Some(RaftMessage::AppendResult {
+                    term: reply_term,
+                    success,
+                    ..
+                }) => {
+                    if reply_term > term {
+                        let mut state = self.state.lock().unwrap();
+
+                        if reply_term > state.term {
+                            self.step_down(&mut state, reply_term, None);
+                        }
+
+                        return;
+                    }
+
+                    if !success {
+                        return;
+                    }
+                }
+                _ => return,
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        if state.role == Role::Leader && state.term == term {
+            self.matched(&mut state, peer.to_string(), index);
+        }
+    }
+
+    /// Records the entries a follower has
+    fn matched(&self, state: &mut RaftState, peer: String, matched: u64) {
+        let match_index = state.match_index.entry(peer.clone()).or_insert(0);
+        *match_index = (*match_index).max(matched);
+
+        state.next_index.insert(peer, matched + 1);
+        self.advance_commit(state);
+    }
+
+    fn become_leader(&self, state: &mut RaftState) {
+        // entries of previous terms are only committed with an entry of the current term
+        let entry = LogEntry {
+            term: state.term,
+            index: state.last_index() + 1,
+            command: None,
+        };
+
+        if let Err(e) = self.persist_entries(state, std::slice::from_ref(&entry)) {
+            println!("[Raft] cannot save the log: {}", e);
+            state.role = Role::Follower;
+            return;
+        }
+
+        state.role = Role::Leader;
+        state.leader = Some(self.id.clone());
+
+        for peer in &self.peers {
+            state
+                .next_index
+                .insert(peer.clone(), state.last_index() + 1);
+            state.match_index.insert(peer.clone(), 0);
+        }
+
+        println!(
+            "[Raft] elected leader for term {}",
+            state.term.to_string().green()
+        );
+
+        state.log.push(entry);
+
+        self.advance_commit(state);
+    }
+
+    fn step_down(&self, state: &mut RaftState, term: u64, leader: Option<String>) {
+        if term > state.term {
+            state.term = term;
+            state.voted_for = None;
+
+            if let Err(e) = self.save_hard_state(state) {
+                println!("[Raft] cannot save the state: {}", e);
+            }
+        }
+
+        if state.role == Role::Leader && leader.as_ref() != Some(&self.id) {
+            println!("[Raft] stepping down in term {}", state.term);
+        }
+
+        if leader.is_some() && state.leader != leader {
+            println!(
+                "[Raft] following {} in term {}",
+                leader.clone().unwrap_or_default().yellow(),
+                state.term
+            );
+        }
+
+        state.role = Role::Follower;
+        state.leader = leader;
+        self.reset_election_deadline(state);
+    }
+
+    /// Commits the last entry of the current term stored on a majority of the nodes
+    fn advance_commit(&self, state: &mut RaftState) {
+        let majority = self.peers.len().div_ceil(2) + 1;
+
+        for index in (state.commit_index + 1..=state.last_index()).rev() {
+            if state.term_at(index) != Some(state.term) {
+                break;
+            }
+
+            let replicas = 1 + state
+                .match_index
+                .values()
+                .filter(|matched| **matched >= index)
+                .count();
+
+            if replicas >= majority {
+                state.commit_index = index;
+                self.committed.notify_one();
+                break;
+            }
+        }
+    }
+
+    /// Drops the oldest flushed entries once there are more than `max_log_entries`,
+    /// the followers still missing them are sent a snapshot
+    fn compact(&self, state: &mut RaftState) {
+        if state.flushed - state.log_start <= self.max_log_entries {
+            return;
+        }
+
+        let start = state.flushed - self.max_log_entries / 2;
+        let start_term = state.term_at(start).unwrap_or(state.start_term);
+
+        let previous = (state.log_start, state.start_term);
+        let kept = state.log.split_off((start - state.log_start) as usize);
+        let compacted = std::mem::replace(&mut state.log, kept);
+
+        state.log_start = start;
+        state.start_term = start_term;
+
+        // the new start is saved first, the entries before it are skipped when loading
+        if let Err(e) = self.save_hard_state(state) {
+            println!("[Raft] cannot save the state: {}", e);
+
+            let kept = std::mem::replace(&mut state.log, compacted);
+            state.log.extend(kept);
+            (state.log_start, state.start_term) = previous;
+            return;
+        }
+
+        if let Err(e) = self.rewrite_log(&state.log) {
+            println!("[Raft] cannot save the compacted log: {}", e);
+        }
+    }
+
+    fn reset_election_deadline(&self, state: &mut RaftState) {
+        let timeout = self.election_timeout.as_millis() as u64;
+        let jitter = rand::thread_rng().gen_range(0..=timeout);
+
+        state.election_deadline = Instant::now() + Duration::from_millis(timeout + jitter);
+    }
+
+    fn save_hard_state(&self, state: &RaftState) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let hard_state = HardState {
+            term: state.term,
+            voted_for: state.voted_for.clone(),
+            last_applied: state.flushed,
+            log_start: state.log_start,
+            start_term: state.start_term,
+        };
+
+        // written aside then renamed, so a crash never leaves a partial state
+        let temp = path.join(format!("{}.tmp", STATE_NAME));
+
+        let mut file = File::create(&temp)?;
+        file.write_all(&serde_json::to_vec(&hard_state).map_err(io::Error::other)?)?;
+        file.sync_data()?;
+
+        fs::rename(temp, path.join(STATE_NAME))
+    }
+
+    /// Appends entries to the saved log. On failure, the log is rewritten without them, so
+    /// a partially written entry does not hide the ones appended later.
+    fn persist_entries(&self, state: &RaftState, entries: &[LogEntry]) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        write_entries(&path.join(LOG_NAME), entries, true).inspect_err(|_| {
+            self.rewrite_log(&state.log).ok();
+        })
+    }
+
+    fn rewrite_log(&self, log: &[LogEntry]) -> io::Result<()> {
+        match &self.path {
+            Some(path) => write_entries(&path.join(LOG_NAME), log, false),
+            None => Ok(()),
+        }
+    }
+}
+
+fn append_result(state: &RaftState, success: bool) -> RaftMessage {
+    RaftMessage::AppendResult {
+        term: state.term,
+        success,
+        last_log_index: state.last_index(),
+    }
+}
+
+/// Reads the saved state and log, and whether the log stopped before the end of the file
+fn load(path: &Path) -> io::Result<(HardState, Vec<LogEntry>, bool)> {
+    fs::create_dir_all(path)?;
+
+    let hard_state: HardState = match File::open(path.join(STATE_NAME)) {
+        Ok(file) => serde_json::from_reader(file).map_err(io::Error::other)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => HardState::default(),
+        Err(e) => return Err(e),
+    };
+
+    let mut log = Vec::new();
+    let mut torn = false;
+
+    if let Ok(file) = File::open(path.join(LOG_NAME)) {
+        let mut reader = BufReader::new(file);
+        let mut next_index = hard_state.log_start + 1;
+
+        // a partially written entry at the end of the log ends it
+        while !matches!(reader.fill_buf(), Ok([]) | Err(_)) {
+            let entry =
+                match Document::from_reader(&mut reader).map(bson::from_document::<LogEntry>) {
+                    Ok(Ok(entry)) => entry,
+                    _ => {
+                        torn = true;
+                        break;
+                    }
+                };
+
+            // compacted before the log was rewritten
+            if entry.index < next_index {
+                continue;
+            }
+
+            if entry.index > next_index {
+                torn = true;
+                break;
+            }
+
+            next_index += 1;
+            log.push(entry);
+        }
+    }
+
+    Ok((hard_state, log, torn))
+}
+
+fn write_entries(path: &Path, entries: &[LogEntry], append: bool) -> io::Result<()> {
+    // a rewritten log is written aside then renamed, so a crash never loses it
+    let target = match append {
+        true => path.to_path_buf(),
+        false => path.with_extension("tmp"),
+    };
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(&target)?;
+
+    for entry in entries {
+        let bytes = bson::to_vec(entry).map_err(io::Error::other)?;
+        file.write_all(&bytes)?;
+    }
+
+    file.sync_data()?;
+
+    match append {
+        true => Ok(()),
+        false => fs::rename(target, path),
+    }
+}
+
+/// Applies the committed commands to the local databases
+pub struct DustDataMachine {
+    config: Arc<schema::RustbaseConfig>,
+    routers: Arc<RwLock<HashMap<String, DustData>>>,
+    cache: Arc<RwLock<Cache>>,
+    oplog: Option<Arc<OpLog>>,
+    system_db: Arc<RwLock<DustData>>,
+    counters: Arc<Counters>,
+}
+
+impl DustDataMachine {
+    pub fn new(
+        config: Arc<schema::RustbaseConfig>,
+        routers: Arc<RwLock<HashMap<String, DustData>>>,
+        cache: Arc<RwLock<Cache>>,
+        oplog: Option<Arc<OpLog>>,
+        system_db: Arc<RwLock<DustData>>,
+        counters: Arc<Counters>,
+    ) -> Self {
+        Self {
+            config,
+            routers,
+            cache,
+            oplog,
+            system_db,
+            counters,
+        }
+    }
+}
+
+impl StateMachine for DustDataMachine {
+    fn apply(&self, index: u64, command: &Command) {
+        // logged on every node, for the change streams and the point-in-time restores
+        if let Some(oplog) = &self.oplog {
+            if let Err(e) = oplog.append(
+                &command.database,
+                command.operation.clone(),
+                command.key.clone(),
+                command.value.clone(),
+            ) {
+                println!("[Raft] cannot log entry {}: {}", index, e);
+            }
+        }
+
+        let entry = OpEntry {
+            seq: index as i64,
+            timestamp: bson::DateTime::now(),
+            database: command.database.clone(),
+            operation: command.operation.clone(),
+            key: command.key.clone(),
+            value: command.value.clone(),
+        };
+
+        let applied = cluster::apply_operation(
+            &self.config,
+            &self.routers,
+            &self.cache,
+            Some(&self.counters),
+            &entry,
+        );
+
+        // counted by every node, each one keeping the quotas of its own catalog
+        let counted = applied.and_then(|change| match (&entry.operation, &entry.key) {
+            (Operation::DropDatabase, _) => {
+                quota::drop_database(&self.system_db, &self.counters, &entry.database)
+            }
+            (_, Some(key)) => quota::charge(
+                &self.system_db,
+                &self.counters,
+                &entry.database,
+                key,
+                command.user.as_deref(),
+                &change,
+            ),
+            (_, None) => Ok(()),
+        });
+
+        if let Err(e) = counted {
+            println!("[Raft] failed to apply entry {}: {:?}", index, e.code);
+        }
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        for (database, dd) in self.routers.write().unwrap().iter_mut() {
+            dd.flush()
+                .map_err(|e| format!("cannot flush {}: {:?}", database, e.code))?;
+        }
+
+        match &self.oplog {
+            Some(oplog) => oplog.sync().map_err(|e| e.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    fn snapshot(&self) -> Vec<Record> {
+        let routers = self.routers.read().unwrap();
+        let mut records = Vec::new();
+
+        for (database, dd) in routers.iter() {
+            if database == "_default" {
+                continue;
+            }
+
+            for key in dd.list_keys().unwrap_or_default() {
+                if let Ok(Some(value)) = dd.get(&key) {
+                    records.push(Record {
+                        database: database.clone(),
+                        key,
+                        value,
+                    });
+                }
+            }
+        }
+
+        records
+    }
+
+    fn restore(&self, records: Vec<Record>) -> Result<(), String> {
+        *self.cache.write().unwrap() = Cache::new(self.config.cache_size);
+
+        let databases: Vec<String> = self
+            .routers
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|database| *database != "_default")
+            .cloned()
+            .collect();
+
+        let mut entries = databases
+            .into_iter()
+            .map(|database| (database, Operation::DropDatabase, None, None))
+            .chain(records.into_iter().map(|record| {
+                (
+                    record.database,
+                    Operation::Insert,
+                    Some(record.key),
+                    Some(record.value),
+                )
+            }));
+
+        entries.try_for_each(|(database, operation, key, value)| {
+            let entry = OpEntry {
+                seq: 0,
+                timestamp: bson::DateTime::now(),
+                database,
+                operation,
+                key,
+                value,
+            };
+
+            cluster::apply_operation(&self.config, &self.routers, &self.cache, None, &entry)
+                .map(|_| ())
+                .map_err(|e| format!("cannot restore {}: {:?}", entry.database, e.code))
+        })?;
+
+        // the keys of the snapshot were counted by the leader, for whom is not known here
+        quota::reset_usage(&self.system_db, &self.counters)
+            .map_err(|e| format!("cannot reset the quotas: {:?}", e.code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::Weak;
+
+    /// The nodes of a cluster running in memory, and the links that are cut
+    #[derive(Default)]
+    struct Network {
+        nodes: Mutex<HashMap<String, Weak<Raft>>>,
+        cut: Mutex<HashSet<(String, String)>>,
+    }
+
+    impl Network {
+        fn isolate(&self, node: &str) {
+            let mut cut = self.cut.lock().unwrap();
+
+            for peer in self.nodes.lock().unwrap().keys() {
+                cut.insert((node.to_string(), peer.clone()));
+                cut.insert((peer.clone(), node.to_string()));
+            }
+        }
+
+        fn heal(&self) {
+            self.cut.lock().unwrap().clear();
+        }
+    }
+
+    struct MemoryTransport {
+        from: String,
+        network: Arc<Network>,
+    }
+
+    #[async_trait]
+    impl Transport for MemoryTransport {
+        async fn call(&self, peer: &str, message: RaftMessage) -> Option<RaftMessage> {
+            let link = (self.from.clone(), peer.to_string());
+
+            if self.network.cut.lock().unwrap().contains(&link) {
+                return None;
+            }
+
+            let node = self.network.nodes.lock().unwrap().get(peer)?.upgrade()?;
+            let reply = node.handle(message).await;
+
+            // the reply is lost if the link was cut meanwhile
+            match self.network.cut.lock().unwrap().contains(&link) {
+                true => None,
+                false => Some(reply),
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct MemoryMachine {
+        records: Mutex<BTreeMap<String, Bson>>,
+        restored: Mutex<bool>,
+    }
+
+    impl MemoryMachine {
+        fn keys(&self) -> Vec<String> {
+            self.records.lock().unwrap().keys().cloned().collect()
+        }
+    }
+
+    impl StateMachine for MemoryMachine {
+        fn apply(&self, _: u64, command: &Command) {
+            let key = command.key.clone().unwrap_or_default();
+            let mut records = self.records.lock().unwrap();
+
+            match command.operation {
+                Operation::Delete => records.remove(&key),
+                _ => records.insert(key, command.value.clone().unwrap_or(Bson::Null)),
+            };
+        }
+
+        fn flush(&self) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn snapshot(&self) -> Vec<Record> {
+            self.records
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(key, value)| Record {
+                    database: "test".to_string(),
+                    key: key.clone(),
+                    value: value.clone(),
+                })
+                .collect()
+        }
+
+        fn restore(&self, records: Vec<Record>) -> Result<(), String> {
+            *self.records.lock().unwrap() = records
+                .into_iter()
+                .map(|record| (record.key, record.value))
+                .collect();
+            *self.restored.lock().unwrap() = true;
+
+            Ok(())
+        }
+    }
+
+    struct Node {
+        raft: Arc<Raft>,
+        machine: Arc<MemoryMachine>,
+        stop: watch::Sender<bool>,
+        task: tokio::task::JoinHandle<()>,
+    }
+
+    fn cluster(size: usize, max_log_entries: u64) -> (Arc<Network>, Vec<Node>) {
+        let network = Arc::new(Network::default());
+        let ids: Vec<String> = (0..size).map(|i| format!("node{}", i)).collect();
+        let mut nodes = Vec::new();
+
+        for id in &ids {
+            let machine = Arc::new(MemoryMachine::default());
+            let transport = Arc::new(MemoryTransport {
+                from: id.clone(),
+                network: network.clone(),
+            });
+
+            let mut raft = Raft::new(
+                id.clone(),
+                ids.iter().filter(|peer| *peer != id).cloned().collect(),
+                None,
+                Duration::from_millis(300),
+                transport,
+                machine.clone(),
+                Handle::current(),
+            )
+            .unwrap();
+            raft.max_log_entries = max_log_entries;
+
+            let raft = Arc::new(raft);
+
+            network
+                .nodes
+                .lock()
+                .unwrap()
+                .insert(id.clone(), Arc::downgrade(&raft));
+            let (stop, receiver) = watch::channel(false);
+            let task = tokio::spawn(raft.clone().run(receiver));
+
+            nodes.push(Node {
+                raft,
+                machine,
+                stop,
+                task,
+            });
+        }
+
+        (network, nodes)
+    }
+
+    async fn eventually(what: &str, check: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if check() {
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        panic!("timed out waiting for {}", what);
+    }
+
+    /// The only leader among some nodes, once there is one
+    async fn leader(nodes: &[Node], among: &[usize]) -> usize {
+        let leaders = || {
+            among
+                .iter()
+                .copied()
+                .filter(|i| nodes[*i].raft.state.lock().unwrap().role == Role::Leader)
+                .collect::<Vec<_>>()
+        };
+
+        eventually("a leader", || leaders().len() == 1).await;
+
+        leaders()[0]
+    }
+
+    fn insert(key: &str) -> Command {
+        Command {
+            database: "test".to_string(),
+            operation: Operation::Insert,
+            key: Some(key.to_string()),
+            value: Some(Bson::Int32(1)),
+            user: None,
+        }
+    }
+
+    fn term(node: &Node) -> u64 {
+        node.raft.state.lock().unwrap().term
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_leader_is_elected_and_replicates_the_writes() {
+        let (_network, nodes) = cluster(3, DEFAULT_MAX_LOG_ENTRIES);
+        let leader = leader(&nodes, &[0, 1, 2]).await;
+
+        nodes[leader].raft.propose(insert("a")).await.unwrap();
+
+        eventually("the write on every node", || {
+            nodes.iter().all(|node| node.machine.keys() == ["a"])
+        })
+        .await;
+
+        let follower = (leader + 1) % 3;
+
+        assert!(matches!(
+            nodes[follower].raft.propose(insert("b")).await,
+            Err(ProposeError::NotLeader(Some(_)))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_new_leader_replaces_a_leader_cut_off() {
+        let (network, nodes) = cluster(3, DEFAULT_MAX_LOG_ENTRIES);
+        let old = leader(&nodes, &[0, 1, 2]).await;
+        let others: Vec<usize> = (0..3).filter(|i| *i != old).collect();
+
+        network.isolate(&nodes[old].raft.id);
+
+        // the old leader cannot commit without a majority
+        let old_raft = nodes[old].raft.clone();
+        let lost = tokio::spawn(async move { old_raft.propose(insert("lost")).await });
+
+        let new = leader(&nodes, &others).await;
+        assert!(term(&nodes[new]) > term(&nodes[old]));
+
+        nodes[new].raft.propose(insert("b")).await.unwrap();
+
+        network.heal();
+
+        // the entry of the old leader conflicts with the log of the new one
+        assert!(matches!(lost.await.unwrap(), Err(ProposeError::Lost)));
+
+        eventually("the old leader to follow", || {
+            nodes[old].raft.state.lock().unwrap().role == Role::Follower
+                && nodes.iter().all(|node| node.machine.keys() == ["b"])
+        })
+        .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_follower_partitioned_catches_up_once_healed() {
+        let (network, nodes) = cluster(3, DEFAULT_MAX_LOG_ENTRIES);
+        let leader = leader(&nodes, &[0, 1, 2]).await;
+        let follower = (leader + 1) % 3;
+
+        network.isolate(&nodes[follower].raft.id);
+
+        // the leader and the other follower are a majority
+        for key in ["a", "b", "c"] {
+            nodes[leader].raft.propose(insert(key)).await.unwrap();
+        }
+
+        assert!(nodes[follower].machine.keys().is_empty());
+
+        network.heal();
+
+        eventually("the follower to catch up", || {
+            nodes
+                .iter()
+                .all(|node| node.machine.keys() == ["a", "b", "c"])
+        })
+        .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_follower_missing_compacted_entries_gets_a_snapshot() {
+        let (network, nodes) = cluster(3, 4);
+        let leader = leader(&nodes, &[0, 1, 2]).await;
+        let follower = (leader + 1) % 3;
+        let other = (leader + 2) % 3;
+
+        network.isolate(&nodes[follower].raft.id);
+
+        let keys: Vec<String> = (0..20).map(|i| format!("key{:02}", i)).collect();
+
+        for key in &keys {
+            nodes[leader].raft.propose(insert(key)).await.unwrap();
+        }
+
+        eventually("the other follower to apply every entry", || {
+            nodes[other].machine.keys() == keys
+        })
+        .await;
+
+        // either of them may lead once the follower is back
+        for i in [leader, other] {
+            nodes[i].raft.flush().await;
+            assert!(nodes[i].raft.state.lock().unwrap().log_start > 0);
+        }
+
+        network.heal();
+
+        eventually("the follower to install a snapshot", || {
+            nodes[follower].machine.keys() == keys
+        })
+        .await;
+
+        assert!(*nodes[follower].machine.restored.lock().unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stopping_flushes_the_applied_entries() {
+        let (_network, mut nodes) = cluster(1, DEFAULT_MAX_LOG_ENTRIES);
+        let node = nodes.remove(0);
+
+        leader(std::slice::from_ref(&node), &[0]).await;
+        node.raft.propose(insert("a")).await.unwrap();
+
+        node.stop.send_replace(true);
+        node.task.await.unwrap();
+
+        let state = node.raft.state.lock().unwrap();
+        assert_eq!(state.flushed, state.last_applied);
+        assert!(state.flushed > 0);
+    }
+
+    #[tokio::test]
+    async fn conflicting_entries_are_truncated() {
+        let transport = Arc::new(MemoryTransport {
+            from: "node".to_string(),
+            network: Arc::new(Network::default()),
+        });
+
+        let raft = Arc::new(
+            Raft::new(
+                "node".to_string(),
+                vec!["leader".to_string()],
+                None,
+                Duration::from_secs(60),
+                transport,
+                Arc::new(MemoryMachine::default()),
+                Handle::current(),
+            )
+            .unwrap(),
+        );
+
+        let entry = |term, index, key: &str| LogEntry {
+            term,
+            index,
+            command: Some(insert(key)),
+        };
+
+        let append = |term, prev_log_index, prev_log_term, entries| RaftMessage::AppendEntries {
+            term,
+            leader: "leader".to_string(),
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit: 1,
+        };
+
+        let success = |reply| matches!(reply, RaftMessage::AppendResult { success, .. } if success);
+
+        let reply = raft
+            .handle(append(1, 0, 0, vec![entry(1, 1, "a"), entry(1, 2, "b")]))
+            .await;
+        assert!(success(reply));
+
+        // the previous entry must match
+        assert!(!success(raft.handle(append(2, 2, 2, vec![])).await));
+
+        let reply = raft.handle(append(2, 1, 1, vec![entry(2, 2, "c")])).await;
+        assert!(success(reply));
+
+        let state = raft.state.lock().unwrap();
+        let keys: Vec<(u64, Option<String>)> = state
+            .log
+            .iter()
+            .map(|entry| (entry.term, entry.command.clone().and_then(|c| c.key)))
+            .collect();
+
+        assert_eq!(
+            keys,
+            [(1, Some("a".to_string())), (2, Some("c".to_string()))]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_partial_entry_at_the_end_of_the_log_is_dropped() {
+        let path = std::env::temp_dir().join(format!("rustbase-raft-torn-{}", std::process::id()));
+        fs::remove_dir_all(&path).ok();
+        fs::create_dir_all(&path).unwrap();
+
+        let entry = |index, key: &str| LogEntry {
+            term: 1,
+            index,
+            command: Some(insert(key)),
+        };
+
+        let log = path.join(LOG_NAME);
+        write_entries(&log, &[entry(1, "a"), entry(2, "b")], true).unwrap();
+
+        // a crash in the middle of the third entry
+        let partial = bson::to_vec(&entry(3, "c")).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&log).unwrap();
+        file.write_all(&partial[..partial.len() / 2]).unwrap();
+        drop(file);
+
+        let open = || {
+            Raft::new(
+                "node".to_string(),
+                vec![],
+                Some(path.clone()),
+                Duration::from_secs(60),
+                Arc::new(MemoryTransport {
+                    from: "node".to_string(),
+                    network: Arc::new(Network::default()),
+                }),
+                Arc::new(MemoryMachine::default()),
+                Handle::current(),
+            )
+            .unwrap()
+        };
+
+        let raft = open();
+        let indexes = |raft: &Raft| -> Vec<u64> {
+            raft.state
+                .lock()
+                .unwrap()
+                .log
+                .iter()
+                .map(|entry| entry.index)
+                .collect()
+        };
+
+        assert_eq!(indexes(&raft), [1, 2]);
+
+        // appended after the readable entries, so found again on the next start
+        let appended = entry(3, "d");
+        let state = raft.state.lock().unwrap();
+        raft.persist_entries(&state, &[appended]).unwrap();
+        drop(state);
+        drop(raft);
+
+        let (_, log, torn) = load(&path).unwrap();
+
+        assert!(!torn);
+        assert_eq!(
+            log.iter().map(|entry| entry.index).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+        assert_eq!(indexes(&open()), [1, 2, 3]);
+
+        fs::remove_dir_all(&path).ok();
+    }
+}
@@ -0,0 +1,106 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use super::{RaftMessage, Transport};
+use crate::config::schema;
+use crate::server::wirewave;
+
+use wirewave::client::Client;
+use wirewave::server::{ReqHeader, Request, Type};
+
+const CALL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Sends the messages in `Cluster` requests (`raft` action), keeping one connection per peer
+pub struct WirewaveTransport {
+    username: Option<String>,
+    password: Option<String>,
+    clients: Mutex<HashMap<String, Client<TcpStream>>>,
+}
+
+impl WirewaveTransport {
+    pub fn new(cluster: &schema::Cluster) -> Self {
+        Self {
+            username: cluster.username.clone(),
+            password: cluster.password.clone(),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn connect(&self, peer: &str) -> Option<Client<TcpStream>> {
+        let mut client = timeout(CALL_TIMEOUT, Client::connect(peer))
+            .await
+            .ok()?
+            .ok()?;
+
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            timeout(CALL_TIMEOUT, client.authenticate(username, password))
+                .await
+                .ok()?
+                .ok()?;
+        }
+
+        Some(client)
+    }
+
+    /// A pooled connection still open, or a new one
+    async fn checkout(&self, peer: &str) -> Option<(Client<TcpStream>, bool)> {
+        let pooled = self.clients.lock().unwrap().remove(peer);
+
+        if let Some(client) = pooled {
+            if client.is_open().await {
+                return Some((client, true));
+            }
+        }
+
+        Some((self.connect(peer).await?, false))
+    }
+}
+
+#[async_trait]
+impl Transport for WirewaveTransport {
+    async fn call(&self, peer: &str, message: RaftMessage) -> Option<RaftMessage> {
+        let request = Request {
+            header: ReqHeader {
+                type_: Type::Cluster,
+                auth: None,
+            },
+            body: bson::doc! {
+                "action": "raft",
+                "message": bson::to_bson(&message).ok()?,
+            },
+        };
+
+        let (mut client, pooled) = self.checkout(peer).await?;
+
+        // a connection that failed or timed out is dropped instead of being reused
+        let response = match timeout(CALL_TIMEOUT, client.request(&request)).await {
+            Ok(Ok(response)) => response,
+            // the peer may have closed a pooled connection since it was checked
+            _ if pooled => {
+                client = self.connect(peer).await?;
+
+                timeout(CALL_TIMEOUT, client.request(&request))
+                    .await
+                    .ok()?
+                    .ok()?
+            }
+            _ => return None,
+        };
+
+        // an error may be the last frame of a connection being closed
+        if response.header.is_error {
+            return None;
+        }
+
+        self.clients
+            .lock()
+            .unwrap()
+            .insert(peer.to_string(), client);
+
+        bson::from_bson(response.body?).ok()
+    }
+}
@@ -0,0 +1,266 @@
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
+
+use super::cache::Cache;
+use super::wirewave;
+use crate::config::{self, schema};
+
+/// The configuration of a running server, reloaded from its file on `SIGHUP` or
+/// with `reload config`.
+pub struct LiveConfig {
+    path: Option<PathBuf>,
+    config: RwLock<Arc<schema::RustbaseConfig>>,
+    pool: RwLock<Arc<ThreadPool>>,
+    cache: Arc<RwLock<Cache>>,
+    tls: Vec<Option<watch::Sender<TlsAcceptor>>>,
+    reloading: Mutex<()>,
+}
+
+impl LiveConfig {
+    pub fn new(
+        path: Option<PathBuf>,
+        config: Arc<schema::RustbaseConfig>,
+        pool: ThreadPool,
+        cache: Arc<RwLock<Cache>>,
+        tls: Vec<Option<watch::Sender<TlsAcceptor>>>,
+    ) -> Self {
+        Self {
+            path,
+            config: RwLock::new(config),
+            pool: RwLock::new(Arc::new(pool)),
+            cache,
+            tls,
+            reloading: Mutex::new(()),
+        }
+    }
+
+    pub fn config(&self) -> Arc<schema::RustbaseConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// The pool running the requests. The requests started before a reload finish on
+    /// the previous pool.
+    pub fn pool(&self) -> Arc<ThreadPool> {
+        self.pool.read().unwrap().clone()
+    }
+
+    /// Reads the configuration file again and applies it. Nothing is applied when the
+    /// file is invalid or changes a field that needs a restart. Returns the fields changed.
+    pub fn reload(&self) -> Result<Vec<String>, String> {
+        let _reloading = self.reloading.lock().unwrap();
+
+        let current = self.config();
+        let new = config::read_configuration(self.path.as_deref())?;
+
+        config::validate::validate(&new).map_err(|problems| problems.join(", "))?;
+
+        check_restart_fields(&current, &new)?;
+
+        let mut changed = Vec::new();
+
+        // everything that can fail is built before anything is applied
+        let pool = if new.threads != current.threads {
+            let pool = ThreadPoolBuilder::new()
+                .num_threads(new.threads)
+                .build()
+                .map_err(|e| format!("threads: {}", e))?;
+
+            Some(pool)
+        } else {
+            None
+        };
+
+        // always rebuilt, so a renewed certificate at the same path is picked up
+        let mut acceptors = Vec::new();
+
+        for (sender, listener) in self.tls.iter().zip(config::listeners(&new)) {
+            if let (Some(sender), Some(tls)) = (sender, &listener.tls) {
+                let acceptor = wirewave::server::tls_acceptor(tls)
+                    .map_err(|e| format!("tls of {}: {}", config::listener_name(&listener), e))?;
+
+                acceptors.push((sender, acceptor));
+            }
+        }
+
+        if new.cache_size != current.cache_size {
+            self.cache.write().unwrap().set_max_size(new.cache_size);
+            changed.push(format!("cache_size: {}", new.cache_size));
+        }
+
+        if let Some(pool) = pool {
+            *self.pool.write().unwrap() = Arc::new(pool);
+            changed.push(format!("threads: {}", new.threads));
+        }
+
+        if !acceptors.is_empty() {
+            for (sender, acceptor) in acceptors {
+                sender.send_replace(acceptor);
+            }
+
+            changed.push("net.tls: certificates reloaded".to_string());
+        }
+
+        if !same(&new.auth, &current.auth) {
+            changed.push("auth".to_string());
+        }
+
+        // read from the configuration by each new connection, nothing to apply here
+        if !same(&new.net.limits, &current.net.limits) {
+            changed.push("net.limits".to_string());
+        }
+
+        *self.config.write().unwrap() = Arc::new(new);
+
+        Ok(changed)
+    }
+}
+
+/// Rejects the changes of the fields only read when the server starts
+fn check_restart_fields(
+    current: &schema::RustbaseConfig,
+    new: &schema::RustbaseConfig,
+) -> Result<(), String> {
+    let fields = [
+        ("storage", same(&current.storage, &new.storage)),
+        ("net.host", current.net.host == new.net.host),
+        ("net.port", current.net.port == new.net.port),
+        (
+            "net.shutdown_timeout",
+            current.net.shutdown_timeout == new.net.shutdown_timeout,
+        ),
+        (
+            "net.tls",
+            current.net.tls.is_some() == new.net.tls.is_some(),
+        ),
+        (
+            "net.listeners",
+            listener_addresses(current) == listener_addresses(new),
+        ),
+        ("net.http", same(&current.net.http, &new.net.http)),
+        ("backup", same(&current.backup, &new.backup)),
+        ("cluster", same(&current.cluster, &new.cluster)),
+    ];
+
+    let changed: Vec<&str> = fields
+        .iter()
+        .filter(|(_, same)| !same)
+        .map(|(field, _)| *field)
+        .collect();
+
+    if changed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} cannot change without a restart",
+            changed.join(", ")
+        ))
+    }
+}
+
+/// What the listeners are bound with, their TLS files can change live
+fn listener_addresses(config: &schema::RustbaseConfig) -> Vec<serde_json::Value> {
+    config::listeners(config)
+        .into_iter()
+        .map(|mut listener| {
+            let tls = listener.tls.take().is_some();
+
+            serde_json::json!({ "listener": listener, "tls": tls })
+        })
+        .collect()
+}
+
+fn same<T: serde::Serialize>(a: &T, b: &T) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn configuration(name: &str) -> (PathBuf, schema::RustbaseConfig) {
+        let dir =
+            std::env::temp_dir().join(format!("rustbase-reload-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut config = config::default_configuration();
+        config.storage.path = dir.join("data");
+
+        (dir.join("rustbaseconf.json"), config)
+    }
+
+    fn write(path: &PathBuf, config: &schema::RustbaseConfig) {
+        fs::write(path, serde_json::to_string(config).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rejects_the_fields_read_at_startup() {
+        let (_, current) = configuration("restart");
+
+        let mut new = current.clone();
+        new.net.shutdown_timeout = Some(5);
+        new.storage.path = new.storage.path.join("other");
+
+        assert_eq!(
+            check_restart_fields(&current, &new).unwrap_err(),
+            "storage, net.shutdown_timeout cannot change without a restart"
+        );
+
+        let mut new = current.clone();
+        new.cache_size += 1;
+        new.threads += 1;
+        new.net.limits = Some(schema::Limits {
+            max_connections: Some(10),
+            ..Default::default()
+        });
+
+        assert!(check_restart_fields(&current, &new).is_ok());
+    }
+
+    #[test]
+    fn reports_the_fields_applied() {
+        let (path, config) = configuration("applied");
+        write(&path, &config);
+
+        let live = LiveConfig::new(
+            Some(path.clone()),
+            Arc::new(config.clone()),
+            ThreadPoolBuilder::new().num_threads(1).build().unwrap(),
+            Arc::new(RwLock::new(Cache::new(config.cache_size))),
+            Vec::new(),
+        );
+
+        assert!(live.reload().unwrap().is_empty());
+
+        let mut new = config.clone();
+        new.cache_size += 1;
+        new.net.limits = Some(schema::Limits {
+            idle_timeout: Some(30),
+            ..Default::default()
+        });
+        write(&path, &new);
+
+        assert_eq!(
+            live.reload().unwrap(),
+            vec![
+                format!("cache_size: {}", new.cache_size),
+                "net.limits".to_string()
+            ]
+        );
+        assert_eq!(
+            live.config().net.limits.as_ref().unwrap().idle_timeout,
+            Some(30)
+        );
+
+        let mut restart = new.clone();
+        restart.net.port += 1;
+        write(&path, &restart);
+
+        assert!(live.reload().is_err());
+        assert_eq!(live.config().net.port, new.net.port);
+    }
+}
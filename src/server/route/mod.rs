@@ -1,15 +1,40 @@
 use std::collections::HashMap;
 use std::fs;
+use std::panic::{self, AssertUnwindSafe};
 use std::path;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::RwLock;
 
 use crate::config::schema;
 use colored::Colorize;
+use dustdata::snapshots::Snapshot;
 use dustdata::DustData;
 
 use super::main::default_dustdata_config;
+use super::{cluster, oplog, quota, raft};
+
+pub const QUARANTINE_DIRECTORY: &str = "_quarantine";
+pub const RESTORE_DIRECTORY: &str = "_restore";
+
+/// Directories of the data path used by the server itself, which are not databases
+pub fn is_reserved_directory(name: &str) -> bool {
+    [
+        QUARANTINE_DIRECTORY,
+        RESTORE_DIRECTORY,
+        oplog::OPLOG_DIRECTORY,
+        cluster::CLUSTER_DIRECTORY,
+        raft::RAFT_DIRECTORY,
+        quota::OWNERS_DIRECTORY,
+    ]
+    .contains(&name)
+}
+
+/// The entries of the system catalog (quotas, schemas) are stored in the system database
+/// next to the users, under keys starting with `$`, which a username cannot
+pub fn is_catalog_key(key: &str) -> bool {
+    key.starts_with('$')
+}
 
 pub fn get_existing_routes(data_path: &Path) -> Vec<String> {
     let mut routes = Vec::new();
@@ -23,6 +48,19 @@ pub fn get_existing_routes(data_path: &Path) -> Vec<String> {
         let entry = entry.unwrap();
         let path = entry.path();
         let route = path.file_name().unwrap().to_str().unwrap().to_string();
+
+        if is_reserved_directory(&route) {
+            continue;
+        }
+
+        if !path.is_dir() {
+            println!(
+                "[Route] {} is not a database directory, ignoring it",
+                route.yellow()
+            );
+            continue;
+        }
+
         routes.push(route);
     }
 
@@ -51,15 +89,149 @@ pub fn initialize_dustdata(
                 continue;
             }
 
-            let dd = dustdata::initialize(default_dustdata_config(config, Some(&route)));
-
-            routers.insert(route, dd);
+            match open_dustdata(config, &route) {
+                Ok(dd) => {
+                    routers.insert(route, dd);
+                }
+                Err(e) => match quarantine_dustdata(&config.storage.path, &route) {
+                    Ok(quarantine) => println!(
+                        "[Route] {} could not be opened ({}), moved to {}",
+                        route.red(),
+                        e,
+                        quarantine.display()
+                    ),
+                    Err(quarantine_error) => println!(
+                        "[Route] {} could not be opened ({}) nor quarantined ({}), skipping it",
+                        route.red(),
+                        e,
+                        quarantine_error
+                    ),
+                },
+            }
         }
     }
 
     Arc::new(RwLock::new(routers))
 }
 
+/// Opens a database, returning an error instead of panicking when its files are unreadable.
+pub fn open_dustdata(config: &schema::RustbaseConfig, database: &str) -> Result<DustData, String> {
+    panic::catch_unwind(|| create_dustdata(config, Some(database))).map_err(panic_message)
+}
+
+/// Opens a copy of a database in a temporary directory and passes it to `f`, so
+/// whatever DustData writes while opening or closing it never touches the original.
+pub fn open_dustdata_read_only<T>(
+    config: &schema::RustbaseConfig,
+    database: &str,
+    f: impl FnOnce(&DustData) -> T,
+) -> Result<T, String> {
+    let copy = std::env::temp_dir().join(format!(
+        "rustbase-{}-{}-{}",
+        database,
+        std::process::id(),
+        bson::DateTime::now().timestamp_millis()
+    ));
+
+    let result = copy_dir(&config.storage.path.join(database), &copy)
+        .map_err(|e| format!("cannot copy the database: {}", e))
+        .and_then(|_| {
+            panic::catch_unwind(|| create_dustdata_at(config, &copy)).map_err(panic_message)
+        })
+        .map(|dd| {
+            let result = f(&dd);
+
+            // closing may panic too, e.g. while flushing the copy
+            panic::catch_unwind(AssertUnwindSafe(|| drop(dd))).ok();
+            result
+        });
+
+    fs::remove_dir_all(&copy).ok();
+
+    result
+}
+
+fn copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(to)?;
+
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that every key of a database can be read back as BSON. Returns the number of
+/// keys and the keys that could not be read.
+pub fn validate_dustdata(dd: &DustData) -> Result<(usize, Vec<String>), String> {
+    let keys = panic::catch_unwind(AssertUnwindSafe(|| dd.list_keys()))
+        .map_err(panic_message)?
+        .map_err(|e| format!("{:?}", e.code))?;
+
+    let corrupt = keys
+        .iter()
+        .filter(|key| {
+            !matches!(
+                panic::catch_unwind(AssertUnwindSafe(|| dd.get(key))),
+                Ok(Ok(Some(_)))
+            )
+        })
+        .cloned()
+        .collect();
+
+    Ok((keys.len(), corrupt))
+}
+
+/// Moves a database directory (or a stray file) out of the way, into `_quarantine`.
+pub fn quarantine_dustdata(data_path: &Path, route: &str) -> Result<PathBuf, String> {
+    let quarantine = data_path.join(QUARANTINE_DIRECTORY);
+
+    if !quarantine.exists() {
+        fs::create_dir_all(&quarantine)
+            .map_err(|e| format!("cannot create {}: {}", quarantine.display(), e))?;
+    }
+
+    let target = quarantine.join(format!(
+        "{}-{}",
+        route,
+        bson::DateTime::now().timestamp_millis()
+    ));
+
+    fs::rename(data_path.join(route), &target)
+        .map_err(|e| format!("cannot move {} to {}: {}", route, target.display(), e))?;
+
+    Ok(target)
+}
+
+/// Files in the data directory, which are not databases
+pub fn orphaned_files(data_path: &Path) -> Vec<PathBuf> {
+    match fs::read_dir(data_path) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| !path.is_dir())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn panic_message(e: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = e.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = e.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown error".to_string()
+    }
+}
+
 pub fn remove_dustdata(data_path: &Path, route: String) {
     let path = path::Path::new(&data_path).join(route);
 
@@ -71,3 +243,85 @@ pub fn remove_dustdata(data_path: &Path, route: String) {
 pub fn create_dustdata(config: &schema::RustbaseConfig, database: Option<&str>) -> DustData {
     dustdata::initialize(default_dustdata_config(config, database))
 }
+
+/// Creates a database in `path` instead of the data path, e.g. to rebuild it before replacing it
+pub fn create_dustdata_at(config: &schema::RustbaseConfig, path: &Path) -> DustData {
+    let mut dd_config = default_dustdata_config(config, None);
+    dd_config.path = path.to_path_buf();
+
+    dustdata::initialize(dd_config)
+}
+
+/// Flushes the database and writes a snapshot of it to `path`. The caller must hold
+/// the router lock, so no write can happen between the flush and the snapshot.
+pub fn snapshot_dustdata(dd: &mut DustData, path: &Path) -> Result<(), String> {
+    dd.flush()
+        .map_err(|e| format!("cannot flush the database: {:?}", e.code))?;
+
+    if !path.exists() {
+        if let Err(e) = fs::create_dir_all(path) {
+            println!(
+                "[Route] cannot create snapshot directory {}: {}",
+                path.display().to_string().red(),
+                e
+            );
+
+            return Err(format!(
+                "cannot create the snapshot directory {}: {}",
+                path.display(),
+                e
+            ));
+        }
+    }
+
+    Snapshot::create_snapshot(&dd.lsm, path.to_path_buf());
+
+    Ok(())
+}
+
+/// Snapshots every database in `routers`, plus the system database as `_default`, into
+/// `path/<database>`. Each database is locked only while its own snapshot is taken.
+pub fn snapshot_all_dustdata(
+    routers: &RwLock<HashMap<String, DustData>>,
+    system_db: &RwLock<DustData>,
+    path: &Path,
+) -> Result<Vec<String>, String> {
+    let databases: Vec<String> = routers
+        .read()
+        .unwrap()
+        .keys()
+        .filter(|database| *database != "_default")
+        .cloned()
+        .collect();
+
+    let mut snapshotted = Vec::new();
+
+    for database in databases {
+        let mut routers = routers.write().unwrap();
+
+        // the database may have been deleted in the meantime
+        if let Some(dd) = routers.get_mut(&database) {
+            snapshot_dustdata(dd, &path.join(&database))?;
+            snapshotted.push(database);
+        }
+    }
+
+    snapshot_dustdata(&mut system_db.write().unwrap(), &path.join("_default"))?;
+    snapshotted.push("_default".to_string());
+
+    Ok(snapshotted)
+}
+
+/// Returns the snapshot file at `path`, or the most recent one if `path` is a snapshot directory.
+pub fn find_snapshot_file(path: &Path) -> Option<PathBuf> {
+    if path.is_file() {
+        return Some(path.to_path_buf());
+    }
+
+    fs::read_dir(path)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .max_by_key(|path| path.metadata().and_then(|meta| meta.modified()).ok())
+}
@@ -0,0 +1,687 @@
+use bson::Bson;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::net::TcpStream;
+use tokio::runtime::Handle;
+use tokio::sync::Notify;
+
+use super::engine::interface::TransactionError;
+use super::wirewave;
+use crate::config::schema;
+
+use wirewave::client::{Client, ClientError};
+use wirewave::server::{ReqHeader, Request, Status, Type};
+
+/// The keys are hashed into a fixed number of partitions, and each partition is owned by
+/// one node. Adding a node only moves the partitions the new node wins.
+pub const PARTITIONS: u64 = 4096;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardOperation {
+    Get,
+    Insert,
+    Update,
+    Delete,
+    List,
+    DropDatabase,
+    Databases,
+}
+
+/// A single operation forwarded by a router to a node, in the body of a `Cluster` request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShardRequest {
+    pub database: String,
+    pub operation: ShardOperation,
+    pub key: Option<String>,
+    pub value: Option<Bson>,
+    /// The user the router runs the operation for, charged by the quotas of the node
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+struct Node {
+    address: String,
+    clients: Mutex<Vec<Client<TcpStream>>>,
+}
+
+impl Node {
+    /// A pooled connection, the ones closed by the node in the meantime are dropped
+    async fn checkout(&self) -> Option<Client<TcpStream>> {
+        loop {
+            let client = self.clients.lock().unwrap().pop()?;
+
+            if client.is_open().await {
+                return Some(client);
+            }
+        }
+    }
+}
+
+pub struct ShardRouter {
+    nodes: Vec<Node>,
+    username: Option<String>,
+    password: Option<String>,
+    runtime: Handle,
+    /// Set while a rebalance runs, the keys may still be on their previous node
+    rebalancing: AtomicBool,
+    /// The keys (database and key) being written or moved, one operation at a time
+    claimed: Mutex<HashSet<(String, String)>>,
+    released: Notify,
+}
+
+/// Holds a key until dropped, see `ShardRouter::claim`
+struct Claim<'a> {
+    router: &'a ShardRouter,
+    key: (String, String),
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.router.claimed.lock().unwrap().remove(&self.key);
+        self.router.released.notify_waiters();
+    }
+}
+
+/// Clears the rebalancing flag when the rebalance ends, even on an error
+struct Rebalancing<'a>(&'a AtomicBool);
+
+impl Drop for Rebalancing<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl ShardRouter {
+    pub fn new(cluster: &schema::Cluster, runtime: Handle) -> Option<Self> {
+        let nodes: Vec<Node> = cluster
+            .nodes
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|address| Node {
+                address,
+                clients: Mutex::new(Vec::new()),
+            })
+            .collect();
+
+        if nodes.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            nodes,
+            username: cluster.username.clone(),
+            password: cluster.password.clone(),
+            runtime,
+            rebalancing: AtomicBool::new(false),
+            claimed: Mutex::new(HashSet::new()),
+            released: Notify::new(),
+        })
+    }
+
+    pub fn nodes(&self) -> Vec<String> {
+        self.nodes.iter().map(|node| node.address.clone()).collect()
+    }
+
+    /// The index of the node owning `key`
+    pub fn owner(&self, key: &str) -> usize {
+        let partition = fnv1a(key.as_bytes()) % PARTITIONS;
+
+        // rendezvous hashing: the node with the highest score wins the partition
+        (0..self.nodes.len())
+            .max_by_key(|&i| fnv1a(format!("{}/{}", self.nodes[i].address, partition).as_bytes()))
+            .unwrap()
+    }
+
+    /// Runs a request on the owning node, or on every node for `List` and `DropDatabase`.
+    /// Blocks the current thread, which must not be a thread of the runtime.
+    pub fn forward(&self, request: ShardRequest) -> Result<Bson, TransactionError> {
+        self.runtime.block_on(self.route(request))
+    }
+
+    async fn route(&self, request: ShardRequest) -> Result<Bson, TransactionError> {
+        match request.operation {
+            ShardOperation::List => {
+                let mut keys = Vec::new();
+
+                for result in self.fan_out(&request).await? {
+                    if let Bson::Array(node_keys) = result {
+                        keys.extend(node_keys);
+                    }
+                }
+
+                keys.sort_by(|a, b| a.as_str().cmp(&b.as_str()));
+                keys.dedup();
+
+                Ok(Bson::Array(keys))
+            }
+
+            ShardOperation::DropDatabase => {
+                self.fan_out(&request).await?;
+
+                Ok(Bson::String(request.database))
+            }
+
+            ShardOperation::Get => self.send_to_holder(&request).await,
+
+            _ => {
+                let key = request.key.clone().unwrap_or_default();
+
+                // a write never overlaps the move of its key
+                let _claim = self.claim(&request.database, &key).await;
+
+                if request.operation == ShardOperation::Insert
+                    && self.rebalancing.load(Ordering::SeqCst)
+                {
+                    let mut get = request.clone();
+                    get.operation = ShardOperation::Get;
+
+                    if self.send_to_holder(&get).await.is_ok() {
+                        return Err(TransactionError::ExternalError(
+                            Status::AlreadyExists,
+                            "key already exists".to_string(),
+                        ));
+                    }
+
+                    return self.send(self.owner(&key), &request).await;
+                }
+
+                self.send_to_holder(&request).await
+            }
+        }
+    }
+
+    /// Sends a request on a key to its owner. During a rebalance, a key missing on its
+    /// owner may not have been moved yet, so the other nodes are tried too.
+    async fn send_to_holder(&self, request: &ShardRequest) -> Result<Bson, TransactionError> {
+        let owner = self.owner(request.key.as_deref().unwrap_or_default());
+        let result = self.send(owner, request).await;
+
+        if !self.rebalancing.load(Ordering::SeqCst) || !result.as_ref().is_err_and(is_missing) {
+            return result;
+        }
+
+        for node in (0..self.nodes.len()).filter(|&node| node != owner) {
+            match self.send(node, request).await {
+                Err(e) if is_missing(&e) => continue,
+                result => return result,
+            }
+        }
+
+        result
+    }
+
+    /// Waits until no other write or move holds the key, then holds it until the
+    /// returned claim is dropped
+    async fn claim(&self, database: &str, key: &str) -> Claim<'_> {
+        let key = (database.to_string(), key.to_string());
+
+        loop {
+            // created before the check, so a release in between is not missed
+            let released = self.released.notified();
+
+            if self.claimed.lock().unwrap().insert(key.clone()) {
+                return Claim { router: self, key };
+            }
+
+            released.await;
+        }
+    }
+
+    /// Runs a request on every node. Nodes without the database are skipped, unless
+    /// no node has it.
+    async fn fan_out(&self, request: &ShardRequest) -> Result<Vec<Bson>, TransactionError> {
+        let mut results = Vec::new();
+        let mut not_found = None;
+
+        for node in 0..self.nodes.len() {
+            match self.send(node, request).await {
+                Ok(result) => results.push(result),
+                Err(TransactionError::ExternalError(Status::NotFound, message)) => {
+                    not_found = Some(message)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        match not_found {
+            Some(message) if results.is_empty() => {
+                Err(TransactionError::ExternalError(Status::NotFound, message))
+            }
+            _ => Ok(results),
+        }
+    }
+
+    async fn send(&self, node: usize, request: &ShardRequest) -> Result<Bson, TransactionError> {
+        let node = &self.nodes[node];
+
+        let unavailable = |e: ClientError| {
+            TransactionError::ExternalError(
+                Status::InternalError,
+                format!("node {} is unavailable: {}", node.address, e),
+            )
+        };
+
+        let mut body = bson::to_document(request).unwrap();
+        body.insert("action", "shard");
+
+        let request = Request {
+            header: ReqHeader {
+                type_: Type::Cluster,
+                auth: None,
+            },
+            body,
+        };
+
+        // a pooled connection may still break (e.g. the node restarted), the request is
+        // then sent once more on a new connection. A broken connection is dropped
+        // instead of going back to the pool.
+        let pooled = match node.checkout().await {
+            Some(mut client) => match client.request(&request).await {
+                Ok(response) => Some((client, response)),
+                Err(_) => None,
+            },
+            None => None,
+        };
+
+        let (client, response) = match pooled {
+            Some(pooled) => pooled,
+            None => {
+                let mut client = self.connect(node).await.map_err(unavailable)?;
+                let response = client.request(&request).await.map_err(unavailable)?;
+
+                (client, response)
+            }
+        };
+
+        node.clients.lock().unwrap().push(client);
+
+        if response.header.is_error {
+            return Err(TransactionError::ExternalError(
+                response.header.status,
+                response.header.messages.unwrap_or_default().join(", "),
+            ));
+        }
+
+        Ok(response.body.unwrap_or(Bson::Null))
+    }
+
+    async fn connect(&self, node: &Node) -> Result<Client<TcpStream>, ClientError> {
+        let mut client = Client::connect(&node.address).await?;
+
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            client.authenticate(username, password).await?;
+        }
+
+        Ok(client)
+    }
+
+    /// Moves every key stored on a node that does not own it, e.g. after a node was
+    /// added. Returns the number of keys moved. The writes to a key wait while it is
+    /// moved, and the keys not moved yet are still found on their previous node.
+    pub async fn rebalance(&self) -> Result<u64, TransactionError> {
+        if self.rebalancing.swap(true, Ordering::SeqCst) {
+            return Err(TransactionError::ExternalError(
+                Status::InternalError,
+                "a rebalance is already running".to_string(),
+            ));
+        }
+
+        let _rebalancing = Rebalancing(&self.rebalancing);
+        let mut moved = 0;
+
+        for node in 0..self.nodes.len() {
+            let databases = self
+                .send(
+                    node,
+                    &shard_request(String::new(), ShardOperation::Databases),
+                )
+                .await?;
+
+            for database in strings(databases) {
+                let keys = self
+                    .send(node, &shard_request(database.clone(), ShardOperation::List))
+                    .await?;
+
+                for key in strings(keys) {
+                    if self.owner(&key) != node && self.move_key(node, &database, &key).await? {
+                        moved += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(moved)
+    }
+
+    /// Moves a key from `node` to its owner. Returns false when the key was written
+    /// or deleted since it was listed, so there is nothing left to move.
+    async fn move_key(
+        &self,
+        node: usize,
+        database: &str,
+        key: &str,
+    ) -> Result<bool, TransactionError> {
+        let _claim = self.claim(database, key).await;
+        let owner = self.owner(key);
+
+        let mut request = shard_request(database.to_string(), ShardOperation::Get);
+        request.key = Some(key.to_string());
+
+        let value = match self.send(node, &request).await {
+            Err(e) if is_missing(&e) => return Ok(false),
+            result => result?,
+        };
+
+        request.operation = ShardOperation::Insert;
+        request.value = Some(value);
+
+        // a key already on its owner was written there after the rebalance started,
+        // so it is newer than the one being moved
+        match self.send(owner, &request).await {
+            Err(TransactionError::ExternalError(Status::AlreadyExists, _)) => {}
+            result => {
+                result?;
+            }
+        }
+
+        request.operation = ShardOperation::Delete;
+        request.value = None;
+        self.send(node, &request).await?;
+
+        Ok(true)
+    }
+}
+
+/// Whether a node answered that the key (or its database) does not exist
+fn is_missing(e: &TransactionError) -> bool {
+    match e {
+        TransactionError::ExternalError(Status::NotFound, _) => true,
+        // the nodes report a missing key on update and delete this way
+        TransactionError::ExternalError(Status::AlreadyExists, message) => {
+            message == "key not exists"
+        }
+        _ => false,
+    }
+}
+
+fn shard_request(database: String, operation: ShardOperation) -> ShardRequest {
+    ShardRequest {
+        database,
+        operation,
+        key: None,
+        value: None,
+        user: None,
+    }
+}
+
+fn strings(bson: Bson) -> Vec<String> {
+    match bson {
+        Bson::Array(values) => values
+            .into_iter()
+            .filter_map(|value| value.as_str().map(|value| value.to_string()))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// FNV-1a, stable across versions and platforms unlike the std hasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use wirewave::server::{write_socket, ResHeader, Response};
+
+    type Store = Arc<Mutex<HashMap<(String, String), Bson>>>;
+
+    /// A node answering the shard requests from memory. With `close`, every
+    /// connection is closed after its first response, like a node restarting.
+    async fn fake_node(close: bool) -> (String, Store) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let store = Store::default();
+        let c_store = store.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let store = c_store.clone();
+
+                tokio::spawn(async move {
+                    loop {
+                        let mut length = [0u8; 4];
+
+                        if socket.read_exact(&mut length).await.is_err() {
+                            return;
+                        }
+
+                        let mut bytes = vec![0u8; i32::from_le_bytes(length) as usize];
+                        bytes[..4].copy_from_slice(&length);
+                        socket.read_exact(&mut bytes[4..]).await.unwrap();
+
+                        let request: Request = bson::from_slice(&bytes).unwrap();
+                        let request: ShardRequest = bson::from_document(request.body).unwrap();
+                        let response = answer(&mut store.lock().unwrap(), request);
+
+                        write_socket(&mut socket, &response).await.unwrap();
+
+                        if close {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        (address, store)
+    }
+
+    fn answer(store: &mut HashMap<(String, String), Bson>, request: ShardRequest) -> Response {
+        let key = (request.database.clone(), request.key.unwrap_or_default());
+
+        let result = match request.operation {
+            ShardOperation::Get => store
+                .get(&key)
+                .cloned()
+                .ok_or((Status::NotFound, "key not found")),
+            ShardOperation::Insert if store.contains_key(&key) => {
+                Err((Status::AlreadyExists, "key already exists"))
+            }
+            ShardOperation::Update | ShardOperation::Delete if !store.contains_key(&key) => {
+                Err((Status::AlreadyExists, "key not exists"))
+            }
+            ShardOperation::Insert | ShardOperation::Update => {
+                store.insert(key, request.value.unwrap());
+                Ok(Bson::Null)
+            }
+            ShardOperation::Delete => {
+                store.remove(&key);
+                Ok(Bson::Null)
+            }
+            ShardOperation::List => Ok(Bson::Array(
+                store
+                    .keys()
+                    .filter(|(database, _)| *database == key.0)
+                    .map(|(_, key)| Bson::String(key.clone()))
+                    .collect(),
+            )),
+            ShardOperation::Databases => {
+                let databases: HashSet<_> = store.keys().map(|(database, _)| database).collect();
+
+                Ok(Bson::Array(
+                    databases.into_iter().cloned().map(Bson::String).collect(),
+                ))
+            }
+            ShardOperation::DropDatabase => Ok(Bson::Null),
+        };
+
+        let (status, messages, body) = match result {
+            Ok(body) => (Status::Ok, None, Some(body)),
+            Err((status, message)) => (status, Some(vec![message.to_string()]), None),
+        };
+
+        Response {
+            header: ResHeader {
+                is_error: messages.is_some(),
+                status,
+                messages,
+            },
+            body,
+        }
+    }
+
+    fn router(nodes: &[&str]) -> ShardRouter {
+        let mut cluster: schema::Cluster = serde_json::from_value(serde_json::json!({
+            "role": "router",
+        }))
+        .unwrap();
+        cluster.nodes = Some(nodes.iter().map(|node| node.to_string()).collect());
+
+        ShardRouter::new(&cluster, Handle::current()).unwrap()
+    }
+
+    fn keyed(operation: ShardOperation, key: &str, value: Option<Bson>) -> ShardRequest {
+        ShardRequest {
+            database: "shop".to_string(),
+            operation,
+            key: Some(key.to_string()),
+            value,
+            user: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn a_new_node_only_takes_keys() {
+        let before = router(&["10.0.0.1:23561", "10.0.0.2:23561"]);
+        let after = router(&["10.0.0.1:23561", "10.0.0.2:23561", "10.0.0.3:23561"]);
+
+        for i in 0..1000 {
+            let key = format!("key-{}", i);
+            let owner = after.owner(&key);
+
+            assert!(owner == 2 || owner == before.owner(&key));
+        }
+    }
+
+    #[tokio::test]
+    async fn a_closed_pooled_connection_is_replaced() {
+        let (address, store) = fake_node(true).await;
+        let shards = router(&[&address]);
+
+        for i in 0..3 {
+            let request = keyed(
+                ShardOperation::Insert,
+                &format!("k{}", i),
+                Some(Bson::Int32(i)),
+            );
+            shards.route(request).await.unwrap();
+        }
+
+        assert_eq!(store.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn rebalance_moves_the_keys_to_their_owner() {
+        let (first, first_store) = fake_node(false).await;
+        let (second, second_store) = fake_node(false).await;
+
+        // every key is on the first node, as before the second one was added
+        for i in 0..50 {
+            let key = ("shop".to_string(), format!("k{}", i));
+            first_store.lock().unwrap().insert(key, Bson::Int32(i));
+        }
+
+        let shards = router(&[&first, &second]);
+        let moved = shards.rebalance().await.unwrap();
+
+        let first_keys = first_store.lock().unwrap().len() as u64;
+        let second_keys = second_store.lock().unwrap().len() as u64;
+
+        assert_eq!(moved, second_keys);
+        assert_eq!(first_keys + second_keys, 50);
+
+        for i in 0..50 {
+            let key = format!("k{}", i);
+            let store = [&first_store, &second_store][shards.owner(&key)];
+
+            assert!(store
+                .lock()
+                .unwrap()
+                .contains_key(&("shop".to_string(), key)));
+        }
+    }
+
+    #[tokio::test]
+    async fn keys_not_moved_yet_are_found_during_a_rebalance() {
+        let (first, first_store) = fake_node(false).await;
+        let (second, second_store) = fake_node(false).await;
+        let shards = router(&[&first, &second]);
+
+        // a key of the second node still on the first one
+        let key = (0..)
+            .map(|i| format!("k{}", i))
+            .find(|key| shards.owner(key) == 1)
+            .unwrap();
+        first_store
+            .lock()
+            .unwrap()
+            .insert(("shop".to_string(), key.clone()), Bson::Int32(1));
+
+        shards.rebalancing.store(true, Ordering::SeqCst);
+
+        let get = keyed(ShardOperation::Get, &key, None);
+        assert_eq!(shards.route(get).await.unwrap(), Bson::Int32(1));
+
+        let insert = keyed(ShardOperation::Insert, &key, Some(Bson::Int32(2)));
+        assert!(shards.route(insert).await.is_err());
+
+        let update = keyed(ShardOperation::Update, &key, Some(Bson::Int32(3)));
+        shards.route(update).await.unwrap();
+
+        assert_eq!(
+            first_store.lock().unwrap().get(&("shop".to_string(), key)),
+            Some(&Bson::Int32(3))
+        );
+        assert!(second_store.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn writes_wait_for_the_move_of_their_key() {
+        let (address, store) = fake_node(false).await;
+        let shards = Arc::new(router(&[&address]));
+
+        let claim = shards.claim("shop", "k").await;
+
+        let c_shards = shards.clone();
+        let write = tokio::spawn(async move {
+            let insert = keyed(ShardOperation::Insert, "k", Some(Bson::Int32(1)));
+            c_shards.route(insert).await
+        });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(store.lock().unwrap().is_empty());
+
+        drop(claim);
+
+        write.await.unwrap().unwrap();
+        assert_eq!(store.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,790 @@
+use bson::{Bson, Document};
+use dustdata::DustData;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// The schemas are stored in the system database next to the users, see `route::is_catalog_key`
+pub const CATALOG_PREFIX: &str = "$schema:";
+
+/// The JSON-Schema keywords that would change the result but are not implemented, refused
+/// instead of being ignored
+const UNSUPPORTED: [&str; 14] = [
+    "pattern",
+    "patternProperties",
+    "$ref",
+    "dependentSchemas",
+    "dependentRequired",
+    "if",
+    "propertyNames",
+    "prefixItems",
+    "additionalItems",
+    "dependencies",
+    "unevaluatedProperties",
+    "unevaluatedItems",
+    "minContains",
+    "maxContains",
+];
+
+const JSON_TYPES: [&str; 7] = [
+    "null", "boolean", "object", "array", "number", "string", "integer",
+];
+
+const BSON_TYPES: [&str; 15] = [
+    "double",
+    "string",
+    "object",
+    "array",
+    "binData",
+    "objectId",
+    "bool",
+    "date",
+    "null",
+    "regex",
+    "int",
+    "timestamp",
+    "long",
+    "decimal",
+    "number",
+];
+
+/// What is done with the writes that do not match the schema
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// They are refused
+    #[default]
+    Strict,
+    /// They are written, and logged
+    Warn,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "strict" => Ok(Mode::Strict),
+            "warn" => Ok(Mode::Warn),
+            _ => Err(format!("unknown mode {}, expected strict or warn", mode)),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mode::Strict => write!(f, "strict"),
+            Mode::Warn => write!(f, "warn"),
+        }
+    }
+}
+
+/// The schema attached to a database
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatabaseSchema {
+    pub schema: Document,
+    #[serde(default)]
+    pub mode: Mode,
+}
+
+impl DatabaseSchema {
+    /// The schema as returned by `show schema`
+    pub fn to_bson(&self, database: &str) -> Bson {
+        Bson::Document(bson::doc! {
+            "database": database,
+            "mode": self.mode.to_string(),
+            "schema": self.schema.clone(),
+        })
+    }
+}
+
+fn key(database: &str) -> String {
+    format!("{}{}", CATALOG_PREFIX, database)
+}
+
+pub fn get(catalog: &DustData, database: &str) -> Option<DatabaseSchema> {
+    catalog
+        .get(&key(database))
+        .ok()
+        .flatten()
+        .and_then(|schema| bson::from_bson(schema).ok())
+}
+
+/// Attaches a schema to a database, or removes it with `None`
+pub fn set(
+    catalog: &mut DustData,
+    database: &str,
+    schema: Option<&DatabaseSchema>,
+) -> dustdata::Result<()> {
+    let key = key(database);
+
+    let schema = match schema {
+        Some(schema) => bson::to_bson(schema).unwrap(),
+        None => {
+            catalog.delete(&key).ok();
+            return Ok(());
+        }
+    };
+
+    match catalog.insert(&key, schema.clone()) {
+        Err(e) if matches!(e.code, dustdata::ErrorCode::KeyExists) => catalog.update(&key, schema),
+        result => result,
+    }
+}
+
+/// Checks that a schema only uses the supported keywords, with values of the right type
+pub fn check_schema(schema: &Document) -> Result<(), String> {
+    let mut problems = Vec::new();
+    check_keywords(schema, "$", &mut problems);
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems.join("; "))
+    }
+}
+
+fn check_at(schema: &Bson, path: &str, problems: &mut Vec<String>) {
+    match schema {
+        Bson::Boolean(_) => {}
+        Bson::Document(schema) => check_keywords(schema, path, problems),
+        _ => problems.push(format!(
+            "{}: a schema must be a document or a boolean",
+            path
+        )),
+    }
+}
+
+fn check_keywords(schema: &Document, path: &str, problems: &mut Vec<String>) {
+    for (keyword, value) in schema {
+        let at = format!("{}.{}", path, keyword);
+
+        let valid = match keyword.as_str() {
+            keyword if UNSUPPORTED.contains(&keyword) => {
+                problems.push(format!("{}: {} is not supported", path, keyword));
+                continue;
+            }
+
+            "type" => type_names(value)
+                .is_some_and(|names| names.iter().all(|name| JSON_TYPES.contains(&name.as_str()))),
+            "bsonType" => type_names(value)
+                .is_some_and(|names| names.iter().all(|name| BSON_TYPES.contains(&name.as_str()))),
+
+            "minimum" | "maximum" | "exclusiveMinimum" | "exclusiveMaximum" => {
+                as_number(value).is_some()
+            }
+            "multipleOf" => as_number(value).is_some_and(|n| n > 0.0),
+            "minLength" | "maxLength" | "minItems" | "maxItems" | "minProperties"
+            | "maxProperties" => as_number(value).is_some_and(|n| n >= 0.0 && n.fract() == 0.0),
+            "uniqueItems" => matches!(value, Bson::Boolean(_)),
+            "enum" => matches!(value, Bson::Array(_)),
+            "required" => match value {
+                Bson::Array(names) => names.iter().all(|name| name.as_str().is_some()),
+                _ => false,
+            },
+
+            "properties" => match value {
+                Bson::Document(properties) => {
+                    for (name, property) in properties {
+                        check_at(property, &format!("{}.{}", at, name), problems);
+                    }
+                    true
+                }
+                _ => false,
+            },
+            "items" | "additionalProperties" | "not" | "contains" => {
+                check_at(value, &at, problems);
+                true
+            }
+            "allOf" | "anyOf" | "oneOf" => match value {
+                Bson::Array(schemas) if !schemas.is_empty() => {
+                    for (index, schema) in schemas.iter().enumerate() {
+                        check_at(schema, &format!("{}[{}]", at, index), problems);
+                    }
+                    true
+                }
+                _ => false,
+            },
+
+            // annotations and unknown keywords do not change the result
+            _ => true,
+        };
+
+        if !valid {
+            problems.push(format!("{}: invalid value {}", at, value));
+        }
+    }
+}
+
+/// Validates a value against a schema, returning every mismatch with the path of the value
+pub fn validate(schema: &Document, value: &Bson) -> Vec<String> {
+    let mut errors = Vec::new();
+    validate_keywords(schema, value, "$", &mut errors);
+
+    errors
+}
+
+fn validate_at(schema: &Bson, value: &Bson, path: &str, errors: &mut Vec<String>) {
+    match schema {
+        Bson::Boolean(false) => errors.push(format!("{}: no value is allowed", path)),
+        Bson::Document(schema) => validate_keywords(schema, value, path, errors),
+        _ => {}
+    }
+}
+
+fn validate_keywords(schema: &Document, value: &Bson, path: &str, errors: &mut Vec<String>) {
+    if let Some(names) = schema.get("type").and_then(type_names) {
+        if !names.iter().any(|name| json_type_matches(name, value)) {
+            errors.push(format!(
+                "{}: expected {}, found {}",
+                path,
+                names.join(" or "),
+                type_name(value)
+            ));
+            return;
+        }
+    }
+
+    if let Some(names) = schema.get("bsonType").and_then(type_names) {
+        if !names.iter().any(|name| bson_type_matches(name, value)) {
+            errors.push(format!(
+                "{}: expected {}, found {}",
+                path,
+                names.join(" or "),
+                type_name(value)
+            ));
+            return;
+        }
+    }
+
+    if let Some(Bson::Array(allowed)) = schema.get("enum") {
+        if !allowed.iter().any(|allowed| same_value(allowed, value)) {
+            errors.push(format!(
+                "{}: {} is not one of the allowed values",
+                path, value
+            ));
+        }
+    }
+
+    if let Some(expected) = schema.get("const") {
+        if !same_value(expected, value) {
+            errors.push(format!("{}: expected {}", path, expected));
+        }
+    }
+
+    if let Some(number) = as_number(value) {
+        validate_number(schema, number, path, errors);
+    }
+
+    match value {
+        Bson::String(string) => validate_string(schema, string, path, errors),
+        Bson::Array(items) => validate_array(schema, items, path, errors),
+        Bson::Document(doc) => validate_document(schema, doc, path, errors),
+        _ => {}
+    }
+
+    if let Some(Bson::Array(schemas)) = schema.get("allOf") {
+        for schema in schemas {
+            validate_at(schema, value, path, errors);
+        }
+    }
+
+    if let Some(Bson::Array(schemas)) = schema.get("anyOf") {
+        if !schemas.iter().any(|schema| matches(schema, value, path)) {
+            errors.push(format!(
+                "{}: does not match any of the schemas of anyOf",
+                path
+            ));
+        }
+    }
+
+    if let Some(Bson::Array(schemas)) = schema.get("oneOf") {
+        let matching = schemas
+            .iter()
+            .filter(|schema| matches(schema, value, path))
+            .count();
+
+        if matching != 1 {
+            errors.push(format!(
+                "{}: matches {} of the schemas of oneOf instead of one",
+                path, matching
+            ));
+        }
+    }
+
+    if let Some(schema) = schema.get("not") {
+        if matches(schema, value, path) {
+            errors.push(format!("{}: must not match the schema of not", path));
+        }
+    }
+}
+
+fn matches(schema: &Bson, value: &Bson, path: &str) -> bool {
+    let mut errors = Vec::new();
+    validate_at(schema, value, path, &mut errors);
+
+    errors.is_empty()
+}
+
+fn validate_number(schema: &Document, number: f64, path: &str, errors: &mut Vec<String>) {
+    let bound = |keyword| schema.get(keyword).and_then(as_number);
+
+    if let Some(minimum) = bound("minimum") {
+        if number < minimum {
+            errors.push(format!("{}: {} is less than {}", path, number, minimum));
+        }
+    }
+
+    if let Some(maximum) = bound("maximum") {
+        if number > maximum {
+            errors.push(format!("{}: {} is greater than {}", path, number, maximum));
+        }
+    }
+
+    if let Some(minimum) = bound("exclusiveMinimum") {
+        if number <= minimum {
+            errors.push(format!(
+                "{}: {} is not greater than {}",
+                path, number, minimum
+            ));
+        }
+    }
+
+    if let Some(maximum) = bound("exclusiveMaximum") {
+        if number >= maximum {
+            errors.push(format!("{}: {} is not less than {}", path, number, maximum));
+        }
+    }
+
+    if let Some(divisor) = bound("multipleOf") {
+        if !is_multiple(number, divisor) {
+            errors.push(format!(
+                "{}: {} is not a multiple of {}",
+                path, number, divisor
+            ));
+        }
+    }
+}
+
+/// Exact for the integers, with a tolerance for the fractions, 0.3 / 0.1 being 2.9999999999999996
+fn is_multiple(number: f64, divisor: f64) -> bool {
+    if number.fract() == 0.0 && divisor.fract() == 0.0 {
+        return number % divisor == 0.0;
+    }
+
+    let quotient = number / divisor;
+
+    (quotient - quotient.round()).abs() <= 1e-9 * quotient.abs().max(1.0)
+}
+
+fn validate_string(schema: &Document, string: &str, path: &str, errors: &mut Vec<String>) {
+    let length = string.chars().count();
+
+    if let Some(min) = limit(schema, "minLength") {
+        if length < min {
+            errors.push(format!("{}: shorter than {} characters", path, min));
+        }
+    }
+
+    if let Some(max) = limit(schema, "maxLength") {
+        if length > max {
+            errors.push(format!("{}: longer than {} characters", path, max));
+        }
+    }
+}
+
+fn validate_array(schema: &Document, items: &[Bson], path: &str, errors: &mut Vec<String>) {
+    if let Some(min) = limit(schema, "minItems") {
+        if items.len() < min {
+            errors.push(format!("{}: has less than {} items", path, min));
+        }
+    }
+
+    if let Some(max) = limit(schema, "maxItems") {
+        if items.len() > max {
+            errors.push(format!("{}: has more than {} items", path, max));
+        }
+    }
+
+    if let Some(Bson::Boolean(true)) = schema.get("uniqueItems") {
+        for (index, item) in items.iter().enumerate() {
+            if items[..index].iter().any(|other| same_value(other, item)) {
+                errors.push(format!("{}[{}]: is a duplicate", path, index));
+            }
+        }
+    }
+
+    if let Some(items_schema) = schema.get("items") {
+        for (index, item) in items.iter().enumerate() {
+            validate_at(items_schema, item, &format!("{}[{}]", path, index), errors);
+        }
+    }
+
+    if let Some(contains) = schema.get("contains") {
+        if !items.iter().any(|item| matches(contains, item, path)) {
+            errors.push(format!("{}: no item matches the schema of contains", path));
+        }
+    }
+}
+
+fn validate_document(schema: &Document, doc: &Document, path: &str, errors: &mut Vec<String>) {
+    if let Some(min) = limit(schema, "minProperties") {
+        if doc.len() < min {
+            errors.push(format!("{}: has less than {} fields", path, min));
+        }
+    }
+
+    if let Some(max) = limit(schema, "maxProperties") {
+        if doc.len() > max {
+            errors.push(format!("{}: has more than {} fields", path, max));
+        }
+    }
+
+    if let Some(Bson::Array(required)) = schema.get("required") {
+        for name in required.iter().filter_map(|name| name.as_str()) {
+            if !doc.contains_key(name) {
+                errors.push(format!("{}.{}: is required", path, name));
+            }
+        }
+    }
+
+    let properties = match schema.get("properties") {
+        Some(Bson::Document(properties)) => Some(properties),
+        _ => None,
+    };
+
+    for (name, value) in doc {
+        let at = format!("{}.{}", path, name);
+
+        match properties.and_then(|properties| properties.get(name)) {
+            Some(property) => validate_at(property, value, &at, errors),
+            None => {
+                if let Some(additional) = schema.get("additionalProperties") {
+                    match additional {
+                        Bson::Boolean(false) => {
+                            errors.push(format!("{}: is not an allowed field", at))
+                        }
+                        additional => validate_at(additional, value, &at, errors),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn type_names(value: &Bson) -> Option<Vec<String>> {
+    match value {
+        Bson::String(name) => Some(vec![name.clone()]),
+        Bson::Array(names) => names
+            .iter()
+            .map(|name| name.as_str().map(|name| name.to_string()))
+            .collect(),
+        _ => None,
+    }
+}
+
+fn json_type_matches(name: &str, value: &Bson) -> bool {
+    match name {
+        "null" => matches!(value, Bson::Null),
+        "boolean" => matches!(value, Bson::Boolean(_)),
+        "object" => matches!(value, Bson::Document(_)),
+        "array" => matches!(value, Bson::Array(_)),
+        "string" => matches!(value, Bson::String(_)),
+        "number" => is_number(value),
+        "integer" => match value {
+            Bson::Int32(_) | Bson::Int64(_) => true,
+            Bson::Double(number) => number.fract() == 0.0,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn bson_type_matches(name: &str, value: &Bson) -> bool {
+    if name == "number" {
+        return is_number(value);
+    }
+
+    type_name(value) == name
+}
+
+fn is_number(value: &Bson) -> bool {
+    matches!(
+        value,
+        Bson::Double(_) | Bson::Int32(_) | Bson::Int64(_) | Bson::Decimal128(_)
+    )
+}
+
+/// The BSON type of a value, as named by the `bsonType` keyword
+pub fn type_name(value: &Bson) -> &'static str {
+    match value {
+        Bson::Double(_) => "double",
+        Bson::String(_) => "string",
+        Bson::Array(_) => "array",
+        Bson::Document(_) => "object",
+        Bson::Boolean(_) => "bool",
+        Bson::Null => "null",
+        Bson::RegularExpression(_) => "regex",
+        Bson::JavaScriptCode(_) => "javascript",
+        Bson::JavaScriptCodeWithScope(_) => "javascriptWithScope",
+        Bson::Int32(_) => "int",
+        Bson::Int64(_) => "long",
+        Bson::Timestamp(_) => "timestamp",
+        Bson::Binary(_) => "binData",
+        Bson::ObjectId(_) => "objectId",
+        Bson::DateTime(_) => "date",
+        Bson::Symbol(_) => "symbol",
+        Bson::Decimal128(_) => "decimal",
+        Bson::Undefined => "undefined",
+        Bson::MaxKey => "maxKey",
+        Bson::MinKey => "minKey",
+        Bson::DbPointer(_) => "dbPointer",
+    }
+}
+
+/// Decimal128 values cannot be compared, their bounds are not checked
+fn as_number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Double(number) => Some(*number),
+        Bson::Int32(number) => Some(*number as f64),
+        Bson::Int64(number) => Some(*number as f64),
+        _ => None,
+    }
+}
+
+fn limit(schema: &Document, keyword: &str) -> Option<usize> {
+    schema
+        .get(keyword)
+        .and_then(as_number)
+        .map(|limit| limit as usize)
+}
+
+/// Compares the numbers by value, so `1` (int64 in RBQL) equals a stored int32 `1`
+fn same_value(a: &Bson, b: &Bson) -> bool {
+    match (as_number(a), as_number(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::{bson, doc};
+
+    fn errors(schema: Document, value: Bson) -> Vec<String> {
+        check_schema(&schema).unwrap();
+        validate(&schema, &value)
+    }
+
+    #[test]
+    fn refuses_the_unsupported_keywords() {
+        for keyword in UNSUPPORTED {
+            let error = check_schema(&doc! { keyword: {} }).unwrap_err();
+
+            assert!(error.contains("is not supported"), "{}", error);
+        }
+
+        let error = check_schema(&doc! {
+            "properties": { "tags": { "prefixItems": [] } }
+        })
+        .unwrap_err();
+
+        assert_eq!(error, "$.properties.tags: prefixItems is not supported");
+    }
+
+    #[test]
+    fn refuses_the_invalid_keyword_values() {
+        for schema in [
+            doc! { "type": "text" },
+            doc! { "bsonType": ["int", "float"] },
+            doc! { "minimum": "1" },
+            doc! { "multipleOf": 0 },
+            doc! { "minLength": -1 },
+            doc! { "maxItems": 1.5 },
+            doc! { "uniqueItems": 1 },
+            doc! { "enum": "a" },
+            doc! { "required": ["a", 1] },
+            doc! { "properties": [] },
+            doc! { "anyOf": [] },
+            doc! { "items": 1 },
+        ] {
+            assert!(check_schema(&schema).is_err(), "{}", schema);
+        }
+
+        assert!(check_schema(&doc! { "title": "a user", "type": ["string", "null"] }).is_ok());
+    }
+
+    #[test]
+    fn checks_the_types() {
+        assert!(errors(doc! { "type": "integer" }, Bson::Double(2.0)).is_empty());
+        assert!(errors(doc! { "type": ["string", "null"] }, Bson::Null).is_empty());
+        assert!(errors(doc! { "bsonType": "long" }, Bson::Int64(1)).is_empty());
+        assert!(errors(doc! { "bsonType": "number" }, Bson::Int32(1)).is_empty());
+
+        assert_eq!(
+            errors(doc! { "type": "integer" }, Bson::Double(2.5)),
+            vec!["$: expected integer, found double"]
+        );
+        assert_eq!(
+            errors(doc! { "bsonType": "int" }, Bson::Int64(1)),
+            vec!["$: expected int, found long"]
+        );
+    }
+
+    #[test]
+    fn checks_the_numbers() {
+        let schema = doc! { "minimum": 1, "maximum": 10 };
+        assert!(errors(schema.clone(), Bson::Int32(10)).is_empty());
+        assert_eq!(errors(schema, Bson::Int32(0)), vec!["$: 0 is less than 1"]);
+
+        let schema = doc! { "exclusiveMinimum": 1, "exclusiveMaximum": 10 };
+        assert_eq!(errors(schema.clone(), Bson::Int32(1)).len(), 1);
+        assert_eq!(errors(schema, Bson::Int32(10)).len(), 1);
+    }
+
+    #[test]
+    fn checks_the_multiples() {
+        assert!(errors(doc! { "multipleOf": 0.1 }, Bson::Double(0.3)).is_empty());
+        assert!(errors(doc! { "multipleOf": 0.01 }, Bson::Double(19.99)).is_empty());
+        assert!(errors(doc! { "multipleOf": 3 }, Bson::Int64(9_007_199_254_740_990)).is_empty());
+
+        assert_eq!(
+            errors(doc! { "multipleOf": 0.1 }, Bson::Double(0.35)),
+            vec!["$: 0.35 is not a multiple of 0.1"]
+        );
+        assert_eq!(errors(doc! { "multipleOf": 2 }, Bson::Int32(7)).len(), 1);
+    }
+
+    #[test]
+    fn checks_the_strings_and_the_arrays() {
+        let schema = doc! { "minLength": 2, "maxLength": 3 };
+        assert!(errors(schema.clone(), bson!("été")).is_empty());
+        assert_eq!(
+            errors(schema, bson!("a")),
+            vec!["$: shorter than 2 characters"]
+        );
+
+        let schema = doc! {
+            "minItems": 1,
+            "maxItems": 3,
+            "uniqueItems": true,
+            "items": { "type": "integer" },
+            "contains": { "minimum": 10 },
+        };
+
+        assert!(errors(schema.clone(), bson!([1, 10])).is_empty());
+        assert_eq!(
+            errors(schema.clone(), bson!([1, 2])),
+            vec!["$: no item matches the schema of contains"]
+        );
+        assert_eq!(
+            errors(schema.clone(), bson!([1, "a", 1])),
+            vec![
+                "$[2]: is a duplicate",
+                "$[1]: expected integer, found string"
+            ]
+        );
+        assert_eq!(
+            errors(schema, bson!([])),
+            vec![
+                "$: has less than 1 items",
+                "$: no item matches the schema of contains",
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_the_documents_with_their_paths() {
+        let schema = doc! {
+            "type": "object",
+            "required": ["name"],
+            "maxProperties": 2,
+            "properties": {
+                "name": { "type": "string" },
+                "address": {
+                    "properties": { "city": { "bsonType": "string" } },
+                    "additionalProperties": false,
+                },
+            },
+        };
+
+        assert!(errors(schema.clone(), bson!({ "name": "ann" })).is_empty());
+        assert_eq!(
+            errors(
+                schema.clone(),
+                bson!({ "address": { "city": 1, "zip": "1000" } })
+            ),
+            vec![
+                "$.name: is required",
+                "$.address.city: expected string, found int",
+                "$.address.zip: is not an allowed field",
+            ]
+        );
+        assert_eq!(
+            errors(schema, bson!({ "name": "ann", "a": 1, "b": 2 })),
+            vec!["$: has more than 2 fields"]
+        );
+
+        let schema = doc! { "additionalProperties": { "type": "integer" } };
+        assert_eq!(
+            errors(schema, bson!({ "a": 1, "b": "2" })),
+            vec!["$.b: expected integer, found string"]
+        );
+    }
+
+    #[test]
+    fn compares_the_numbers_by_value_in_enum_and_const() {
+        let schema = doc! { "enum": [1, 2.5, "a"] };
+
+        assert!(errors(schema.clone(), Bson::Int32(1)).is_empty());
+        assert!(errors(schema.clone(), Bson::Int64(1)).is_empty());
+        assert!(errors(schema.clone(), Bson::Double(1.0)).is_empty());
+        assert!(errors(schema.clone(), Bson::Double(2.5)).is_empty());
+        assert_eq!(
+            errors(schema, Bson::Int64(3)),
+            vec!["$: 3 is not one of the allowed values"]
+        );
+
+        assert!(errors(doc! { "const": Bson::Int64(7) }, Bson::Int32(7)).is_empty());
+        assert_eq!(errors(doc! { "const": "a" }, bson!("b")).len(), 1);
+    }
+
+    #[test]
+    fn combines_the_schemas() {
+        let schema = doc! {
+            "oneOf": [{ "type": "integer" }, { "minimum": 5 }],
+            "not": { "const": 3 },
+        };
+
+        assert!(errors(schema.clone(), Bson::Int32(1)).is_empty());
+        assert!(errors(schema.clone(), Bson::Double(5.5)).is_empty());
+        assert_eq!(
+            errors(schema.clone(), Bson::Int32(7)),
+            vec!["$: matches 2 of the schemas of oneOf instead of one"]
+        );
+        assert_eq!(
+            errors(schema, Bson::Int32(3)),
+            vec!["$: must not match the schema of not"]
+        );
+
+        let schema = doc! { "anyOf": [{ "type": "string" }, { "type": "null" }] };
+        assert!(errors(schema.clone(), Bson::Null).is_empty());
+        assert_eq!(
+            errors(schema, Bson::Int32(1)),
+            vec!["$: does not match any of the schemas of anyOf"]
+        );
+
+        let schema = doc! { "allOf": [{ "minimum": 1 }, { "maximum": 2 }] };
+        assert_eq!(
+            errors(schema, Bson::Int32(3)),
+            vec!["$: 3 is greater than 2"]
+        );
+
+        assert_eq!(
+            errors(doc! { "properties": { "a": false } }, bson!({ "a": 1 })),
+            vec!["$.a: no value is allowed"]
+        );
+    }
+}
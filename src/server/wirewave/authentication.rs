@@ -1,10 +1,19 @@
-use rustbase_scram::{AuthenticationProvider, AuthenticationStatus, PasswordInfo, ScramServer};
+use bson::Bson;
+use rand::Rng;
+use rustbase_scram::{
+    hash_password, AuthenticationProvider, AuthenticationStatus, PasswordInfo, ScramServer,
+};
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, RwLock};
+use std::collections::HashSet;
+use std::fmt;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex, RwLock};
 
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 use super::server;
+use crate::config::{schema, spec};
+use crate::server::route;
 
 use server::read_socket;
 use server::{ResHeader, Response, Status};
@@ -12,10 +21,17 @@ use server::{ResHeader, Response, Status};
 #[derive(Clone)]
 pub struct DefaultAuthenticationProvider {
     pub dustdata: Arc<RwLock<dustdata::DustData>>,
+    /// The users already reported with a weaker hash, shared by the clones
+    reported: Arc<Mutex<HashSet<String>>>,
 }
 
 impl AuthenticationProvider for DefaultAuthenticationProvider {
     fn get_password_for(&self, username: &str) -> Option<PasswordInfo> {
+        // the quotas share the system database, they are not users
+        if route::is_catalog_key(username) {
+            return None;
+        }
+
         let dustdata = self.dustdata.read().unwrap();
 
         let user = dustdata.get(username).unwrap();
@@ -28,7 +44,7 @@ impl AuthenticationProvider for DefaultAuthenticationProvider {
 
             Some(PasswordInfo::new(
                 hashed_password.to_vec(),
-                4096,
+                stored_iterations(user),
                 salt.to_vec(),
             ))
         } else {
@@ -37,6 +53,206 @@ impl AuthenticationProvider for DefaultAuthenticationProvider {
     }
 }
 
+impl DefaultAuthenticationProvider {
+    pub fn new(dustdata: Arc<RwLock<dustdata::DustData>>) -> Self {
+        Self {
+            dustdata,
+            reported: Arc::default(),
+        }
+    }
+
+    pub fn has_user(&self, username: &str) -> bool {
+        if route::is_catalog_key(username) {
+            return false;
+        }
+
+        let dustdata = self.dustdata.read().unwrap();
+
+        matches!(dustdata.get(username), Ok(Some(_)))
+    }
+
+    /// Checks a password sent in clear, e.g. with HTTP Basic auth. SCRAM never needs this.
+    pub fn verify_password(&self, username: &str, password: &str) -> bool {
+        let dustdata = self.dustdata.read().unwrap();
+
+        let user = match dustdata.get(username) {
+            Ok(Some(user)) => user,
+            _ => return false,
+        };
+
+        let user = match user.as_document() {
+            Some(user) => user,
+            None => return false,
+        };
+
+        let (stored, salt) = match (
+            user.get_binary_generic("password"),
+            user.get_binary_generic("salt"),
+        ) {
+            (Ok(stored), Ok(salt)) => (stored, salt),
+            _ => return false,
+        };
+
+        let iterations = NonZeroU32::new(stored_iterations(user).max(1) as u32).unwrap();
+        let hashed = hash_password(password, iterations, salt);
+
+        // compares every byte, so the time taken does not tell where they differ
+        hashed.len() == stored.len()
+            && hashed
+                .iter()
+                .zip(stored.iter())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// The iterations the password of `username` was hashed with
+    pub fn iterations_of(&self, username: &str) -> Option<u16> {
+        let dustdata = self.dustdata.read().unwrap();
+        let user = dustdata.get(username).ok()??;
+
+        Some(stored_iterations(user.as_document()?))
+    }
+
+    /// Hashes the password again when it was hashed with fewer `iterations`. Only possible
+    /// once the password was verified in clear, e.g. with HTTP Basic auth.
+    pub fn rehash(&self, username: &str, password: &str, iterations: u16) {
+        let mut dustdata = self.dustdata.write().unwrap();
+
+        let mut user = match dustdata.get(username) {
+            Ok(Some(Bson::Document(user))) => user,
+            _ => return,
+        };
+
+        let previous = stored_iterations(&user);
+
+        if previous >= iterations {
+            return;
+        }
+
+        // the permission and the other fields are kept
+        user.extend(new_credentials(password, iterations));
+
+        match dustdata.update(username, Bson::Document(user)) {
+            Ok(()) => {
+                self.reported.lock().unwrap().remove(username);
+
+                println!(
+                    "[Auth] the password of {} is rehashed from {} to {} iterations",
+                    username, previous, iterations
+                );
+            }
+            Err(e) => println!(
+                "[Auth] cannot rehash the password of {}: {:?}",
+                username, e.code
+            ),
+        }
+    }
+
+    /// Logs once that a user logged in with SCRAM has a weaker hash, which SCRAM cannot
+    /// replace as the password is never sent. `show user` lists these users.
+    pub fn report_weak_hash(&self, username: &str, iterations: u16) {
+        let previous = match self.iterations_of(username) {
+            Some(previous) if previous < iterations => previous,
+            _ => return,
+        };
+
+        if self.reported.lock().unwrap().insert(username.to_string()) {
+            println!(
+                "[Auth] the password of {} is hashed with {} iterations instead of {}, it is rehashed at its next HTTP Basic login or when it is changed",
+                username, previous, iterations
+            );
+        }
+    }
+}
+
+/// The users created before the iterations were stored used 4096
+pub const LEGACY_ITERATIONS: u16 = 4096;
+
+/// The iterations a user document was hashed with
+pub fn stored_iterations(user: &bson::Document) -> u16 {
+    user.get_i32("iterations")
+        .ok()
+        .and_then(|iterations| u16::try_from(iterations).ok())
+        .unwrap_or(LEGACY_ITERATIONS)
+}
+
+/// The iterations used to hash the new passwords
+pub fn configured_iterations(auth: Option<&schema::Auth>) -> u16 {
+    auth.and_then(|auth| auth.iterations)
+        .unwrap_or(spec::DEFAULT_SCRAM_ITERATIONS)
+}
+
+/// The salted password of a user, as stored in the system database
+pub fn new_credentials(password: &str, iterations: u16) -> bson::Document {
+    let salt = rand::thread_rng().gen::<[u8; 32]>().to_vec();
+    let hashed_password = hash_password(
+        password,
+        NonZeroU32::new(iterations.max(1) as u32).unwrap(),
+        &salt,
+    )
+    .to_vec();
+
+    bson::doc! {
+        "iterations": iterations as i32,
+        "password": bson::Binary {
+            subtype: bson::spec::BinarySubtype::Generic,
+            bytes: hashed_password,
+        },
+        "salt": bson::Binary {
+            subtype: bson::spec::BinarySubtype::Generic,
+            bytes: salt,
+        },
+    }
+}
+
+/// Authenticates a new connection with the configured mechanism, the users being looked
+/// up through `provider`
+pub async fn authenticate<P, IO>(
+    auth_type: &schema::AuthType,
+    provider: P,
+    stream: &mut IO,
+) -> Result<String, AuthFailure>
+where
+    P: AuthenticationProvider,
+    IO: AsyncWrite + AsyncRead + Unpin,
+{
+    match auth_type {
+        schema::AuthType::ScramSha256 => {
+            authentication_challenge(ScramServer::new(provider), stream).await
+        }
+    }
+}
+
+/// Why a client could not authenticate
+#[derive(Debug)]
+pub enum AuthFailure {
+    UnknownUser(String),
+    BadProof(String),
+    NotAuthorized(String),
+    Malformed(String),
+    ConnectionLost(String),
+}
+
+impl fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthFailure::UnknownUser(username) => write!(f, "unknown user {}", username),
+            AuthFailure::BadProof(username) => write!(f, "bad proof for {}", username),
+            AuthFailure::NotAuthorized(username) => write!(f, "{} is not authorized", username),
+            AuthFailure::Malformed(e) => write!(f, "malformed message: {}", e),
+            AuthFailure::ConnectionLost(e) => write!(f, "connection lost: {}", e),
+        }
+    }
+}
+
+fn scram_failure(error: rustbase_scram::Error) -> AuthFailure {
+    match error {
+        rustbase_scram::Error::InvalidUser(username) => AuthFailure::UnknownUser(username),
+        rustbase_scram::Error::Protocol(kind) => AuthFailure::Malformed(format!("{:?}", kind)),
+        e => AuthFailure::Malformed(e.to_string()),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct AuthRequest {
     challenge: String,
@@ -63,55 +279,120 @@ fn process_authentication_request(buffer: &[u8]) -> Result<AuthRequest, Response
 }
 
 #[allow(clippy::unused_io_amount)]
-pub async fn authentication_challenge<IO>(
-    scram_server: ScramServer<DefaultAuthenticationProvider>,
+async fn authentication_challenge<P, IO>(
+    scram_server: ScramServer<P>,
     stream: &mut IO,
-) -> (AuthenticationStatus, Option<String>)
+) -> Result<String, AuthFailure>
 where
+    P: AuthenticationProvider,
     IO: AsyncWrite + AsyncRead + Unpin,
 {
     let mut buffer = vec![0; 1028];
 
-    let client_first =
-        match process_authentication_request(&read_socket(stream, &mut buffer).await.unwrap()) {
-            Ok(request) => request.challenge,
-            Err(response) => {
-                stream
-                    .write_all(&bson::to_vec(&response).unwrap())
-                    .await
-                    .unwrap();
-                return (AuthenticationStatus::NotAuthenticated, None);
-            }
-        };
+    let client_first = read_challenge(stream, &mut buffer).await?;
 
-    let scram_first = scram_server.handle_client_first(&client_first).unwrap();
+    let scram_first = scram_server
+        .handle_client_first(&client_first)
+        .map_err(scram_failure)?;
 
     let (scram_server, server_first) = scram_first.server_first();
 
-    let username = scram_server.authcid;
-
-    stream.write_all(server_first.as_bytes()).await.unwrap();
-
-    let client_final =
-        match process_authentication_request(&read_socket(stream, &mut buffer).await.unwrap()) {
-            Ok(request) => request.challenge,
-            Err(response) => {
-                stream
-                    .write_all(&bson::to_vec(&response).unwrap())
-                    .await
-                    .unwrap();
-                return (
-                    AuthenticationStatus::NotAuthenticated,
-                    Some(username.to_string()),
-                );
-            }
-        };
+    let username = scram_server.authcid.to_string();
+
+    stream
+        .write_all(server_first.as_bytes())
+        .await
+        .map_err(|e| AuthFailure::ConnectionLost(e.to_string()))?;
 
-    let scram_server = scram_server.handle_client_final(&client_final).unwrap();
+    let client_final = read_challenge(stream, &mut buffer).await?;
+
+    let scram_server = scram_server
+        .handle_client_final(&client_final)
+        .map_err(scram_failure)?;
 
     let (status, server_final) = scram_server.server_final();
 
-    stream.write_all(server_final.as_bytes()).await.unwrap();
+    stream
+        .write_all(server_final.as_bytes())
+        .await
+        .map_err(|e| AuthFailure::ConnectionLost(e.to_string()))?;
+
+    match status {
+        AuthenticationStatus::Authenticated => Ok(username),
+        AuthenticationStatus::NotAuthenticated => Err(AuthFailure::BadProof(username)),
+        AuthenticationStatus::NotAuthorized => Err(AuthFailure::NotAuthorized(username)),
+    }
+}
+
+async fn read_challenge<IO>(stream: &mut IO, buffer: &mut [u8]) -> Result<String, AuthFailure>
+where
+    IO: AsyncWrite + AsyncRead + Unpin,
+{
+    let message = read_socket(stream, buffer)
+        .await
+        .map_err(|e| AuthFailure::ConnectionLost(e.to_string()))?;
+
+    if message.is_empty() {
+        return Err(AuthFailure::ConnectionLost(
+            "closed by the client".to_string(),
+        ));
+    }
+
+    match process_authentication_request(&message) {
+        Ok(request) => Ok(request.challenge),
+        Err(response) => {
+            let reason = response
+                .header
+                .messages
+                .clone()
+                .unwrap_or_default()
+                .join(", ");
 
-    (status, Some(username.to_string()))
+            stream
+                .write_all(&bson::to_vec(&response).unwrap())
+                .await
+                .ok();
+
+            Err(AuthFailure::Malformed(reason))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth(iterations: Option<u16>) -> schema::Auth {
+        schema::Auth {
+            enable_auth_bypass: None,
+            auth_type: None,
+            required: None,
+            admin: None,
+            iterations,
+        }
+    }
+
+    #[test]
+    fn stored_iterations_default_to_legacy() {
+        assert_eq!(stored_iterations(&bson::doc! {}), LEGACY_ITERATIONS);
+        assert_eq!(
+            stored_iterations(&bson::doc! { "iterations": 20_000 }),
+            20_000
+        );
+        assert_eq!(
+            stored_iterations(&bson::doc! { "iterations": 100_000 }),
+            LEGACY_ITERATIONS
+        );
+        assert_eq!(stored_iterations(&new_credentials("pw", 1000)), 1000);
+    }
+
+    #[test]
+    fn configured_iterations_default_to_spec() {
+        assert_eq!(configured_iterations(None), spec::DEFAULT_SCRAM_ITERATIONS);
+        assert_eq!(
+            configured_iterations(Some(&auth(None))),
+            spec::DEFAULT_SCRAM_ITERATIONS
+        );
+        assert_eq!(configured_iterations(Some(&auth(Some(20_000)))), 20_000);
+    }
 }
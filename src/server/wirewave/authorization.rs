@@ -24,6 +24,15 @@ impl UserPermission {
         }
     }
 
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserPermission::Read => "read",
+            UserPermission::Write => "write",
+            UserPermission::ReadAndWrite => "read_and_write",
+            UserPermission::Admin => "admin",
+        }
+    }
+
     pub fn from_i32(i: i32) -> Result<UserPermission, UserPermissionError> {
         match i {
             0 => Ok(UserPermission::Read),
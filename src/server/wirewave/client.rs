@@ -0,0 +1,150 @@
+use std::io;
+use std::time::Duration;
+
+use rustbase_scram::ScramClient;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use super::server::{read_socket, write_socket, ReqHeader, Request, Response, Type};
+
+const BUFFER_SIZE: usize = 8 * 1024;
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    Authentication(String),
+    BadResponse(String),
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "io error: {}", e),
+            ClientError::Authentication(e) => write!(f, "authentication failed: {}", e),
+            ClientError::BadResponse(e) => write!(f, "bad response: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+/// A minimal Wirewave client, used by the CLI and by server to server communication.
+pub struct Client<IO> {
+    stream: IO,
+}
+
+impl Client<TcpStream> {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, ClientError> {
+        let stream = TcpStream::connect(addr).await?;
+
+        Ok(Self { stream })
+    }
+
+    /// Whether an idle connection can still be used, i.e. the server has neither closed
+    /// it nor sent anything unrequested. Does not wait for the network.
+    pub async fn is_open(&self) -> bool {
+        let mut byte = [0u8; 1];
+
+        tokio::time::timeout(Duration::ZERO, self.stream.peek(&mut byte))
+            .await
+            .is_err()
+    }
+}
+
+impl<IO> Client<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Runs the SCRAM-SHA-256 handshake. Must be called before any request
+    /// when the server requires authentication.
+    pub async fn authenticate(
+        &mut self,
+        username: &str,
+        password: &str,
+    ) -> Result<(), ClientError> {
+        let mut buffer = vec![0; BUFFER_SIZE];
+
+        let scram = ScramClient::new(username, password, None);
+        let (scram, client_first) = scram.client_first();
+
+        self.stream.write_all(client_first.as_bytes()).await?;
+
+        let server_first = read_socket(&mut self.stream, &mut buffer).await?;
+        let server_first = String::from_utf8(server_first)
+            .map_err(|e| ClientError::Authentication(e.to_string()))?;
+
+        let scram = scram
+            .handle_server_first(&server_first)
+            .map_err(|e| ClientError::Authentication(e.to_string()))?;
+        let (scram, client_final) = scram.client_final();
+
+        self.stream.write_all(client_final.as_bytes()).await?;
+
+        let server_final = read_socket(&mut self.stream, &mut buffer).await?;
+        let server_final = String::from_utf8(server_final)
+            .map_err(|e| ClientError::Authentication(e.to_string()))?;
+
+        scram
+            .handle_server_final(&server_final)
+            .map_err(|e| ClientError::Authentication(e.to_string()))
+    }
+
+    pub async fn request(&mut self, request: &Request) -> Result<Response, ClientError> {
+        write_socket(&mut self.stream, request).await?;
+
+        self.read_response().await
+    }
+
+    /// Sends a query to the given database. `variables` are made available to the
+    /// query as `$name`, which keeps their BSON types intact.
+    pub async fn query(
+        &mut self,
+        database: &str,
+        query: &str,
+        variables: Option<bson::Document>,
+    ) -> Result<Response, ClientError> {
+        let mut body = bson::doc! {
+            "database": database,
+            "query": query,
+        };
+
+        if let Some(variables) = variables {
+            body.insert("variables", variables);
+        }
+
+        let request = Request {
+            header: ReqHeader {
+                type_: Type::Query,
+                auth: None,
+            },
+            body,
+        };
+
+        self.request(&request).await
+    }
+
+    pub async fn read_response(&mut self) -> Result<Response, ClientError> {
+        // every response is a single BSON document, prefixed by its length
+        let mut length = [0u8; 4];
+        self.stream.read_exact(&mut length).await?;
+
+        let size = i32::from_le_bytes(length);
+
+        if size < 5 {
+            return Err(ClientError::BadResponse(format!(
+                "invalid document length {}",
+                size
+            )));
+        }
+
+        let mut bytes = vec![0u8; size as usize];
+        bytes[..4].copy_from_slice(&length);
+        self.stream.read_exact(&mut bytes[4..]).await?;
+
+        bson::from_slice(&bytes).map_err(|e| ClientError::BadResponse(e.to_string()))
+    }
+}
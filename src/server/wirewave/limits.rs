@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use super::server::{Error, Status};
+use crate::config::schema::{Limits, RateLimit};
+
+/// Above this many buckets, the full ones are dropped: they are the same as new ones
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+/// Counts the open connections and the requests of every client, against `net.limits`.
+/// The limits are passed on each call, so a reload applies to the next connections.
+#[derive(Debug, Default)]
+pub struct Limiter {
+    connections: Mutex<Connections>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[derive(Debug, Default)]
+struct Connections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    per_user: HashMap<String, usize>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// An open connection, counted until it is dropped
+pub struct ConnectionSlot {
+    limiter: Arc<Limiter>,
+    ip: Option<IpAddr>,
+    username: Option<String>,
+}
+
+impl Limiter {
+    /// Counts a new connection, unless the server or its address is at the limit
+    pub fn connect(
+        self: &Arc<Self>,
+        limits: &Limits,
+        ip: Option<IpAddr>,
+    ) -> Result<ConnectionSlot, Error> {
+        let mut connections = self.connections.lock().unwrap();
+
+        if let Some(max) = limits.max_connections {
+            if connections.total >= max {
+                return Err(too_many_connections(format!(
+                    "the server is at its limit of {} connections",
+                    max
+                )));
+            }
+        }
+
+        if let (Some(ip), Some(max)) = (ip, limits.max_connections_per_ip) {
+            if connections.per_ip.get(&ip).copied().unwrap_or(0) >= max {
+                return Err(too_many_connections(format!(
+                    "{} is at its limit of {} connections",
+                    ip, max
+                )));
+            }
+        }
+
+        connections.total += 1;
+
+        if let Some(ip) = ip {
+            *connections.per_ip.entry(ip).or_default() += 1;
+        }
+
+        Ok(ConnectionSlot {
+            limiter: self.clone(),
+            ip,
+            username: None,
+        })
+    }
+
+    /// Takes a token from the bucket of `client`, a user or an address
+    pub fn allow_request(&self, limits: &Limits, client: &str) -> Result<(), Error> {
+        let rate_limit = match &limits.rate_limit {
+            Some(rate_limit) => rate_limit,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let capacity = burst(rate_limit);
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > MAX_IDLE_BUCKETS {
+            buckets.retain(|_, bucket| refill(bucket, rate_limit, now) < capacity);
+        }
+
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+
+        bucket.tokens = refill(bucket, rate_limit, now);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            return Err(Error {
+                message: format!(
+                    "{} is limited to {} requests per second",
+                    client, rate_limit.requests_per_second
+                ),
+                query_message: None,
+                status: Status::RateLimited,
+            });
+        }
+
+        bucket.tokens -= 1.0;
+
+        Ok(())
+    }
+}
+
+impl ConnectionSlot {
+    /// Counts the connection for its user once authenticated
+    pub fn authenticated(&mut self, limits: &Limits, username: &str) -> Result<(), Error> {
+        let mut connections = self.limiter.connections.lock().unwrap();
+
+        if let Some(max) = limits.max_connections_per_user {
+            if connections.per_user.get(username).copied().unwrap_or(0) >= max {
+                return Err(too_many_connections(format!(
+                    "{} is at its limit of {} connections",
+                    username, max
+                )));
+            }
+        }
+
+        *connections
+            .per_user
+            .entry(username.to_string())
+            .or_default() += 1;
+        self.username = Some(username.to_string());
+
+        Ok(())
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = self.limiter.connections.lock().unwrap();
+
+        connections.total -= 1;
+
+        if let Some(ip) = &self.ip {
+            release(&mut connections.per_ip, ip);
+        }
+
+        if let Some(username) = &self.username {
+            release(&mut connections.per_user, username);
+        }
+    }
+}
+
+fn release<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+fn burst(rate_limit: &RateLimit) -> f64 {
+    rate_limit
+        .burst
+        .unwrap_or(rate_limit.requests_per_second)
+        .max(1) as f64
+}
+
+/// The tokens of `bucket` at `now`, refilled at `requests_per_second`
+fn refill(bucket: &Bucket, rate_limit: &RateLimit, now: Instant) -> f64 {
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+
+    (bucket.tokens + elapsed * rate_limit.requests_per_second as f64).min(burst(rate_limit))
+}
+
+fn too_many_connections(message: String) -> Error {
+    Error {
+        message,
+        query_message: None,
+        status: Status::TooManyConnections,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limits(requests_per_second: u32, burst: Option<u32>) -> Limits {
+        Limits {
+            rate_limit: Some(RateLimit {
+                requests_per_second,
+                burst,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn bucket_allows_the_burst_then_limits() {
+        let limiter = Limiter::default();
+        let limits = limits(1, Some(3));
+
+        for _ in 0..3 {
+            assert!(limiter.allow_request(&limits, "alice").is_ok());
+        }
+
+        let error = limiter.allow_request(&limits, "alice").unwrap_err();
+        assert!(matches!(error.status, Status::RateLimited));
+
+        // Each client has its own bucket
+        assert!(limiter.allow_request(&limits, "bob").is_ok());
+    }
+
+    #[test]
+    fn bucket_without_rate_limit_is_unlimited() {
+        let limiter = Limiter::default();
+
+        for _ in 0..100 {
+            assert!(limiter.allow_request(&Limits::default(), "alice").is_ok());
+        }
+    }
+
+    #[test]
+    fn bucket_refills_up_to_the_burst() {
+        let rate_limit = RateLimit {
+            requests_per_second: 10,
+            burst: Some(5),
+        };
+        let now = Instant::now();
+        let bucket = |elapsed_ms| Bucket {
+            tokens: 0.0,
+            updated: now - Duration::from_millis(elapsed_ms),
+        };
+
+        assert_eq!(refill(&bucket(0), &rate_limit, now), 0.0);
+        assert!((refill(&bucket(200), &rate_limit, now) - 2.0).abs() < 1e-9);
+        assert_eq!(refill(&bucket(60_000), &rate_limit, now), 5.0);
+    }
+
+    #[test]
+    fn burst_defaults_to_the_rate() {
+        let rate = |requests_per_second, burst| RateLimit {
+            requests_per_second,
+            burst,
+        };
+
+        assert_eq!(burst(&rate(7, None)), 7.0);
+        assert_eq!(burst(&rate(7, Some(2))), 2.0);
+        assert_eq!(burst(&rate(0, None)), 1.0);
+    }
+}
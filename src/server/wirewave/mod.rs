@@ -1,3 +1,6 @@
-mod authentication;
+pub mod authentication;
 pub mod authorization;
+pub mod client;
+pub mod limits;
 pub mod server;
+pub mod websocket;
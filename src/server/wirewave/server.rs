@@ -1,27 +1,37 @@
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 
+use std::fmt;
 use std::fs::File;
 use std::future::Future;
 use std::io::{self, BufReader};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::{TcpListener, ToSocketAddrs};
-
-use rustls_pemfile::{certs, pkcs8_private_keys};
-use tokio_rustls::rustls::{self, Certificate, PrivateKey};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+
+use rustls_pemfile::{certs, read_all, Item};
+use tokio_rustls::rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient,
+};
+use tokio_rustls::rustls::{self, Certificate, PrivateKey, RootCertStore};
 use tokio_rustls::TlsAcceptor;
 
-use rustbase_scram::{AuthenticationStatus, ScramServer};
-
 use super::authentication;
+use super::limits::{ConnectionSlot, Limiter};
+use super::websocket::WebSocketIo;
 use crate::config;
 
-use authentication::authentication_challenge;
+use authentication::{authenticate, AuthFailure, DefaultAuthenticationProvider};
 
-use config::schema::Tls;
+use config::schema::{AuthType, ClientAuth, Limits, Tls};
 
 fn load_certs(path: &String) -> io::Result<Vec<Certificate>> {
     certs(&mut BufReader::new(File::open(path)?))
@@ -29,18 +39,40 @@ fn load_certs(path: &String) -> io::Result<Vec<Certificate>> {
         .map(|mut certs| certs.drain(..).map(Certificate).collect())
 }
 
+/// Loads the PKCS#8, RSA (PKCS#1) and EC (SEC1) keys of a PEM file
 fn load_keys(path: &String) -> io::Result<Vec<PrivateKey>> {
-    pkcs8_private_keys(&mut BufReader::new(File::open(path)?))
+    read_all(&mut BufReader::new(File::open(path)?))
         .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key"))
-        .map(|mut keys| keys.drain(..).map(PrivateKey).collect())
+        .map(|items| {
+            items
+                .into_iter()
+                .filter_map(|item| match item {
+                    Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => {
+                        Some(PrivateKey(key))
+                    }
+                    _ => None,
+                })
+                .collect()
+        })
 }
 
 const BUFFER_SIZE: usize = 8 * 1024;
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
 
 #[async_trait]
 pub trait Wirewave: Send + Sync + 'static {
     async fn request(&self, request: Request, username: Option<String>) -> Result<Response, Error>;
-    async fn new_connection(&self, username: Option<String>, addr: SocketAddr);
+    async fn subscribe(
+        &self,
+        request: Request,
+        username: Option<String>,
+    ) -> Result<mpsc::Receiver<Response>, Error>;
+    async fn cluster(
+        &self,
+        request: Request,
+        username: Option<String>,
+    ) -> Result<mpsc::Receiver<Response>, Error>;
+    async fn new_connection(&self, username: Option<String>, peer: Peer);
     async fn server_context(&self) -> ServerContext;
 }
 
@@ -59,111 +91,697 @@ struct _Inner<T>(Arc<T>);
 
 pub struct Server<T: Wirewave> {
     svc: WirewaveServer<T>,
-    auth_provider: authentication::DefaultAuthenticationProvider,
+    auth_provider: DefaultAuthenticationProvider,
 }
 
 impl<T: Wirewave> Server<T> {
     pub fn new(svc: WirewaveServer<T>, system_db: Arc<RwLock<dustdata::DustData>>) -> Self {
-        let auth_provider = authentication::DefaultAuthenticationProvider {
-            dustdata: system_db,
-        };
+        let auth_provider = DefaultAuthenticationProvider::new(system_db);
 
         Self { svc, auth_provider }
     }
 
-    pub async fn serve<A: ToSocketAddrs>(self, addr: A) {
-        let listener = TcpListener::bind(addr).await.unwrap();
+    /// Serves every endpoint until the shutdown signal, then drains the connections
+    pub async fn serve(self, endpoints: Vec<Endpoint>, shutdown: Shutdown) {
+        let (sender, mut accepted) = mpsc::channel(64);
+        let mut listeners = JoinSet::new();
+
+        for (index, endpoint) in endpoints.iter().enumerate() {
+            let bound = match &endpoint.address {
+                Address::Tcp(address) => TcpListener::bind(address).await.map(|listener| {
+                    listeners.spawn(accept_tcp(listener, index, Connection::Tcp, sender.clone()))
+                }),
+
+                Address::WebSocket(address) => TcpListener::bind(address).await.map(|listener| {
+                    listeners.spawn(accept_tcp(
+                        listener,
+                        index,
+                        Connection::WebSocket,
+                        sender.clone(),
+                    ))
+                }),
+
+                #[cfg(unix)]
+                Address::Unix { path, mode } => bind_unix(path, *mode)
+                    .map(|listener| listeners.spawn(accept_unix(listener, index, sender.clone()))),
+
+                #[cfg(not(unix))]
+                Address::Unix { .. } => Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "unix sockets are not supported on this platform",
+                )),
+            };
+
+            if let Err(e) = bound {
+                println!("[Wirewave] cannot listen on {}: {}", endpoint.address, e);
+                std::process::exit(1);
+            }
+        }
+
+        drop(sender);
+
+        let mut signal = shutdown.signal.clone();
+        let mut connections = JoinSet::new();
 
         loop {
-            let (mut stream, addr) = listener.accept().await.unwrap();
+            let (connection, index) = tokio::select! {
+                Some(accepted) = accepted.recv() => accepted,
+                _ = connections.join_next(), if !connections.is_empty() => continue,
+                _ = signal.changed() => break,
+            };
 
-            let svc = self.svc.clone();
-            let server_context = self.svc.inner.0.server_context().await;
+            let endpoint = &endpoints[index];
 
-            let server = ScramServer::new(self.auth_provider.clone());
-            tokio::spawn(async move {
-                let username = if server_context.require_authentication {
-                    let (status, username) = authentication_challenge(server, &mut stream).await;
+            let mut server_context = self.svc.inner.0.server_context().await;
 
-                    if status != AuthenticationStatus::Authenticated {
-                        println!("[Wirewave] authentication failed: {:?}", status);
-                        stream.shutdown().await.unwrap();
+            if let Some(required) = endpoint.auth_required {
+                server_context.require_authentication = required;
+            }
 
-                        return;
-                    }
+            // the acceptor can be replaced while serving, e.g. when the certificate is renewed
+            let acceptor = endpoint.tls.as_ref().map(|tls| tls.borrow().clone());
+
+            // counted before the handshakes, until the connection ends
+            let slot = match server_context
+                .limiter
+                .connect(&server_context.limits, connection.ip())
+            {
+                Ok(slot) => slot,
+                Err(error) => {
+                    connections.spawn(refuse_connection(connection, acceptor.is_some(), error));
+                    continue;
+                }
+            };
 
-                    username
-                } else {
-                    None
-                };
+            connections.spawn(accept_connection(
+                self.svc.clone(),
+                self.auth_provider.clone(),
+                server_context,
+                slot,
+                connection,
+                acceptor,
+                endpoint.peer_credentials,
+                shutdown.signal.clone(),
+            ));
+        }
 
-                svc.inner.0.new_connection(username.clone(), addr).await;
+        // stops accepting, the listeners are closed with their tasks
+        listeners.shutdown().await;
 
-                handle_connection(stream, move |request| {
-                    let svc = svc.clone();
-                    let username = username.clone();
-                    async move { svc.inner.0.request(request, username).await }
-                })
-                .await;
-            });
+        for endpoint in &endpoints {
+            if let Address::Unix { path, .. } = &endpoint.address {
+                std::fs::remove_file(path).ok();
+            }
         }
+
+        drain(connections, shutdown.timeout).await;
     }
+}
 
-    pub async fn serve_tls<A: ToSocketAddrs>(self, addr: A, tls_config: &Tls) {
-        let certs = load_certs(&tls_config.ca_file).unwrap();
-        let keys = load_keys(&tls_config.pem_key_file).unwrap();
+/// An address the server listens on, and how its connections are served
+pub struct Endpoint {
+    pub address: Address,
+    pub tls: Option<watch::Receiver<TlsAcceptor>>,
+    /// Overrides whether the clients of this endpoint must authenticate
+    pub auth_required: Option<bool>,
+    /// Authenticates the clients of a unix socket as the user named after their OS user
+    pub peer_credentials: bool,
+}
 
-        let server_tls_config = rustls::ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(certs, keys[0].clone())
-            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
-            .unwrap();
+pub enum Address {
+    Tcp(String),
+    /// Wirewave in the binary messages of a WebSocket, e.g. for the browsers
+    WebSocket(String),
+    Unix {
+        path: PathBuf,
+        mode: Option<u32>,
+    },
+}
 
-        let acceptor = TlsAcceptor::from(Arc::new(server_tls_config));
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Tcp(address) => write!(f, "rustbase://{}", address),
+            Address::WebSocket(address) => write!(f, "ws://{}", address),
+            Address::Unix { path, .. } => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
 
-        let listener = TcpListener::bind(addr).await.unwrap();
+/// Where a connection comes from
+#[derive(Debug, Clone)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    WebSocket(SocketAddr),
+    Unix { uid: Option<u32> },
+}
 
-        loop {
-            let (stream, addr) = listener.accept().await.unwrap();
+impl Peer {
+    /// The address of a network client
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Tcp(addr) | Peer::WebSocket(addr) => Some(addr.ip()),
+            Peer::Unix { .. } => None,
+        }
+    }
+
+    /// Whether the client runs on this machine. A WebSocket client never is: any web page
+    /// open in a browser of this machine can connect to a loopback address.
+    pub fn is_local(&self) -> bool {
+        match self {
+            Peer::Tcp(addr) => addr.ip().is_loopback(),
+            Peer::WebSocket(_) => false,
+            Peer::Unix { .. } => true,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::WebSocket(addr) => write!(f, "{} (WebSocket)", addr),
+            Peer::Unix { uid: Some(uid) } => write!(f, "unix socket (uid {})", uid),
+            Peer::Unix { uid: None } => write!(f, "unix socket"),
+        }
+    }
+}
+
+enum Connection {
+    Tcp(TcpStream, SocketAddr),
+    WebSocket(TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Connection {
+    fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Connection::Tcp(_, addr) | Connection::WebSocket(_, addr) => Some(addr.ip()),
+            #[cfg(unix)]
+            Connection::Unix(_) => None,
+        }
+    }
+}
 
-            let svc = self.svc.clone();
+/// Closes a connection over the limits. Only the clients without a handshake (plain TCP and
+/// unix sockets) are told why, the others would have to complete it first.
+async fn refuse_connection(connection: Connection, tls: bool, error: Error) {
+    match connection {
+        Connection::Tcp(stream, addr) if !tls => refuse(stream, &Peer::Tcp(addr), error).await,
+        Connection::Tcp(_, addr) => println!("[Wirewave] refused {}: {}", addr, error.message),
+        Connection::WebSocket(_, addr) => println!(
+            "[Wirewave] refused {}: {}",
+            Peer::WebSocket(addr),
+            error.message
+        ),
+        #[cfg(unix)]
+        Connection::Unix(stream) => {
+            let uid = stream.peer_cred().ok().map(|cred| cred.uid());
+
+            refuse(stream, &Peer::Unix { uid }, error).await
+        }
+    }
+}
 
-            let server = ScramServer::new(self.auth_provider.clone());
+async fn accept_tcp(
+    listener: TcpListener,
+    index: usize,
+    connection: fn(TcpStream, SocketAddr) -> Connection,
+    sender: mpsc::Sender<(Connection, usize)>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                if sender
+                    .send((connection(stream, addr), index))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Err(e) => {
+                // e.g. too many open files, retried after a pause
+                println!("[Wirewave] failed to accept a connection: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+            }
+        }
+    }
+}
 
-            let acceptor = acceptor.clone();
+/// Binds a unix socket, replacing the one left by a previous run
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the path exists and is not a socket",
+            ));
+        }
 
-            let server_context = self.svc.inner.0.server_context().await;
+        std::fs::remove_file(path)?;
+    }
 
-            tokio::spawn(async move {
-                let mut stream = acceptor.accept(stream).await.unwrap();
+    let listener = UnixListener::bind(path)?;
 
-                let username = if server_context.require_authentication {
-                    let (status, username) = authentication_challenge(server, &mut stream).await;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
 
-                    if status != AuthenticationStatus::Authenticated {
-                        println!("[Wirewave] authentication failed: {:?}", status);
-                        stream.shutdown().await.unwrap();
+    Ok(listener)
+}
 
-                        return;
-                    }
+#[cfg(unix)]
+async fn accept_unix(
+    listener: UnixListener,
+    index: usize,
+    sender: mpsc::Sender<(Connection, usize)>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                if sender
+                    .send((Connection::Unix(stream), index))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Err(e) => {
+                println!("[Wirewave] failed to accept a connection: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+            }
+        }
+    }
+}
 
-                    username
-                } else {
-                    None
-                };
+// the peer credentials only exist for unix sockets
+#[cfg_attr(not(unix), allow(unused_variables))]
+#[allow(clippy::too_many_arguments)]
+async fn accept_connection<T: Wirewave>(
+    svc: WirewaveServer<T>,
+    provider: DefaultAuthenticationProvider,
+    server_context: ServerContext,
+    slot: ConnectionSlot,
+    connection: Connection,
+    acceptor: Option<TlsAcceptor>,
+    peer_credentials: bool,
+    signal: watch::Receiver<bool>,
+) {
+    let (stream, peer) = match connection {
+        Connection::Tcp(stream, addr) => (stream, Peer::Tcp(addr)),
+        Connection::WebSocket(stream, addr) => (stream, Peer::WebSocket(addr)),
+
+        #[cfg(unix)]
+        Connection::Unix(stream) => {
+            let uid = stream.peer_cred().ok().map(|cred| cred.uid());
+
+            let identity = if peer_credentials {
+                uid.and_then(os_username)
+            } else {
+                None
+            };
 
-                svc.inner.0.new_connection(username.clone(), addr).await;
+            let peer = Peer::Unix { uid };
+
+            return serve_connection(
+                svc,
+                provider,
+                server_context,
+                slot,
+                stream,
+                peer,
+                identity,
+                signal,
+            )
+            .await;
+        }
+    };
 
-                handle_connection(stream, move |request| {
-                    let svc = svc.clone();
-                    let username = username.clone();
-                    async move { svc.inner.0.request(request, username).await }
-                })
-                .await;
-            });
+    let acceptor = match acceptor {
+        Some(acceptor) => acceptor,
+        None => {
+            return serve_connection(
+                svc,
+                provider,
+                server_context,
+                slot,
+                stream,
+                peer,
+                None,
+                signal,
+            )
+            .await
+        }
+    };
+
+    let idle_timeout = server_context.limits.idle_timeout.map(Duration::from_secs);
+
+    let stream = match within(idle_timeout, acceptor.accept(stream)).await {
+        Some(Ok(stream)) => stream,
+        Some(Err(e)) => {
+            println!("[Wirewave] TLS handshake with {} failed: {}", peer, e);
+            return;
+        }
+        None => {
+            println!("[Wirewave] TLS handshake with {} timed out", peer);
+            return;
+        }
+    };
+
+    let identity = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(certificate_user);
+
+    serve_connection(
+        svc,
+        provider,
+        server_context,
+        slot,
+        stream,
+        peer,
+        identity,
+        signal,
+    )
+    .await;
+}
+
+/// The name of an OS user
+#[cfg(unix)]
+fn os_username(uid: u32) -> Option<String> {
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+
+    // SAFETY: the buffers outlive the call, and `pw_name` points into `buffer` on success
+    let code = unsafe {
+        libc::getpwuid_r(
+            uid,
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+
+    if code != 0 || result.is_null() {
+        return None;
+    }
+
+    let name = unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) };
+
+    name.to_str().ok().map(|name| name.to_string())
+}
+
+pub fn tls_acceptor(tls_config: &Tls) -> io::Result<TlsAcceptor> {
+    let certs = load_certs(&tls_config.ca_file)?;
+    let key = load_keys(&tls_config.pem_key_file)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no private key found"))?;
+
+    let builder = rustls::ServerConfig::builder().with_safe_defaults();
+
+    let builder = match &tls_config.client_ca_file {
+        Some(client_ca_file) => {
+            let mut roots = RootCertStore::empty();
+
+            for cert in load_certs(client_ca_file)? {
+                roots
+                    .add(&cert)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            }
+
+            let verifier = match tls_config.client_auth.clone().unwrap_or_default() {
+                ClientAuth::Required => AllowAnyAuthenticatedClient::new(roots),
+                ClientAuth::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+            };
+
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server_tls_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_tls_config)))
+}
+
+/// The user a verified client certificate maps to: the common name of its subject
+fn certificate_user(cert: &Certificate) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    let common_name = cert.subject().iter_common_name().next()?;
+
+    common_name.as_str().ok().map(|name| name.to_string())
+}
+
+/// Stops the server: no connection is accepted once `signal` is set, idle clients are
+/// told the server is shutting down, and the in-flight requests have `timeout` to finish.
+#[derive(Clone)]
+pub struct Shutdown {
+    pub signal: watch::Receiver<bool>,
+    pub timeout: Duration,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn serve_connection<T, IO>(
+    svc: WirewaveServer<T>,
+    provider: DefaultAuthenticationProvider,
+    server_context: ServerContext,
+    slot: ConnectionSlot,
+    stream: IO,
+    peer: Peer,
+    identity: Option<String>,
+    signal: watch::Receiver<bool>,
+) where
+    T: Wirewave,
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    if !matches!(peer, Peer::WebSocket(_)) {
+        return serve_wirewave(
+            svc,
+            provider,
+            server_context,
+            slot,
+            stream,
+            peer,
+            identity,
+            signal,
+        )
+        .await;
+    }
+
+    let idle_timeout = server_context.limits.idle_timeout.map(Duration::from_secs);
+
+    match within(idle_timeout, tokio_tungstenite::accept_async(stream)).await {
+        Some(Ok(socket)) => {
+            serve_wirewave(
+                svc,
+                provider,
+                server_context,
+                slot,
+                WebSocketIo::new(socket),
+                peer,
+                identity,
+                signal,
+            )
+            .await
+        }
+        Some(Err(e)) => println!("[Wirewave] WebSocket handshake with {} failed: {}", peer, e),
+        None => println!("[Wirewave] WebSocket handshake with {} timed out", peer),
+    }
+}
+
+/// Authenticates the client, then answers its requests until it leaves or the server stops
+#[allow(clippy::too_many_arguments)]
+async fn serve_wirewave<T, IO>(
+    svc: WirewaveServer<T>,
+    provider: DefaultAuthenticationProvider,
+    server_context: ServerContext,
+    mut slot: ConnectionSlot,
+    mut stream: IO,
+    peer: Peer,
+    identity: Option<String>,
+    signal: watch::Receiver<bool>,
+) where
+    T: Wirewave,
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let limits = server_context.limits.clone();
+
+    let idle_timeout = limits.idle_timeout.map(Duration::from_secs);
+
+    // the bypass is only honoured for the clients on this machine
+    let bypass = server_context.auth_bypass && peer.is_local();
+
+    if server_context.require_authentication && bypass {
+        println!("[Wirewave] authentication bypassed for {}", peer);
+    }
+
+    // a verified client certificate, or the OS user of a unix socket, stands for the
+    // user of the same name
+    let certified = match identity {
+        Some(identity) if provider.has_user(&identity) => Some(identity),
+        Some(identity) => {
+            println!(
+                "[Wirewave] the identity of {} names the unknown user {}",
+                peer, identity
+            );
+            None
+        }
+        None => None,
+    };
+
+    let username = if certified.is_some() {
+        certified
+    } else if server_context.require_authentication && !bypass {
+        let authentication = within(
+            idle_timeout,
+            authenticate(&server_context.auth_type, provider.clone(), &mut stream),
+        )
+        .await
+        .unwrap_or_else(|| Err(AuthFailure::ConnectionLost("idle timeout".to_string())));
+
+        let username = match authentication {
+            Ok(username) => username,
+            Err(failure) => {
+                println!("[Wirewave] authentication failed for {}: {}", peer, failure);
+                stream.shutdown().await.ok();
+
+                return;
+            }
+        };
+
+        provider.report_weak_hash(&username, server_context.iterations);
+
+        Some(username)
+    } else {
+        None
+    };
+
+    if let Some(username) = &username {
+        if let Err(error) = slot.authenticated(&limits, username) {
+            return refuse(stream, &peer, error).await;
         }
     }
+
+    // the anonymous clients share the bucket of their address
+    let client = username
+        .clone()
+        .or_else(|| peer.ip().map(|ip| ip.to_string()))
+        .unwrap_or_else(|| peer.to_string());
+
+    svc.inner.0.new_connection(username.clone(), peer).await;
+
+    let c_svc = svc.clone();
+    let c_username = username.clone();
+    let c_limiter = server_context.limiter.clone();
+    let c_limits = limits.clone();
+    let c_client = client.clone();
+    let limiter = server_context.limiter;
+    let cluster_user = server_context.cluster_user;
+    let request_timeout = limits.request_timeout;
+
+    handle_connection(
+        stream,
+        signal,
+        idle_timeout,
+        move |request| {
+            let svc = svc.clone();
+            let username = username.clone();
+            let allowed = limiter.allow_request(&limits, &client);
+
+            async move {
+                allowed?;
+
+                within(
+                    request_timeout.map(Duration::from_secs),
+                    svc.inner.0.request(request, username),
+                )
+                .await
+                .unwrap_or_else(|| Err(request_timed_out(request_timeout.unwrap_or_default())))
+            }
+        },
+        move |request| {
+            let svc = c_svc.clone();
+            let username = c_username.clone();
+
+            // the servers of a cluster, authenticated as its user, are not throttled
+            let allowed = match request.header.type_ {
+                Type::Cluster if c_username.is_some() && c_username == cluster_user => Ok(()),
+                _ => c_limiter.allow_request(&c_limits, &c_client),
+            };
+
+            async move {
+                allowed?;
+
+                match request.header.type_ {
+                    Type::Cluster => svc.inner.0.cluster(request, username).await,
+                    _ => svc.inner.0.subscribe(request, username).await,
+                }
+            }
+        },
+    )
+    .await;
+}
+
+/// Tells the client why it cannot be served, then closes the connection
+async fn refuse<IO>(mut stream: IO, peer: &Peer, error: Error)
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    println!("[Wirewave] refused {}: {}", peer, error.message);
+
+    write_socket(&mut stream, &error_response(error)).await.ok();
+    stream.shutdown().await.ok();
+}
+
+/// Runs `future`, giving up after `timeout` when set
+pub async fn within<F: Future>(timeout: Option<Duration>, future: F) -> Option<F::Output> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// The query keeps running on the pool, only its answer is dropped
+pub fn request_timed_out(seconds: u64) -> Error {
+    Error {
+        message: format!("the request took more than {} seconds", seconds),
+        query_message: None,
+        status: Status::Timeout,
+    }
+}
+
+async fn drain(mut connections: JoinSet<()>, timeout: Duration) {
+    println!(
+        "[Wirewave] waiting for {} connections to finish",
+        connections.len()
+    );
+
+    let finished = tokio::time::timeout(timeout, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+
+    if finished.is_err() {
+        println!(
+            "[Wirewave] {} connections did not finish in time, closing them",
+            connections.len()
+        );
+
+        connections.shutdown().await;
+    }
 }
 
 impl<T: Wirewave> Clone for WirewaveServer<T> {
@@ -198,7 +816,8 @@ pub enum Type {
     Query,      // query to database
     Ping,       // ping to server
     PreRequest, // pre request to server
-    Cluster,    // cluster request
+    Cluster,    // replication between the servers of a cluster
+    Subscribe,  // stream the changes of a database
 }
 
 // ----
@@ -237,16 +856,33 @@ pub enum Status {
     BadAuth,
     BadBody,
     NotAuthorized,
+    NotLeader,
     Reserved,
     SyntaxError,
+    TooManyConnections,
+    RateLimited,
+    QuotaExceeded,
+    SchemaViolation,
 
     // ----
     InternalError,
+    ShuttingDown,
+    Timeout,
 }
 
 #[derive(Debug, Clone)]
 pub struct ServerContext {
     pub require_authentication: bool,
+    /// Lets the clients connecting from localhost in without authenticating
+    pub auth_bypass: bool,
+    pub auth_type: AuthType,
+    /// The iterations the passwords are expected to be hashed with
+    pub iterations: u16,
+    pub limits: Limits,
+    /// Counts the connections and the requests against `limits`
+    pub limiter: Arc<Limiter>,
+    /// The user the servers of the cluster authenticate as, not rate limited
+    pub cluster_user: Option<String>,
 }
 
 // if is ok, return request else return response and send to client
@@ -270,6 +906,28 @@ fn process_request(buf: &[u8]) -> Result<Request, Response> {
     Ok(request)
 }
 
+fn error_response(error: Error) -> Response {
+    Response {
+        body: None,
+        header: ResHeader {
+            status: error.status,
+            messages: Some(vec![error.message]),
+            is_error: true,
+        },
+    }
+}
+
+fn shutdown_response() -> Response {
+    Response {
+        body: None,
+        header: ResHeader {
+            status: Status::ShuttingDown,
+            messages: Some(vec!["the server is shutting down".to_string()]),
+            is_error: true,
+        },
+    }
+}
+
 pub async fn read_socket<IO>(socket: &mut IO, buffer: &mut [u8]) -> io::Result<Vec<u8>>
 where
     IO: AsyncRead + AsyncWrite + Unpin,
@@ -303,16 +961,39 @@ where
     socket.write_all(&data).await
 }
 
-async fn handle_connection<F, Fut, IO>(mut socket: IO, callback: F)
-where
+async fn handle_connection<F, Fut, S, SFut, IO>(
+    mut socket: IO,
+    mut signal: watch::Receiver<bool>,
+    idle_timeout: Option<Duration>,
+    callback: F,
+    stream: S,
+) where
     F: Fn(Request) -> Fut,
     Fut: Future<Output = Result<Response, Error>>,
+    S: Fn(Request) -> SFut,
+    SFut: Future<Output = Result<mpsc::Receiver<Response>, Error>>,
     IO: AsyncRead + AsyncWrite + Unpin,
 {
     let mut buffer = vec![0; BUFFER_SIZE];
 
     loop {
-        let request_bytes = read_socket(&mut socket, &mut buffer).await.unwrap();
+        // a request in flight is answered before the shutdown is noticed
+        let request_bytes = tokio::select! {
+            read = within(idle_timeout, read_socket(&mut socket, &mut buffer)) => match read {
+                Some(read) => read.unwrap(),
+                // closed without a response, which a pooled client would take for the
+                // answer of its next request
+                None => {
+                    socket.shutdown().await.ok();
+                    return;
+                }
+            },
+            _ = signal.changed() => {
+                write_socket(&mut socket, &shutdown_response()).await.ok();
+                socket.shutdown().await.ok();
+                return;
+            }
+        };
 
         if request_bytes.is_empty() {
             break;
@@ -339,19 +1020,65 @@ where
                         continue;
                     }
 
+                    Type::Subscribe | Type::Cluster => {
+                        let acknowledge = matches!(request.header.type_, Type::Subscribe);
+
+                        let mut events = match stream(request).await {
+                            Ok(events) => events,
+                            Err(error) => {
+                                write_socket(&mut socket, &error_response(error)).await.ok();
+                                continue;
+                            }
+                        };
+
+                        if acknowledge {
+                            let ack = Response {
+                                body: None,
+                                header: ResHeader {
+                                    status: Status::Ok,
+                                    messages: None,
+                                    is_error: false,
+                                },
+                            };
+
+                            write_socket(&mut socket, &ack).await.ok();
+                        }
+
+                        // the connection only streams events until they end or the client closes it
+                        loop {
+                            tokio::select! {
+                                event = events.recv() => match event {
+                                    Some(event) => {
+                                        if write_socket(&mut socket, &event).await.is_err() {
+                                            return;
+                                        }
+                                    }
+                                    None => break,
+                                },
+
+                                read = socket.read(&mut buffer) => {
+                                    if matches!(read, Ok(0) | Err(_)) {
+                                        return;
+                                    }
+                                }
+
+                                _ = signal.changed() => {
+                                    write_socket(&mut socket, &shutdown_response()).await.ok();
+                                    socket.shutdown().await.ok();
+                                    return;
+                                }
+                            }
+                        }
+
+                        continue;
+                    }
+
                     _ => request,
                 };
 
                 let response = match callback(request).await {
                     Ok(response) => response,
-                    Err(error) => Response {
-                        body: None,
-                        header: ResHeader {
-                            status: error.status,
-                            messages: Some(vec![error.message]),
-                            is_error: true,
-                        },
-                    },
+                    Err(error) => error_response(error),
                 };
 
                 write_socket(&mut socket, &response).await.ok();
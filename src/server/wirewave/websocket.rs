@@ -0,0 +1,172 @@
+use futures_util::{Sink, Stream};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+
+/// Carries Wirewave over WebSocket: each write is sent as one binary message, and the
+/// messages received are read back to back, so the framing of a TCP stream is kept.
+pub struct WebSocketIo<IO> {
+    socket: WebSocketStream<IO>,
+    pending: Vec<u8>,
+    position: usize,
+}
+
+impl<IO> WebSocketIo<IO> {
+    pub fn new(socket: WebSocketStream<IO>) -> Self {
+        Self {
+            socket,
+            pending: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+fn io_error(error: tungstenite::Error) -> io::Error {
+    match error {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            io::Error::new(io::ErrorKind::BrokenPipe, error)
+        }
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+impl<IO> AsyncRead for WebSocketIo<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        while this.position == this.pending.len() {
+            let message = match ready!(Pin::new(&mut this.socket).poll_next(cx)) {
+                Some(message) => message.map_err(io_error)?,
+                // closed, read as the end of the stream
+                None => return Poll::Ready(Ok(())),
+            };
+
+            match message {
+                Message::Binary(data) => this.pending = data,
+                Message::Text(text) => this.pending = text.into_bytes(),
+                Message::Close(_) => return Poll::Ready(Ok(())),
+                // the pings are answered by tungstenite
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+            }
+
+            this.position = 0;
+        }
+
+        let n = buf.remaining().min(this.pending.len() - this.position);
+        buf.put_slice(&this.pending[this.position..this.position + n]);
+        this.position += n;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<IO> AsyncWrite for WebSocketIo<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut socket = Pin::new(&mut self.socket);
+
+        ready!(socket.as_mut().poll_ready(cx)).map_err(io_error)?;
+        socket
+            .as_mut()
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(io_error)?;
+
+        // sent right away, what is left is flushed by the next read or write
+        if let Poll::Ready(Err(e)) = socket.poll_flush(cx) {
+            return Poll::Ready(Err(io_error(e)));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket).poll_flush(cx).map_err(io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket).poll_close(cx).map_err(io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tungstenite::protocol::Role;
+
+    async fn pair() -> (
+        WebSocketIo<tokio::io::DuplexStream>,
+        WebSocketStream<tokio::io::DuplexStream>,
+    ) {
+        let (server, client) = tokio::io::duplex(4096);
+
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+
+        (WebSocketIo::new(server), client)
+    }
+
+    #[tokio::test]
+    async fn reads_the_messages_back_to_back() {
+        let (mut io, mut client) = pair().await;
+
+        client.send(Message::Binary(b"ab".to_vec())).await.unwrap();
+        client.send(Message::Ping(b"?".to_vec())).await.unwrap();
+        client.send(Message::Text("cd".to_string())).await.unwrap();
+        client.send(Message::Binary(b"ef".to_vec())).await.unwrap();
+
+        // a read smaller than a message keeps the rest for the next one
+        let mut first = [0; 3];
+        io.read_exact(&mut first).await.unwrap();
+        let mut rest = [0; 3];
+        io.read_exact(&mut rest).await.unwrap();
+
+        assert_eq!(&first, b"abc");
+        assert_eq!(&rest, b"def");
+    }
+
+    #[tokio::test]
+    async fn writes_one_binary_message_per_write() {
+        let (mut io, mut client) = pair().await;
+
+        io.write_all(b"hello").await.unwrap();
+        io.flush().await.unwrap();
+
+        match client.next().await {
+            Some(Ok(Message::Binary(data))) => assert_eq!(data, b"hello"),
+            message => panic!("expected a binary message, got {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_a_close_as_the_end_of_the_stream() {
+        let (mut io, mut client) = pair().await;
+
+        client.send(Message::Binary(b"x".to_vec())).await.unwrap();
+        client.close(None).await.unwrap();
+
+        let mut data = Vec::new();
+        io.read_to_end(&mut data).await.unwrap();
+
+        assert_eq!(data, b"x");
+    }
+}
@@ -0,0 +1,266 @@
+use bson::Decimal128;
+
+/// The BSON crate stores Decimal128 values but cannot read or write them as text, this
+/// converts them with the IEEE 754-2008 binary integer decimal encoding.
+const MAX_DIGITS: usize = 34;
+const EXPONENT_BIAS: i32 = 6176;
+const MAX_EXPONENT: i32 = 6111;
+const MIN_EXPONENT: i32 = -6176;
+
+const COEFFICIENT_BITS: u32 = 113;
+const NAN: u128 = 0x1F << 122;
+const INFINITY: u128 = 0x1E << 122;
+
+/// Parses a decimal like `1.10`, `-2.5E+3`, `NaN` or `Infinity`, keeping its digits: `1.10`
+/// stays `1.10`. The values that would need rounding are refused.
+pub fn parse(input: &str) -> Result<Decimal128, String> {
+    let input = input.trim();
+
+    let (negative, unsigned) = match input.as_bytes().first() {
+        Some(b'-') => (true, &input[1..]),
+        Some(b'+') => (false, &input[1..]),
+        _ => (false, input),
+    };
+
+    let sign = if negative { 1u128 << 127 } else { 0 };
+
+    if unsigned.eq_ignore_ascii_case("nan") {
+        return Ok(from_bits(NAN));
+    }
+
+    if unsigned.eq_ignore_ascii_case("infinity") || unsigned.eq_ignore_ascii_case("inf") {
+        return Ok(from_bits(sign | INFINITY));
+    }
+
+    let invalid = || format!("{} is not a decimal", input);
+
+    let (mantissa, exponent) = match unsigned.find(['e', 'E']) {
+        Some(index) => (
+            &unsigned[..index],
+            unsigned[index + 1..]
+                .parse::<i32>()
+                .map_err(|_| invalid())?,
+        ),
+        None => (unsigned, 0),
+    };
+
+    let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+
+    if integer.is_empty() && fraction.is_empty()
+        || !integer
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+    {
+        return Err(invalid());
+    }
+
+    let mut digits: String = format!("{}{}", integer, fraction)
+        .trim_start_matches('0')
+        .to_string();
+    let mut exponent = exponent
+        .checked_sub(fraction.len() as i32)
+        .ok_or_else(invalid)?;
+
+    // the trailing zeros can go into the exponent, the other digits cannot be dropped
+    while digits.len() > MAX_DIGITS && digits.ends_with('0') {
+        digits.pop();
+        exponent += 1;
+    }
+
+    while exponent < MIN_EXPONENT && digits.ends_with('0') {
+        digits.pop();
+        exponent += 1;
+    }
+
+    while exponent > MAX_EXPONENT && !digits.is_empty() && digits.len() < MAX_DIGITS {
+        digits.push('0');
+        exponent -= 1;
+    }
+
+    if digits.is_empty() {
+        // zero keeps its exponent, within the range
+        exponent = exponent.clamp(MIN_EXPONENT, MAX_EXPONENT);
+    }
+
+    if digits.len() > MAX_DIGITS {
+        return Err(format!("{} has more than {} digits", input, MAX_DIGITS));
+    }
+
+    if !(MIN_EXPONENT..=MAX_EXPONENT).contains(&exponent) {
+        return Err(format!("{} is out of the range of a decimal", input));
+    }
+
+    let coefficient: u128 = if digits.is_empty() {
+        0
+    } else {
+        digits.parse().map_err(|_| invalid())?
+    };
+
+    let biased = (exponent + EXPONENT_BIAS) as u128;
+
+    Ok(from_bits(sign | biased << COEFFICIENT_BITS | coefficient))
+}
+
+/// Formats a decimal the way it was written, in scientific notation when the exponent is large
+pub fn format(decimal: &Decimal128) -> String {
+    let bits = u128::from_le_bytes(decimal.bytes());
+    let sign = if bits >> 127 == 1 { "-" } else { "" };
+
+    if bits & NAN == NAN {
+        return "NaN".to_string();
+    }
+
+    if bits & NAN == INFINITY {
+        return format!("{}Infinity", sign);
+    }
+
+    let max_coefficient = 10u128.pow(MAX_DIGITS as u32) - 1;
+
+    let (biased, coefficient) = if (bits >> 125) & 0b11 == 0b11 {
+        // this form only holds coefficients too large to be valid, read as zero
+        ((bits >> 111) & 0x3FFF, 0)
+    } else {
+        (
+            (bits >> COEFFICIENT_BITS) & 0x3FFF,
+            bits & ((1 << COEFFICIENT_BITS) - 1),
+        )
+    };
+
+    let coefficient = if coefficient > max_coefficient {
+        0
+    } else {
+        coefficient
+    };
+
+    let exponent = biased as i32 - EXPONENT_BIAS;
+    let digits = coefficient.to_string();
+    let adjusted = exponent + digits.len() as i32 - 1;
+
+    let unsigned = if exponent <= 0 && adjusted >= -6 {
+        let point = digits.len() as i32 + exponent;
+
+        if exponent == 0 {
+            digits
+        } else if point > 0 {
+            format!(
+                "{}.{}",
+                &digits[..point as usize],
+                &digits[point as usize..]
+            )
+        } else {
+            format!("0.{}{}", "0".repeat(-point as usize), digits)
+        }
+    } else {
+        let (first, rest) = digits.split_at(1);
+        let rest = if rest.is_empty() {
+            String::new()
+        } else {
+            format!(".{}", rest)
+        };
+
+        format!("{}{}E{:+}", first, rest, adjusted)
+    };
+
+    format!("{}{}", sign, unsigned)
+}
+
+fn from_bits(bits: u128) -> Decimal128 {
+    Decimal128::from_bytes(bits.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &str) -> String {
+        format(&parse(input).unwrap())
+    }
+
+    #[test]
+    fn keeps_the_digits() {
+        assert_eq!(round_trip("1.10"), "1.10");
+        assert_eq!(round_trip("-2.5E+3"), "-2.5E+3");
+        assert_eq!(round_trip("0.000001"), "0.000001");
+        assert_eq!(round_trip("1E-7"), "1E-7");
+    }
+
+    #[test]
+    fn keeps_the_sign_of_zero() {
+        assert_eq!(round_trip("-0"), "-0");
+        assert_eq!(round_trip("0"), "0");
+        assert_eq!(round_trip("-0.00"), "-0.00");
+    }
+
+    #[test]
+    fn reaches_the_limits_of_the_exponent() {
+        assert_eq!(round_trip("1E+6111"), "1E+6111");
+        assert_eq!(round_trip("1E-6176"), "1E-6176");
+        assert_eq!(round_trip("-1E+6111"), "-1E+6111");
+    }
+
+    #[test]
+    fn keeps_34_digits() {
+        let digits = "1234567890123456789012345678901234";
+
+        assert_eq!(round_trip(digits), digits);
+        assert_eq!(
+            round_trip("9999999999999999999999999999999999"),
+            "9999999999999999999999999999999999"
+        );
+        assert_eq!(
+            round_trip("-1.234567890123456789012345678901234"),
+            "-1.234567890123456789012345678901234"
+        );
+    }
+
+    #[test]
+    fn reads_the_special_values() {
+        assert_eq!(round_trip("NaN"), "NaN");
+        assert_eq!(round_trip("Infinity"), "Infinity");
+        assert_eq!(round_trip("-Infinity"), "-Infinity");
+        assert_eq!(round_trip("-inf"), "-Infinity");
+    }
+
+    #[test]
+    fn moves_the_zeros_into_the_exponent() {
+        // the trailing zeros of a 35-digit coefficient are not lost
+        assert_eq!(
+            round_trip("12345678901234567890123456789012340"),
+            "1.234567890123456789012345678901234E+34"
+        );
+        // an exponent over the range is brought back by padding the coefficient
+        assert_eq!(
+            round_trip("1E+6144"),
+            "1.000000000000000000000000000000000E+6144"
+        );
+        assert_eq!(round_trip("0E+9999"), "0E+6111");
+    }
+
+    #[test]
+    fn refuses_the_values_needing_rounding() {
+        assert!(parse("12345678901234567890123456789012345").is_err());
+        assert!(parse("1.2345678901234567890123456789012345").is_err());
+        assert!(parse("1E+6145").is_err());
+        assert!(parse("1E-6177").is_err());
+        assert!(parse("1.5E-6176").is_err());
+    }
+
+    #[test]
+    fn refuses_what_is_not_a_decimal() {
+        for input in [
+            "",
+            ".",
+            "-",
+            "1.2.3",
+            "1e",
+            "1E+",
+            "abc",
+            "1,5",
+            "0x10",
+            "1E99999999999",
+        ] {
+            assert!(parse(input).is_err(), "{} was read", input);
+        }
+    }
+}
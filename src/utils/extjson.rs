@@ -0,0 +1,130 @@
+use bson::{Bson, Document};
+use serde_json::{Map, Value};
+
+use super::decimal;
+
+/// The relaxed extended JSON of a value. Unlike `Bson::into_relaxed_extjson`, the decimals
+/// are written as `{"$numberDecimal": "1.10"}` instead of panicking.
+pub fn to_relaxed(value: Bson) -> Value {
+    convert(value, Bson::into_relaxed_extjson)
+}
+
+/// The canonical extended JSON of a value, with the decimals as in `to_relaxed`
+pub fn to_canonical(value: Bson) -> Value {
+    convert(value, Bson::into_canonical_extjson)
+}
+
+fn convert(value: Bson, extjson: fn(Bson) -> Value) -> Value {
+    match value {
+        Bson::Decimal128(decimal) => {
+            serde_json::json!({ "$numberDecimal": decimal::format(&decimal) })
+        }
+        Bson::Array(items) => Value::Array(
+            items
+                .into_iter()
+                .map(|item| convert(item, extjson))
+                .collect(),
+        ),
+        Bson::Document(doc) => Value::Object(
+            doc.into_iter()
+                .map(|(key, value)| (key, convert(value, extjson)))
+                .collect(),
+        ),
+        value => extjson(value),
+    }
+}
+
+/// Reads extended JSON, including the `$numberDecimal` values the BSON crate refuses
+pub fn from_value(value: Value) -> Result<Bson, String> {
+    match value {
+        Value::Object(map) => from_object(map),
+        Value::Array(items) => items
+            .into_iter()
+            .map(from_value)
+            .collect::<Result<_, _>>()
+            .map(Bson::Array),
+        value => Bson::try_from(value).map_err(|e| e.to_string()),
+    }
+}
+
+fn from_object(map: Map<String, Value>) -> Result<Bson, String> {
+    if let Some(Value::String(decimal)) = map.get("$numberDecimal") {
+        if map.len() == 1 {
+            return decimal::parse(decimal).map(Bson::Decimal128);
+        }
+    }
+
+    // the other extended types ($oid, $date...) are read by the BSON crate
+    if map.keys().any(|key| key.starts_with('$')) {
+        return Bson::try_from(Value::Object(map)).map_err(|e| e.to_string());
+    }
+
+    map.into_iter()
+        .map(|(key, value)| from_value(value).map(|value| (key, value)))
+        .collect::<Result<Document, _>>()
+        .map(Bson::Document)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn decimal(input: &str) -> Bson {
+        Bson::Decimal128(decimal::parse(input).unwrap())
+    }
+
+    #[test]
+    fn writes_the_decimals_as_text() {
+        let value = Bson::Document(bson::doc! { "price": decimal("1.10"), "n": [decimal("-0")] });
+
+        assert_eq!(
+            to_relaxed(value.clone()),
+            json!({ "price": { "$numberDecimal": "1.10" }, "n": [{ "$numberDecimal": "-0" }] })
+        );
+        assert_eq!(
+            to_canonical(value),
+            json!({ "price": { "$numberDecimal": "1.10" }, "n": [{ "$numberDecimal": "-0" }] })
+        );
+    }
+
+    #[test]
+    fn round_trips_the_decimals() {
+        for input in [
+            "1.10",
+            "-0",
+            "1E+6111",
+            "1E-6176",
+            "1234567890123456789012345678901234",
+            "NaN",
+            "Infinity",
+            "-Infinity",
+        ] {
+            let value = decimal(input);
+            let read = from_value(to_relaxed(value.clone())).unwrap();
+
+            assert_eq!(read, value, "{}", input);
+            assert_eq!(to_relaxed(read), json!({ "$numberDecimal": input }));
+        }
+    }
+
+    #[test]
+    fn refuses_the_decimals_needing_rounding() {
+        assert!(
+            from_value(json!({ "$numberDecimal": "12345678901234567890123456789012345" })).is_err()
+        );
+        assert!(from_value(json!({ "a": [{ "$numberDecimal": "1E-6177" }] })).is_err());
+    }
+
+    #[test]
+    fn reads_the_other_extended_types() {
+        assert_eq!(
+            from_value(json!({ "id": { "$oid": "507f1f77bcf86cd799439011" }, "n": 1 })).unwrap(),
+            Bson::Document(bson::doc! {
+                "id": bson::oid::ObjectId::parse_str("507f1f77bcf86cd799439011").unwrap(),
+                "n": 1,
+            })
+        );
+        assert!(from_value(json!({ "$oid": "nope" })).is_err());
+    }
+}
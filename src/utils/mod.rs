@@ -1,3 +1,6 @@
+pub mod decimal;
+pub mod extjson;
+
 use std::path::{Path, PathBuf};
 
 pub fn get_current_path() -> PathBuf {